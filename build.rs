@@ -1,5 +1,35 @@
+extern crate cc;
 extern crate lalrpop;
 
+use std::env;
+use std::process::Command;
+
 fn main() {
+    // printing any `rerun-if-changed` stops cargo from rerunning this on every change
+    println!("cargo:rerun-if-changed=src/parser/grammar.lalrpop");
     lalrpop::process_root().unwrap();
+
+    if env::var_os("CARGO_FEATURE_LLVM").is_some() {
+        build_llvm_wrapper();
+    }
+}
+
+/// Compile the bits of the LLVM backend the C API doesn't have, against the same LLVM llvm-sys uses.
+fn build_llvm_wrapper() {
+    println!("cargo:rerun-if-changed=src/backend/llvm/wrapper.cpp");
+    println!("cargo:rerun-if-env-changed=LLVM_SYS_130_PREFIX");
+    let llvm_config = match env::var("LLVM_SYS_130_PREFIX") {
+        Ok(prefix) => format!("{}/bin/llvm-config", prefix),
+        Err(_) => "llvm-config".to_string(),
+    };
+    let output = Command::new(&llvm_config).arg("--cxxflags").output()
+        .unwrap_or_else(|e| panic!("couldn't run {}: {}", llvm_config, e));
+    let cxxflags = String::from_utf8(output.stdout).unwrap();
+
+    let mut build = cc::Build::new();
+    build.cpp(true).file("src/backend/llvm/wrapper.cpp").warnings(false);
+    for flag in cxxflags.split_whitespace() {
+        build.flag(flag);
+    }
+    build.compile("nuvae_llvm_wrapper");
 }
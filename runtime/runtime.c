@@ -0,0 +1,28 @@
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+/*
+ * Runtime support linked into every nuvae executable.
+ */
+
+/* Allocators are opaque to the runtime for now, so every allocation comes from the system heap. */
+void *nuvae_alloc(void *allocator, int64_t size) {
+    (void) allocator;
+    return malloc((size_t) size);
+}
+
+/* `print` writes the bytes of a `Str`, which don't end with a zero byte. */
+void nuvae_print(const uint8_t *data, int64_t len) {
+    fwrite(data, 1, (size_t) len, stdout);
+}
+
+/* Called when a program can't continue, like when arithmetic overflows. The message starts with
+ * where in the source it happened. */
+void nuvae_panic(const uint8_t *message, int64_t len) {
+    fflush(stdout);
+    fputs("panic: ", stderr);
+    fwrite(message, 1, (size_t) len, stderr);
+    fputc('\n', stderr);
+    abort();
+}
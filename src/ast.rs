@@ -1,4 +1,5 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Formatter;
 use generational_arena::{Arena, Index};
@@ -8,6 +9,7 @@ pub type TypeIndex = Index;
 pub type NodeIndex = Index;
 pub type StatementIndex = Index;
 pub type ExpressionIndex = Index;
+pub type Span = codespan::Span;
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Path(pub Vec<String>);
@@ -42,6 +44,13 @@ pub struct ProgramArena {
     pub node_arena: Arena<Node>,
     pub statement_arena: Arena<Statement>,
     pub expression_arena: Arena<Expression>,
+    pub node_spans: HashMap<NodeIndex, Span>,
+    pub statement_spans: HashMap<StatementIndex, Span>,
+    pub expression_spans: HashMap<ExpressionIndex, Span>,
+    /// The `///` comments before nodes, without the slashes.
+    pub node_docs: HashMap<NodeIndex, String>,
+    /// The name of the struct each node declared inside a struct belongs to.
+    pub node_owners: HashMap<NodeIndex, String>,
 }
 
 impl ProgramArena {
@@ -51,6 +60,11 @@ impl ProgramArena {
             node_arena: Arena::new(),
             statement_arena: Arena::new(),
             expression_arena: Arena::new(),
+            node_spans: HashMap::new(),
+            statement_spans: HashMap::new(),
+            expression_spans: HashMap::new(),
+            node_docs: HashMap::new(),
+            node_owners: HashMap::new(),
         }
     }
 }
@@ -59,6 +73,9 @@ impl ProgramArena {
 pub struct Program {
     pub path: Path,
     pub file_name: String,
+    /// Name of the source file as it was given to the parser.
+    pub source_name: String,
+    pub source: String,
     pub imports: Vec<Path>,
     pub program_arena: ProgramArena,
 }
@@ -71,6 +88,23 @@ impl Program {
     pub fn expression(&self, index: ExpressionIndex) -> &Expression {
         self.program_arena.expression_arena.get(index).unwrap()
     }
+
+    pub fn node_span(&self, index: NodeIndex) -> Option<Span> {
+        self.program_arena.node_spans.get(&index).copied()
+    }
+
+    /// The struct a node is declared in, if it isn't at the top level.
+    pub fn node_owner(&self, index: NodeIndex) -> Option<&str> {
+        self.program_arena.node_owners.get(&index).map(String::as_str)
+    }
+
+    pub fn statement_span(&self, index: StatementIndex) -> Option<Span> {
+        self.program_arena.statement_spans.get(&index).copied()
+    }
+
+    pub fn expression_span(&self, index: ExpressionIndex) -> Option<Span> {
+        self.program_arena.expression_spans.get(&index).copied()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -87,6 +121,10 @@ pub enum Type {
     Reference(TypeIndex, bool),
     Optional(TypeIndex),
     Function(Vec<TypeIndex>, TypeIndex),
+    /// `[T; N]`, `N` values of type `T`.
+    Array(TypeIndex, u128),
+    /// `[]T`, a view of values of type `T` that knows how many there are.
+    Slice(TypeIndex),
 }
 
 #[derive(Clone, Debug)]
@@ -126,11 +164,32 @@ pub enum Access {
     Internal,
 }
 
+/// An attribute written before a function, like `@inline`.
+#[derive(Clone, Debug)]
+pub struct AstAttribute {
+    pub name: String,
+    pub span: Span,
+}
+
+/// How a method takes the value it's called on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Receiver {
+    /// `self`
+    Value,
+    /// `&self`
+    Ref,
+    /// `&mut self`
+    RefMut,
+}
+
 #[derive(Clone, Debug)]
 pub struct AstFunction {
     pub access: Access,
+    pub attributes: Vec<AstAttribute>,
     pub name: String,
     pub type_params: Vec<TypedName>,
+    /// The `self` a method takes before its other parameters.
+    pub receiver: Option<Receiver>,
     pub params: Vec<TypedName>,
     pub return_type: TypeIndex,
     pub statements: Vec<StatementIndex>,
@@ -153,6 +212,7 @@ pub enum Node {
     FunctionPrototype {
         name: String,
         type_params: Vec<TypedName>,
+        receiver: Option<Receiver>,
         params: Vec<TypedName>,
         return_type: TypeIndex,
     },
@@ -181,6 +241,9 @@ pub enum Node {
 pub struct EnumVariant {
     pub name: String,
     pub params: Vec<TypedName>,
+    pub span: Span,
+    /// The `///` comment before the variant, without the slashes.
+    pub doc: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -194,12 +257,17 @@ pub enum Statement {
         function: ExpressionIndex,
         args: Vec<ExpressionIndex>,
     },
+    /// An expression that's evaluated for what it does rather than its value, like a method call.
+    Expression {
+        value: ExpressionIndex,
+    },
     Let {
         name: TypedName,
         value: ExpressionIndex,
     },
+    /// Assignment to a variable, a field or what a pointer points to.
     Assign {
-        name: String,
+        place: ExpressionIndex,
         value: ExpressionIndex,
     },
     Return {
@@ -210,9 +278,15 @@ pub enum Statement {
 #[derive(Clone, Debug)]
 pub enum Expression {
     Ref(String),
-    NatLiteral(i64),
+    /// An integer literal, which takes the type of what it's used with and has to fit it.
+    NatLiteral(u128),
+    FloatLiteral(f64),
     BoolLiteral(bool),
+    /// A string literal, with its escapes already replaced.
+    StrLiteral(String),
+    CharLiteral(char),
     BinOp(ExpressionIndex, BinOpType, ExpressionIndex),
+    UnaryOp(UnaryOpType, ExpressionIndex),
     FieldAccessor {
         aggregate: ExpressionIndex,
         value: ExpressionIndex,
@@ -225,14 +299,55 @@ pub enum Expression {
         typ: TypeIndex,
         allocator: ExpressionIndex,
     },
+    /// `Box { x: 1 }`, which makes a struct with every field given, or `new Box { x: 1 } in a`,
+    /// which makes it with an allocator.
+    StructLiteral {
+        typ: TypeIndex,
+        fields: Vec<(String, ExpressionIndex)>,
+        allocator: Option<ExpressionIndex>,
+    },
+    /// `{ x: 1, y: 2 }`, which makes a row.
+    RowLiteral {
+        fields: Vec<(String, ExpressionIndex)>,
+    },
+    /// `[1, 2, 3]`, which makes an array with the default allocator.
+    ArrayLiteral {
+        values: Vec<ExpressionIndex>,
+    },
+    /// `a[i]`, an element of an array or slice.
+    Index {
+        aggregate: ExpressionIndex,
+        index: ExpressionIndex,
+    },
     Dereference {
         pointer: ExpressionIndex,
     },
     Denull {
         optional: ExpressionIndex,
     },
+    /// `value.&`, or `value.&mut` when the reference can be written through.
     Borrow {
         value: ExpressionIndex,
+        mutable: bool,
+    },
+    /// `value as typ`, which converts between integer and float types.
+    Cast {
+        value: ExpressionIndex,
+        typ: TypeIndex,
+    },
+    /// A number literal with a suffix, like `7u8`, which has the type the suffix stands for.
+    Suffixed {
+        literal: ExpressionIndex,
+        typ: TypeIndex,
+    },
+    /// `|x, acc| x + acc` or `fun(x) { ... }`, a function that captures the variables it uses
+    /// from the one it's in. `|...|` lambdas return their body. Made with the default allocator,
+    /// or with the one given with `new |x| x + 1 in a`.
+    Lambda {
+        params: Vec<TypedName>,
+        return_type: Option<TypeIndex>,
+        statements: Vec<StatementIndex>,
+        allocator: Option<ExpressionIndex>,
     },
 }
 
@@ -248,6 +363,13 @@ impl Expression {
                     format!("{}", self)
                 }
             }
+            Expression::UnaryOp(o, a) => {
+                if let Some(a_exp) = program_arena.expression_arena.get(*a) {
+                    format!("({} {})", o, a_exp.to_string(program_arena))
+                } else {
+                    format!("{}", self)
+                }
+            }
             Expression::FieldAccessor { aggregate, value } => {
                 let agg_opt = program_arena.expression_arena.get(*aggregate);
                 let value_opt = program_arena.expression_arena.get(*value);
@@ -280,14 +402,27 @@ impl fmt::Display for Expression {
             Expression::NatLiteral(n) => {
                 write!(f, "{}", n)
             }
+            Expression::FloatLiteral(n) => {
+                write!(f, "{:?}", n)
+            }
             Expression::BoolLiteral(b) => {
                 write!(f, "{}", b)
             }
+            Expression::StrLiteral(s) => {
+                write!(f, "{:?}", s)
+            }
+            Expression::CharLiteral(c) => {
+                write!(f, "{:?}", c)
+            }
             Expression::BinOp(a, o, b) => {
                 let (a_index, _) = a.into_raw_parts();
                 let (b_index, _) = b.into_raw_parts();
                 write!(f, "#{} {} #{}", a_index, o, b_index)
             }
+            Expression::UnaryOp(o, a) => {
+                let (a_index, _) = a.into_raw_parts();
+                write!(f, "{} #{}", o, a_index)
+            }
             Expression::FieldAccessor { aggregate, value } => {
                 let (agg_index, _) = aggregate.into_raw_parts();
                 let (value_index, _) = value.into_raw_parts();
@@ -303,6 +438,31 @@ impl fmt::Display for Expression {
                 let (allocator_index, _) = allocator.into_raw_parts();
                 write!(f, "new #{} in #{}", type_index, allocator_index)
             }
+            Expression::StructLiteral { typ, fields, allocator } => {
+                let (type_index, _) = typ.into_raw_parts();
+                let fields: Vec<String> = fields.iter()
+                    .map(|(name, value)| format!("{}: #{}", name, value.into_raw_parts().0))
+                    .collect();
+                match allocator {
+                    Some(allocator) => write!(f, "new #{} {{{}}} in #{}", type_index, fields.join(", "), allocator.into_raw_parts().0),
+                    None => write!(f, "#{} {{{}}}", type_index, fields.join(", ")),
+                }
+            }
+            Expression::RowLiteral { fields } => {
+                let fields: Vec<String> = fields.iter()
+                    .map(|(name, value)| format!("{}: #{}", name, value.into_raw_parts().0))
+                    .collect();
+                write!(f, "{{{}}}", fields.join(", "))
+            }
+            Expression::ArrayLiteral { values } => {
+                let values: Vec<String> = values.iter().map(|value| format!("#{}", value.into_raw_parts().0)).collect();
+                write!(f, "[{}]", values.join(", "))
+            }
+            Expression::Index { aggregate, index } => {
+                let (agg_index, _) = aggregate.into_raw_parts();
+                let (index_index, _) = index.into_raw_parts();
+                write!(f, "#{}[#{}]", agg_index, index_index)
+            }
             Expression::Dereference { pointer } => {
                 let (pointer_index, _) = pointer.into_raw_parts();
                 write!(f, "{}.*", pointer_index)
@@ -311,9 +471,27 @@ impl fmt::Display for Expression {
                 let (optional_index, _) = optional.into_raw_parts();
                 write!(f, "{}.?", optional_index)
             }
-            Expression::Borrow { value } => {
+            Expression::Borrow { value, mutable } => {
+                let (value_index, _) = value.into_raw_parts();
+                write!(f, "{}.&{}", value_index, if *mutable { "mut" } else { "" })
+            }
+            Expression::Cast { value, typ } => {
                 let (value_index, _) = value.into_raw_parts();
-                write!(f, "{}.&", value_index)
+                let (type_index, _) = typ.into_raw_parts();
+                write!(f, "#{} as #{}", value_index, type_index)
+            }
+            Expression::Suffixed { literal, typ } => {
+                let (literal_index, _) = literal.into_raw_parts();
+                let (type_index, _) = typ.into_raw_parts();
+                write!(f, "#{}: #{}", literal_index, type_index)
+            }
+            Expression::Lambda { params, statements, allocator, .. } => {
+                let params: Vec<&str> = params.iter().map(|param| param.name.as_str()).collect();
+                let statements: Vec<usize> = statements.iter().map(|statement| statement.into_raw_parts().0).collect();
+                match allocator {
+                    Some(allocator) => write!(f, "new |{}| {:?} in #{}", params.join(", "), statements, allocator.into_raw_parts().0),
+                    None => write!(f, "|{}| {:?}", params.join(", "), statements),
+                }
             }
         }
     }
@@ -321,16 +499,86 @@ impl fmt::Display for Expression {
 
 #[derive(Copy, Clone, Debug)]
 pub enum BinOpType {
+    /// Integer addition, subtraction and multiplication trap when the result doesn't fit its type,
+    /// unless overflow checks are off and they wrap.
     Plus,
     Minus,
     Star,
+    /// Integer division and remainder trap when dividing by zero, and when a signed division
+    /// overflows because the smallest value of a type is divided by -1.
     ForwardSlash,
     LessThan,
     GreaterThan,
     LessThanEqualTo,
     GreaterThanEqualTo,
+    EqualTo,
+    NotEqualTo,
+    /// The remainder of a division, which has the sign of the dividend. For floats it's
+    /// `x - trunc(x / y) * y`.
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
+    /// Shifts have the type of their left operand, and shift by the right one modulo its width.
+    ShiftLeft,
+    /// An arithmetic shift for signed integers and a logical one for unsigned integers.
+    ShiftRight,
     And,
     Or,
+    /// `+%`, `-%` and `*%` always wrap around in two's complement.
+    WrappingPlus,
+    WrappingMinus,
+    WrappingStar,
+    /// `+|`, `-|` and `*|` clamp the result to the smallest or largest value of its type.
+    SaturatingPlus,
+    SaturatingMinus,
+    SaturatingStar,
+}
+
+/// What an integer operator does when its result doesn't fit its type.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    Trap,
+    Wrap,
+    Saturate,
+}
+
+impl BinOpType {
+    /// Split an operator that can overflow into the plain arithmetic operator and what it does on
+    /// overflow. Floats don't overflow, so for them only the plain operator matters.
+    pub fn overflow(&self) -> Option<(BinOpType, Overflow)> {
+        match self {
+            BinOpType::Plus | BinOpType::Minus | BinOpType::Star => Some((*self, Overflow::Trap)),
+            BinOpType::WrappingPlus => Some((BinOpType::Plus, Overflow::Wrap)),
+            BinOpType::WrappingMinus => Some((BinOpType::Minus, Overflow::Wrap)),
+            BinOpType::WrappingStar => Some((BinOpType::Star, Overflow::Wrap)),
+            BinOpType::SaturatingPlus => Some((BinOpType::Plus, Overflow::Saturate)),
+            BinOpType::SaturatingMinus => Some((BinOpType::Minus, Overflow::Saturate)),
+            BinOpType::SaturatingStar => Some((BinOpType::Star, Overflow::Saturate)),
+            _ => None,
+        }
+    }
+
+    /// The wrapping version of an arithmetic operator, which is what it means with overflow
+    /// checks turned off.
+    pub fn wrapping(&self) -> BinOpType {
+        match self {
+            BinOpType::Plus => BinOpType::WrappingPlus,
+            BinOpType::Minus => BinOpType::WrappingMinus,
+            BinOpType::Star => BinOpType::WrappingStar,
+            op => *op,
+        }
+    }
+
+    /// Whether the operator compares its operands and results in a `Bool`.
+    pub fn is_comparison(&self) -> bool {
+        matches!(self, BinOpType::LessThan | BinOpType::GreaterThan | BinOpType::LessThanEqualTo
+            | BinOpType::GreaterThanEqualTo | BinOpType::EqualTo | BinOpType::NotEqualTo)
+    }
+
+    pub fn is_shift(&self) -> bool {
+        matches!(self, BinOpType::ShiftLeft | BinOpType::ShiftRight)
+    }
 }
 
 impl fmt::Display for BinOpType {
@@ -344,8 +592,53 @@ impl fmt::Display for BinOpType {
             BinOpType::GreaterThan => ">",
             BinOpType::LessThanEqualTo => "<=",
             BinOpType::GreaterThanEqualTo => ">=",
+            BinOpType::EqualTo => "==",
+            BinOpType::NotEqualTo => "!=",
+            BinOpType::Percent => "%",
+            BinOpType::Ampersand => "&",
+            BinOpType::Pipe => "|",
+            BinOpType::Caret => "^",
+            BinOpType::ShiftLeft => "<<",
+            BinOpType::ShiftRight => ">>",
             BinOpType::And => "and",
             BinOpType::Or => "or",
+            BinOpType::WrappingPlus => "+%",
+            BinOpType::WrappingMinus => "-%",
+            BinOpType::WrappingStar => "*%",
+            BinOpType::SaturatingPlus => "+|",
+            BinOpType::SaturatingMinus => "-|",
+            BinOpType::SaturatingStar => "*|",
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum UnaryOpType {
+    /// Negation. For signed integers it traps when negating the smallest value of the type,
+    /// unless overflow checks are off and it wraps. Unsigned integers always wrap.
+    Minus,
+    /// Negation that always wraps, which is what `-` means with overflow checks turned off.
+    WrappingMinus,
+    /// Logical negation of a value's truthiness, like `and` and `or`.
+    Not,
+}
+
+impl UnaryOpType {
+    /// The wrapping version of an operator, which is what it means with overflow checks turned off.
+    pub fn wrapping(&self) -> UnaryOpType {
+        match self {
+            UnaryOpType::Minus => UnaryOpType::WrappingMinus,
+            op => *op,
+        }
+    }
+}
+
+impl fmt::Display for UnaryOpType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            UnaryOpType::Minus => "-",
+            UnaryOpType::WrappingMinus => "-%",
+            UnaryOpType::Not => "not",
         })
     }
 }
@@ -1 +1,45 @@
-mod llvm;
+use crate::ir::Module;
+
+pub mod c;
+pub mod cranelift;
+pub mod interpreter;
+pub mod linker;
+#[cfg(feature = "llvm")]
+pub mod llvm;
+pub mod wasm;
+
+/// Name of the runtime function that `new T in allocator` is lowered to.
+/// Its signature is `i8* nuvae_alloc(i8* allocator, i64 size)`.
+pub const ALLOC_FUNCTION: &str = "nuvae_alloc";
+
+/// Name of the runtime function that `print` is lowered to.
+/// Its signature is `void nuvae_print(i8* data, i64 len)`.
+pub const PRINT_FUNCTION: &str = "nuvae_print";
+
+/// Name of the runtime function that is called when a program can't continue, like when arithmetic
+/// overflows or an index is out of bounds. Its signature is `void nuvae_panic(i8* message, i64 len)`,
+/// the message starts with where in the source it happened, and it doesn't return.
+pub const PANIC_FUNCTION: &str = "nuvae_panic";
+
+/// What a backend produced for a module. The compiler decides how to write it out,
+/// which may mean handing it to the system linker or C compiler.
+pub enum Artifact {
+    /// An object file for the target.
+    Object(Vec<u8>),
+    /// Assembly for the target.
+    #[cfg(feature = "llvm")]
+    Assembly(String),
+    /// Textual LLVM IR.
+    #[cfg(feature = "llvm")]
+    LlvmIr(String),
+    /// C99 source, which still has to go through a C compiler unless `--emit=c` was given.
+    C(String),
+    /// A WebAssembly module in the binary format.
+    Wasm(Vec<u8>),
+}
+
+/// A code generator that can be plugged into the compiler. Implementations are created
+/// from the options and pick the artifact they produce based on `--emit`.
+pub trait Backend {
+    fn compile_module(&mut self, module: &Module) -> Result<Artifact, String>;
+}
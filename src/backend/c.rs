@@ -0,0 +1,1239 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use crate::ast::{BinOpType, Overflow, UnaryOpType};
+use crate::backend::{Artifact, Backend, ALLOC_FUNCTION, PANIC_FUNCTION, PRINT_FUNCTION};
+use crate::ir::{FloatTy, IntTy, IrBlockIndex, IrEnumVariant, IrFunction, IrInstruction, IrInstructionIndex, IrNode, IrNodeIndex, IrType, IrTypeIndex, IrTypedName, Module, UIntTy};
+use crate::options::{EmitKind, Options};
+
+/// Identifiers that can't be used for nuvae names in C. Besides the keywords this has the
+/// names from the headers the output includes and common library functions, which compilers
+/// warn about redeclaring.
+const RESERVED: &[&str] = &[
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else", "enum",
+    "extern", "float", "for", "goto", "if", "inline", "int", "long", "register", "restrict", "return",
+    "short", "signed", "sizeof", "static", "struct", "switch", "typedef", "union", "unsigned", "void",
+    "volatile", "while", "bool", "true", "false", "main",
+    "int8_t", "int16_t", "int32_t", "int64_t", "uint8_t", "uint16_t", "uint32_t", "uint64_t",
+    "intptr_t", "uintptr_t",
+    "abort", "abs", "calloc", "exit", "free", "malloc", "memcmp", "memcpy", "memmove", "memset",
+    "printf", "putchar", "puts", "realloc", "strcmp", "strcpy", "strlen",
+    "ceil", "cos", "exp", "fabs", "floor", "fmax", "fmin", "log", "pow", "round", "sin", "sqrt", "tan",
+];
+
+/// Write bytes as the inside of a C string literal. Octal escapes always have three digits so
+/// a digit after one can't become part of it.
+fn string_literal(bytes: &[u8]) -> String {
+    let mut literal = String::with_capacity(bytes.len());
+    for byte in bytes {
+        match byte {
+            b'"' | b'\\' | b'?' => {
+                literal.push('\\');
+                literal.push(*byte as char);
+            }
+            b'\n' => literal.push_str("\\n"),
+            0x20..=0x7e => literal.push(*byte as char),
+            _ => write!(literal, "\\{:03o}", byte).unwrap(),
+        }
+    }
+    literal
+}
+
+/// Turn a nuvae name into a C identifier that doesn't clash with C itself. Methods, which are
+/// named `Owner::name`, become `Owner__name`.
+fn identifier(name: &str) -> String {
+    if RESERVED.contains(&name) {
+        format!("{}_", name)
+    } else {
+        name.replace("::", "__")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum CKind {
+    Void,
+    Bool,
+    Integer { bits: u32, signed: bool },
+    Float,
+    /// Pointers, along with what they point to when that's known.
+    Pointer(Option<Box<CType>>),
+    Aggregate,
+}
+
+/// A C type. Every type has a name that can be put in front of a declarator, complex types
+/// like function pointers get a typedef.
+#[derive(Clone, Debug, PartialEq)]
+struct CType {
+    name: String,
+    kind: CKind,
+}
+
+impl CType {
+    fn new(name: &str, kind: CKind) -> Self {
+        Self { name: name.to_string(), kind }
+    }
+
+    fn integer(bits: u32, signed: bool) -> Self {
+        let name = if signed { format!("int{}_t", bits) } else { format!("uint{}_t", bits) };
+        Self { name, kind: CKind::Integer { bits, signed } }
+    }
+
+    fn pointer_to(self) -> Self {
+        let name = if self.name.ends_with('*') { format!("{}*", self.name) } else { format!("{} *", self.name) };
+        Self { name, kind: CKind::Pointer(Some(Box::new(self))) }
+    }
+
+    /// Declare a variable, parameter or field of this type.
+    fn declare(&self, name: &str) -> String {
+        if self.name.ends_with('*') {
+            format!("{}{}", self.name, name)
+        } else {
+            format!("{} {}", self.name, name)
+        }
+    }
+}
+
+/// A C expression for an instruction's value, along with its IR type when it's known.
+#[derive(Clone, Debug)]
+struct Value {
+    expr: String,
+    c_type: CType,
+    typ: Option<IrTypeIndex>,
+    /// Integer and float literals don't have a type until they're used.
+    literal: bool,
+}
+
+impl Value {
+    fn new(expr: String, c_type: CType, typ: Option<IrTypeIndex>) -> Self {
+        Self { expr, c_type, typ, literal: false }
+    }
+
+    fn is_float(&self) -> bool {
+        self.c_type.kind == CKind::Float
+    }
+}
+
+/// A `let` binding or parameter.
+#[derive(Clone)]
+struct Local {
+    c_name: String,
+    c_type: CType,
+    typ: Option<IrTypeIndex>,
+}
+
+/// State that only lives while a single function is being emitted.
+struct FunctionState {
+    return_type: CType,
+    locals: HashMap<String, Local>,
+    values: HashMap<IrInstructionIndex, Value>,
+    /// C names that are already used in the function.
+    names: HashSet<String>,
+    /// Instructions whose values are used by another instruction.
+    used_values: HashSet<IrInstructionIndex>,
+    /// Names read by a `Ref` somewhere in the function.
+    used_names: HashSet<String>,
+    /// The places stores write to and borrows take the address of, which are lvalues rather than values.
+    places: HashSet<IrInstructionIndex>,
+    labels: HashMap<IrBlockIndex, String>,
+    body: String,
+}
+
+impl FunctionState {
+    /// Get a C name based on `name` that isn't used yet in this function.
+    fn fresh_name(&mut self, name: &str, globals: &HashSet<String>) -> String {
+        let base = identifier(name);
+        let mut c_name = base.clone();
+        let mut i = 1;
+        while self.names.contains(&c_name) || globals.contains(&c_name) {
+            c_name = format!("{}_{}", base, i);
+            i += 1;
+        }
+        self.names.insert(c_name.clone());
+        c_name
+    }
+
+    fn line(&mut self, line: &str) {
+        self.body.push_str("    ");
+        self.body.push_str(line);
+        self.body.push('\n');
+    }
+}
+
+/// Plugs the C backend into the compiler. The C compiler used to build the output
+/// decides the target, so `--target` isn't supported.
+pub struct CCodegen<'options> {
+    options: &'options Options,
+}
+
+impl<'o> CCodegen<'o> {
+    pub fn new(options: &'o Options) -> Self {
+        Self { options }
+    }
+}
+
+impl Backend for CCodegen<'_> {
+    fn compile_module(&mut self, module: &Module) -> Result<Artifact, String> {
+        if self.options.target.is_some() {
+            return Err("the C backend compiles for the target of the C compiler, pass a cross compiler with --linker".to_string());
+        }
+        match self.options.emit {
+            EmitKind::LlvmIr | EmitKind::Wasm | EmitKind::Wat => Err(format!("the C backend can't emit {:?}", self.options.emit)),
+            _ => CBackend::new(module).compile().map(Artifact::C),
+        }
+    }
+}
+
+/// Backend that translates a module into portable C99.
+pub struct CBackend<'module> {
+    module: &'module Module,
+    type_cache: HashMap<IrTypeIndex, CType>,
+    /// Typedefs for rows, optionals and function pointers, by the layout they were made for.
+    derived_types: HashMap<String, CType>,
+    struct_names: Vec<String>,
+    type_definitions: String,
+    struct_definitions: String,
+    /// The `Str`s of string literals.
+    constants: String,
+    prototypes: String,
+    definitions: String,
+    functions: HashMap<IrNodeIndex, String>,
+    function_names: HashMap<String, IrNodeIndex>,
+    global_names: HashSet<String>,
+}
+
+impl<'m> CBackend<'m> {
+    pub fn new(module: &'m Module) -> Self {
+        Self {
+            module,
+            type_cache: HashMap::new(),
+            derived_types: HashMap::new(),
+            struct_names: vec![],
+            type_definitions: String::new(),
+            struct_definitions: String::new(),
+            constants: String::new(),
+            prototypes: String::new(),
+            definitions: String::new(),
+            functions: HashMap::new(),
+            function_names: HashMap::new(),
+            global_names: HashSet::new(),
+        }
+    }
+
+    /// Translate every struct, enum and function in the module and return the C source.
+    pub fn compile(&mut self) -> Result<String, String> {
+        let module = self.module;
+        self.check_types()?;
+        for (_, node) in module.module_arena.node_arena.iter() {
+            match node {
+                IrNode::Struct { name, fields, .. } => self.emit_struct(name, fields),
+                IrNode::Enum { name, variants } => self.emit_enum(name, variants),
+                IrNode::Function(_) | IrNode::Error => {}
+            }
+        }
+        // all functions are declared up front so they can call each other in any order
+        for (index, function) in module.functions() {
+            self.declare_function(index, function);
+        }
+        for (index, function) in module.functions() {
+            self.emit_function(index, function)?;
+        }
+        self.emit_main_wrapper()?;
+
+        let mut header = format!("/* Generated by nuvae from {}. */\n", module.source_name);
+        header.push_str("#include <stdbool.h>\n#include <stdint.h>");
+        let forward_declarations: String = self.struct_names.iter()
+            .map(|name| format!("struct {};\n", name))
+            .collect();
+        let runtime = format!("void abort(void);\nvoid *{}(void *allocator, int64_t size);\nvoid {}(const uint8_t *data, int64_t len);\n\
+                               void {}(const uint8_t *message, int64_t len);",
+                              ALLOC_FUNCTION, PRINT_FUNCTION, PANIC_FUNCTION);
+        let sections = [&header, &forward_declarations, &self.type_definitions, &self.struct_definitions,
+            &runtime, &self.prototypes, &self.constants, &self.definitions];
+        let mut source = sections.iter()
+            .map(|section| section.trim())
+            .filter(|section| !section.is_empty())
+            .collect::<Vec<&str>>()
+            .join("\n\n");
+        source.push('\n');
+        Ok(source)
+    }
+
+    /// Report the types C doesn't have, which `convert_type` can't convert.
+    fn check_types(&self) -> Result<(), String> {
+        for (_, typ) in self.module.module_arena.type_arena.iter() {
+            match typ {
+                IrType::Int(IntTy::ISize) | IrType::UInt(UIntTy::USize) => {}
+                IrType::Int(IntTy::I128) | IrType::UInt(UIntTy::U128) => {
+                    return Err("128-bit integers aren't supported by the C backend".to_string());
+                }
+                IrType::Float(f) if !matches!(f, FloatTy::F32 | FloatTy::F64) => {
+                    return Err(format!("{}-bit floats aren't supported by the C backend", f.bits()));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn declare_struct(&mut self, name: &str) -> String {
+        let name = identifier(name);
+        if !self.struct_names.contains(&name) {
+            self.struct_names.push(name.clone());
+        }
+        name
+    }
+
+    fn emit_struct(&mut self, name: &str, fields: &[IrTypedName]) {
+        let name = self.declare_struct(name);
+        let body = self.fields(fields, 1);
+        writeln!(self.struct_definitions, "struct {} {{\n{}}};\n", name, body).unwrap();
+    }
+
+    /// Enums are a tag along with a union of the fields of every variant that has any.
+    fn emit_enum(&mut self, name: &str, variants: &[IrEnumVariant]) {
+        let name = self.declare_struct(name);
+        if variants.is_empty() {
+            writeln!(self.struct_definitions, "struct {} {{\n    char unused;\n}};\n", name).unwrap();
+            return;
+        }
+        let tags: Vec<String> = variants.iter()
+            .map(|variant| format!("    {}_{}", name, variant.name))
+            .collect();
+        writeln!(self.struct_definitions, "enum {}_tag {{\n{}\n}};\n", name, tags.join(",\n")).unwrap();
+
+        let mut union = String::new();
+        for variant in variants.iter() {
+            if !variant.fields.is_empty() {
+                let body = self.fields(&variant.fields, 3);
+                writeln!(union, "        struct {{\n{}        }} {};", body, identifier(&variant.name)).unwrap();
+            }
+        }
+        let mut definition = format!("struct {} {{\n    enum {}_tag tag;\n", name, name);
+        if !union.is_empty() {
+            writeln!(definition, "    union {{\n{}    }} as;", union).unwrap();
+        }
+        definition.push_str("};\n");
+        writeln!(self.struct_definitions, "{}", definition).unwrap();
+    }
+
+    /// Field declarations, one per line at the given indentation. Empty structs aren't valid C99,
+    /// so they get a placeholder field.
+    fn fields(&mut self, fields: &[IrTypedName], indent: usize) -> String {
+        let indent = "    ".repeat(indent);
+        if fields.is_empty() {
+            return format!("{}char unused;\n", indent);
+        }
+        let mut body = String::new();
+        for field in fields {
+            let field_type = self.convert_type(field.typ);
+            writeln!(body, "{}{};", indent, field_type.declare(&identifier(&field.name))).unwrap();
+        }
+        body
+    }
+
+    fn signature(&mut self, c_name: &str, function: &IrFunction, param_names: &[String]) -> String {
+        let mut params = Vec::with_capacity(function.params.len());
+        for (param, param_name) in function.params.iter().zip(param_names) {
+            params.push(self.convert_type(param.typ).declare(param_name));
+        }
+        let params = if params.is_empty() { "void".to_string() } else { params.join(", ") };
+        let return_type = self.convert_type(function.return_type);
+        return_type.declare(&format!("{}({})", c_name, params))
+    }
+
+    fn declare_function(&mut self, index: IrNodeIndex, function: &IrFunction) {
+        // `main` gets a wrapper with the signature C expects
+        let base = if function.name == "main" { "nuvae_main".to_string() } else { identifier(&function.name) };
+        let c_name = self.fresh_global_name(&base);
+
+        let param_names: Vec<String> = function.params.iter().map(|param| identifier(&param.name)).collect();
+        let signature = self.signature(&c_name, function, &param_names);
+        writeln!(self.prototypes, "{};", signature).unwrap();
+        self.functions.insert(index, c_name);
+        self.function_names.entry(function.name.clone()).or_insert(index);
+    }
+
+    /// Get a name based on `base` that no function or constant has yet.
+    fn fresh_global_name(&mut self, base: &str) -> String {
+        let mut c_name = base.to_string();
+        let mut i = 1;
+        while self.global_names.contains(&c_name) {
+            c_name = format!("{}_{}", base, i);
+            i += 1;
+        }
+        self.global_names.insert(c_name.clone());
+        c_name
+    }
+
+    /// Emit the C `main`, which calls the nuvae `main`. Like in executables from the other
+    /// backends, a `main` with parameters gets the argument count and the arguments.
+    fn emit_main_wrapper(&mut self) -> Result<(), String> {
+        let main = match self.function_names.get("main") {
+            Some(main) => *main,
+            None => return Ok(()),
+        };
+        let function = match self.module.node(main) {
+            IrNode::Function(function) => function,
+            _ => return Ok(()),
+        };
+        let mut args = Vec::with_capacity(function.params.len());
+        for (param, arg) in function.params.iter().zip(["argc", "argv"]) {
+            let c_type = self.convert_type(param.typ);
+            match c_type.kind {
+                CKind::Aggregate | CKind::Void => return Err(format!("`main` can't take a {} as its parameter `{}`", self.module.type_name(param.typ), param.name)),
+                _ => args.push(format!("({}) (intptr_t) {}", c_type.name, arg)),
+            }
+        }
+        if function.params.len() > args.len() {
+            return Err("`main` can only take the argument count and the argument vector".to_string());
+        }
+        let (params, unused) = if args.is_empty() {
+            ("void", "")
+        } else if args.len() == 1 {
+            ("int argc, char **argv", "    (void) argv;\n")
+        } else {
+            ("int argc, char **argv", "")
+        };
+        let c_name = self.functions.get(&main).unwrap().clone();
+        let call = format!("{}({})", c_name, args.join(", "));
+        let call = match self.convert_type(function.return_type).kind {
+            CKind::Void => format!("    {};\n    return 0;", call),
+            CKind::Pointer(_) => format!("    return {} != 0;", call),
+            _ => format!("    return (int) {};", call),
+        };
+        writeln!(self.definitions, "int main({}) {{\n{}{}\n}}", params, unused, call).unwrap();
+        Ok(())
+    }
+
+    fn emit_function(&mut self, index: IrNodeIndex, function: &IrFunction) -> Result<(), String> {
+        let module = self.module;
+        let c_name = self.functions.get(&index).unwrap().clone();
+        let return_type = self.convert_type(function.return_type);
+        let mut state = FunctionState {
+            return_type,
+            locals: HashMap::new(),
+            values: HashMap::new(),
+            names: HashSet::new(),
+            used_values: HashSet::new(),
+            used_names: HashSet::new(),
+            places: module.places(function),
+            labels: HashMap::new(),
+            body: String::new(),
+        };
+
+        // find out which values are used and which blocks need labels before emitting anything
+        for block in function.blocks.iter() {
+            for ins in module.block(*block).instructions.iter() {
+                self.record_uses(&mut state, *ins);
+            }
+        }
+        let mut param_names = Vec::with_capacity(function.params.len());
+        for param in function.params.iter() {
+            let c_name = state.fresh_name(&param.name, &self.global_names);
+            param_names.push(c_name.clone());
+            let c_type = self.convert_type(param.typ);
+            state.locals.insert(param.name.clone(), Local { c_name, c_type, typ: Some(param.typ) });
+        }
+
+        for block in function.blocks.iter() {
+            if let Some(label) = state.labels.get(block).cloned() {
+                state.body.push_str(&format!("{}:;\n", label));
+            }
+            let mut terminated = false;
+            for ins in module.block(*block).instructions.iter() {
+                self.emit_instruction(&mut state, *ins)?;
+                if module.instruction(*ins).is_terminator() {
+                    terminated = true;
+                    break;
+                }
+            }
+            if !terminated {
+                if state.return_type.kind == CKind::Void {
+                    state.line("return;");
+                } else {
+                    // control can't fall off the end of a block
+                    state.line("abort();");
+                }
+            }
+        }
+
+        let signature = self.signature(&c_name, function, &param_names);
+        write!(self.definitions, "{} {{\n{}}}\n\n", signature, state.body).unwrap();
+        Ok(())
+    }
+
+    fn record_uses(&mut self, state: &mut FunctionState, index: IrInstructionIndex) {
+        let module = self.module;
+        let label = |state: &mut FunctionState, block: &IrBlockIndex| {
+            let next = state.labels.len();
+            state.labels.entry(*block).or_insert_with(|| format!("block{}", next));
+        };
+        let operands = match module.instruction(index) {
+            IrInstruction::Ref(name) => {
+                state.used_names.insert(name.clone());
+                vec![]
+            }
+            IrInstruction::NatLiteral(_) | IrInstruction::BoolLiteral(_) | IrInstruction::StackAlloc { .. } | IrInstruction::Error => vec![],
+            IrInstruction::StrLiteral(_) | IrInstruction::CharLiteral(_) | IrInstruction::FloatLiteral(_) => vec![],
+            IrInstruction::BinOp(lhs, _, rhs) => vec![*lhs, *rhs],
+            IrInstruction::UnaryOp(_, value) => vec![*value],
+            // the field name isn't a use of a local
+            IrInstruction::FieldAccessor { aggregate, .. } => vec![*aggregate],
+            IrInstruction::FunctionCall { function, args } => {
+                let mut operands = vec![*function];
+                operands.extend(args.iter().copied());
+                operands
+            }
+            IrInstruction::New { allocator, .. } => vec![*allocator],
+            IrInstruction::RowLiteral { values, .. } => values.clone(),
+            IrInstruction::Dereference { pointer } => vec![*pointer],
+            IrInstruction::Index { base, index } => vec![*base, *index],
+            IrInstruction::BoundsCheck { index, len } => vec![*index, *len],
+            IrInstruction::Denull { optional } => vec![*optional],
+            IrInstruction::Borrow { value } | IrInstruction::Print { value } | IrInstruction::Cast { value, .. } => vec![*value],
+            IrInstruction::Let { value, .. } | IrInstruction::Assign { value, .. } => vec![*value],
+            // the place is written, not read
+            IrInstruction::Store { value, .. } => vec![*value],
+            IrInstruction::Branch { condition, true_branch, false_branch } => {
+                label(state, true_branch);
+                label(state, false_branch);
+                vec![*condition]
+            }
+            IrInstruction::Jump { target } => {
+                label(state, target);
+                vec![]
+            }
+            IrInstruction::Return { value } => vec![*value],
+        };
+        state.used_values.extend(operands);
+    }
+
+    /// Get the value of an instruction operand. `Ref`s and literals are turned into expressions
+    /// at their use, every other instruction has already been emitted into a temporary.
+    fn operand(&mut self, state: &mut FunctionState, index: IrInstructionIndex) -> Result<Value, String> {
+        if let Some(value) = state.values.get(&index) {
+            return Ok(value.clone());
+        }
+        let value = match self.module.instruction(index) {
+            IrInstruction::Ref(name) => {
+                if let Some(local) = state.locals.get(name) {
+                    Value::new(local.c_name.clone(), local.c_type.clone(), local.typ)
+                } else if let Some(function) = self.function_names.get(name).copied() {
+                    let c_name = self.functions.get(&function).unwrap().clone();
+                    let function = match self.module.node(function) {
+                        IrNode::Function(function) => function,
+                        _ => unreachable!(),
+                    };
+                    let params: Vec<IrTypeIndex> = function.params.iter().map(|param| param.typ).collect();
+                    let c_type = self.function_pointer_type(&params, function.return_type);
+                    Value::new(c_name, c_type, None)
+                } else {
+                    return Err(format!("couldn't find `{}`", name));
+                }
+            }
+            IrInstruction::NatLiteral(n) => {
+                // `check_types` reports 128-bit integers, so the low 64 bits are all a literal has
+                let n = &(*n as i64);
+                let expr = if *n == i64::MIN {
+                    "INT64_MIN".to_string()
+                } else if (i32::MIN as i64..=i32::MAX as i64).contains(n) {
+                    n.to_string()
+                } else {
+                    format!("INT64_C({})", n)
+                };
+                Value { expr, c_type: CType::integer(64, true), typ: None, literal: true }
+            }
+            IrInstruction::FloatLiteral(n) => {
+                Value { expr: format!("{:?}", n), c_type: CType::new("double", CKind::Float), typ: None, literal: true }
+            }
+            IrInstruction::BoolLiteral(b) => Value::new(b.to_string(), CType::new("bool", CKind::Bool), None),
+            IrInstruction::CharLiteral(c) => {
+                Value { expr: (*c as u32).to_string(), c_type: CType::integer(64, true), typ: None, literal: true }
+            }
+            ins => return Err(format!("instruction {:?} was used before it was emitted", ins)),
+        };
+        Ok(value)
+    }
+
+    fn emit_instruction(&mut self, state: &mut FunctionState, index: IrInstructionIndex) -> Result<(), String> {
+        let module = self.module;
+        if state.places.contains(&index) {
+            // stores turn these into lvalues themselves
+            return Ok(());
+        }
+        let value = match module.instruction(index) {
+            // these are turned into expressions where they are used
+            IrInstruction::Ref(_) | IrInstruction::NatLiteral(_) | IrInstruction::FloatLiteral(_)
+            | IrInstruction::BoolLiteral(_) | IrInstruction::CharLiteral(_) => return Ok(()),
+            IrInstruction::StrLiteral(string) => self.emit_string(string),
+            IrInstruction::BinOp(lhs, op, rhs) => {
+                let lhs = self.operand(state, *lhs)?;
+                let rhs = self.operand(state, *rhs)?;
+                self.emit_bin_op(state, index, lhs, *op, rhs)?
+            }
+            IrInstruction::UnaryOp(op, value) => {
+                let value = self.operand(state, *value)?;
+                self.emit_unary_op(state, index, *op, value)
+            }
+            IrInstruction::Cast { value, typ } => {
+                let value = self.operand(state, *value)?;
+                let c_type = self.convert_type(*typ);
+                Value::new(self.coerce(&value, &c_type), c_type, Some(*typ))
+            }
+            IrInstruction::FieldAccessor { aggregate, value } => {
+                let field_name = match module.instruction(*value) {
+                    IrInstruction::Ref(name) => name.clone(),
+                    ins => return Err(format!("can't access field {:?}", ins)),
+                };
+                let aggregate = self.operand(state, *aggregate)?;
+                self.emit_field_access(aggregate, &field_name)?
+            }
+            IrInstruction::FunctionCall { function, args } => {
+                let callee = self.operand(state, *function)?;
+                let (params, return_type) = self.callee_signature(&callee)?;
+                if params.len() != args.len() {
+                    return Err(format!("expected {} arguments but got {}", params.len(), args.len()));
+                }
+                let mut c_args = Vec::with_capacity(args.len());
+                for (arg, param) in args.iter().zip(params) {
+                    let arg = self.operand(state, *arg)?;
+                    let param_type = self.convert_type(param);
+                    c_args.push(self.coerce(&arg, &param_type));
+                }
+                let c_type = self.convert_type(return_type);
+                Value::new(format!("{}({})", callee.expr, c_args.join(", ")), c_type, Some(return_type))
+            }
+            IrInstruction::New { typ, allocator } => {
+                let allocator = self.operand(state, *allocator)?;
+                self.emit_new(*typ, allocator)?
+            }
+            IrInstruction::StackAlloc { typ } => {
+                let (result_type, allocated_type) = self.allocation_types(*typ)?;
+                let c_name = state.fresh_name("s", &self.global_names);
+                // objects start out zeroed, like those the interpreter allocates
+                state.line(&format!("{} = {{0}};", allocated_type.declare(&c_name)));
+                match module.typ(*typ) {
+                    // arrays decay to a pointer to their first element
+                    IrType::Array(..) => Value::new(c_name, result_type, Some(*typ)),
+                    IrType::Base(_) => Value::new(format!("&{}", c_name), result_type, Some(*typ)),
+                    _ => Value::new(format!("&{}", c_name), result_type, None),
+                }
+            }
+            IrInstruction::RowLiteral { typ, values } => {
+                let c_type = self.convert_type(*typ);
+                let fields = match module.typ(*typ) {
+                    IrType::Row(fields) => fields,
+                    t => return Err(format!("{:?} isn't a row type", t)),
+                };
+                let mut c_values = Vec::with_capacity(values.len());
+                for (field, value) in fields.iter().zip(values) {
+                    let value = self.operand(state, *value)?;
+                    let field_type = self.convert_type(field.typ);
+                    c_values.push(self.coerce(&value, &field_type));
+                }
+                if c_values.is_empty() {
+                    c_values.push("0".to_string());
+                }
+                Value::new(format!("({}) {{ {} }}", c_type.name, c_values.join(", ")), c_type, Some(*typ))
+            }
+            IrInstruction::Dereference { pointer } => {
+                let pointer = self.operand(state, *pointer)?;
+                self.emit_dereference(pointer)?
+            }
+            IrInstruction::Index { base, index } => self.emit_element(state, *base, *index)?,
+            IrInstruction::BoundsCheck { index, len } => {
+                let i = self.operand(state, *index)?;
+                let len = self.operand(state, *len)?;
+                // negative indices are past the end once they're unsigned
+                let out_of_bounds = format!("(uint64_t) {} >= (uint64_t) {}", i.expr, len.expr);
+                self.emit_panic_if(state, &out_of_bounds, &module.bounds_message(*index));
+                return Ok(());
+            }
+            IrInstruction::Denull { optional } => {
+                let optional = self.operand(state, *optional)?;
+                let typ = optional.typ.and_then(|t| match module.typ(t) {
+                    IrType::Optional(inner) => Some(*inner),
+                    _ => None,
+                });
+                if optional.c_type.kind == CKind::Aggregate {
+                    // non-pointer optionals are a { present, value } pair
+                    let inner = typ.ok_or_else(|| format!("can't denull {:?}", optional.c_type))?;
+                    let c_type = self.convert_type(inner);
+                    Value::new(format!("{}.value", optional.expr), c_type, typ)
+                } else {
+                    Value::new(optional.expr, optional.c_type, typ.or(optional.typ))
+                }
+            }
+            // borrowing a field, element or pointee gives the address of that place
+            IrInstruction::Borrow { value } if state.places.contains(value) => {
+                let place = self.place(state, *value)?;
+                Value::new(format!("&{}", place.expr), place.c_type.pointer_to(), None)
+            }
+            IrInstruction::Borrow { value } => {
+                // borrowing a local gives its address, anything else is stored in a temporary first
+                let local = match module.instruction(*value) {
+                    IrInstruction::Ref(name) => state.locals.get(name).cloned(),
+                    _ => None,
+                };
+                let local = match local {
+                    Some(local) => local,
+                    None => {
+                        let value = self.operand(state, *value)?;
+                        let c_name = state.fresh_name("t", &self.global_names);
+                        state.line(&format!("{} = {};", value.c_type.declare(&c_name), value.expr));
+                        Local { c_name, c_type: value.c_type, typ: value.typ }
+                    }
+                };
+                let c_type = local.c_type.pointer_to();
+                Value::new(format!("&{}", local.c_name), c_type, None)
+            }
+            IrInstruction::Print { value } => {
+                let value = self.operand(state, *value)?;
+                let data = self.emit_field_access(value.clone(), "data")?;
+                let len = self.emit_field_access(value, "len")?;
+                state.line(&format!("{}({}, (int64_t) {});", PRINT_FUNCTION, data.expr, len.expr));
+                return Ok(());
+            }
+            IrInstruction::Let { name, value } => {
+                let value = self.operand(state, *value)?;
+                // untyped bindings take the type of their value
+                let (c_type, typ) = match (module.typ(name.typ), value.typ) {
+                    (IrType::Unknown, Some(value_type)) => (self.convert_type(value_type), Some(value_type)),
+                    (IrType::Unknown, None) if value.is_float() => (value.c_type.clone(), module.float_literal_type()),
+                    (IrType::Unknown, None) if !value.literal && value.c_type.kind != CKind::Void => (value.c_type.clone(), None),
+                    _ => (self.convert_type(name.typ), Some(name.typ)),
+                };
+                let c_name = state.fresh_name(&name.name, &self.global_names);
+                let expr = self.coerce(&value, &c_type);
+                state.line(&format!("{} = {};", c_type.declare(&c_name), expr));
+                if !state.used_names.contains(&name.name) {
+                    state.line(&format!("(void) {};", c_name));
+                }
+                state.locals.insert(name.name.clone(), Local { c_name, c_type, typ });
+                return Ok(());
+            }
+            IrInstruction::Assign { name, value } => {
+                let value = self.operand(state, *value)?;
+                let local = state.locals.get(name).cloned().ok_or_else(|| format!("couldn't find `{}`", name))?;
+                let expr = self.coerce(&value, &local.c_type);
+                state.line(&format!("{} = {};", local.c_name, expr));
+                return Ok(());
+            }
+            IrInstruction::Store { place, value } => {
+                let value = self.operand(state, *value)?;
+                let place = self.place(state, *place)?;
+                let expr = self.coerce(&value, &place.c_type);
+                state.line(&format!("{} = {};", place.expr, expr));
+                return Ok(());
+            }
+            IrInstruction::Branch { condition, true_branch, false_branch } => {
+                let condition = self.operand(state, *condition)?;
+                let condition = Self::truthy(&condition);
+                let true_label = state.labels.get(true_branch).unwrap().clone();
+                let false_label = state.labels.get(false_branch).unwrap().clone();
+                state.line(&format!("if ({}) goto {}; else goto {};", condition, true_label, false_label));
+                return Ok(());
+            }
+            IrInstruction::Jump { target } => {
+                let label = state.labels.get(target).unwrap().clone();
+                state.line(&format!("goto {};", label));
+                return Ok(());
+            }
+            IrInstruction::Return { value } => {
+                let value = self.operand(state, *value)?;
+                if state.return_type.kind == CKind::Void {
+                    state.line("return;");
+                } else {
+                    let return_type = state.return_type.clone();
+                    let expr = self.coerce(&value, &return_type);
+                    state.line(&format!("return {};", expr));
+                }
+                return Ok(());
+            }
+            IrInstruction::Error => return Err("tried to emit an error instruction".to_string()),
+        };
+
+        // every value is computed once, in order, into a temporary
+        if value.c_type.kind == CKind::Void {
+            state.line(&format!("{};", value.expr));
+            state.values.insert(index, Value::new(String::new(), value.c_type, value.typ));
+        } else if !state.used_values.contains(&index) {
+            state.line(&format!("(void) {};", value.expr));
+        } else {
+            let c_name = state.fresh_name("t", &self.global_names);
+            state.line(&format!("{} = {};", value.c_type.declare(&c_name), value.expr));
+            state.values.insert(index, Value::new(c_name, value.c_type, value.typ));
+        }
+        Ok(())
+    }
+
+    /// Get the parameter and return types of something that is being called.
+    fn callee_signature(&self, callee: &Value) -> Result<(Vec<IrTypeIndex>, IrTypeIndex), String> {
+        if let Some(function) = self.functions.iter().find(|(_, c_name)| **c_name == callee.expr).map(|(index, _)| *index) {
+            if let IrNode::Function(function) = self.module.node(function) {
+                return Ok((function.params.iter().map(|param| param.typ).collect(), function.return_type));
+            }
+        }
+        match callee.typ.map(|t| self.module.typ(t)) {
+            Some(IrType::Function(params, return_type)) => Ok((params.clone(), *return_type)),
+            _ => Err("tried to call a value that isn't a function".to_string()),
+        }
+    }
+
+    fn emit_bin_op(&mut self, state: &mut FunctionState, index: IrInstructionIndex, lhs: Value, op: BinOpType, rhs: Value) -> Result<Value, String> {
+        let bool_type = CType::new("bool", CKind::Bool);
+        if let BinOpType::And | BinOpType::Or = op {
+            let op = if let BinOpType::And = op { "&&" } else { "||" };
+            return Ok(Value::new(format!("{} {} {}", Self::truthy(&lhs), op, Self::truthy(&rhs)), bool_type, None));
+        }
+
+        if lhs.is_float() || rhs.is_float() {
+            return self.emit_float_bin_op(lhs, op, rhs);
+        }
+        if op.is_shift() {
+            return Ok(Self::emit_shift(lhs, op, rhs));
+        }
+        if let (CKind::Pointer(_), _) | (_, CKind::Pointer(_)) = (&lhs.c_type.kind, &rhs.c_type.kind) {
+            let expr = format!("(void *) {} {} (void *) {}", lhs.expr, op, rhs.expr);
+            return Ok(Value::new(expr, bool_type, None));
+        }
+
+        // untyped literals take the type of the other operand,
+        // otherwise integer operands are widened to the larger of the two types
+        let bits = |value: &Value| match value.c_type.kind {
+            CKind::Integer { bits, .. } => bits,
+            _ => 1,
+        };
+        let (c_type, typ) = if rhs.literal && !lhs.literal {
+            (lhs.c_type.clone(), lhs.typ)
+        } else if lhs.literal && !rhs.literal {
+            (rhs.c_type.clone(), rhs.typ)
+        } else if bits(&lhs) >= bits(&rhs) {
+            (lhs.c_type.clone(), lhs.typ.or(rhs.typ))
+        } else {
+            (rhs.c_type.clone(), rhs.typ.or(lhs.typ))
+        };
+        let c_type = match c_type.kind {
+            CKind::Integer { .. } => c_type,
+            _ => CType::integer(64, true),
+        };
+        let signed = !(self.is_unsigned(lhs.typ) || self.is_unsigned(rhs.typ));
+        let l = self.coerce(&lhs, &c_type);
+        let r = self.coerce(&rhs, &c_type);
+
+        let (bits, type_signed) = match c_type.kind {
+            CKind::Integer { bits, signed } => (bits, signed),
+            _ => unreachable!(),
+        };
+        if op.overflow().is_some() {
+            let expr = self.emit_arithmetic(state, index, &l, op, &r, CType::integer(bits, signed));
+            return Ok(Value::new(format!("({}) {}", c_type.name, expr), c_type, typ));
+        }
+        if let BinOpType::ForwardSlash | BinOpType::Percent = op {
+            // C compilers reject dividing by a constant zero, so it's only the panic
+            if rhs.literal && rhs.expr == "0" {
+                self.emit_panic(state, &self.module.division_by_zero_message(index, op));
+                state.line("abort();");
+                return Ok(Value::new(format!("({}) 0", c_type.name), c_type, typ));
+            }
+            self.emit_panic_if(state, &format!("{} == 0", r), &self.module.division_by_zero_message(index, op));
+            // dividing the smallest signed value by -1 overflows, which is undefined in C
+            if type_signed {
+                let min = format!("{}_MIN", c_type.name.trim_end_matches("_t").to_uppercase());
+                self.emit_panic_if(state, &format!("{} == {} && {} == -1", l, min, r), &self.module.overflow_message(index, op));
+            }
+        }
+        let compare = |op: &str| {
+            if signed == type_signed {
+                format!("{} {} {}", l, op, r)
+            } else {
+                let compare_type = CType::integer(bits, signed);
+                format!("({}) {} {} ({}) {}", compare_type.name, l, op, compare_type.name, r)
+            }
+        };
+        let value = match op {
+            BinOpType::ForwardSlash => Value::new(format!("({})({} / {})", c_type.name, l, r), c_type.clone(), typ),
+            BinOpType::LessThan => Value::new(compare("<"), bool_type, None),
+            BinOpType::GreaterThan => Value::new(compare(">"), bool_type, None),
+            BinOpType::LessThanEqualTo => Value::new(compare("<="), bool_type, None),
+            BinOpType::GreaterThanEqualTo => Value::new(compare(">="), bool_type, None),
+            BinOpType::EqualTo => Value::new(compare("=="), bool_type, None),
+            BinOpType::NotEqualTo => Value::new(compare("!="), bool_type, None),
+            BinOpType::Percent => Value::new(format!("({})({} % {})", c_type.name, l, r), c_type.clone(), typ),
+            BinOpType::Ampersand => Value::new(format!("({})({} & {})", c_type.name, l, r), c_type.clone(), typ),
+            BinOpType::Pipe => Value::new(format!("({})({} | {})", c_type.name, l, r), c_type.clone(), typ),
+            BinOpType::Caret => Value::new(format!("({})({} ^ {})", c_type.name, l, r), c_type.clone(), typ),
+            _ => unreachable!(),
+        };
+        Ok(value)
+    }
+
+    /// Add, subtract or multiply two integers as `arithmetic_type`, and trap, wrap or saturate like
+    /// the operator says when the result doesn't fit it. Returns the name of the temporary the
+    /// result is in.
+    fn emit_arithmetic(&mut self, state: &mut FunctionState, index: IrInstructionIndex, l: &str, op: BinOpType, r: &str,
+                       arithmetic_type: CType) -> String {
+        let (op, overflow) = op.overflow().unwrap();
+        let c_name = state.fresh_name("t", &self.global_names);
+        if overflow == Overflow::Wrap {
+            // wrapping arithmetic is done on 64-bit unsigned integers so it's never undefined
+            let symbol = match op {
+                BinOpType::Plus => "+",
+                BinOpType::Minus => "-",
+                _ => "*",
+            };
+            state.line(&format!("{} = ({})((uint64_t) {} {} (uint64_t) {});",
+                                arithmetic_type.declare(&c_name), arithmetic_type.name, l, symbol, r));
+            return c_name;
+        }
+        let builtin = match op {
+            BinOpType::Plus => "__builtin_add_overflow",
+            BinOpType::Minus => "__builtin_sub_overflow",
+            _ => "__builtin_mul_overflow",
+        };
+        state.line(&format!("{};", arithmetic_type.declare(&c_name)));
+        let (l, r) = (format!("({}) {}", arithmetic_type.name, l), format!("({}) {}", arithmetic_type.name, r));
+        let overflowed = format!("{}({}, {}, &{})", builtin, l, r, c_name);
+        match overflow {
+            Overflow::Saturate => {
+                let limit = arithmetic_type.name.trim_end_matches("_t").to_uppercase();
+                let (min, max) = (format!("{}_MIN", limit), format!("{}_MAX", limit));
+                let saturated = match (op, arithmetic_type.kind) {
+                    // a signed result overflows towards the sign of the right operand, or for a
+                    // product the sign the exact result has
+                    (BinOpType::Plus, CKind::Integer { signed: true, .. }) => format!("{} < 0 ? {} : {}", r, min, max),
+                    (BinOpType::Minus, CKind::Integer { signed: true, .. }) => format!("{} < 0 ? {} : {}", r, max, min),
+                    (_, CKind::Integer { signed: true, .. }) => format!("({} < 0) != ({} < 0) ? {} : {}", l, r, min, max),
+                    (BinOpType::Minus, _) => "0".to_string(),
+                    _ => max,
+                };
+                state.line(&format!("if ({}) {} = {};", overflowed, c_name, saturated));
+            }
+            _ => self.emit_panic_if(state, &overflowed, &self.module.overflow_message(index, op)),
+        }
+        c_name
+    }
+
+    /// Call the panic runtime function with a message when a condition holds.
+    fn emit_panic_if(&self, state: &mut FunctionState, condition: &str, message: &str) {
+        state.line(&format!("if ({}) {}", condition, Self::panic_call(message)));
+    }
+
+    /// Call the panic runtime function with a message.
+    fn emit_panic(&self, state: &mut FunctionState, message: &str) {
+        state.line(&Self::panic_call(message));
+    }
+
+    fn panic_call(message: &str) -> String {
+        format!("{}((const uint8_t *) \"{}\", {});", PANIC_FUNCTION, string_literal(message.as_bytes()), message.len())
+    }
+
+    /// Emit a shift, which has the type of the value that is shifted. The amount is masked to the
+    /// width of that type since shifting by more is undefined in C.
+    fn emit_shift(lhs: Value, op: BinOpType, rhs: Value) -> Value {
+        let (c_type, typ) = match lhs.c_type.kind {
+            CKind::Integer { .. } => (lhs.c_type.clone(), lhs.typ),
+            _ => (CType::integer(64, true), None),
+        };
+        let bits = match c_type.kind {
+            CKind::Integer { bits, .. } => bits,
+            _ => unreachable!(),
+        };
+        let amount = format!("({} & {})", rhs.expr, bits - 1);
+        let expr = match op {
+            // left shifts of negative numbers are undefined, so they're done unsigned
+            BinOpType::ShiftLeft => format!("({})((uint64_t) {} << {})", c_type.name, lhs.expr, amount),
+            _ => format!("({})(({}) {} >> {})", c_type.name, c_type.name, lhs.expr, amount),
+        };
+        Value::new(expr, c_type, typ)
+    }
+
+    fn emit_unary_op(&mut self, state: &mut FunctionState, index: IrInstructionIndex, op: UnaryOpType, value: Value) -> Value {
+        match op {
+            UnaryOpType::Minus | UnaryOpType::WrappingMinus if value.is_float() => {
+                Value::new(format!("-({})", value.expr), value.c_type, value.typ)
+            }
+            UnaryOpType::Minus | UnaryOpType::WrappingMinus => {
+                let (c_type, typ) = match value.c_type.kind {
+                    CKind::Integer { .. } => (value.c_type.clone(), value.typ),
+                    _ => (CType::integer(64, true), None),
+                };
+                if let (UnaryOpType::Minus, CKind::Integer { signed: true, .. }) = (op, &c_type.kind) {
+                    let min = format!("{}_MIN", c_type.name.trim_end_matches("_t").to_uppercase());
+                    self.emit_panic_if(state, &format!("{} == {}", value.expr, min), &self.module.negation_message(index));
+                }
+                // negating the smallest signed integer is undefined, so it's done unsigned to wrap
+                Value::new(format!("({})(0 - (uint64_t) {})", c_type.name, value.expr), c_type, typ)
+            }
+            UnaryOpType::Not => Value::new(format!("!({})", Self::truthy(&value)), CType::new("bool", CKind::Bool), None),
+        }
+    }
+
+    /// Emit an operator where at least one operand is a float. Both operands are converted to the
+    /// float type, which is the wider one when both are floats unless one is an untyped literal.
+    fn emit_float_bin_op(&mut self, lhs: Value, op: BinOpType, rhs: Value) -> Result<Value, String> {
+        let lhs_wins = match (lhs.is_float(), rhs.is_float()) {
+            (true, true) if lhs.literal != rhs.literal => rhs.literal,
+            (true, true) => lhs.c_type.name == "double" || rhs.c_type.name == "float",
+            (lhs_float, _) => lhs_float,
+        };
+        let (c_type, typ) = if lhs_wins { (lhs.c_type.clone(), lhs.typ) } else { (rhs.c_type.clone(), rhs.typ) };
+        let (l, r) = (self.coerce(&lhs, &c_type), self.coerce(&rhs, &c_type));
+        // floats don't overflow, they become infinite
+        let op = op.overflow().map_or(op, |(op, _)| op);
+        match op {
+            BinOpType::Percent => {
+                // written out rather than calling fmod so the program doesn't need libm
+                let trunc = if c_type.name == "float" { "__builtin_truncf" } else { "__builtin_trunc" };
+                Ok(Value::new(format!("{l} - {}({l} / {r}) * {r}", trunc, l = l, r = r), c_type, typ))
+            }
+            BinOpType::Ampersand | BinOpType::Pipe | BinOpType::Caret | BinOpType::ShiftLeft | BinOpType::ShiftRight => {
+                Err(format!("can't apply {} to floats", op))
+            }
+            _ if op.is_comparison() => Ok(Value::new(format!("{} {} {}", l, op, r), CType::new("bool", CKind::Bool), None)),
+            _ => Ok(Value::new(format!("{} {} {}", l, op, r), c_type, typ)),
+        }
+    }
+
+    /// Get an lvalue for a place a store writes to. Fields of rows are inside the row, fields of
+    /// structs are inside the struct their pointer points to.
+    fn place(&mut self, state: &mut FunctionState, index: IrInstructionIndex) -> Result<Value, String> {
+        let module = self.module;
+        match module.instruction(index) {
+            IrInstruction::FieldAccessor { aggregate, value } if state.places.contains(&index) => {
+                let field_name = match module.instruction(*value) {
+                    IrInstruction::Ref(name) => name.clone(),
+                    ins => return Err(format!("can't access field {:?}", ins)),
+                };
+                let aggregate = self.place(state, *aggregate)?;
+                self.emit_field_access(aggregate, &field_name)
+            }
+            IrInstruction::Dereference { pointer } if state.places.contains(&index) => {
+                let pointer = self.operand(state, *pointer)?;
+                let pointee = self.emit_dereference(pointer)?;
+                Ok(Value::new(format!("({})", pointee.expr), pointee.c_type, pointee.typ))
+            }
+            IrInstruction::Index { base, index: element } if state.places.contains(&index) => self.emit_element(state, *base, *element),
+            // locals and temporaries are lvalues already
+            _ => self.operand(state, index),
+        }
+    }
+
+    /// An element of an array or slice, whose base points to the first element.
+    fn emit_element(&mut self, state: &mut FunctionState, base: IrInstructionIndex, index: IrInstructionIndex) -> Result<Value, String> {
+        let base = self.operand(state, base)?;
+        let index = self.operand(state, index)?;
+        let typ = base.typ.and_then(|t| match self.module.typ(t) {
+            IrType::Array(element, _) | IrType::Reference(element, _) => Some(*element),
+            _ => None,
+        });
+        let c_type = match (&base.c_type.kind, typ) {
+            (_, Some(typ)) => self.convert_type(typ),
+            (CKind::Pointer(Some(pointee)), None) => *pointee.clone(),
+            _ => return Err(format!("can't index {:?}", base.c_type)),
+        };
+        let index = self.coerce(&index, &CType::integer(64, true));
+        Ok(Value::new(format!("{}[{}]", base.expr, index), c_type, typ))
+    }
+
+    fn emit_dereference(&mut self, pointer: Value) -> Result<Value, String> {
+        let typ = pointer.typ.and_then(|t| match self.module.typ(t) {
+            IrType::Reference(inner, _) => Some(*inner),
+            _ => None,
+        });
+        let c_type = match (&pointer.c_type.kind, typ) {
+            (_, Some(typ)) => self.convert_type(typ),
+            (CKind::Pointer(Some(pointee)), None) => *pointee.clone(),
+            _ => return Err(format!("can't dereference {:?}", pointer.c_type)),
+        };
+        Ok(Value::new(format!("*{}", pointer.expr), c_type, typ))
+    }
+
+    /// Access a field of a row value or of a struct through its pointer.
+    fn emit_field_access(&mut self, aggregate: Value, field_name: &str) -> Result<Value, String> {
+        let fields = match aggregate.typ.map(|t| self.module.typ(t)) {
+            Some(IrType::Row(fields)) => fields.clone(),
+            Some(IrType::Base(name)) => match self.module.find_struct(name) {
+                Some(IrNode::Struct { fields, .. }) => fields.clone(),
+                _ => return Err(format!("couldn't find struct {}", name)),
+            },
+            t => return Err(format!("can't access field `{}` of {:?}", field_name, t)),
+        };
+        let field = fields.iter().find(|f| f.name == field_name)
+            .ok_or_else(|| format!("couldn't find field `{}`", field_name))?;
+        let c_type = self.convert_type(field.typ);
+        let accessor = if let CKind::Pointer(_) = aggregate.c_type.kind { "->" } else { "." };
+        Ok(Value::new(format!("{}{}{}", aggregate.expr, accessor, identifier(field_name)), c_type, Some(field.typ)))
+    }
+
+    /// Lower `new T in allocator` to a call to the allocator runtime function.
+    /// The type of a pointer to a new `typ` and the type of what it points to.
+    fn allocation_types(&mut self, typ: IrTypeIndex) -> Result<(CType, CType), String> {
+        // nominal types are already pointers to their struct, everything else gets a new pointer
+        let converted = self.convert_type(typ);
+        let types = match (self.module.typ(typ), &converted.kind) {
+            (IrType::Base(name), CKind::Pointer(Some(pointee))) => {
+                let defined = self.module.module_arena.node_arena.iter().any(|(_, node)| match node {
+                    IrNode::Struct { name: struct_name, .. } | IrNode::Enum { name: struct_name, .. } => struct_name == name,
+                    _ => false,
+                });
+                if !defined {
+                    return Err(format!("can't allocate {:?} because its size is unknown", self.module.typ(typ)));
+                }
+                (converted.clone(), *pointee.clone())
+            }
+            // arrays are already pointers to their first element
+            (IrType::Array(_, len), CKind::Pointer(Some(element))) => {
+                let layout = format!("array{{{}; {}}}", element.name, len);
+                let element = element.declare("");
+                let array = self.derived_type("array", layout, CKind::Aggregate, |name| {
+                    format!("typedef {}{}[{}];\n", element, name, len)
+                });
+                (converted, array)
+            }
+            _ => (converted.clone().pointer_to(), converted),
+        };
+        Ok(types)
+    }
+
+    fn emit_new(&mut self, typ: IrTypeIndex, allocator: Value) -> Result<Value, String> {
+        let (result_type, allocated_type) = self.allocation_types(typ)?;
+        let allocator = self.coerce(&allocator, &CType::new("void *", CKind::Pointer(None)));
+        let typ = if let IrType::Base(_) | IrType::Array(..) = self.module.typ(typ) { Some(typ) } else { None };
+        let expr = format!("({}){}({}, (int64_t) sizeof({}))", result_type.name, ALLOC_FUNCTION, allocator, allocated_type.name);
+        Ok(Value::new(expr, result_type, typ))
+    }
+
+    /// Define the `Str` of a string literal as a static and return a pointer to it.
+    fn emit_string(&mut self, string: &str) -> Value {
+        let str_type = self.module.str_type().expect("string literals have the `Str` type");
+        let c_type = self.convert_type(str_type);
+        let pointee = match &c_type.kind {
+            CKind::Pointer(Some(pointee)) => pointee.name.clone(),
+            _ => unreachable!(),
+        };
+        let c_name = self.fresh_global_name("nuvae_str");
+        writeln!(self.constants, "static {} {} = {{ (uint8_t *) \"{}\", {} }};",
+                 pointee, c_name, string_literal(string.as_bytes()), string.len()).unwrap();
+        Value::new(format!("&{}", c_name), c_type, Some(str_type))
+    }
+
+    /// Get a condition for a value by comparing it against zero.
+    fn truthy(value: &Value) -> String {
+        match value.c_type.kind {
+            CKind::Bool => value.expr.clone(),
+            _ => format!("{} != 0", value.expr),
+        }
+    }
+
+    /// Convert a value to the given type with a cast where C wouldn't convert it implicitly.
+    fn coerce(&self, value: &Value, target: &CType) -> String {
+        if value.c_type == *target {
+            return value.expr.clone();
+        }
+        match (&value.c_type.kind, &target.kind) {
+            (CKind::Integer { .. }, CKind::Integer { bits, .. }) if value.literal => {
+                match value.expr.parse::<i64>() {
+                    Ok(n) if *bits >= 32 || (n >= 0 && n < 1 << (*bits - 1)) => value.expr.clone(),
+                    _ => format!("({}) {}", target.name, value.expr),
+                }
+            }
+            (_, CKind::Bool) if value.c_type.kind != CKind::Aggregate => format!("{} != 0", value.expr),
+            (CKind::Float, CKind::Integer { signed, .. }) => {
+                // converting a float that's out of range is undefined, so it saturates like `as`
+                let limit = target.name.trim_end_matches("_t").to_uppercase();
+                let min = if *signed { format!("{}_MIN", limit) } else { "0".to_string() };
+                format!("({x} != {x} ? 0 : {x} <= {min} ? {min} : {x} >= {limit}_MAX ? {limit}_MAX : ({name}) {x})",
+                        x = value.expr, min = min, limit = limit, name = target.name)
+            }
+            (CKind::Integer { .. } | CKind::Bool | CKind::Float, CKind::Integer { .. } | CKind::Float) |
+            (CKind::Pointer(_), CKind::Pointer(_)) => format!("({}) {}", target.name, value.expr),
+            (CKind::Pointer(_), CKind::Integer { .. }) | (CKind::Integer { .. }, CKind::Pointer(_)) => {
+                format!("({}) (intptr_t) {}", target.name, value.expr)
+            }
+            _ => value.expr.clone(),
+        }
+    }
+
+    fn is_unsigned(&self, typ: Option<IrTypeIndex>) -> bool {
+        match typ.map(|t| self.module.typ(t)) {
+            Some(IrType::UInt(_)) => true,
+            Some(IrType::Refinement(_, inner, _)) => self.is_unsigned(Some(*inner)),
+            _ => false,
+        }
+    }
+
+    /// Get the typedef for a derived type with the given layout, defining it the first time.
+    fn derived_type(&mut self, prefix: &str, layout: String, kind: CKind, definition: impl FnOnce(&str) -> String) -> CType {
+        if let Some(c_type) = self.derived_types.get(&layout) {
+            return c_type.clone();
+        }
+        let name = format!("nuvae_{}_{}", prefix, self.derived_types.len());
+        writeln!(self.type_definitions, "{}", definition(&name)).unwrap();
+        let c_type = CType::new(&name, kind);
+        self.derived_types.insert(layout, c_type.clone());
+        c_type
+    }
+
+    fn function_pointer_type(&mut self, params: &[IrTypeIndex], return_type: IrTypeIndex) -> CType {
+        let mut param_types = Vec::with_capacity(params.len());
+        for param in params {
+            param_types.push(self.convert_type(*param).name);
+        }
+        let params = if param_types.is_empty() { "void".to_string() } else { param_types.join(", ") };
+        let return_type = self.convert_type(return_type).name;
+        let layout = format!("{}(*)({})", return_type, params);
+        self.derived_type("function", layout, CKind::Pointer(None), |name| {
+            format!("typedef {} (*{})({});", return_type, name, params)
+        })
+    }
+
+    fn convert_type(&mut self, type_index: IrTypeIndex) -> CType {
+        if let Some(typ) = self.type_cache.get(&type_index) {
+            return typ.clone();
+        }
+        let c_type = match self.module.typ(type_index).clone() {
+            IrType::Bool => CType::new("bool", CKind::Bool),
+            IrType::Int(IntTy::ISize) => CType::new("intptr_t", CKind::Integer { bits: IntTy::ISize.bits(), signed: true }),
+            IrType::UInt(UIntTy::USize) => CType::new("uintptr_t", CKind::Integer { bits: UIntTy::USize.bits(), signed: false }),
+            IrType::Int(i) if i.bits() <= 64 => CType::integer(i.bits(), true),
+            IrType::UInt(u) if u.bits() <= 64 => CType::integer(u.bits(), false),
+            IrType::Int(_) | IrType::UInt(_) => unreachable!("`check_types` reports 128-bit integers"),
+            IrType::Float(FloatTy::F32) => CType::new("float", CKind::Float),
+            IrType::Float(FloatTy::F64) => CType::new("double", CKind::Float),
+            IrType::Float(_) => unreachable!("`check_types` reports floats C doesn't have"),
+            // nominal types are passed around by reference
+            IrType::Base(name) => {
+                let name = self.declare_struct(&name);
+                CType::new(&format!("struct {}", name), CKind::Aggregate).pointer_to()
+            }
+            IrType::Refinement(_, inner, _) => self.convert_type(inner),
+            IrType::Row(fields) => {
+                let body = self.fields(&fields, 1);
+                let layout = format!("row{{{}}}", body);
+                self.derived_type("row", layout, CKind::Aggregate, |name| {
+                    format!("typedef struct {{\n{}}} {};\n", body, name)
+                })
+            }
+            IrType::Reference(inner, _) => self.convert_type(inner).pointer_to(),
+            // arrays are passed around as a pointer to their first element
+            IrType::Array(element, _) => self.convert_type(element).pointer_to(),
+            IrType::Optional(inner) => {
+                // optional pointers are nullable pointers
+                let inner = self.convert_type(inner);
+                if let CKind::Pointer(_) = inner.kind {
+                    inner
+                } else {
+                    let layout = format!("optional{{{}}}", inner.name);
+                    self.derived_type("optional", layout, CKind::Aggregate, |name| {
+                        format!("typedef struct {{\n    bool present;\n    {};\n}} {};\n", inner.declare("value"), name)
+                    })
+                }
+            }
+            IrType::Function(params, return_type) => self.function_pointer_type(&params, return_type),
+            IrType::Void => CType::new("void", CKind::Void),
+            IrType::Unknown => CType::integer(64, true),
+        };
+        self.type_cache.insert(type_index, c_type.clone());
+        c_type
+    }
+}
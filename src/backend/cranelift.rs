@@ -0,0 +1,1276 @@
+use std::collections::{HashMap, HashSet};
+use cranelift_codegen::ir::{self as clif, AbiParam, InstBuilder, MemFlagsData, StackSlot, StackSlotData, StackSlotKind, TrapCode, UserFuncName};
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_module::{default_libcall_names, DataDescription, DataId, FuncId, Linkage, Module as _};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use crate::ast::{BinOpType, Overflow, UnaryOpType};
+use crate::backend::{Artifact, Backend, ALLOC_FUNCTION, PANIC_FUNCTION, PRINT_FUNCTION};
+use crate::ir::{IrBlockIndex, IrFunction, IrInstruction, IrInstructionIndex, IrNode, IrNodeIndex, IrType, IrTypeIndex, IrTypedName, Module};
+use crate::options::{EmitKind, OptLevel, Options};
+
+/// Plugs the Cranelift backend into the compiler. Cranelift only produces object files,
+/// but it's much faster than LLVM at `-O0` and doesn't need a system LLVM.
+pub struct CraneliftCodegen<'options> {
+    options: &'options Options,
+}
+
+impl<'o> CraneliftCodegen<'o> {
+    pub fn new(options: &'o Options) -> Self {
+        Self { options }
+    }
+}
+
+impl Backend for CraneliftCodegen<'_> {
+    fn compile_module(&mut self, module: &Module) -> Result<Artifact, String> {
+        match self.options.emit {
+            EmitKind::Object | EmitKind::Executable => {}
+            kind => return Err(format!("the Cranelift backend can't emit {:?}", kind)),
+        }
+        if self.options.debug_info {
+            return Err("the Cranelift backend doesn't emit debug info, use --backend=llvm".to_string());
+        }
+        let object = create_object_module(self.options, &module.name)?;
+        CraneliftBackend::new(module, object).compile().map(Artifact::Object)
+    }
+}
+
+/// Set up an object module for the requested target, or the host when there isn't one.
+fn create_object_module(options: &Options, name: &str) -> Result<ObjectModule, String> {
+    let mut flags = settings::builder();
+    let opt_level = match options.opt_level {
+        OptLevel::O0 => "none",
+        OptLevel::O1 | OptLevel::O2 | OptLevel::O3 => "speed",
+        OptLevel::Os => "speed_and_size",
+    };
+    flags.set("opt_level", opt_level).map_err(|e| e.to_string())?;
+    flags.set("is_pic", "true").map_err(|e| e.to_string())?;
+    // 128 bit integers are passed and returned the way LLVM passes them
+    flags.set("enable_llvm_abi_extensions", "true").map_err(|e| e.to_string())?;
+
+    let mut isa = match &options.target {
+        Some(target) => cranelift_codegen::isa::lookup_by_name(target)
+            .map_err(|e| format!("couldn't compile for {}: {}", target, e))?,
+        None => cranelift_native::builder()?,
+    };
+    if options.cpu.is_some() {
+        return Err("the Cranelift backend doesn't support --cpu, use --features instead".to_string());
+    }
+    // features use LLVM's syntax, like +has_avx,-has_sse41
+    for feature in options.features.iter().flat_map(|features| features.split(',')) {
+        let result = match feature.strip_prefix('-') {
+            Some(name) => isa.set(name, "false"),
+            None => isa.enable(feature.trim_start_matches('+')),
+        };
+        result.map_err(|e| format!("unknown feature `{}`: {}", feature, e))?;
+    }
+    let isa = isa.finish(settings::Flags::new(flags)).map_err(|e| e.to_string())?;
+    let builder = ObjectBuilder::new(isa, name, default_libcall_names()).map_err(|e| e.to_string())?;
+    Ok(ObjectModule::new(builder))
+}
+
+/// Where the value of an instruction, parameter or `let` binding lives.
+#[derive(Clone, Copy, Debug)]
+enum Place {
+    Value(clif::Value),
+    /// Parameters and `let` bindings, which can be reassigned.
+    Variable(Variable),
+    /// A stack slot, for locals whose address is taken.
+    Stack(StackSlot),
+    Int(i128),
+    Float(f64),
+    Bool(bool),
+    Function(FuncId),
+    Void,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Value {
+    place: Place,
+    clif_type: Option<clif::Type>,
+    typ: Option<IrTypeIndex>,
+    /// What a pointer points to, when that isn't clear from its type.
+    pointee: Option<clif::Type>,
+}
+
+impl Value {
+    fn new(place: Place, clif_type: Option<clif::Type>, typ: Option<IrTypeIndex>) -> Self {
+        Self { place, clif_type, typ, pointee: None }
+    }
+}
+
+/// State that only lives while a single function is being emitted.
+struct FunctionState<'f> {
+    builder: FunctionBuilder<'f>,
+    return_type: Option<clif::Type>,
+    named: HashMap<String, Value>,
+    values: HashMap<IrInstructionIndex, Value>,
+    /// Names of the locals whose address is taken, which have to live in stack slots.
+    borrowed: HashSet<String>,
+    /// Instructions that are only emitted where they are stored to, borrowed or read through.
+    places: HashSet<IrInstructionIndex>,
+    blocks: HashMap<IrBlockIndex, clif::Block>,
+}
+
+/// Backend that lowers a module to Cranelift IR and writes an object file.
+pub struct CraneliftBackend<'module> {
+    module: &'module Module,
+    object: ObjectModule,
+    pointer_type: clif::Type,
+    alloc_function: Option<FuncId>,
+    print_function: Option<FuncId>,
+    panic_function: Option<FuncId>,
+    functions: HashMap<IrNodeIndex, FuncId>,
+    functions_by_name: HashMap<String, IrNodeIndex>,
+}
+
+impl<'m> CraneliftBackend<'m> {
+    pub fn new(module: &'m Module, object: ObjectModule) -> Self {
+        let pointer_type = object.target_config().pointer_type();
+        Self {
+            module,
+            object,
+            pointer_type,
+            alloc_function: None,
+            print_function: None,
+            panic_function: None,
+            functions: HashMap::new(),
+            functions_by_name: HashMap::new(),
+        }
+    }
+
+    /// Emit every function in the module and return the object file. All functions are
+    /// declared before any bodies are emitted so they can call each other in any order.
+    pub fn compile(mut self) -> Result<Vec<u8>, String> {
+        self.check_types()?;
+        let module = self.module;
+        for (index, function) in module.functions() {
+            let params: Vec<IrTypeIndex> = function.params.iter().map(|param| param.typ).collect();
+            let signature = self.signature(&params, function.return_type)?;
+            let id = self.object.declare_function(&function.name, Linkage::Export, &signature)
+                .map_err(|e| e.to_string())?;
+            self.functions.insert(index, id);
+            self.functions_by_name.entry(function.name.clone()).or_insert(index);
+        }
+        for (index, function) in module.functions() {
+            self.emit_function(index, function)?;
+        }
+        self.object.finish().emit().map_err(|e| format!("couldn't write the object file: {}", e))
+    }
+
+    fn signature(&self, params: &[IrTypeIndex], return_type: IrTypeIndex) -> Result<clif::Signature, String> {
+        let mut signature = self.object.make_signature();
+        for param in params {
+            signature.params.push(AbiParam::new(self.value_type(*param)?));
+        }
+        if let Some(return_type) = self.clif_type(return_type) {
+            signature.returns.push(AbiParam::new(return_type));
+        }
+        Ok(signature)
+    }
+
+    fn emit_function(&mut self, index: IrNodeIndex, function: &IrFunction) -> Result<(), String> {
+        let module = self.module;
+        let id = *self.functions.get(&index).unwrap();
+        let mut context = self.object.make_context();
+        let params: Vec<IrTypeIndex> = function.params.iter().map(|param| param.typ).collect();
+        context.func.signature = self.signature(&params, function.return_type)?;
+        context.func.name = UserFuncName::user(0, id.as_u32());
+        let mut builder_context = FunctionBuilderContext::new();
+        let mut state = FunctionState {
+            builder: FunctionBuilder::new(&mut context.func, &mut builder_context),
+            return_type: self.clif_type(function.return_type),
+            named: HashMap::new(),
+            values: HashMap::new(),
+            borrowed: HashSet::new(),
+            places: module.places(function),
+            blocks: HashMap::new(),
+        };
+        for block in function.blocks.iter() {
+            let clif_block = state.builder.create_block();
+            state.blocks.insert(*block, clif_block);
+        }
+        for block in function.blocks.iter() {
+            for ins in module.block(*block).instructions.iter() {
+                if let IrInstruction::Borrow { value } = module.instruction(*ins) {
+                    if let IrInstruction::Ref(name) = module.instruction(*value) {
+                        state.borrowed.insert(name.clone());
+                    }
+                }
+            }
+        }
+
+        // the parameters are bound in a separate entry block so the first block can be jumped to
+        let entry = state.builder.create_block();
+        state.builder.append_block_params_for_function_params(entry);
+        state.builder.switch_to_block(entry);
+        for (i, param) in function.params.iter().enumerate() {
+            let clif_type = self.value_type(param.typ)?;
+            let value = state.builder.block_params(entry)[i];
+            let local = self.new_local(&mut state, &param.name, clif_type, Some(param.typ), None);
+            self.store_local(&mut state, &local, value)?;
+        }
+        let first_block = *state.blocks.get(&function.blocks[0]).unwrap();
+        state.builder.ins().jump(first_block, &[]);
+
+        for block in function.blocks.iter() {
+            state.builder.switch_to_block(*state.blocks.get(block).unwrap());
+            let mut terminated = false;
+            for ins in module.block(*block).instructions.iter() {
+                self.emit_instruction(&mut state, *ins)?;
+                if module.instruction(*ins).is_terminator() {
+                    terminated = true;
+                    break;
+                }
+            }
+            if !terminated {
+                if state.return_type.is_none() {
+                    state.builder.ins().return_(&[]);
+                } else {
+                    state.builder.ins().trap(TrapCode::unwrap_user(1));
+                }
+            }
+        }
+        let FunctionState { mut builder, .. } = state;
+        builder.seal_all_blocks();
+        builder.finalize(self.object.target_config());
+
+        self.object.define_function(id, &mut context)
+            .map_err(|e| format!("couldn't compile `{}`: {:?}", function.name, e))?;
+        Ok(())
+    }
+
+    /// Make a parameter or `let` binding. Locals whose address is taken get a stack slot,
+    /// everything else is a variable that Cranelift turns into SSA values.
+    fn new_local(&mut self, state: &mut FunctionState, name: &str, clif_type: clif::Type,
+                 typ: Option<IrTypeIndex>, pointee: Option<clif::Type>) -> Value {
+        let place = if state.borrowed.contains(name) {
+            Place::Stack(self.new_stack_slot(state, clif_type))
+        } else {
+            Place::Variable(state.builder.declare_var(clif_type))
+        };
+        let local = Value { place, clif_type: Some(clif_type), typ, pointee };
+        state.named.insert(name.to_string(), local);
+        local
+    }
+
+    fn new_stack_slot(&mut self, state: &mut FunctionState, clif_type: clif::Type) -> StackSlot {
+        let size = clif_type.bytes();
+        let slot = StackSlotData::new(StackSlotKind::ExplicitSlot, size, size.trailing_zeros() as u8);
+        state.builder.create_sized_stack_slot(slot)
+    }
+
+    fn store_local(&mut self, state: &mut FunctionState, local: &Value, value: clif::Value) -> Result<(), String> {
+        match local.place {
+            Place::Variable(variable) => state.builder.def_var(variable, value),
+            Place::Stack(slot) => {
+                state.builder.ins().stack_store(self.pointer_type, value, slot, 0);
+            }
+            place => return Err(format!("can't assign to {:?}", place)),
+        }
+        Ok(())
+    }
+
+    /// Get the value of an instruction operand. `Ref`s and literals are materialized at their use,
+    /// every other instruction has already been emitted.
+    fn operand(&mut self, state: &mut FunctionState, index: IrInstructionIndex) -> Result<Value, String> {
+        if let Some(value) = state.values.get(&index) {
+            return Ok(*value);
+        }
+        let value = match self.module.instruction(index) {
+            IrInstruction::Ref(name) => {
+                if let Some(value) = state.named.get(name) {
+                    *value
+                } else if let Some(function) = self.functions_by_name.get(name) {
+                    let id = *self.functions.get(function).unwrap();
+                    Value::new(Place::Function(id), Some(self.pointer_type), None)
+                } else {
+                    return Err(format!("couldn't find `{}`", name));
+                }
+            }
+            IrInstruction::NatLiteral(n) => Value::new(Place::Int(*n), Some(clif::types::I64), None),
+            IrInstruction::FloatLiteral(n) => Value::new(Place::Float(*n), Some(clif::types::F64), None),
+            IrInstruction::CharLiteral(c) => Value::new(Place::Int(*c as i128), Some(clif::types::I64), None),
+            IrInstruction::BoolLiteral(b) => Value::new(Place::Bool(*b), Some(clif::types::I8), None),
+            ins => return Err(format!("instruction {:?} was used before it was emitted", ins)),
+        };
+        Ok(value)
+    }
+
+    /// Get an operand as a Cranelift value of the `target` type.
+    fn materialize(&mut self, state: &mut FunctionState, value: &Value, target: clif::Type) -> Result<clif::Value, String> {
+        let source = match value.place {
+            Place::Int(n) => {
+                return Ok(match target {
+                    clif::types::F32 => state.builder.ins().f32const(n as f32),
+                    clif::types::F64 => state.builder.ins().f64const(n as f64),
+                    // there are no 128 bit constants, so they're made of their two halves
+                    clif::types::I128 => {
+                        let low = state.builder.ins().iconst(clif::types::I64, n as i64);
+                        let high = state.builder.ins().iconst(clif::types::I64, (n >> 64) as i64);
+                        state.builder.ins().iconcat(low, high)
+                    }
+                    // narrow constants have to be zero extended to 64 bits
+                    t if t.bits() < 64 => state.builder.ins().iconst(t, n as i64 & ((1 << t.bits()) - 1)),
+                    t => state.builder.ins().iconst(t, n as i64),
+                });
+            }
+            Place::Float(x) => match target {
+                clif::types::F32 => return Ok(state.builder.ins().f32const(x as f32)),
+                _ => state.builder.ins().f64const(x),
+            },
+            Place::Bool(b) => state.builder.ins().iconst(clif::types::I8, b as i64),
+            Place::Function(id) => {
+                let function = self.object.declare_func_in_func(id, state.builder.func);
+                state.builder.ins().func_addr(self.pointer_type, function)
+            }
+            Place::Value(v) => v,
+            Place::Variable(variable) => state.builder.use_var(variable),
+            Place::Stack(slot) => state.builder.ins().stack_load(self.pointer_type, value.clif_type.unwrap(), slot, 0),
+            Place::Void => return Err("tried to use a Void value".to_string()),
+        };
+        let signed = !self.is_unsigned(value.typ) && !matches!(value.place, Place::Bool(_))
+            && !matches!(value.typ.map(|t| self.module.typ(t)), Some(IrType::Bool));
+        self.convert(state, source, target, signed)
+    }
+
+    /// Convert a value to another type. `signed` is the signedness of the integer side, which is
+    /// the source for integers and the target for floats that become integers.
+    fn convert(&mut self, state: &mut FunctionState, value: clif::Value, target: clif::Type, signed: bool) -> Result<clif::Value, String> {
+        let source = state.builder.func.dfg.value_type(value);
+        if source.is_float() && target.is_int() {
+            return Ok(Self::float_to_int(state, value, target, signed));
+        }
+        let ins = state.builder.ins();
+        let converted = match (source, target) {
+            (s, t) if s == t => value,
+            (s, t) if s.is_int() && t.is_int() && s.bits() < t.bits() => {
+                if signed { ins.sextend(t, value) } else { ins.uextend(t, value) }
+            }
+            (s, t) if s.is_int() && t.is_int() => ins.ireduce(t, value),
+            (s, t) if s.is_int() && t.is_float() => {
+                if signed { ins.fcvt_from_sint(t, value) } else { ins.fcvt_from_uint(t, value) }
+            }
+            (clif::types::F32, clif::types::F64) => ins.fpromote(target, value),
+            (clif::types::F64, clif::types::F32) => ins.fdemote(target, value),
+            (s, t) => return Err(format!("can't convert {} to {}", s, t)),
+        };
+        Ok(converted)
+    }
+
+    /// Convert a float to an integer type, rounding toward zero and saturating at the bounds of
+    /// the type, with NaN becoming zero.
+    fn float_to_int(state: &mut FunctionState, value: clif::Value, target: clif::Type, signed: bool) -> clif::Value {
+        let source = state.builder.func.dfg.value_type(value);
+        let ins = state.builder.ins();
+        if target.bits() >= 32 {
+            // 128 bit integers saturate at the bounds of 64 bit ones
+            let wide = if target.bits() > 64 { clif::types::I64 } else { target };
+            let v = if signed { ins.fcvt_to_sint_sat(wide, value) } else { ins.fcvt_to_uint_sat(wide, value) };
+            return match target.bits() > 64 {
+                true if signed => state.builder.ins().sextend(target, v),
+                true => state.builder.ins().uextend(target, v),
+                false => v,
+            };
+        }
+        // there are no saturating conversions to narrow integers, so the float is clamped to their
+        // bounds first, which keeps NaN
+        let bits = target.bits();
+        let (min, max) = if signed {
+            (-(1i64 << (bits - 1)) as f64, ((1i64 << (bits - 1)) - 1) as f64)
+        } else {
+            (0.0, ((1i64 << bits) - 1) as f64)
+        };
+        let (min, max) = match source {
+            clif::types::F32 => (ins.f32const(min as f32), state.builder.ins().f32const(max as f32)),
+            _ => (ins.f64const(min), state.builder.ins().f64const(max)),
+        };
+        let clamped = state.builder.ins().fmax(value, min);
+        let clamped = state.builder.ins().fmin(clamped, max);
+        let v = state.builder.ins().fcvt_to_sint_sat(clif::types::I32, clamped);
+        state.builder.ins().ireduce(target, v)
+    }
+
+    /// Get an operand as an `i8` that is 1 when it's true.
+    fn truthy(&mut self, state: &mut FunctionState, value: &Value) -> Result<clif::Value, String> {
+        match value.place {
+            Place::Bool(b) => return Ok(state.builder.ins().iconst(clif::types::I8, b as i64)),
+            Place::Float(x) => return Ok(state.builder.ins().iconst(clif::types::I8, (x != 0.0) as i64)),
+            _ => {}
+        }
+        let clif_type = value.clif_type.unwrap_or(clif::types::I64);
+        let v = self.materialize(state, value, clif_type)?;
+        if clif_type.is_float() {
+            // NaN is truthy, like any other float that isn't zero
+            let zero = match clif_type {
+                clif::types::F32 => state.builder.ins().f32const(0.0),
+                _ => state.builder.ins().f64const(0.0),
+            };
+            return Ok(state.builder.ins().fcmp(FloatCC::NotEqual, v, zero));
+        }
+        Ok(state.builder.ins().icmp_imm_s(IntCC::NotEqual, v, 0))
+    }
+
+    fn emit_instruction(&mut self, state: &mut FunctionState, index: IrInstructionIndex) -> Result<(), String> {
+        let module = self.module;
+        if state.places.contains(&index) {
+            return Ok(());
+        }
+        let value = match module.instruction(index) {
+            // these are materialized where they are used
+            IrInstruction::Ref(_) | IrInstruction::NatLiteral(_) | IrInstruction::FloatLiteral(_)
+            | IrInstruction::BoolLiteral(_) | IrInstruction::CharLiteral(_) => return Ok(()),
+            IrInstruction::StrLiteral(string) => {
+                let str_type = module.str_type().expect("string literals have the `Str` type");
+                let header = self.define_string(str_type, string)?;
+                let header = self.object.declare_data_in_func(header, state.builder.func);
+                let pointer = state.builder.ins().symbol_value(self.pointer_type, header);
+                Value::new(Place::Value(pointer), Some(self.pointer_type), Some(str_type))
+            }
+            IrInstruction::BinOp(lhs, op, rhs) => {
+                let lhs = self.operand(state, *lhs)?;
+                let rhs = self.operand(state, *rhs)?;
+                self.emit_bin_op(state, index, lhs, *op, rhs)?
+            }
+            IrInstruction::UnaryOp(op, value) => {
+                let value = self.operand(state, *value)?;
+                self.emit_unary_op(state, index, *op, value)?
+            }
+            IrInstruction::Cast { value, typ } => {
+                let value = self.operand(state, *value)?;
+                let clif_type = self.value_type(*typ)?;
+                let v = match value.clif_type {
+                    // floats become unsigned integers when the target type is unsigned
+                    Some(source) if source.is_float() && clif_type.is_int() => {
+                        let v = self.materialize(state, &value, source)?;
+                        let signed = !self.is_unsigned(Some(*typ));
+                        self.convert(state, v, clif_type, signed)?
+                    }
+                    _ => self.materialize(state, &value, clif_type)?,
+                };
+                Value::new(Place::Value(v), Some(clif_type), Some(*typ))
+            }
+            IrInstruction::FieldAccessor { aggregate, value } => {
+                let field_name = self.field_name(*value)?;
+                let aggregate = self.operand(state, *aggregate)?;
+                self.emit_field_access(state, aggregate, &field_name)?
+            }
+            IrInstruction::FunctionCall { function, args } => {
+                let callee = self.operand(state, *function)?;
+                self.emit_call(state, callee, args)?
+            }
+            IrInstruction::New { typ, allocator } => {
+                let allocator = self.operand(state, *allocator)?;
+                self.emit_new(state, *typ, allocator)?
+            }
+            IrInstruction::StackAlloc { typ } => self.emit_stack_alloc(state, *typ)?,
+            IrInstruction::RowLiteral { typ, values } => {
+                let values = values.iter().map(|value| self.operand(state, *value)).collect::<Result<Vec<Value>, String>>()?;
+                self.emit_row(state, *typ, &values)?
+            }
+            IrInstruction::Dereference { pointer } => {
+                let pointer = self.operand(state, *pointer)?;
+                self.emit_dereference(state, pointer)?
+            }
+            IrInstruction::Index { base, index } => self.emit_index(state, *base, *index)?,
+            IrInstruction::BoundsCheck { index, len } => {
+                let i = self.operand(state, *index)?;
+                let i = self.materialize(state, &i, clif::types::I64)?;
+                let len = self.operand(state, *len)?;
+                let len = self.materialize(state, &len, clif::types::I64)?;
+                // negative indices are past the end once they're unsigned
+                let out_of_bounds = state.builder.ins().icmp(IntCC::UnsignedGreaterThanOrEqual, i, len);
+                self.emit_panic_if(state, out_of_bounds, &module.bounds_message(*index));
+                return Ok(());
+            }
+            IrInstruction::Denull { optional } => {
+                let optional = self.operand(state, *optional)?;
+                let typ = optional.typ.and_then(|t| match module.typ(t) {
+                    IrType::Optional(inner) => Some(*inner),
+                    _ => None,
+                });
+                match typ {
+                    // non-pointer optionals point to their value, or are null
+                    Some(inner) if !self.is_pointer(inner) => {
+                        let clif_type = self.value_type(inner)?;
+                        let address = self.materialize(state, &optional, self.pointer_type)?;
+                        let loaded = state.builder.ins().load(clif_type, MemFlagsData::new(), address, 0);
+                        Value::new(Place::Value(loaded), Some(clif_type), Some(inner))
+                    }
+                    _ => Value { typ: typ.or(optional.typ), ..optional },
+                }
+            }
+            // borrowing a field, element or pointee gives the address of that place
+            IrInstruction::Borrow { value } if state.places.contains(value) => {
+                let (address, clif_type) = self.place_address(state, *value)?;
+                let mut result = Value::new(Place::Value(address), Some(self.pointer_type), None);
+                result.pointee = Some(clif_type);
+                result
+            }
+            IrInstruction::Borrow { value } => {
+                // borrowing a local gives the address of its stack slot, anything else is spilled first
+                let borrowed = self.operand(state, *value)?;
+                let clif_type = borrowed.clif_type.ok_or("can't borrow a Void value")?;
+                let slot = match borrowed.place {
+                    Place::Stack(slot) => slot,
+                    _ => {
+                        let v = self.materialize(state, &borrowed, clif_type)?;
+                        let slot = self.new_stack_slot(state, clif_type);
+                        state.builder.ins().stack_store(self.pointer_type, v, slot, 0);
+                        slot
+                    }
+                };
+                let address = state.builder.ins().stack_addr(self.pointer_type, slot, 0);
+                let mut result = Value::new(Place::Value(address), Some(self.pointer_type), None);
+                result.pointee = Some(clif_type);
+                result
+            }
+            IrInstruction::Print { value } => {
+                let value = self.operand(state, *value)?;
+                let data = self.emit_field_access(state, value, "data")?;
+                let data = self.materialize(state, &data, self.pointer_type)?;
+                let len = self.emit_field_access(state, value, "len")?;
+                let len = self.materialize(state, &len, clif::types::I64)?;
+                let print_function = self.print_function();
+                let print_function = self.object.declare_func_in_func(print_function, state.builder.func);
+                state.builder.ins().call(print_function, &[data, len]);
+                return Ok(());
+            }
+            IrInstruction::Let { name, value } => {
+                let value = self.operand(state, *value)?;
+                // untyped bindings take the type of their value
+                let typ = match (module.typ(name.typ), value.typ) {
+                    (IrType::Unknown, Some(value_type)) => Some(value_type),
+                    (IrType::Unknown, None) if matches!(value.place, Place::Float(_)) => module.float_literal_type(),
+                    (IrType::Unknown, None) if !matches!(value.place, Place::Int(_)) => None,
+                    _ => Some(name.typ),
+                };
+                let clif_type = match typ {
+                    Some(typ) => self.clif_type(typ),
+                    None => value.clif_type,
+                }.ok_or("can't bind a Void value")?;
+                let v = self.materialize(state, &value, clif_type)?;
+                let local = self.new_local(state, &name.name, clif_type, typ, value.pointee);
+                self.store_local(state, &local, v)?;
+                return Ok(());
+            }
+            IrInstruction::Assign { name, value } => {
+                let value = self.operand(state, *value)?;
+                let local = *state.named.get(name).ok_or_else(|| format!("couldn't find `{}`", name))?;
+                let v = self.materialize(state, &value, local.clif_type.unwrap())?;
+                self.store_local(state, &local, v)?;
+                return Ok(());
+            }
+            IrInstruction::Store { place, value } => {
+                let value = self.operand(state, *value)?;
+                self.emit_store(state, *place, value)?;
+                return Ok(());
+            }
+            IrInstruction::Branch { condition, true_branch, false_branch } => {
+                let condition = self.operand(state, *condition)?;
+                let condition = self.truthy(state, &condition)?;
+                let true_block = *state.blocks.get(true_branch).unwrap();
+                let false_block = *state.blocks.get(false_branch).unwrap();
+                state.builder.ins().brif(condition, true_block, &[], false_block, &[]);
+                return Ok(());
+            }
+            IrInstruction::Jump { target } => {
+                let target = *state.blocks.get(target).unwrap();
+                state.builder.ins().jump(target, &[]);
+                return Ok(());
+            }
+            IrInstruction::Return { value } => {
+                let value = self.operand(state, *value)?;
+                match state.return_type {
+                    Some(return_type) => {
+                        let v = self.materialize(state, &value, return_type)?;
+                        state.builder.ins().return_(&[v]);
+                    }
+                    None => {
+                        state.builder.ins().return_(&[]);
+                    }
+                }
+                return Ok(());
+            }
+            IrInstruction::Error => return Err("tried to emit an error instruction".to_string()),
+        };
+        state.values.insert(index, value);
+        Ok(())
+    }
+
+    fn emit_call(&mut self, state: &mut FunctionState, callee: Value, args: &[IrInstructionIndex]) -> Result<Value, String> {
+        let direct = match callee.place {
+            Place::Function(id) => self.functions.iter()
+                .find(|(_, function)| **function == id)
+                .map(|(index, _)| *index),
+            _ => None,
+        };
+        let (params, return_type) = match direct {
+            Some(index) => match self.module.node(index) {
+                IrNode::Function(function) => (function.params.iter().map(|param| param.typ).collect(), function.return_type),
+                _ => unreachable!(),
+            },
+            None => match callee.typ.map(|t| self.module.typ(t)) {
+                Some(IrType::Function(params, return_type)) => (params.clone(), *return_type),
+                _ => return Err("tried to call a value that isn't a function".to_string()),
+            },
+        };
+        if params.len() != args.len() {
+            return Err(format!("expected {} arguments but got {}", params.len(), args.len()));
+        }
+        let mut arg_values = Vec::with_capacity(args.len());
+        for (arg, param) in args.iter().zip(params.iter()) {
+            let arg = self.operand(state, *arg)?;
+            let param_type = self.value_type(*param)?;
+            arg_values.push(self.materialize(state, &arg, param_type)?);
+        }
+        let call = match (direct, callee.place) {
+            (Some(_), Place::Function(id)) => {
+                let function = self.object.declare_func_in_func(id, state.builder.func);
+                state.builder.ins().call(function, &arg_values)
+            }
+            _ => {
+                let signature = self.signature(&params, return_type)?;
+                let signature = state.builder.import_signature(signature);
+                let callee = self.materialize(state, &callee, self.pointer_type)?;
+                state.builder.ins().call_indirect(signature, callee, &arg_values)
+            }
+        };
+        Ok(match state.builder.inst_results(call).first() {
+            Some(result) => Value::new(Place::Value(*result), self.clif_type(return_type), Some(return_type)),
+            None => Value::new(Place::Void, None, Some(return_type)),
+        })
+    }
+
+    fn emit_bin_op(&mut self, state: &mut FunctionState, index: IrInstructionIndex, lhs: Value, op: BinOpType, rhs: Value) -> Result<Value, String> {
+        if let BinOpType::And | BinOpType::Or = op {
+            let lhs = self.truthy(state, &lhs)?;
+            let rhs = self.truthy(state, &rhs)?;
+            let result = if let BinOpType::And = op {
+                state.builder.ins().band(lhs, rhs)
+            } else {
+                state.builder.ins().bor(lhs, rhs)
+            };
+            return Ok(Value::new(Place::Value(result), Some(clif::types::I8), None));
+        }
+        if op.is_shift() {
+            // shifts have the type of the value that is shifted, and Cranelift already takes the
+            // amount modulo its width
+            let clif_type = lhs.clif_type.unwrap_or(clif::types::I64);
+            let l = self.materialize(state, &lhs, clif_type)?;
+            let r = self.materialize(state, &rhs, clif_type)?;
+            let result = match op {
+                BinOpType::ShiftLeft => state.builder.ins().ishl(l, r),
+                _ if self.is_unsigned(lhs.typ) => state.builder.ins().ushr(l, r),
+                _ => state.builder.ins().sshr(l, r),
+            };
+            return Ok(Value::new(Place::Value(result), Some(clif_type), lhs.typ));
+        }
+
+        // integers are converted to the type of a float operand, untyped literals take the type of
+        // the other operand, otherwise operands are widened to the larger of the two types
+        let width = |value: &Value| value.clif_type.map(|t| t.bits()).unwrap_or(64);
+        let is_float = |value: &Value| value.clif_type.is_some_and(|t| t.is_float());
+        let lhs_literal = matches!(lhs.place, Place::Int(_) | Place::Float(_));
+        let rhs_literal = matches!(rhs.place, Place::Int(_) | Place::Float(_));
+        let (clif_type, typ) = if is_float(&lhs) != is_float(&rhs) {
+            if is_float(&lhs) { (lhs.clif_type, lhs.typ) } else { (rhs.clif_type, rhs.typ) }
+        } else if rhs_literal && !lhs_literal {
+            (lhs.clif_type, lhs.typ)
+        } else if lhs_literal && !rhs_literal {
+            (rhs.clif_type, rhs.typ)
+        } else if width(&lhs) >= width(&rhs) {
+            (lhs.clif_type, lhs.typ.or(rhs.typ))
+        } else {
+            (rhs.clif_type, rhs.typ.or(lhs.typ))
+        };
+        let clif_type = clif_type.unwrap_or(clif::types::I64);
+        let signed = !(self.is_unsigned(lhs.typ) || self.is_unsigned(rhs.typ));
+        let l = self.materialize(state, &lhs, clif_type)?;
+        let r = self.materialize(state, &rhs, clif_type)?;
+        if !clif_type.is_float() && op.overflow().is_some() {
+            let result = self.emit_arithmetic(state, index, l, op, r, signed);
+            return Ok(Value::new(Place::Value(result), Some(clif_type), typ));
+        }
+        // floats don't overflow, they become infinite
+        let op = op.overflow().map_or(op, |(op, _)| op);
+
+        if let (false, BinOpType::ForwardSlash | BinOpType::Percent) = (clif_type.is_float(), op) {
+            self.emit_division_checks(state, index, l, op, r, signed);
+        }
+
+        let ins = state.builder.ins();
+        let result = if clif_type.is_float() {
+            match op {
+                BinOpType::Plus => ins.fadd(l, r),
+                BinOpType::Minus => ins.fsub(l, r),
+                BinOpType::Star => ins.fmul(l, r),
+                BinOpType::ForwardSlash => ins.fdiv(l, r),
+                BinOpType::LessThan => ins.fcmp(FloatCC::LessThan, l, r),
+                BinOpType::GreaterThan => ins.fcmp(FloatCC::GreaterThan, l, r),
+                BinOpType::LessThanEqualTo => ins.fcmp(FloatCC::LessThanOrEqual, l, r),
+                BinOpType::GreaterThanEqualTo => ins.fcmp(FloatCC::GreaterThanOrEqual, l, r),
+                BinOpType::EqualTo => ins.fcmp(FloatCC::Equal, l, r),
+                // NaN isn't equal to anything, itself included
+                BinOpType::NotEqualTo => ins.fcmp(FloatCC::NotEqual, l, r),
+                BinOpType::Percent => {
+                    let quotient = ins.fdiv(l, r);
+                    let quotient = state.builder.ins().trunc(quotient);
+                    let product = state.builder.ins().fmul(quotient, r);
+                    state.builder.ins().fsub(l, product)
+                }
+                BinOpType::Ampersand | BinOpType::Pipe | BinOpType::Caret | BinOpType::ShiftLeft | BinOpType::ShiftRight => {
+                    return Err(format!("can't apply {} to floats", op));
+                }
+                _ => unreachable!(),
+            }
+        } else {
+            let compare = |signed_cc, unsigned_cc| if signed { signed_cc } else { unsigned_cc };
+            match op {
+                BinOpType::ForwardSlash => if signed { ins.sdiv(l, r) } else { ins.udiv(l, r) },
+                BinOpType::LessThan => ins.icmp(compare(IntCC::SignedLessThan, IntCC::UnsignedLessThan), l, r),
+                BinOpType::GreaterThan => ins.icmp(compare(IntCC::SignedGreaterThan, IntCC::UnsignedGreaterThan), l, r),
+                BinOpType::LessThanEqualTo => ins.icmp(compare(IntCC::SignedLessThanOrEqual, IntCC::UnsignedLessThanOrEqual), l, r),
+                BinOpType::GreaterThanEqualTo => ins.icmp(compare(IntCC::SignedGreaterThanOrEqual, IntCC::UnsignedGreaterThanOrEqual), l, r),
+                BinOpType::EqualTo => ins.icmp(IntCC::Equal, l, r),
+                BinOpType::NotEqualTo => ins.icmp(IntCC::NotEqual, l, r),
+                BinOpType::Percent => if signed { ins.srem(l, r) } else { ins.urem(l, r) },
+                BinOpType::Ampersand => ins.band(l, r),
+                BinOpType::Pipe => ins.bor(l, r),
+                BinOpType::Caret => ins.bxor(l, r),
+                _ => unreachable!(),
+            }
+        };
+        if op.is_comparison() {
+            Ok(Value::new(Place::Value(result), Some(clif::types::I8), None))
+        } else {
+            Ok(Value::new(Place::Value(result), Some(clif_type), typ))
+        }
+    }
+
+    /// Add, subtract or multiply two integers of the same type, and trap, wrap or saturate like
+    /// the operator says when the result doesn't fit it.
+    fn emit_arithmetic(&mut self, state: &mut FunctionState, index: IrInstructionIndex, l: clif::Value, op: BinOpType,
+                       r: clif::Value, signed: bool) -> clif::Value {
+        let (op, overflow) = op.overflow().unwrap();
+        let ins = state.builder.ins();
+        if overflow == Overflow::Wrap {
+            return match op {
+                BinOpType::Plus => ins.iadd(l, r),
+                BinOpType::Minus => ins.isub(l, r),
+                _ => ins.imul(l, r),
+            };
+        }
+        let (result, overflowed) = match (op, signed) {
+            (BinOpType::Plus, true) => ins.sadd_overflow(l, r),
+            (BinOpType::Plus, false) => ins.uadd_overflow(l, r),
+            (BinOpType::Minus, true) => ins.ssub_overflow(l, r),
+            (BinOpType::Minus, false) => ins.usub_overflow(l, r),
+            (_, true) => ins.smul_overflow(l, r),
+            (_, false) => ins.umul_overflow(l, r),
+        };
+        if overflow == Overflow::Saturate {
+            let clif_type = state.builder.func.dfg.value_type(l);
+            let bits = clif_type.bits();
+            // immediates are the bits of the value, so the limits are masked to the width of the type
+            let all_ones = if bits >= 64 { -1 } else { (1i64 << bits) - 1 };
+            let min = state.builder.ins().iconst(clif_type, (1i64 << (bits - 1)) & all_ones);
+            let max = state.builder.ins().iconst(clif_type, !(1i64 << (bits - 1)) & all_ones);
+            let limit = match (op, signed) {
+                // a signed result overflows towards the sign of the right operand, or for a
+                // product the sign the exact result has
+                (_, true) => {
+                    let negative = if let BinOpType::Star = op { state.builder.ins().bxor(l, r) } else { r };
+                    let negative = state.builder.ins().icmp_imm_s(IntCC::SignedLessThan, negative, 0);
+                    let (when_negative, otherwise) = if let BinOpType::Minus = op { (max, min) } else { (min, max) };
+                    state.builder.ins().select(negative, when_negative, otherwise)
+                }
+                (BinOpType::Minus, false) => state.builder.ins().iconst(clif_type, 0),
+                _ => state.builder.ins().iconst(clif_type, all_ones),
+            };
+            return state.builder.ins().select(overflowed, limit, result);
+        }
+        let message = self.module.overflow_message(index, op);
+        self.emit_panic_if(state, overflowed, &message);
+        result
+    }
+
+    /// Check that an integer division or remainder doesn't divide by zero, or overflow by
+    /// dividing the smallest signed value by -1. Cranelift would trap without saying why.
+    fn emit_division_checks(&mut self, state: &mut FunctionState, index: IrInstructionIndex, l: clif::Value, op: BinOpType,
+                            r: clif::Value, signed: bool) {
+        let by_zero = state.builder.ins().icmp_imm_s(IntCC::Equal, r, 0);
+        let message = self.module.division_by_zero_message(index, op);
+        self.emit_panic_if(state, by_zero, &message);
+        if signed {
+            let min = self.int_min(state.builder.func.dfg.value_type(l));
+            let smallest = state.builder.ins().icmp_imm_s(IntCC::Equal, l, min);
+            let minus_one = state.builder.ins().icmp_imm_s(IntCC::Equal, r, -1);
+            let overflowed = state.builder.ins().band(smallest, minus_one);
+            let message = self.module.overflow_message(index, op);
+            self.emit_panic_if(state, overflowed, &message);
+        }
+    }
+
+    /// The smallest value of a signed integer type.
+    fn int_min(&self, clif_type: clif::Type) -> i64 {
+        i64::MIN >> (64 - clif_type.bits())
+    }
+
+    /// Call the panic runtime function with a message when `condition` is true, and carry on in
+    /// a new block otherwise.
+    fn emit_panic_if(&mut self, state: &mut FunctionState, condition: clif::Value, message: &str) {
+        let panic_block = state.builder.create_block();
+        let continue_block = state.builder.create_block();
+        state.builder.set_cold_block(panic_block);
+        state.builder.ins().brif(condition, panic_block, &[], continue_block, &[]);
+        state.builder.switch_to_block(panic_block);
+        self.emit_panic(state, message);
+        state.builder.switch_to_block(continue_block);
+    }
+
+    /// Call the panic runtime function with a message, which ends the current block.
+    fn emit_panic(&mut self, state: &mut FunctionState, message: &str) {
+        let id = self.object.declare_anonymous_data(false, false)
+            .unwrap_or_else(|e| panic!("couldn't declare a panic message: {}", e));
+        let mut bytes = DataDescription::new();
+        bytes.define(message.as_bytes().into());
+        self.object.define_data(id, &bytes)
+            .unwrap_or_else(|e| panic!("couldn't define a panic message: {}", e));
+        let data = self.object.declare_data_in_func(id, state.builder.func);
+        let data = state.builder.ins().symbol_value(self.pointer_type, data);
+        let len = state.builder.ins().iconst(clif::types::I64, message.len() as i64);
+        let panic_function = self.panic_function();
+        let panic_function = self.object.declare_func_in_func(panic_function, state.builder.func);
+        state.builder.ins().call(panic_function, &[data, len]);
+        state.builder.ins().trap(TrapCode::unwrap_user(1));
+    }
+
+    fn emit_unary_op(&mut self, state: &mut FunctionState, index: IrInstructionIndex, op: UnaryOpType, value: Value) -> Result<Value, String> {
+        Ok(match op {
+            UnaryOpType::Minus | UnaryOpType::WrappingMinus => {
+                let clif_type = value.clif_type.unwrap_or(clif::types::I64);
+                let v = self.materialize(state, &value, clif_type)?;
+                if let (UnaryOpType::Minus, false) = (op, clif_type.is_float() || self.is_unsigned(value.typ)) {
+                    let min = self.int_min(clif_type);
+                    let overflowed = state.builder.ins().icmp_imm_s(IntCC::Equal, v, min);
+                    let message = self.module.negation_message(index);
+                    self.emit_panic_if(state, overflowed, &message);
+                }
+                let result = if clif_type.is_float() { state.builder.ins().fneg(v) } else { state.builder.ins().ineg(v) };
+                Value::new(Place::Value(result), Some(clif_type), value.typ)
+            }
+            UnaryOpType::Not => {
+                let truthy = self.truthy(state, &value)?;
+                let result = state.builder.ins().bxor_imm_u(truthy, 1);
+                Value::new(Place::Value(result), Some(clif::types::I8), None)
+            }
+        })
+    }
+
+    /// Access a field of a row or struct through its pointer.
+    fn emit_field_access(&mut self, state: &mut FunctionState, aggregate: Value, field_name: &str) -> Result<Value, String> {
+        let (offset, field_type) = self.field(aggregate.typ, field_name)?;
+        let clif_type = self.value_type(field_type)?;
+        let address = self.materialize(state, &aggregate, self.pointer_type)?;
+        let loaded = state.builder.ins().load(clif_type, MemFlagsData::new(), address, offset);
+        Ok(Value::new(Place::Value(loaded), Some(clif_type), Some(field_type)))
+    }
+
+    /// The offset and type of a field of a struct or row type.
+    fn field(&self, typ: Option<IrTypeIndex>, field_name: &str) -> Result<(i32, IrTypeIndex), String> {
+        let fields = self.aggregate_fields(typ)
+            .ok_or_else(|| format!("can't access field `{}` of {:?}", field_name, typ))?;
+        let (offsets, _) = self.layout(&fields)?;
+        let field_index = fields.iter().position(|f| f.name == field_name)
+            .ok_or_else(|| format!("couldn't find field `{}`", field_name))?;
+        Ok((offsets[field_index] as i32, fields[field_index].typ))
+    }
+
+    /// The type a pointer points to, and the Cranelift type it's loaded as.
+    fn pointee(&self, pointer: &Value) -> Result<(Option<IrTypeIndex>, clif::Type), String> {
+        let typ = pointer.typ.and_then(|t| match self.module.typ(t) {
+            IrType::Reference(inner, _) => Some(*inner),
+            _ => None,
+        });
+        let clif_type = match typ {
+            Some(typ) => self.value_type(typ)?,
+            None => pointer.pointee.ok_or_else(|| format!("can't dereference {:?}", pointer))?,
+        };
+        Ok((typ, clif_type))
+    }
+
+    fn emit_dereference(&mut self, state: &mut FunctionState, pointer: Value) -> Result<Value, String> {
+        let (typ, clif_type) = self.pointee(&pointer)?;
+        let address = self.materialize(state, &pointer, self.pointer_type)?;
+        let loaded = state.builder.ins().load(clif_type, MemFlagsData::new(), address, 0);
+        Ok(Value::new(Place::Value(loaded), Some(clif_type), typ))
+    }
+
+    /// The address of an element of an array or slice, whose base points to the first element,
+    /// along with the element's type and the Cranelift type it's loaded as.
+    fn element(&mut self, state: &mut FunctionState, base: IrInstructionIndex, index: IrInstructionIndex)
+               -> Result<(clif::Value, Option<IrTypeIndex>, clif::Type), String> {
+        let base = self.operand(state, base)?;
+        let typ = base.typ.and_then(|t| match self.module.typ(t) {
+            IrType::Array(element, _) | IrType::Reference(element, _) => Some(*element),
+            _ => None,
+        });
+        let clif_type = match typ {
+            Some(typ) => self.value_type(typ)?,
+            None => base.pointee.ok_or_else(|| format!("can't index {:?}", base))?,
+        };
+        let index = self.operand(state, index)?;
+        let i = self.materialize(state, &index, self.pointer_type)?;
+        let offset = state.builder.ins().imul_imm_u(i, clif_type.bytes() as i64);
+        let address = self.materialize(state, &base, self.pointer_type)?;
+        Ok((state.builder.ins().iadd(address, offset), typ, clif_type))
+    }
+
+    fn emit_index(&mut self, state: &mut FunctionState, base: IrInstructionIndex, index: IrInstructionIndex) -> Result<Value, String> {
+        let (address, typ, clif_type) = self.element(state, base, index)?;
+        let loaded = state.builder.ins().load(clif_type, MemFlagsData::new(), address, 0);
+        Ok(Value::new(Place::Value(loaded), Some(clif_type), typ))
+    }
+
+    fn field_name(&self, field: IrInstructionIndex) -> Result<String, String> {
+        match self.module.instruction(field) {
+            IrInstruction::Ref(name) => Ok(name.clone()),
+            ins => Err(format!("can't access field {:?}", ins)),
+        }
+    }
+
+    /// Read a place, which isn't emitted on its own. Anything else is an ordinary operand.
+    fn place_value(&mut self, state: &mut FunctionState, index: IrInstructionIndex) -> Result<Value, String> {
+        if !state.places.contains(&index) {
+            return self.operand(state, index);
+        }
+        match self.module.instruction(index) {
+            IrInstruction::FieldAccessor { aggregate, value } => {
+                let field_name = self.field_name(*value)?;
+                let aggregate = self.place_value(state, *aggregate)?;
+                self.emit_field_access(state, aggregate, &field_name)
+            }
+            IrInstruction::Dereference { pointer } => {
+                let pointer = self.operand(state, *pointer)?;
+                self.emit_dereference(state, pointer)
+            }
+            IrInstruction::Index { base, index } => self.emit_index(state, *base, *index),
+            ins => Err(format!("{:?} isn't a place", ins)),
+        }
+    }
+
+    /// The address of a place a borrow takes, and the Cranelift type of what's there. Fields of
+    /// structs are inside the struct their pointer points to.
+    fn place_address(&mut self, state: &mut FunctionState, index: IrInstructionIndex) -> Result<(clif::Value, clif::Type), String> {
+        match self.module.instruction(index) {
+            IrInstruction::FieldAccessor { aggregate, value } => {
+                let field_name = self.field_name(*value)?;
+                let container = self.place_value(state, *aggregate)?;
+                let (offset, field_type) = self.field(container.typ, &field_name)?;
+                let clif_type = self.value_type(field_type)?;
+                let address = self.materialize(state, &container, self.pointer_type)?;
+                Ok((state.builder.ins().iadd_imm_s(address, offset as i64), clif_type))
+            }
+            IrInstruction::Dereference { pointer } => {
+                let pointer = self.operand(state, *pointer)?;
+                let (_, clif_type) = self.pointee(&pointer)?;
+                Ok((self.materialize(state, &pointer, self.pointer_type)?, clif_type))
+            }
+            IrInstruction::Index { base, index } => {
+                let (address, _, clif_type) = self.element(state, *base, *index)?;
+                Ok((address, clif_type))
+            }
+            ins => Err(format!("{:?} isn't a place", ins)),
+        }
+    }
+
+    /// Write a value to a local, a pointee or a field. Rows are values that may be shared, so a
+    /// field of one is written by copying the row and storing the copy where the row came from.
+    fn emit_store(&mut self, state: &mut FunctionState, place: IrInstructionIndex, value: Value) -> Result<(), String> {
+        match self.module.instruction(place) {
+            IrInstruction::Ref(name) => {
+                let local = *state.named.get(name).ok_or_else(|| format!("couldn't find `{}`", name))?;
+                let v = self.materialize(state, &value, local.clif_type.unwrap())?;
+                self.store_local(state, &local, v)?;
+            }
+            IrInstruction::Dereference { pointer } => {
+                let pointer = self.operand(state, *pointer)?;
+                let (_, clif_type) = self.pointee(&pointer)?;
+                let address = self.materialize(state, &pointer, self.pointer_type)?;
+                let v = self.materialize(state, &value, clif_type)?;
+                state.builder.ins().store(MemFlagsData::new(), v, address, 0);
+            }
+            IrInstruction::Index { base, index } => {
+                let (address, _, clif_type) = self.element(state, *base, *index)?;
+                let v = self.materialize(state, &value, clif_type)?;
+                state.builder.ins().store(MemFlagsData::new(), v, address, 0);
+            }
+            IrInstruction::FieldAccessor { aggregate, value: field } => {
+                let field_name = self.field_name(*field)?;
+                let container = self.place_value(state, *aggregate)?;
+                let fields = self.aggregate_fields(container.typ)
+                    .ok_or_else(|| format!("can't access field `{}` of {:?}", field_name, container.typ))?;
+                let (offsets, size) = self.layout(&fields)?;
+                let field_index = fields.iter().position(|f| f.name == field_name)
+                    .ok_or_else(|| format!("couldn't find field `{}`", field_name))?;
+                let field_type = self.value_type(fields[field_index].typ)?;
+                let v = self.materialize(state, &value, field_type)?;
+                let address = self.materialize(state, &container, self.pointer_type)?;
+                if !self.is_row(container.typ.unwrap()) {
+                    state.builder.ins().store(MemFlagsData::new(), v, address, offsets[field_index] as i32);
+                    return Ok(());
+                }
+                let allocator = Value::new(Place::Int(0), Some(clif::types::I64), None);
+                let copy = self.emit_alloc(state, &allocator, size)?;
+                for (i, field) in fields.iter().enumerate() {
+                    let offset = offsets[i] as i32;
+                    let v = if i == field_index {
+                        v
+                    } else {
+                        let clif_type = self.value_type(field.typ)?;
+                        state.builder.ins().load(clif_type, MemFlagsData::new(), address, offset)
+                    };
+                    state.builder.ins().store(MemFlagsData::new(), v, copy, offset);
+                }
+                let copy = Value::new(Place::Value(copy), Some(self.pointer_type), container.typ);
+                self.emit_store(state, *aggregate, copy)?;
+            }
+            // anything else is a temporary, so nothing can see the write
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Rows are kept in memory from the default allocator, like `new` objects.
+    fn emit_row(&mut self, state: &mut FunctionState, typ: IrTypeIndex, values: &[Value]) -> Result<Value, String> {
+        let fields = self.aggregate_fields(Some(typ)).expect("row literals have row types");
+        let (offsets, size) = self.layout(&fields)?;
+        let allocator = Value::new(Place::Int(0), Some(clif::types::I64), None);
+        let pointer = self.emit_alloc(state, &allocator, size)?;
+        for (i, (field, value)) in fields.iter().zip(values).enumerate() {
+            let field_type = self.value_type(field.typ)?;
+            let v = self.materialize(state, value, field_type)?;
+            state.builder.ins().store(MemFlagsData::new(), v, pointer, offsets[i] as i32);
+        }
+        Ok(Value::new(Place::Value(pointer), Some(self.pointer_type), Some(typ)))
+    }
+
+    /// Lower `new T in allocator` to a call to the allocator.
+    /// The size of a new `typ`, the type of the pointer to it and what that points to, when that
+    /// isn't clear from the pointer's type.
+    fn allocation(&self, typ: IrTypeIndex) -> Result<(u32, Option<IrTypeIndex>, Option<clif::Type>), String> {
+        // nominal types are already pointers to their struct, everything else gets a new pointer
+        Ok(match self.module.typ(typ) {
+            IrType::Base(name) => match self.module.find_struct(name) {
+                Some(IrNode::Struct { fields, .. }) => (self.layout(fields)?.1, Some(typ), None),
+                _ => return Err(format!("can't allocate {:?} because its size is unknown", self.module.typ(typ))),
+            },
+            // arrays are already pointers to their first element, and their elements follow it
+            IrType::Array(element, len) => {
+                let clif_type = self.clif_type(*element).ok_or_else(|| format!("can't allocate {:?}", self.module.typ(typ)))?;
+                (clif_type.bytes() * *len as u32, Some(typ), None)
+            }
+            _ => {
+                let clif_type = self.clif_type(typ).ok_or_else(|| format!("can't allocate {:?}", self.module.typ(typ)))?;
+                (clif_type.bytes(), None, Some(clif_type))
+            }
+        })
+    }
+
+    fn emit_new(&mut self, state: &mut FunctionState, typ: IrTypeIndex, allocator: Value) -> Result<Value, String> {
+        let (size, result_type, pointee) = self.allocation(typ)?;
+        let pointer = self.emit_alloc(state, &allocator, size)?;
+        let mut result = Value::new(Place::Value(pointer), Some(self.pointer_type), result_type);
+        result.pointee = pointee;
+        Ok(result)
+    }
+
+    fn emit_alloc(&mut self, state: &mut FunctionState, allocator: &Value, size: u32) -> Result<clif::Value, String> {
+        let alloc_function = self.alloc_function();
+        let alloc_function = self.object.declare_func_in_func(alloc_function, state.builder.func);
+        let allocator = self.materialize(state, allocator, self.pointer_type)?;
+        let size = state.builder.ins().iconst(clif::types::I64, size as i64);
+        let call = state.builder.ins().call(alloc_function, &[allocator, size]);
+        Ok(state.builder.inst_results(call)[0])
+    }
+
+    fn emit_stack_alloc(&mut self, state: &mut FunctionState, typ: IrTypeIndex) -> Result<Value, String> {
+        let (size, result_type, pointee) = self.allocation(typ)?;
+        // whole words, so they can be zeroed 8 bytes at a time
+        let size = size.div_ceil(8).max(1) * 8;
+        let slot = StackSlotData::new(StackSlotKind::ExplicitSlot, size, 3);
+        let slot = state.builder.create_sized_stack_slot(slot);
+        let pointer = state.builder.ins().stack_addr(self.pointer_type, slot, 0);
+        // objects start out zeroed, like those the interpreter allocates
+        let config = self.object.target_config();
+        state.builder.emit_small_memset(config, pointer, 0, size as u64, 8, MemFlagsData::new());
+        let mut result = Value::new(Place::Value(pointer), Some(self.pointer_type), result_type);
+        result.pointee = pointee;
+        Ok(result)
+    }
+
+    /// Declare the allocator the first time it's needed.
+    fn alloc_function(&mut self) -> FuncId {
+        if let Some(id) = self.alloc_function {
+            return id;
+        }
+        let mut signature = self.object.make_signature();
+        signature.params.push(AbiParam::new(self.pointer_type));
+        signature.params.push(AbiParam::new(clif::types::I64));
+        signature.returns.push(AbiParam::new(self.pointer_type));
+        let id = self.object.declare_function(ALLOC_FUNCTION, Linkage::Import, &signature)
+            .unwrap_or_else(|e| panic!("couldn't declare {}: {}", ALLOC_FUNCTION, e));
+        self.alloc_function = Some(id);
+        id
+    }
+
+    /// Declare the runtime function `print` calls the first time it's needed.
+    fn print_function(&mut self) -> FuncId {
+        if let Some(id) = self.print_function {
+            return id;
+        }
+        let mut signature = self.object.make_signature();
+        signature.params.push(AbiParam::new(self.pointer_type));
+        signature.params.push(AbiParam::new(clif::types::I64));
+        let id = self.object.declare_function(PRINT_FUNCTION, Linkage::Import, &signature)
+            .unwrap_or_else(|e| panic!("couldn't declare {}: {}", PRINT_FUNCTION, e));
+        self.print_function = Some(id);
+        id
+    }
+
+    /// Declare the panic runtime function the first time it's needed.
+    fn panic_function(&mut self) -> FuncId {
+        if let Some(id) = self.panic_function {
+            return id;
+        }
+        let mut signature = self.object.make_signature();
+        signature.params.push(AbiParam::new(self.pointer_type));
+        signature.params.push(AbiParam::new(clif::types::I64));
+        let id = self.object.declare_function(PANIC_FUNCTION, Linkage::Import, &signature)
+            .unwrap_or_else(|e| panic!("couldn't declare {}: {}", PANIC_FUNCTION, e));
+        self.panic_function = Some(id);
+        id
+    }
+
+    /// Put the bytes of a string literal and the `Str` that points to them in read-only data,
+    /// returning the `Str`.
+    fn define_string(&mut self, str_type: IrTypeIndex, string: &str) -> Result<DataId, String> {
+        let declare = |object: &mut ObjectModule, description: &DataDescription| {
+            let id = object.declare_anonymous_data(false, false)
+                .unwrap_or_else(|e| panic!("couldn't declare a string literal: {}", e));
+            object.define_data(id, description)
+                .unwrap_or_else(|e| panic!("couldn't define a string literal: {}", e));
+            id
+        };
+        let mut bytes = DataDescription::new();
+        bytes.define(string.as_bytes().into());
+        let bytes = declare(&mut self.object, &bytes);
+
+        let fields = self.aggregate_fields(Some(str_type)).unwrap();
+        let (offsets, size) = self.layout(&fields)?;
+        let len_bytes = self.value_type(fields[1].typ)?.bytes() as usize;
+        let len = match self.object.isa().endianness() {
+            clif::Endianness::Little => (string.len() as u64).to_le_bytes()[..len_bytes].to_vec(),
+            clif::Endianness::Big => (string.len() as u64).to_be_bytes()[8 - len_bytes..].to_vec(),
+        };
+        let mut contents = vec![0; size as usize];
+        let len_offset = offsets[1] as usize;
+        contents[len_offset..len_offset + len_bytes].copy_from_slice(&len);
+        let mut header = DataDescription::new();
+        header.define(contents.into());
+        header.set_align(8);
+        let data = self.object.declare_data_in_data(bytes, &mut header);
+        header.write_data_addr(offsets[0], data, 0);
+        Ok(declare(&mut self.object, &header))
+    }
+
+    fn aggregate_fields(&self, typ: Option<IrTypeIndex>) -> Option<Vec<IrTypedName>> {
+        match typ.map(|t| self.module.typ(t)) {
+            Some(IrType::Row(fields)) => Some(fields.clone()),
+            Some(IrType::Base(name)) => match self.module.find_struct(name) {
+                Some(IrNode::Struct { fields, .. }) => Some(fields.clone()),
+                _ => None,
+            },
+            Some(IrType::Refinement(_, inner, _)) => self.aggregate_fields(Some(*inner)),
+            _ => None,
+        }
+    }
+
+    /// Lay out fields with their natural alignment, returning their offsets and the total size.
+    fn layout(&self, fields: &[IrTypedName]) -> Result<(Vec<u32>, u32), String> {
+        let mut offsets = Vec::with_capacity(fields.len());
+        let mut size: u32 = 0;
+        let mut align = 1;
+        for field in fields {
+            let field_size = self.value_type(field.typ)?.bytes();
+            size = size.div_ceil(field_size) * field_size;
+            offsets.push(size);
+            size += field_size;
+            align = align.max(field_size);
+        }
+        Ok((offsets, size.div_ceil(align) * align))
+    }
+
+    fn is_pointer(&self, typ: IrTypeIndex) -> bool {
+        match self.module.typ(typ) {
+            IrType::Base(_) | IrType::Reference(_, _) | IrType::Row(_) | IrType::Optional(_) | IrType::Function(_, _) => true,
+            IrType::Array(..) => true,
+            IrType::Refinement(_, inner, _) => self.is_pointer(*inner),
+            _ => false,
+        }
+    }
+
+    fn is_row(&self, typ: IrTypeIndex) -> bool {
+        match self.module.typ(typ) {
+            IrType::Row(_) => true,
+            IrType::Refinement(_, inner, _) => self.is_row(*inner),
+            _ => false,
+        }
+    }
+
+    fn is_unsigned(&self, typ: Option<IrTypeIndex>) -> bool {
+        match typ.map(|t| self.module.typ(t)) {
+            Some(IrType::UInt(_)) => true,
+            Some(IrType::Refinement(_, inner, _)) => self.is_unsigned(Some(*inner)),
+            _ => false,
+        }
+    }
+
+    fn check_types(&self) -> Result<(), String> {
+        for (_, typ) in self.module.module_arena.type_arena.iter() {
+            if let IrType::Float(f) = typ {
+                if !matches!(f.bits(), 32 | 64) {
+                    return Err(format!("{}-bit floats aren't supported by the Cranelift backend", f.bits()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The Cranelift type of a type that has values, like that of a parameter or a field.
+    fn value_type(&self, typ: IrTypeIndex) -> Result<clif::Type, String> {
+        self.clif_type(typ).ok_or_else(|| format!("can't use a value of type `{}`", self.module.type_name(typ)))
+    }
+
+    /// The Cranelift type values of `typ` are kept in. Structs, rows and optionals that
+    /// aren't pointers already are pointers to memory.
+    fn clif_type(&self, typ: IrTypeIndex) -> Option<clif::Type> {
+        Some(match self.module.typ(typ) {
+            IrType::Bool => clif::types::I8,
+            IrType::Int(i) => clif::Type::int(i.bits() as u16).unwrap(),
+            IrType::UInt(u) => clif::Type::int(u.bits() as u16).unwrap(),
+            IrType::Float(f) => match f.bits() {
+                32 => clif::types::F32,
+                64 => clif::types::F64,
+                bits => unreachable!("`check_types` reports {}-bit floats", bits),
+            },
+            IrType::Refinement(_, inner, _) => return self.clif_type(*inner),
+            IrType::Base(_) | IrType::Row(_) | IrType::Reference(_, _) | IrType::Optional(_) | IrType::Function(_, _) => self.pointer_type,
+            IrType::Array(..) => self.pointer_type,
+            IrType::Void => return None,
+            IrType::Unknown => clif::types::I64,
+        })
+    }
+}
@@ -0,0 +1,882 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use crate::ast::{BinOpType, Overflow, UnaryOpType};
+use crate::ir::{FloatTy, IntTy, IrBlockIndex, IrFunction, IrInstruction, IrInstructionIndex, IrNode, IrNodeIndex, IrType, IrTypeIndex, Module, UIntTy};
+
+/// Calls nested deeper than this are reported as a stack overflow instead of overflowing
+/// the interpreter's own stack.
+const MAX_CALL_DEPTH: usize = 10_000;
+
+/// Stack size of the thread `run` interprets on, which is enough for `MAX_CALL_DEPTH` calls.
+const STACK_SIZE: usize = 512 * 1024 * 1024;
+
+/// Address of a cell on the interpreter's heap.
+pub type Address = usize;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// Integers of every width. They are wrapped to the width of their type whenever they are
+    /// stored, passed or returned, which matches what the LLVM backend does. Signed integers are
+    /// kept sign extended and unsigned ones zero extended, except `UInt128`s, which are kept as
+    /// the bits of an i128.
+    Int(i128),
+    /// Floats of every width. `Float32`s are rounded to single precision whenever they are
+    /// stored, passed or returned.
+    Float(f64),
+    Bool(bool),
+    Pointer(Address),
+    /// A pointer to a field inside the value in a heap cell, by the path of field indices to it,
+    /// which borrowing a field of a struct gives.
+    Interior(Address, Vec<usize>),
+    Null,
+    Function(IrNodeIndex),
+    /// Structs and rows, with their fields in declaration order.
+    Aggregate(Vec<Value>),
+    Void,
+}
+
+impl Value {
+    /// Convert a value to an integer, the way the JIT returns results.
+    pub fn as_i64(&self) -> i64 {
+        match self {
+            Value::Int(i) => *i as i64,
+            Value::Float(f) => *f as i64,
+            Value::Bool(b) => *b as i64,
+            Value::Pointer(address) | Value::Interior(address, _) => *address as i64,
+            _ => 0,
+        }
+    }
+}
+
+/// A value along with its IR type when it's known.
+#[derive(Clone, Debug)]
+struct Typed {
+    value: Value,
+    typ: Option<IrTypeIndex>,
+}
+
+impl Typed {
+    fn new(value: Value, typ: Option<IrTypeIndex>) -> Self {
+        Self { value, typ }
+    }
+}
+
+/// State of a single call.
+struct Frame {
+    locals: HashMap<String, (Address, IrTypeIndex)>,
+    values: HashMap<IrInstructionIndex, Typed>,
+}
+
+/// Reference interpreter that executes a module's IR block by block. Every local and every
+/// allocation lives in a cell on a simple heap, so pointers are just cell addresses.
+pub struct Interpreter<'module> {
+    module: &'module Module,
+    heap: Vec<Value>,
+    functions: HashMap<String, IrNodeIndex>,
+    /// Where the `Str` of each string literal that was evaluated is, so it's only allocated once
+    /// like the constant data the backends put it in.
+    strings: HashMap<IrInstructionIndex, Address>,
+    /// The places stores write to and borrows take the address of in every function, which
+    /// aren't read.
+    places: HashSet<IrInstructionIndex>,
+    depth: usize,
+}
+
+impl<'m> Interpreter<'m> {
+    pub fn new(module: &'m Module) -> Self {
+        let mut functions = HashMap::new();
+        let mut places = HashSet::new();
+        for (index, function) in module.functions() {
+            functions.entry(function.name.clone()).or_insert(index);
+            places.extend(module.places(function));
+        }
+        Self {
+            module,
+            // address 0 is never handed out so it can't be confused with null
+            heap: vec![Value::Void],
+            functions,
+            strings: HashMap::new(),
+            places,
+            depth: 0,
+        }
+    }
+
+    /// Call a function by name with integer arguments and return its result as an integer.
+    /// Every interpreted call nests a few Rust calls, so this runs on a thread with a big stack.
+    pub fn run(&mut self, name: &str, args: &[i64]) -> Result<i64, String> {
+        let args = args.iter().map(|arg| Value::Int(*arg as i128)).collect();
+        std::thread::scope(|scope| {
+            std::thread::Builder::new()
+                .stack_size(STACK_SIZE)
+                .spawn_scoped(scope, || self.call(name, args))
+                .map_err(|e| format!("couldn't start the interpreter: {}", e))?
+                .join()
+                .map_err(|_| "the interpreter panicked".to_string())?
+        }).map(|value| value.as_i64())
+    }
+
+    /// Call a function by name.
+    pub fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, String> {
+        let index = *self.functions.get(name).ok_or(format!("couldn't find function `{}`", name))?;
+        let args = args.into_iter().map(|arg| Typed::new(arg, None)).collect();
+        Ok(self.call_function(index, args)?.value)
+    }
+
+    /// Read the value stored in a heap cell.
+    pub fn load(&self, address: Address) -> Result<&Value, String> {
+        match self.heap.get(address) {
+            Some(value) if address != 0 => Ok(value),
+            _ => Err(format!("invalid pointer {}", address)),
+        }
+    }
+
+    fn store(&mut self, address: Address, value: Value) -> Result<(), String> {
+        match self.heap.get_mut(address) {
+            Some(cell) if address != 0 => {
+                *cell = value;
+                Ok(())
+            }
+            _ => Err(format!("invalid pointer {}", address)),
+        }
+    }
+
+    /// Read the field at `path` inside the value in a heap cell, following a field index for
+    /// each level of rows.
+    fn load_at(&self, address: Address, path: &[usize]) -> Result<&Value, String> {
+        let mut value = self.load(address)?;
+        for index in path {
+            value = match value {
+                Value::Aggregate(values) => &values[*index],
+                value => return Err(format!("can't access field {} of {:?}", index, value)),
+            };
+        }
+        Ok(value)
+    }
+
+    /// Write the field at `path` inside the value in a heap cell.
+    fn store_at(&mut self, address: Address, path: &[usize], value: Value) -> Result<(), String> {
+        let mut cell = match self.heap.get_mut(address) {
+            Some(cell) if address != 0 => cell,
+            _ => return Err(format!("invalid pointer {}", address)),
+        };
+        for index in path {
+            cell = match cell {
+                Value::Aggregate(values) => &mut values[*index],
+                value => return Err(format!("can't access field {} of {:?}", index, value)),
+            };
+        }
+        *cell = value;
+        Ok(())
+    }
+
+    fn allocate(&mut self, value: Value) -> Address {
+        self.heap.push(value);
+        self.heap.len() - 1
+    }
+
+    fn function(&self, index: IrNodeIndex) -> &'m IrFunction {
+        match self.module.node(index) {
+            IrNode::Function(function) => function,
+            node => panic!("{:?} isn't a function", node),
+        }
+    }
+
+    fn call_function(&mut self, index: IrNodeIndex, args: Vec<Typed>) -> Result<Typed, String> {
+        let function = self.function(index);
+        if args.len() != function.params.len() {
+            return Err(format!("{} expected {} arguments but got {}", function.name, function.params.len(), args.len()));
+        }
+        if self.depth >= MAX_CALL_DEPTH {
+            return Err(format!("stack overflow calling {}", function.name));
+        }
+
+        let mut frame = Frame {
+            locals: HashMap::new(),
+            values: HashMap::new(),
+        };
+        for (param, arg) in function.params.iter().zip(args) {
+            let value = self.convert(arg, param.typ);
+            let address = self.allocate(value);
+            frame.locals.insert(param.name.clone(), (address, param.typ));
+        }
+
+        self.depth += 1;
+        let result = self.execute(function, &mut frame);
+        self.depth -= 1;
+        let result = result?;
+        Ok(Typed::new(self.convert(result, function.return_type), Some(function.return_type)))
+    }
+
+    /// Run the blocks of a function starting at its entry block until it returns.
+    fn execute(&mut self, function: &IrFunction, frame: &mut Frame) -> Result<Typed, String> {
+        let mut block = function.blocks[0];
+        loop {
+            match self.execute_block(frame, block)? {
+                Control::Jump(target) => block = target,
+                Control::Return(value) => return Ok(value),
+                Control::FallThrough => {
+                    return match self.module.typ(function.return_type) {
+                        IrType::Void => Ok(Typed::new(Value::Void, None)),
+                        _ => Err(format!("{} reached the end of a block without returning", function.name)),
+                    };
+                }
+            }
+        }
+    }
+
+    fn execute_block(&mut self, frame: &mut Frame, block: IrBlockIndex) -> Result<Control, String> {
+        let module = self.module;
+        for index in module.block(block).instructions.iter() {
+            if self.places.contains(index) {
+                // stores and borrows find these themselves
+                continue;
+            }
+            let value = match module.instruction(*index) {
+                // these are evaluated where they are used
+                IrInstruction::Ref(_) | IrInstruction::NatLiteral(_) | IrInstruction::FloatLiteral(_)
+                | IrInstruction::BoolLiteral(_) | IrInstruction::CharLiteral(_) => continue,
+                IrInstruction::StrLiteral(string) => self.string(*index, string),
+                IrInstruction::BinOp(lhs, op, rhs) => {
+                    let lhs = self.operand(frame, *lhs)?;
+                    let rhs = self.operand(frame, *rhs)?;
+                    self.bin_op(*index, lhs, *op, rhs)?
+                }
+                IrInstruction::UnaryOp(op, value) => {
+                    let value = self.operand(frame, *value)?;
+                    self.unary_op(*index, *op, value)?
+                }
+                IrInstruction::Cast { value, typ } => {
+                    let value = self.operand(frame, *value)?;
+                    Typed::new(self.convert(value, *typ), Some(*typ))
+                }
+                IrInstruction::FieldAccessor { aggregate, value } => {
+                    let field_name = match module.instruction(*value) {
+                        IrInstruction::Ref(name) => name,
+                        ins => return Err(format!("can't access field {:?}", ins)),
+                    };
+                    let aggregate = self.operand(frame, *aggregate)?;
+                    self.field_access(aggregate, field_name)?
+                }
+                IrInstruction::FunctionCall { function, args } => {
+                    let callee = self.operand(frame, *function)?;
+                    let mut arg_values = Vec::with_capacity(args.len());
+                    for arg in args {
+                        arg_values.push(self.operand(frame, *arg)?);
+                    }
+                    match callee.value {
+                        Value::Function(index) => self.call_function(index, arg_values)?,
+                        value => return Err(format!("tried to call {:?}", value)),
+                    }
+                }
+                IrInstruction::New { typ, allocator } => {
+                    self.operand(frame, *allocator)?;
+                    self.new_object(*typ)
+                }
+                // the interpreter has no frames in its memory, objects on the stack live as long as any other
+                IrInstruction::StackAlloc { typ } => self.new_object(*typ),
+                IrInstruction::RowLiteral { typ, values } => {
+                    let fields = match module.typ(*typ) {
+                        IrType::Row(fields) => fields,
+                        t => return Err(format!("{:?} isn't a row type", t)),
+                    };
+                    let mut field_values = Vec::with_capacity(values.len());
+                    for (field, value) in fields.iter().zip(values) {
+                        let value = self.operand(frame, *value)?;
+                        field_values.push(self.convert(value, field.typ));
+                    }
+                    Typed::new(Value::Aggregate(field_values), Some(*typ))
+                }
+                IrInstruction::Dereference { pointer } => {
+                    let pointer = self.operand(frame, *pointer)?;
+                    let typ = pointer.typ.and_then(|t| match module.typ(t) {
+                        IrType::Reference(inner, _) => Some(*inner),
+                        _ => None,
+                    });
+                    match pointer.value {
+                        Value::Pointer(address) => Typed::new(self.load(address)?.clone(), typ),
+                        Value::Interior(address, path) => Typed::new(self.load_at(address, &path)?.clone(), typ),
+                        value => return Err(format!("tried to dereference {:?}", value)),
+                    }
+                }
+                IrInstruction::Index { base, index } => {
+                    let (address, typ) = self.element(frame, *base, *index)?;
+                    Typed::new(self.load(address)?.clone(), typ)
+                }
+                IrInstruction::BoundsCheck { index, len } => {
+                    let i = self.operand(frame, *index)?.value.as_i64();
+                    let len = self.operand(frame, *len)?.value.as_i64();
+                    // negative indices are past the end once they're unsigned
+                    if i as u64 >= len as u64 {
+                        return Err(module.bounds_message(*index));
+                    }
+                    continue;
+                }
+                IrInstruction::Denull { optional } => {
+                    let optional = self.operand(frame, *optional)?;
+                    let typ = optional.typ.and_then(|t| match module.typ(t) {
+                        IrType::Optional(inner) => Some(*inner),
+                        _ => None,
+                    });
+                    match optional.value {
+                        Value::Null => return Err("tried to denull a null value".to_string()),
+                        value => Typed::new(value, typ),
+                    }
+                }
+                // borrowing a field, element or pointee gives where that place is
+                IrInstruction::Borrow { value } if self.places.contains(value) => {
+                    match self.place(frame, *value)? {
+                        (address, path, _) if path.is_empty() => Typed::new(Value::Pointer(address), None),
+                        (address, path, _) => Typed::new(Value::Interior(address, path), None),
+                    }
+                }
+                IrInstruction::Borrow { value } => {
+                    // borrowing a local gives the address of its cell
+                    let local = match module.instruction(*value) {
+                        IrInstruction::Ref(name) => frame.locals.get(name).copied(),
+                        _ => None,
+                    };
+                    match local {
+                        Some((address, _)) => Typed::new(Value::Pointer(address), None),
+                        None => {
+                            let value = self.operand(frame, *value)?;
+                            Typed::new(Value::Pointer(self.allocate(value.value)), None)
+                        }
+                    }
+                }
+                IrInstruction::Print { value } => {
+                    let value = self.operand(frame, *value)?;
+                    self.print(value)?;
+                    continue;
+                }
+                IrInstruction::Let { name, value } => {
+                    let value = self.operand(frame, *value)?;
+                    // untyped bindings take the type of their value
+                    let typ = match (module.typ(name.typ), value.typ, &value.value) {
+                        (IrType::Unknown, Some(value_type), _) => value_type,
+                        (IrType::Unknown, None, Value::Float(_)) => module.float_literal_type().unwrap_or(name.typ),
+                        _ => name.typ,
+                    };
+                    let value = self.convert(value, typ);
+                    let address = self.allocate(value);
+                    frame.locals.insert(name.name.clone(), (address, typ));
+                    continue;
+                }
+                IrInstruction::Assign { name, value } => {
+                    let value = self.operand(frame, *value)?;
+                    let (address, typ) = *frame.locals.get(name).ok_or(format!("couldn't find `{}`", name))?;
+                    let value = self.convert(value, typ);
+                    self.store(address, value)?;
+                    continue;
+                }
+                IrInstruction::Store { place, value } => {
+                    let value = self.operand(frame, *value)?;
+                    let (address, path, typ) = self.place(frame, *place)?;
+                    let value = match typ {
+                        Some(typ) => self.convert(value, typ),
+                        None => value.value,
+                    };
+                    self.store_at(address, &path, value)?;
+                    continue;
+                }
+                IrInstruction::Branch { condition, true_branch, false_branch } => {
+                    let condition = self.operand(frame, *condition)?;
+                    return Ok(Control::Jump(if Self::truthy(&condition.value) { *true_branch } else { *false_branch }));
+                }
+                IrInstruction::Jump { target } => return Ok(Control::Jump(*target)),
+                IrInstruction::Return { value } => return Ok(Control::Return(self.operand(frame, *value)?)),
+                IrInstruction::Error => return Err("tried to execute an error instruction".to_string()),
+            };
+            frame.values.insert(*index, value);
+        }
+        Ok(Control::FallThrough)
+    }
+
+    /// Get the value of an operand. `Ref`s and literals are evaluated at their use,
+    /// every other instruction has already been executed.
+    fn operand(&mut self, frame: &mut Frame, index: IrInstructionIndex) -> Result<Typed, String> {
+        if let Some(value) = frame.values.get(&index) {
+            return Ok(value.clone());
+        }
+        match self.module.instruction(index) {
+            IrInstruction::Ref(name) => {
+                if let Some((address, typ)) = frame.locals.get(name) {
+                    Ok(Typed::new(self.load(*address)?.clone(), Some(*typ)))
+                } else if let Some(function) = self.functions.get(name) {
+                    Ok(Typed::new(Value::Function(*function), None))
+                } else {
+                    Err(format!("couldn't find `{}`", name))
+                }
+            }
+            IrInstruction::NatLiteral(n) => Ok(Typed::new(Value::Int(*n), None)),
+            IrInstruction::FloatLiteral(n) => Ok(Typed::new(Value::Float(*n), None)),
+            IrInstruction::BoolLiteral(b) => Ok(Typed::new(Value::Bool(*b), None)),
+            IrInstruction::CharLiteral(c) => Ok(Typed::new(Value::Int(*c as i128), None)),
+            ins => Err(format!("instruction {:?} was used before it was executed", ins)),
+        }
+    }
+
+    fn bin_op(&mut self, index: IrInstructionIndex, lhs: Typed, op: BinOpType, rhs: Typed) -> Result<Typed, String> {
+        if let BinOpType::And | BinOpType::Or = op {
+            let (l, r) = (Self::truthy(&lhs.value), Self::truthy(&rhs.value));
+            let result = if let BinOpType::And = op { l && r } else { l || r };
+            return Ok(Typed::new(Value::Bool(result), None));
+        }
+
+        if let (Value::Float(_), _) | (_, Value::Float(_)) = (&lhs.value, &rhs.value) {
+            return self.float_bin_op(lhs, op, rhs);
+        }
+        if let (Value::Pointer(_) | Value::Interior(..) | Value::Null | Value::Function(_), _)
+            | (_, Value::Pointer(_) | Value::Interior(..) | Value::Null | Value::Function(_)) = (&lhs.value, &rhs.value) {
+            return match op {
+                BinOpType::EqualTo => Ok(Typed::new(Value::Bool(lhs.value == rhs.value), None)),
+                BinOpType::NotEqualTo => Ok(Typed::new(Value::Bool(lhs.value != rhs.value), None)),
+                _ => Err(format!("can't apply {} to {:?} and {:?}", op, lhs.value, rhs.value)),
+            };
+        }
+
+        // the result has the type of the typed operand, like in the LLVM backend,
+        // except for shifts which have the type of the value that is shifted
+        let (typ, unsigned) = if op.is_shift() {
+            (lhs.typ, self.is_unsigned(lhs.typ))
+        } else {
+            (lhs.typ.or(rhs.typ), self.is_unsigned(lhs.typ) || self.is_unsigned(rhs.typ))
+        };
+        let (l, r) = match (&lhs.value, &rhs.value) {
+            (Value::Int(l), Value::Int(r)) => (*l, *r),
+            (Value::Bool(l), Value::Bool(r)) => {
+                // bitwise operators on two bools are logical ones
+                match op {
+                    BinOpType::Ampersand => return Ok(Typed::new(Value::Bool(*l & *r), None)),
+                    BinOpType::Pipe => return Ok(Typed::new(Value::Bool(*l | *r), None)),
+                    BinOpType::Caret => return Ok(Typed::new(Value::Bool(*l ^ *r), None)),
+                    _ => (*l as i128, *r as i128),
+                }
+            }
+            (l, r) => return Err(format!("can't apply {} to {:?} and {:?}", op, l, r)),
+        };
+        let value = match op.overflow() {
+            Some(_) => self.arithmetic(index, l, op, r, typ, unsigned)?,
+            None => self.int_bin_op(index, l, op, r, typ, unsigned)?,
+        };
+        match (value, typ) {
+            (Value::Int(i), Some(typ)) => Ok(Typed::new(self.wrap(i, typ), Some(typ))),
+            // integers whose type isn't known have 64 bits
+            (Value::Int(i), None) => Ok(Typed::new(Value::Int(i as i64 as i128), None)),
+            (value, _) => Ok(Typed::new(value, None)),
+        }
+    }
+
+    /// Add, subtract or multiply integers of type `typ`, and trap, wrap or saturate like the
+    /// operator says when the result doesn't fit it.
+    fn arithmetic(&self, index: IrInstructionIndex, l: i128, op: BinOpType, r: i128, typ: Option<IrTypeIndex>, unsigned: bool) -> Result<Value, String> {
+        let (op, overflow) = op.overflow().unwrap();
+        let bits = self.int_bits(typ);
+        // the exact result when it fits the type, and the result saturated at its bounds
+        let (exact, saturated) = if unsigned {
+            let (l, r) = (l as u128, r as u128);
+            let (exact, saturated) = match op {
+                BinOpType::Plus => (l.checked_add(r), l.saturating_add(r)),
+                BinOpType::Minus => (l.checked_sub(r), l.saturating_sub(r)),
+                _ => (l.checked_mul(r), l.saturating_mul(r)),
+            };
+            let max = u128::MAX >> (128 - bits);
+            (exact.filter(|exact| *exact <= max).map(|exact| exact as i128), saturated.min(max) as i128)
+        } else {
+            let (exact, saturated) = match op {
+                BinOpType::Plus => (l.checked_add(r), l.saturating_add(r)),
+                BinOpType::Minus => (l.checked_sub(r), l.saturating_sub(r)),
+                _ => (l.checked_mul(r), l.saturating_mul(r)),
+            };
+            let (min, max) = (i128::MIN >> (128 - bits), i128::MAX >> (128 - bits));
+            (exact.filter(|exact| (min..=max).contains(exact)), saturated.clamp(min, max))
+        };
+        if let Some(exact) = exact {
+            return Ok(Value::Int(exact));
+        }
+        match overflow {
+            Overflow::Trap => Err(self.module.overflow_message(index, op)),
+            // the result is wrapped to the width of its type afterwards
+            Overflow::Wrap => Ok(Value::Int(match op {
+                BinOpType::Plus => l.wrapping_add(r),
+                BinOpType::Minus => l.wrapping_sub(r),
+                _ => l.wrapping_mul(r),
+            })),
+            Overflow::Saturate => Ok(Value::Int(saturated)),
+        }
+    }
+
+    fn int_bin_op(&self, index: IrInstructionIndex, l: i128, op: BinOpType, r: i128, typ: Option<IrTypeIndex>, unsigned: bool) -> Result<Value, String> {
+        let compare = |signed: bool, unsigned_result: bool| if unsigned { unsigned_result } else { signed };
+        if let BinOpType::ForwardSlash | BinOpType::Percent = op {
+            if r == 0 {
+                return Err(self.module.division_by_zero_message(index, op));
+            }
+            // the smallest value of the type divided by -1 is one more than the largest
+            if !unsigned && r == -1 && l == self.int_min(typ) {
+                return Err(self.module.overflow_message(index, op));
+            }
+        }
+        Ok(match op {
+            BinOpType::ForwardSlash => {
+                if unsigned {
+                    Value::Int(((l as u128) / (r as u128)) as i128)
+                } else {
+                    Value::Int(l.wrapping_div(r))
+                }
+            }
+            BinOpType::LessThan => Value::Bool(compare(l < r, (l as u128) < (r as u128))),
+            BinOpType::GreaterThan => Value::Bool(compare(l > r, (l as u128) > (r as u128))),
+            BinOpType::LessThanEqualTo => Value::Bool(compare(l <= r, (l as u128) <= (r as u128))),
+            BinOpType::GreaterThanEqualTo => Value::Bool(compare(l >= r, (l as u128) >= (r as u128))),
+            BinOpType::EqualTo => Value::Bool(l == r),
+            BinOpType::NotEqualTo => Value::Bool(l != r),
+            BinOpType::Percent => {
+                if unsigned {
+                    Value::Int(((l as u128) % (r as u128)) as i128)
+                } else {
+                    Value::Int(l.wrapping_rem(r))
+                }
+            }
+            BinOpType::Ampersand => Value::Int(l & r),
+            BinOpType::Pipe => Value::Int(l | r),
+            BinOpType::Caret => Value::Int(l ^ r),
+            // values are kept zero or sign extended, so shifting them as 128 bit values works
+            BinOpType::ShiftLeft => Value::Int(l.wrapping_shl(self.shift_amount(r, typ))),
+            BinOpType::ShiftRight if unsigned => Value::Int(((l as u128) >> self.shift_amount(r, typ)) as i128),
+            BinOpType::ShiftRight => Value::Int(l >> self.shift_amount(r, typ)),
+            _ => unreachable!(),
+        })
+    }
+
+    /// Apply an operator to two numbers where at least one is a float. The other one is converted
+    /// to its type, and when both are floats the result has the wider type of the two.
+    fn float_bin_op(&mut self, lhs: Typed, op: BinOpType, rhs: Typed) -> Result<Typed, String> {
+        let typ = match (self.float_bits(lhs.typ), self.float_bits(rhs.typ)) {
+            (Some(l), Some(r)) if r > l => rhs.typ,
+            (Some(_), _) => lhs.typ,
+            (_, Some(_)) => rhs.typ,
+            _ => None,
+        };
+        let float = |typed: &Typed| match typed.value {
+            Value::Float(f) => Ok(f),
+            Value::Int(i) if self.is_unsigned(typed.typ) => Ok(i as u128 as f64),
+            Value::Int(i) => Ok(i as f64),
+            ref value => Err(format!("can't apply {} to {:?}", op, value)),
+        };
+        let (l, r) = (float(&lhs)?, float(&rhs)?);
+        // floats don't overflow, they become infinite
+        let op = op.overflow().map_or(op, |(op, _)| op);
+        let value = match op {
+            BinOpType::Plus => Value::Float(l + r),
+            BinOpType::Minus => Value::Float(l - r),
+            BinOpType::Star => Value::Float(l * r),
+            BinOpType::ForwardSlash => Value::Float(l / r),
+            BinOpType::LessThan => return Ok(Typed::new(Value::Bool(l < r), None)),
+            BinOpType::GreaterThan => return Ok(Typed::new(Value::Bool(l > r), None)),
+            BinOpType::LessThanEqualTo => return Ok(Typed::new(Value::Bool(l <= r), None)),
+            BinOpType::GreaterThanEqualTo => return Ok(Typed::new(Value::Bool(l >= r), None)),
+            BinOpType::EqualTo => return Ok(Typed::new(Value::Bool(l == r), None)),
+            BinOpType::NotEqualTo => return Ok(Typed::new(Value::Bool(l != r), None)),
+            BinOpType::Percent => Value::Float(l - (l / r).trunc() * r),
+            BinOpType::Ampersand | BinOpType::Pipe | BinOpType::Caret | BinOpType::ShiftLeft | BinOpType::ShiftRight => {
+                return Err(format!("can't apply {} to floats", op));
+            }
+            _ => unreachable!(),
+        };
+        match typ {
+            Some(typ) => Ok(Typed::new(self.convert(Typed::new(value, None), typ), Some(typ))),
+            None => Ok(Typed::new(value, None)),
+        }
+    }
+
+    fn unary_op(&mut self, index: IrInstructionIndex, op: UnaryOpType, value: Typed) -> Result<Typed, String> {
+        match (op, value.value) {
+            (UnaryOpType::Not, value) => Ok(Typed::new(Value::Bool(!Self::truthy(&value)), None)),
+            (UnaryOpType::Minus, Value::Int(i)) if !self.is_unsigned(value.typ) && i == self.int_min(value.typ) => {
+                Err(self.module.negation_message(index))
+            }
+            (UnaryOpType::Minus | UnaryOpType::WrappingMinus, Value::Int(i)) => match value.typ {
+                Some(typ) => Ok(Typed::new(self.wrap(i.wrapping_neg(), typ), Some(typ))),
+                None => Ok(Typed::new(Value::Int(i.wrapping_neg()), None)),
+            },
+            (UnaryOpType::Minus | UnaryOpType::WrappingMinus, Value::Float(f)) => Ok(Typed::new(Value::Float(-f), value.typ)),
+            (op, value) => Err(format!("can't apply {} to {:?}", op, value)),
+        }
+    }
+
+    /// How far to shift a value of type `typ`, which is the amount modulo its width.
+    fn shift_amount(&self, amount: i128, typ: Option<IrTypeIndex>) -> u32 {
+        (amount & (self.int_bits(typ) as i128 - 1)) as u32
+    }
+
+    /// The width of an integer type, where integers whose type isn't known have 64 bits.
+    fn int_bits(&self, typ: Option<IrTypeIndex>) -> u32 {
+        match typ.map(|t| self.module.typ(t)) {
+            Some(IrType::Int(i)) => i.bits(),
+            Some(IrType::UInt(u)) => u.bits(),
+            Some(IrType::Refinement(_, inner, _)) => self.int_bits(Some(*inner)),
+            _ => 64,
+        }
+    }
+
+    /// The smallest value of a signed integer type, sign extended like the interpreter keeps it.
+    fn int_min(&self, typ: Option<IrTypeIndex>) -> i128 {
+        i128::MIN >> (128 - self.int_bits(typ))
+    }
+
+    /// Find where a place a store writes to is: the heap cell it's in, the path of field indices
+    /// inside the cell, and its type. Fields of rows are inside the row, fields of structs are
+    /// inside the struct their pointer points to.
+    fn place(&mut self, frame: &mut Frame, index: IrInstructionIndex) -> Result<(Address, Vec<usize>, Option<IrTypeIndex>), String> {
+        let module = self.module;
+        match module.instruction(index) {
+            IrInstruction::FieldAccessor { aggregate, value } if self.places.contains(&index) => {
+                let field_name = match module.instruction(*value) {
+                    IrInstruction::Ref(name) => name,
+                    ins => return Err(format!("can't access field {:?}", ins)),
+                };
+                let (address, mut path, typ) = self.place(frame, *aggregate)?;
+                let (field_index, field_type) = self.field(typ, field_name)?;
+                if let Some(IrType::Base(_)) = typ.map(|t| module.typ(t)) {
+                    return match self.load_at(address, &path)? {
+                        Value::Pointer(address) => Ok((*address, vec![field_index], Some(field_type))),
+                        value => Err(format!("can't access field `{}` of {:?}", field_name, value)),
+                    };
+                }
+                path.push(field_index);
+                Ok((address, path, Some(field_type)))
+            }
+            IrInstruction::Dereference { pointer } if self.places.contains(&index) => {
+                let pointer = self.operand(frame, *pointer)?;
+                let typ = pointer.typ.and_then(|t| match module.typ(t) {
+                    IrType::Reference(inner, _) => Some(*inner),
+                    _ => None,
+                });
+                match pointer.value {
+                    Value::Pointer(address) => Ok((address, vec![], typ)),
+                    Value::Interior(address, path) => Ok((address, path, typ)),
+                    value => Err(format!("tried to dereference {:?}", value)),
+                }
+            }
+            IrInstruction::Index { base, index: element } if self.places.contains(&index) => {
+                let (address, typ) = self.element(frame, *base, *element)?;
+                Ok((address, vec![], typ))
+            }
+            IrInstruction::Ref(name) if frame.locals.contains_key(name) => {
+                let (address, typ) = frame.locals[name];
+                Ok((address, vec![], Some(typ)))
+            }
+            // a value that isn't stored anywhere, like the result of a call, gets a cell of its own
+            _ => {
+                let value = self.operand(frame, index)?;
+                Ok((self.allocate(value.value), vec![], value.typ))
+            }
+        }
+    }
+
+    /// The cell of an element of an array or slice and its type. Elements are in consecutive
+    /// cells, and the base points to the first of them.
+    fn element(&mut self, frame: &mut Frame, base: IrInstructionIndex, index: IrInstructionIndex) -> Result<(Address, Option<IrTypeIndex>), String> {
+        let base = self.operand(frame, base)?;
+        let i = self.operand(frame, index)?.value.as_i64();
+        let typ = base.typ.and_then(|t| match self.module.typ(t) {
+            IrType::Array(element, _) | IrType::Reference(element, _) => Some(*element),
+            _ => None,
+        });
+        match base.value {
+            Value::Pointer(address) => Ok((address.wrapping_add(i as usize), typ)),
+            value => Err(format!("tried to index {:?}", value)),
+        }
+    }
+
+    /// The index and type of a field of a struct or row type.
+    fn field(&self, typ: Option<IrTypeIndex>, field_name: &str) -> Result<(usize, IrTypeIndex), String> {
+        let fields = match typ.map(|t| self.module.typ(t)) {
+            Some(IrType::Row(fields)) => fields,
+            Some(IrType::Base(name)) => match self.module.find_struct(name) {
+                Some(IrNode::Struct { fields, .. }) => fields,
+                _ => return Err(format!("couldn't find struct {}", name)),
+            },
+            t => return Err(format!("can't access field `{}` of {:?}", field_name, t)),
+        };
+        let field_index = fields.iter().position(|f| f.name == field_name)
+            .ok_or(format!("couldn't find field `{}`", field_name))?;
+        Ok((field_index, fields[field_index].typ))
+    }
+
+    /// Access a field of a row value or of a struct through its pointer.
+    fn field_access(&mut self, aggregate: Typed, field_name: &str) -> Result<Typed, String> {
+        let (field_index, field_type) = self.field(aggregate.typ, field_name)?;
+
+        let aggregate = match aggregate.value {
+            Value::Pointer(address) => self.load(address)?.clone(),
+            Value::Interior(address, path) => self.load_at(address, &path)?.clone(),
+            value => value,
+        };
+        match aggregate {
+            Value::Aggregate(values) => Ok(Typed::new(values[field_index].clone(), Some(field_type))),
+            value => Err(format!("can't access field `{}` of {:?}", field_name, value)),
+        }
+    }
+
+    /// Get the `Str` of a string literal. Its bytes are in consecutive cells, followed by the cell
+    /// of its header.
+    fn string(&mut self, index: IrInstructionIndex, string: &str) -> Typed {
+        let typ = self.module.str_type();
+        if let Some(address) = self.strings.get(&index) {
+            return Typed::new(Value::Pointer(*address), typ);
+        }
+        let data = self.heap.len();
+        for byte in string.bytes() {
+            self.allocate(Value::Int(byte as i128));
+        }
+        let header = self.allocate(Value::Aggregate(vec![Value::Pointer(data), Value::Int(string.len() as i128)]));
+        self.strings.insert(index, header);
+        Typed::new(Value::Pointer(header), typ)
+    }
+
+    /// Write the bytes of a `Str` to standard output.
+    fn print(&mut self, string: Typed) -> Result<(), String> {
+        let header = match string.value {
+            Value::Pointer(address) => self.load(address)?,
+            value => return Err(format!("tried to print {:?}", value)),
+        };
+        let (data, len) = match header {
+            Value::Aggregate(fields) => match fields.as_slice() {
+                [Value::Pointer(data), Value::Int(len)] => (*data, *len as usize),
+                _ => return Err(format!("tried to print {:?}", header)),
+            },
+            value => return Err(format!("tried to print {:?}", value)),
+        };
+        let mut bytes = Vec::with_capacity(len);
+        for address in data..data + len {
+            bytes.push(self.load(address)?.as_i64() as u8);
+        }
+        let mut stdout = std::io::stdout();
+        stdout.write_all(&bytes).and_then(|_| stdout.flush()).map_err(|e| format!("couldn't print: {}", e))
+    }
+
+    /// Allocate a `typ` that starts out as zero, as `new` does.
+    fn new_object(&mut self, typ: IrTypeIndex) -> Typed {
+        let value = match self.module.typ(typ) {
+            IrType::Array(element, len) => {
+                let first = self.heap.len();
+                for _ in 0..*len {
+                    let value = self.zero(*element);
+                    self.allocate(value);
+                }
+                return Typed::new(Value::Pointer(first), Some(typ));
+            }
+            _ => self.zero(typ),
+        };
+        // nominal types are already references to their struct
+        let result_type = match self.module.typ(typ) {
+            IrType::Base(_) => Some(typ),
+            _ => None,
+        };
+        Typed::new(Value::Pointer(self.allocate(value)), result_type)
+    }
+
+    /// The value a freshly allocated `typ` starts out with.
+    fn zero(&self, typ: IrTypeIndex) -> Value {
+        let fields = match self.module.typ(typ) {
+            IrType::Bool => return Value::Bool(false),
+            IrType::Reference(_, _) | IrType::Optional(_) | IrType::Array(..) => return Value::Null,
+            IrType::Refinement(_, inner, _) => return self.zero(*inner),
+            IrType::Row(fields) => fields,
+            IrType::Base(name) => match self.module.find_struct(name) {
+                Some(IrNode::Struct { fields, .. }) => fields,
+                _ => return Value::Null,
+            },
+            IrType::Float(_) => return Value::Float(0.0),
+            _ => return Value::Int(0),
+        };
+        Value::Aggregate(fields.iter().map(|field| self.zero(field.typ)).collect())
+    }
+
+    /// Convert a value to the representation of `typ`, wrapping integers to its width. Floats
+    /// become integers by rounding toward zero and saturating, the way `as` does.
+    fn convert(&self, value: Typed, typ: IrTypeIndex) -> Value {
+        let unsigned = self.is_unsigned(value.typ);
+        match (value.value, self.module.typ(typ)) {
+            (Value::Int(i), IrType::Bool) => Value::Bool(i != 0),
+            (Value::Int(i), IrType::Float(f)) if unsigned => Self::round(i as u128 as f64, *f),
+            (Value::Int(i), IrType::Float(f)) => Self::round(i as f64, *f),
+            (Value::Float(x), IrType::Float(f)) => Self::round(x, *f),
+            (Value::Float(x), IrType::Refinement(_, inner, _)) => self.convert(Typed::new(Value::Float(x), None), *inner),
+            // 128 bit integers saturate at the bounds of 64 bit ones, like in the Cranelift backend
+            (Value::Float(x), IrType::Int(i)) => Value::Int(match i {
+                IntTy::I8 => x as i8 as i128,
+                IntTy::I16 => x as i16 as i128,
+                IntTy::I32 => x as i32 as i128,
+                _ => x as i64 as i128,
+            }),
+            (Value::Float(x), IrType::UInt(u)) => Value::Int(match u {
+                UIntTy::U8 => x as u8 as i128,
+                UIntTy::U16 => x as u16 as i128,
+                UIntTy::U32 => x as u32 as i128,
+                _ => x as u64 as i128,
+            }),
+            (Value::Float(x), IrType::Unknown) => Value::Int(x as i64 as i128),
+            (Value::Float(x), IrType::Bool) => Value::Bool(x != 0.0),
+            (Value::Bool(b), IrType::Int(_)) | (Value::Bool(b), IrType::UInt(_)) | (Value::Bool(b), IrType::Unknown) => Value::Int(b as i128),
+            (Value::Int(i), _) => self.wrap(i, typ),
+            (value, _) => value,
+        }
+    }
+
+    fn wrap(&self, i: i128, typ: IrTypeIndex) -> Value {
+        Value::Int(match self.module.typ(typ) {
+            IrType::Int(int) if int.bits() < 128 => {
+                let shift = 128 - int.bits();
+                (i << shift) >> shift
+            }
+            IrType::UInt(uint) if uint.bits() < 128 => i & ((1 << uint.bits()) - 1),
+            IrType::Refinement(_, inner, _) => return self.wrap(i, *inner),
+            // 128 bit integers are kept as they are
+            _ => i,
+        })
+    }
+
+    /// Round a float to the precision of a float type.
+    fn round(x: f64, typ: FloatTy) -> Value {
+        match typ {
+            FloatTy::F32 => Value::Float(x as f32 as f64),
+            _ => Value::Float(x),
+        }
+    }
+
+    /// The width of a float type, or `None` when it isn't one.
+    fn float_bits(&self, typ: Option<IrTypeIndex>) -> Option<u32> {
+        match typ.map(|t| self.module.typ(t)) {
+            Some(IrType::Float(f)) => Some(f.bits()),
+            Some(IrType::Refinement(_, inner, _)) => self.float_bits(Some(*inner)),
+            _ => None,
+        }
+    }
+
+    fn is_unsigned(&self, typ: Option<IrTypeIndex>) -> bool {
+        match typ.map(|t| self.module.typ(t)) {
+            Some(IrType::UInt(_)) => true,
+            Some(IrType::Refinement(_, inner, _)) => self.is_unsigned(Some(*inner)),
+            _ => false,
+        }
+    }
+
+    fn truthy(value: &Value) -> bool {
+        match value {
+            Value::Bool(b) => *b,
+            Value::Int(i) => *i != 0,
+            Value::Float(f) => *f != 0.0,
+            Value::Null => false,
+            _ => true,
+        }
+    }
+}
+
+/// Where execution goes after a block.
+enum Control {
+    Jump(IrBlockIndex),
+    Return(Typed),
+    FallThrough,
+}
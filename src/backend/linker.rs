@@ -0,0 +1,45 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Source of the runtime functions that generated code calls into.
+const RUNTIME_SOURCE: &str = include_str!("../../runtime/runtime.c");
+
+/// Link object files into an executable with the system linker driver. The runtime is
+/// compiled by the same driver so it is built for whatever target the driver links for.
+pub fn link(linker: &str, objects: &[PathBuf], output: &Path, scratch_dir: &Path) -> Result<(), String> {
+    let runtime_path = write_runtime(scratch_dir)?;
+    let mut inputs = objects.to_vec();
+    inputs.push(runtime_path);
+    run(linker, &[], &inputs, output)
+}
+
+/// Compile C source with the system compiler driver. `flags` decide what is produced, with no
+/// flags the runtime is compiled in as well and the output is an executable.
+pub fn compile_c(compiler: &str, flags: &[&str], source: &Path, output: &Path, scratch_dir: &Path) -> Result<(), String> {
+    let mut inputs = vec![source.to_path_buf()];
+    if !flags.iter().any(|flag| *flag == "-c" || *flag == "-S") {
+        inputs.push(write_runtime(scratch_dir)?);
+    }
+    run(compiler, flags, &inputs, output)
+}
+
+fn write_runtime(scratch_dir: &Path) -> Result<PathBuf, String> {
+    let runtime_path = scratch_dir.join("nuvae_runtime.c");
+    std::fs::write(&runtime_path, RUNTIME_SOURCE)
+        .map_err(|e| format!("couldn't write {}: {}", runtime_path.display(), e))?;
+    Ok(runtime_path)
+}
+
+fn run(driver: &str, flags: &[&str], inputs: &[PathBuf], output: &Path) -> Result<(), String> {
+    let status = Command::new(driver)
+        .args(flags)
+        .args(inputs)
+        .arg("-o")
+        .arg(output)
+        .status()
+        .map_err(|e| format!("couldn't run `{}`: {}", driver, e))?;
+    if !status.success() {
+        return Err(format!("`{}` failed with {}", driver, status));
+    }
+    Ok(())
+}
@@ -1,58 +1,1225 @@
-use std::collections::HashMap;
-use std::ffi::CString;
-use std::os::raw::c_uint;
-use crate::ir::{FloatTy, IrType, IrTypeIndex, Module};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_uint};
+use std::ptr;
+use crate::ast::{BinOpType, Overflow, UnaryOpType};
+use crate::ir::{FloatTy, IrFunction, IrInstruction, IrInstructionIndex, IrNode, IrNodeIndex, IrType, IrTypeIndex, Module};
 use llvm_sys::*;
+use llvm_sys::analysis::*;
 use llvm_sys::core::*;
 use llvm_sys::error::*;
+use llvm_sys::orc2::*;
 use llvm_sys::prelude::*;
 use llvm_sys::target::*;
 use llvm_sys::target_machine::*;
-use crate::ast::Type;
-use crate::Compiler;
+use llvm_sys::transforms::pass_builder::*;
+use crate::ast::Span;
+use crate::backend::{Artifact, Backend, ALLOC_FUNCTION, PANIC_FUNCTION, PRINT_FUNCTION};
+use crate::options::{EmitKind, OptLevel, Options};
+use debug_info::DebugInfo;
 
-pub struct LLVMBackend<'compiler> {
-    compiler: &'compiler Compiler,
-    module: &'compiler Module,
+mod debug_info;
+pub mod jit;
+
+extern "C" {
+    /// Mark a call `musttail`, which the C API of LLVM 13 can't do. Defined in `llvm/wrapper.cpp`.
+    fn nuvae_set_must_tail(call: LLVMValueRef);
+}
+
+fn cstr(s: &str) -> CString {
+    CString::new(s).unwrap()
+}
+
+/// A stack slot for a parameter or `let` binding.
+#[derive(Clone, Copy)]
+struct Local {
+    pointer: LLVMValueRef,
+    llvm_type: LLVMTypeRef,
+    typ: IrTypeIndex,
+}
+
+/// A value produced by an instruction, along with its IR type when it's known.
+#[derive(Clone, Copy)]
+struct Value {
+    llvm_value: LLVMValueRef,
+    typ: Option<IrTypeIndex>,
+}
+
+impl Value {
+    fn new(llvm_value: LLVMValueRef, typ: Option<IrTypeIndex>) -> Self {
+        Self { llvm_value, typ }
+    }
+}
+
+/// State that only lives while a single function is being emitted.
+struct FunctionState {
+    function: LLVMValueRef,
+    span: Option<Span>,
+    subprogram: Option<LLVMMetadataRef>,
+    entry_block: LLVMBasicBlockRef,
+    return_type: IrTypeIndex,
+    locals: HashMap<String, Local>,
+    values: HashMap<IrInstructionIndex, Value>,
+    /// Calls whose result is returned right away.
+    tail_calls: HashSet<IrInstructionIndex>,
+    /// Whether the function borrows anything, so calls can't be tail calls.
+    has_borrows: bool,
+    /// The places stores write to and borrows take the address of, which aren't loaded.
+    places: HashSet<IrInstructionIndex>,
+}
+
+/// Plugs the LLVM backend into the compiler. Every module gets its own context and
+/// target machine, which are set up from the options.
+pub struct LlvmCodegen<'options> {
+    options: &'options Options,
+}
+
+impl<'o> LlvmCodegen<'o> {
+    pub fn new(options: &'o Options) -> Self {
+        Self { options }
+    }
+}
+
+impl Backend for LlvmCodegen<'_> {
+    fn compile_module(&mut self, module: &Module) -> Result<Artifact, String> {
+        let emit = self.options.emit;
+        match emit {
+            EmitKind::C => return Err("only the C backend can emit C, use --backend=c".to_string()),
+            EmitKind::Wasm | EmitKind::Wat => return Err("only the wasm backend can emit WebAssembly, use --backend=wasm".to_string()),
+            _ => {}
+        }
+        let mut backend = LLVMBackend::new(self.options, module)?;
+        backend.compile()?;
+        backend.verify()?;
+        backend.optimize()?;
+        match emit {
+            EmitKind::LlvmIr => Ok(Artifact::LlvmIr(backend.ir_string())),
+            EmitKind::Assembly => {
+                let assembly = backend.emit_code(LLVMCodeGenFileType::LLVMAssemblyFile)?;
+                Ok(Artifact::Assembly(String::from_utf8_lossy(&assembly).into_owned()))
+            }
+            _ => Ok(Artifact::Object(backend.emit_code(LLVMCodeGenFileType::LLVMObjectFile)?)),
+        }
+    }
+}
+
+pub struct LLVMBackend<'a> {
+    options: &'a Options,
+    module: &'a Module,
+    thread_safe_context: LLVMOrcThreadSafeContextRef,
     context: LLVMContextRef,
     builder: LLVMBuilderRef,
     llvm_module: LLVMModuleRef,
+    target_machine: LLVMTargetMachineRef,
+    debug_info: Option<DebugInfo>,
     type_cache: HashMap<IrTypeIndex, LLVMTypeRef>,
+    struct_cache: HashMap<String, LLVMTypeRef>,
+    functions: HashMap<IrNodeIndex, LLVMValueRef>,
+    function_names: HashMap<String, (LLVMValueRef, IrTypeIndex)>,
 }
 
 impl<'c> LLVMBackend<'c> {
-    pub fn new(compiler: &'c Compiler, module: &'c Module) -> Self {
+    pub fn new(options: &'c Options, module: &'c Module) -> Result<Self, String> {
         unsafe {
-            LLVMInitializeX86TargetInfo();
-            LLVMInitializeX86Target();
-            LLVMInitializeX86TargetMC();
-            LLVMInitializeX86AsmPrinter();
-            let context = LLVMContextCreate();
+            LLVM_InitializeAllTargetInfos();
+            LLVM_InitializeAllTargets();
+            LLVM_InitializeAllTargetMCs();
+            LLVM_InitializeAllAsmPrinters();
+            // the context is owned by a thread safe context so the module can be handed to the JIT
+            let thread_safe_context = LLVMOrcCreateNewThreadSafeContext();
+            let context = LLVMOrcThreadSafeContextGetContext(thread_safe_context);
             let module_name = CString::new(module.name.clone()).unwrap();
             let llvm_module: LLVMModuleRef = LLVMModuleCreateWithNameInContext(module_name.as_ptr(), context);
             let builder: LLVMBuilderRef = LLVMCreateBuilderInContext(context);
 
-            /*
-            let target_triple = LLVMGetDefaultTargetTriple();
-            let mut target = ptr::null_mut();
-            let mut error = ptr::null_mut();
-            LLVMGetTargetFromTriple(target_triple, target, error);
-            LLVMDisposeErrorMessage(*error);
-            let target_machine = LLVMCreateTargetMachine(*target, target_triple, cstr(""), cstr(""), LLVMCodeGenOptLevel::LLVMCodeGenLevelNone, LLVMRelocMode::LLVMRelocDefault, LLVMCodeModel::LLVMCodeModelDefault);
-            LLVMDisposeMessage(target_triple);
-            */
-
-            Self {
-                compiler,
+            let mut backend = Self {
+                options,
                 module,
+                thread_safe_context,
                 context,
                 builder,
                 llvm_module,
+                target_machine: ptr::null_mut(),
+                debug_info: None,
                 type_cache: HashMap::new(),
+                struct_cache: HashMap::new(),
+                functions: HashMap::new(),
+                function_names: HashMap::new(),
+            };
+            backend.create_target_machine()?;
+            if options.debug_info {
+                backend.create_debug_info();
+            }
+            Ok(backend)
+        }
+    }
+
+    /// Create the target machine for the requested triple, cpu and features,
+    /// and set up the module's triple and data layout to match it.
+    unsafe fn create_target_machine(&mut self) -> Result<(), String> {
+        let options = self.options;
+        let triple = match &options.target {
+            Some(target) => Self::take_message(LLVMNormalizeTargetTriple(cstr(target).as_ptr())),
+            None => Self::take_message(LLVMGetDefaultTargetTriple()),
+        };
+        let triple = cstr(&triple);
+
+        let mut target: LLVMTargetRef = ptr::null_mut();
+        let mut error: *mut c_char = ptr::null_mut();
+        if LLVMGetTargetFromTriple(triple.as_ptr(), &mut target, &mut error) != 0 {
+            return Err(Self::take_message(error));
+        }
+
+        let cpu = cstr(options.cpu.as_deref().unwrap_or("generic"));
+        let features = cstr(options.features.as_deref().unwrap_or(""));
+        let codegen_level = match options.opt_level {
+            OptLevel::O0 => LLVMCodeGenOptLevel::LLVMCodeGenLevelNone,
+            OptLevel::O1 => LLVMCodeGenOptLevel::LLVMCodeGenLevelLess,
+            OptLevel::O2 | OptLevel::Os => LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+            OptLevel::O3 => LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+        };
+        self.target_machine = LLVMCreateTargetMachine(target, triple.as_ptr(), cpu.as_ptr(), features.as_ptr(),
+                                                      codegen_level,
+                                                      LLVMRelocMode::LLVMRelocPIC,
+                                                      LLVMCodeModel::LLVMCodeModelDefault);
+        if self.target_machine.is_null() {
+            return Err(format!("couldn't create a target machine for {}", triple.to_string_lossy()));
+        }
+
+        LLVMSetTarget(self.llvm_module, triple.as_ptr());
+        let data_layout = LLVMCreateTargetDataLayout(self.target_machine);
+        LLVMSetModuleDataLayout(self.llvm_module, data_layout);
+        LLVMDisposeTargetData(data_layout);
+        Ok(())
+    }
+
+    /// Emit every function in the module. All functions are declared before any bodies are
+    /// emitted so they can call each other regardless of the order they were defined in.
+    pub fn compile(&mut self) -> Result<(), String> {
+        unsafe {
+            let module = self.module;
+            for (index, function) in module.functions() {
+                self.declare_function(index, function);
+            }
+            for (index, function) in module.functions() {
+                self.emit_function(index, function)?;
+            }
+            self.finalize_debug_info();
+        }
+        Ok(())
+    }
+
+    /// Run the LLVM verifier over the module, returning its report if the module is broken.
+    pub fn verify(&self) -> Result<(), String> {
+        unsafe {
+            let mut message: *mut c_char = ptr::null_mut();
+            let broken = LLVMVerifyModule(self.llvm_module, LLVMVerifierFailureAction::LLVMReturnStatusAction, &mut message);
+            let report = Self::take_message(message);
+            if broken != 0 {
+                Err(report)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    /// Run LLVM's standard module pass pipeline for the requested optimization level.
+    pub fn optimize(&self) -> Result<(), String> {
+        let pipeline = match self.options.opt_level {
+            OptLevel::O0 => return Ok(()),
+            OptLevel::O1 => "default<O1>",
+            OptLevel::O2 => "default<O2>",
+            OptLevel::O3 => "default<O3>",
+            OptLevel::Os => "default<Os>",
+        };
+        unsafe {
+            let pipeline = cstr(pipeline);
+            let options = LLVMCreatePassBuilderOptions();
+            let error = LLVMRunPasses(self.llvm_module, pipeline.as_ptr(), self.target_machine, options);
+            LLVMDisposePassBuilderOptions(options);
+            if !error.is_null() {
+                return Err(Self::take_error(error));
+            }
+            Ok(())
+        }
+    }
+
+    /// Get the textual LLVM IR for the module.
+    pub fn ir_string(&self) -> String {
+        unsafe { Self::take_message(LLVMPrintModuleToString(self.llvm_module)) }
+    }
+
+    /// Generate an object file or assembly for the target machine in memory.
+    pub fn emit_code(&self, file_type: LLVMCodeGenFileType) -> Result<Vec<u8>, String> {
+        unsafe {
+            let mut message: *mut c_char = ptr::null_mut();
+            let mut buffer: LLVMMemoryBufferRef = ptr::null_mut();
+            if LLVMTargetMachineEmitToMemoryBuffer(self.target_machine, self.llvm_module, file_type,
+                                                   &mut message, &mut buffer) != 0 {
+                return Err(Self::take_message(message));
+            }
+            let start = LLVMGetBufferStart(buffer) as *const u8;
+            let code = std::slice::from_raw_parts(start, LLVMGetBufferSize(buffer)).to_vec();
+            LLVMDisposeMemoryBuffer(buffer);
+            Ok(code)
+        }
+    }
+
+    /// Copy the message of an LLVM error into a String and free the error.
+    unsafe fn take_error(error: LLVMErrorRef) -> String {
+        let message = LLVMGetErrorMessage(error);
+        let string = CStr::from_ptr(message).to_string_lossy().into_owned();
+        LLVMDisposeErrorMessage(message);
+        string
+    }
+
+    /// Copy a message allocated by LLVM into a String and free it.
+    unsafe fn take_message(message: *mut c_char) -> String {
+        if message.is_null() {
+            return String::new();
+        }
+        let string = CStr::from_ptr(message).to_string_lossy().into_owned();
+        LLVMDisposeMessage(message);
+        string
+    }
+
+    unsafe fn declare_function(&mut self, index: IrNodeIndex, function: &IrFunction) {
+        let mut params = Vec::with_capacity(function.params.len());
+        for param in function.params.iter() {
+            params.push(self.convert_type(param.typ));
+        }
+        let return_type = self.convert_type(function.return_type);
+        let function_type = LLVMFunctionType(return_type, params.as_mut_ptr(), params.len() as c_uint, 0);
+        let name = cstr(&function.name);
+        let llvm_function = LLVMAddFunction(self.llvm_module, name.as_ptr(), function_type);
+        self.functions.insert(index, llvm_function);
+        self.function_names.entry(function.name.clone()).or_insert((llvm_function, function.return_type));
+    }
+
+    unsafe fn emit_function(&mut self, index: IrNodeIndex, function: &IrFunction) -> Result<(), String> {
+        let llvm_function = *self.functions.get(&index).unwrap();
+
+        // every IR block gets a basic block, after an entry block for the stack slots since the
+        // first IR block can be jumped back to
+        let entry_block = LLVMAppendBasicBlockInContext(self.context, llvm_function, cstr("entry").as_ptr());
+        let mut blocks = HashMap::new();
+        for block in function.blocks.iter() {
+            let llvm_block = LLVMAppendBasicBlockInContext(self.context, llvm_function, cstr("").as_ptr());
+            blocks.insert(*block, llvm_block);
+        }
+
+        let subprogram = self.debug_function(function, llvm_function);
+        let mut state = FunctionState {
+            function: llvm_function,
+            span: function.span,
+            subprogram,
+            entry_block,
+            return_type: function.return_type,
+            locals: HashMap::new(),
+            values: HashMap::new(),
+            tail_calls: function.blocks.iter().filter_map(|block| self.module.tail_call(*block)).collect(),
+            has_borrows: self.module.has_borrows(function),
+            places: self.module.places(function),
+        };
+
+        // parameters are spilled into stack slots so they can be reassigned
+        LLVMPositionBuilderAtEnd(self.builder, entry_block);
+        self.set_debug_location(&state, function.span);
+        for (i, param) in function.params.iter().enumerate() {
+            let llvm_param = LLVMGetParam(llvm_function, i as c_uint);
+            let param_name = cstr(&param.name);
+            LLVMSetValueName2(llvm_param, param_name.as_ptr(), param.name.len());
+            let local = self.new_local(&mut state, &param.name, param.typ);
+            LLVMBuildStore(self.builder, llvm_param, local.pointer);
+            self.debug_variable(&state, &param.name, local.typ, local.pointer, function.span, Some(i as u32 + 1));
+        }
+        LLVMBuildBr(self.builder, *blocks.get(&function.blocks[0]).unwrap());
+
+        for block in function.blocks.iter() {
+            LLVMPositionBuilderAtEnd(self.builder, *blocks.get(block).unwrap());
+            let mut terminated = false;
+            for ins in self.module.block(*block).instructions.iter() {
+                self.set_debug_location(&state, self.module.instruction_span(*ins));
+                self.emit_instruction(&mut state, &blocks, *ins)?;
+                if self.module.instruction(*ins).is_terminator() {
+                    terminated = true;
+                    break;
+                }
+            }
+            if !terminated {
+                if let IrType::Void = self.module.typ(function.return_type) {
+                    LLVMBuildRetVoid(self.builder);
+                } else {
+                    LLVMBuildUnreachable(self.builder);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocate a stack slot at the start of the entry block and register it as a local.
+    unsafe fn new_local(&mut self, state: &mut FunctionState, name: &str, typ: IrTypeIndex) -> Local {
+        let llvm_type = self.convert_type(typ);
+        let pointer = self.entry_alloca(state, llvm_type, name);
+        let local = Local { pointer, llvm_type, typ };
+        state.locals.insert(name.to_string(), local);
+        local
+    }
+
+    /// Make a stack slot at the start of the entry block, so it's allocated once per call.
+    unsafe fn entry_alloca(&mut self, state: &FunctionState, llvm_type: LLVMTypeRef, name: &str) -> LLVMValueRef {
+        let entry_builder = LLVMCreateBuilderInContext(self.context);
+        let first = LLVMGetFirstInstruction(state.entry_block);
+        if first.is_null() {
+            LLVMPositionBuilderAtEnd(entry_builder, state.entry_block);
+        } else {
+            LLVMPositionBuilderBefore(entry_builder, first);
+        }
+        let name = cstr(name);
+        let pointer = LLVMBuildAlloca(entry_builder, llvm_type, name.as_ptr());
+        LLVMDisposeBuilder(entry_builder);
+        pointer
+    }
+
+    /// Get the value of an instruction operand. `Ref`s and literals are materialized at their use,
+    /// every other instruction has already been emitted in block order.
+    unsafe fn operand(&mut self, state: &mut FunctionState, index: IrInstructionIndex) -> Result<Value, String> {
+        if let Some(value) = state.values.get(&index) {
+            return Ok(*value);
+        }
+        let module = self.module;
+        Ok(match module.instruction(index) {
+            IrInstruction::Ref(name) => {
+                if let Some(local) = state.locals.get(name) {
+                    let local_name = cstr(name);
+                    let load = LLVMBuildLoad2(self.builder, local.llvm_type, local.pointer, local_name.as_ptr());
+                    Value::new(load, Some(local.typ))
+                } else if let Some((function, _)) = self.function_names.get(name) {
+                    Value::new(*function, None)
+                } else {
+                    return Err(format!("couldn't find `{}`", name));
+                }
+            }
+            // literals that don't fit 64 bits are only used with 128 bit types, which they're
+            // truncated to if they're used with narrower ones
+            IrInstruction::NatLiteral(n) if *n as i64 as i128 != *n => {
+                let words = [*n as u64, (*n >> 64) as u64];
+                Value::new(LLVMConstIntOfArbitraryPrecision(LLVMInt128TypeInContext(self.context), 2, words.as_ptr()), None)
+            }
+            IrInstruction::NatLiteral(n) => {
+                Value::new(LLVMConstInt(LLVMInt64TypeInContext(self.context), *n as u64, 1), None)
+            }
+            IrInstruction::FloatLiteral(n) => {
+                Value::new(LLVMConstReal(LLVMDoubleTypeInContext(self.context), *n), None)
+            }
+            IrInstruction::CharLiteral(c) => {
+                Value::new(LLVMConstInt(LLVMInt64TypeInContext(self.context), *c as u64, 1), None)
+            }
+            IrInstruction::BoolLiteral(b) => {
+                Value::new(LLVMConstInt(LLVMInt1TypeInContext(self.context), *b as u64, 0), None)
+            }
+            ins => return Err(format!("instruction {:?} was used before it was emitted", ins)),
+        })
+    }
+
+    unsafe fn emit_instruction(&mut self, state: &mut FunctionState,
+                               blocks: &HashMap<crate::ir::IrBlockIndex, LLVMBasicBlockRef>, index: IrInstructionIndex) -> Result<(), String> {
+        let empty = cstr("");
+        let module = self.module;
+        if state.places.contains(&index) {
+            // stores work out where these are themselves
+            return Ok(());
+        }
+        let value = match module.instruction(index) {
+            // these are materialized where they are used
+            IrInstruction::Ref(_) | IrInstruction::NatLiteral(_) | IrInstruction::FloatLiteral(_)
+            | IrInstruction::BoolLiteral(_) | IrInstruction::CharLiteral(_) => return Ok(()),
+            IrInstruction::StrLiteral(string) => self.emit_string(string),
+            IrInstruction::BinOp(lhs, op, rhs) => {
+                let lhs = self.operand(state, *lhs)?;
+                let rhs = self.operand(state, *rhs)?;
+                self.emit_bin_op(state, index, lhs, *op, rhs)?
+            }
+            IrInstruction::UnaryOp(op, value) => {
+                let value = self.operand(state, *value)?;
+                self.emit_unary_op(state, index, *op, value)
+            }
+            IrInstruction::Cast { value, typ } => {
+                let value = self.operand(state, *value)?;
+                self.emit_cast(value, *typ)
+            }
+            IrInstruction::FieldAccessor { aggregate, value } => {
+                let field_name = match self.module.instruction(*value) {
+                    IrInstruction::Ref(name) => name.clone(),
+                    ins => return Err(format!("can't access field {:?}", ins)),
+                };
+                let aggregate = self.operand(state, *aggregate)?;
+                self.emit_field_access(aggregate, &field_name)?
+            }
+            IrInstruction::FunctionCall { function, args } => {
+                let callee = self.operand(state, *function)?;
+                let (function_type, return_type) = if !LLVMIsAFunction(callee.llvm_value).is_null() {
+                    let return_type = self.function_names.values()
+                        .find(|(f, _)| *f == callee.llvm_value)
+                        .map(|(_, ret)| *ret);
+                    (LLVMGlobalGetValueType(callee.llvm_value), return_type)
+                } else {
+                    let callee_type = LLVMTypeOf(callee.llvm_value);
+                    if LLVMGetTypeKind(callee_type) != LLVMTypeKind::LLVMPointerTypeKind {
+                        return Err("tried to call a value that isn't a function".to_string());
+                    }
+                    let return_type = callee.typ.and_then(|t| match self.module.typ(t) {
+                        IrType::Function(_, ret) => Some(*ret),
+                        _ => None,
+                    });
+                    (LLVMGetElementType(callee_type), return_type)
+                };
+
+                let param_count = LLVMCountParamTypes(function_type) as usize;
+                let mut param_types = vec![ptr::null_mut(); param_count];
+                LLVMGetParamTypes(function_type, param_types.as_mut_ptr());
+                if param_count != args.len() {
+                    return Err(format!("expected {} arguments but got {}", param_count, args.len()));
+                }
+                let mut llvm_args = Vec::with_capacity(args.len());
+                for (arg, param_type) in args.iter().zip(param_types) {
+                    let arg = self.operand(state, *arg)?;
+                    llvm_args.push(self.coerce(arg, param_type));
+                }
+                let call = LLVMBuildCall2(self.builder, function_type, callee.llvm_value,
+                                          llvm_args.as_mut_ptr(), llvm_args.len() as c_uint, empty.as_ptr());
+                // the callee can take over the caller's frame unless something in it is borrowed
+                if state.tail_calls.contains(&index) && !state.has_borrows {
+                    if function_type == LLVMGlobalGetValueType(state.function) {
+                        nuvae_set_must_tail(call);
+                    } else {
+                        LLVMSetTailCall(call, 1);
+                    }
+                }
+                Value::new(call, return_type)
+            }
+            IrInstruction::New { typ, allocator } => {
+                let allocator = self.operand(state, *allocator)?;
+                self.emit_new(*typ, allocator)?
+            }
+            IrInstruction::StackAlloc { typ } => {
+                let (result_type, allocated_type) = self.allocation_types(*typ)?;
+                let pointer = self.entry_alloca(state, allocated_type, "");
+                // objects start out zeroed, like those the interpreter allocates
+                LLVMBuildStore(self.builder, LLVMConstNull(allocated_type), pointer);
+                let typ = if let IrType::Base(_) | IrType::Array(..) = self.module.typ(*typ) { Some(*typ) } else { None };
+                Value::new(LLVMBuildBitCast(self.builder, pointer, result_type, empty.as_ptr()), typ)
+            }
+            IrInstruction::RowLiteral { typ, values } => {
+                let row_type = self.convert_type(*typ);
+                let mut row = LLVMGetUndef(row_type);
+                for (i, value) in values.iter().enumerate() {
+                    let value = self.operand(state, *value)?;
+                    let field = self.coerce(value, LLVMStructGetTypeAtIndex(row_type, i as c_uint));
+                    row = LLVMBuildInsertValue(self.builder, row, field, i as c_uint, empty.as_ptr());
+                }
+                Value::new(row, Some(*typ))
+            }
+            IrInstruction::Dereference { pointer } => {
+                let pointer = self.operand(state, *pointer)?;
+                let pointer_type = LLVMTypeOf(pointer.llvm_value);
+                let load = LLVMBuildLoad2(self.builder, LLVMGetElementType(pointer_type), pointer.llvm_value, empty.as_ptr());
+                let typ = pointer.typ.and_then(|t| match self.module.typ(t) {
+                    IrType::Reference(inner, _) => Some(*inner),
+                    _ => None,
+                });
+                Value::new(load, typ)
+            }
+            IrInstruction::Index { base, index } => {
+                let (pointer, typ) = self.emit_element(state, *base, *index)?;
+                let load = LLVMBuildLoad2(self.builder, LLVMGetElementType(LLVMTypeOf(pointer)), pointer, empty.as_ptr());
+                Value::new(load, typ)
+            }
+            IrInstruction::BoundsCheck { index, len } => {
+                let i64_type = LLVMInt64TypeInContext(self.context);
+                let i = self.operand(state, *index)?;
+                let i = self.coerce(i, i64_type);
+                let len = self.operand(state, *len)?;
+                let len = self.coerce(len, i64_type);
+                // negative indices are past the end once they're unsigned
+                let out_of_bounds = LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntUGE, i, len, empty.as_ptr());
+                self.emit_panic_if(state, out_of_bounds, "out_of_bounds", &self.module.bounds_message(*index));
+                return Ok(());
+            }
+            IrInstruction::Denull { optional } => {
+                let optional = self.operand(state, *optional)?;
+                let typ = optional.typ.and_then(|t| match self.module.typ(t) {
+                    IrType::Optional(inner) => Some(*inner),
+                    _ => None,
+                });
+                if LLVMGetTypeKind(LLVMTypeOf(optional.llvm_value)) == LLVMTypeKind::LLVMStructTypeKind {
+                    // non-pointer optionals are a { i1, T } pair
+                    Value::new(LLVMBuildExtractValue(self.builder, optional.llvm_value, 1, empty.as_ptr()), typ)
+                } else {
+                    Value::new(optional.llvm_value, typ)
+                }
+            }
+            // borrowing a field, element or pointee gives the address of that place
+            IrInstruction::Borrow { value } if state.places.contains(value) => Value::new(self.emit_place(state, *value)?.0, None),
+            IrInstruction::Borrow { value } => {
+                // borrowing a local gives the address of its stack slot
+                if let IrInstruction::Ref(name) = self.module.instruction(*value) {
+                    if let Some(local) = state.locals.get(name) {
+                        state.values.insert(index, Value::new(local.pointer, None));
+                        return Ok(());
+                    }
+                }
+                let value = self.operand(state, *value)?;
+                let slot = LLVMBuildAlloca(self.builder, LLVMTypeOf(value.llvm_value), empty.as_ptr());
+                LLVMBuildStore(self.builder, value.llvm_value, slot);
+                Value::new(slot, None)
+            }
+            IrInstruction::Print { value } => {
+                let value = self.operand(state, *value)?;
+                self.emit_print(value)?;
+                return Ok(());
+            }
+            IrInstruction::Let { name, value } => {
+                let value = self.operand(state, *value)?;
+                // untyped bindings take the type of their value
+                let typ = match (self.module.typ(name.typ), value.typ) {
+                    (IrType::Unknown, Some(value_type)) => value_type,
+                    (IrType::Unknown, None) if is_float(LLVMTypeOf(value.llvm_value)) => {
+                        self.module.float_literal_type().unwrap_or(name.typ)
+                    }
+                    _ => name.typ,
+                };
+                let local = self.new_local(state, &name.name, typ);
+                let llvm_value = self.coerce(value, local.llvm_type);
+                LLVMBuildStore(self.builder, llvm_value, local.pointer);
+                self.debug_variable(state, &name.name, typ, local.pointer, module.instruction_span(index), None);
+                return Ok(());
+            }
+            IrInstruction::Assign { name, value } => {
+                let value = self.operand(state, *value)?;
+                let local = *state.locals.get(name).ok_or_else(|| format!("couldn't find `{}`", name))?;
+                let llvm_value = self.coerce(value, local.llvm_type);
+                LLVMBuildStore(self.builder, llvm_value, local.pointer);
+                return Ok(());
+            }
+            IrInstruction::Store { place, value } => {
+                let value = self.operand(state, *value)?;
+                let pointer = self.emit_place(state, *place)?.0;
+                let llvm_value = self.coerce(value, LLVMGetElementType(LLVMTypeOf(pointer)));
+                LLVMBuildStore(self.builder, llvm_value, pointer);
+                return Ok(());
+            }
+            IrInstruction::Branch { condition, true_branch, false_branch } => {
+                let condition = self.operand(state, *condition)?;
+                let condition = self.emit_truthy(condition.llvm_value);
+                LLVMBuildCondBr(self.builder, condition, *blocks.get(true_branch).unwrap(), *blocks.get(false_branch).unwrap());
+                return Ok(());
+            }
+            IrInstruction::Jump { target } => {
+                LLVMBuildBr(self.builder, *blocks.get(target).unwrap());
+                return Ok(());
+            }
+            IrInstruction::Return { value } => {
+                let value = self.operand(state, *value)?;
+                if let IrType::Void = self.module.typ(state.return_type) {
+                    LLVMBuildRetVoid(self.builder);
+                } else {
+                    let return_type = LLVMGetReturnType(LLVMGlobalGetValueType(state.function));
+                    let llvm_value = self.coerce(value, return_type);
+                    LLVMBuildRet(self.builder, llvm_value);
+                }
+                return Ok(());
+            }
+            IrInstruction::Error => return Err("tried to emit an error instruction".to_string()),
+        };
+        state.values.insert(index, value);
+        Ok(())
+    }
+
+    unsafe fn emit_bin_op(&mut self, state: &FunctionState, index: IrInstructionIndex, lhs: Value, op: BinOpType, rhs: Value) -> Result<Value, String> {
+        let empty = cstr("");
+        let signed = !(self.is_unsigned(lhs.typ) || self.is_unsigned(rhs.typ));
+
+        // untyped literals take the type of the other operand,
+        // otherwise integer operands are widened to the larger of the two types
+        let lhs_type = LLVMTypeOf(lhs.llvm_value);
+        let rhs_type = LLVMTypeOf(rhs.llvm_value);
+        let lhs_literal = lhs.typ.is_none() && LLVMIsConstant(lhs.llvm_value) != 0;
+        let rhs_literal = rhs.typ.is_none() && LLVMIsConstant(rhs.llvm_value) != 0;
+        if is_float(lhs_type) || is_float(rhs_type) {
+            return self.emit_float_bin_op(lhs, op, rhs, lhs_literal, rhs_literal);
+        }
+        if op.is_shift() {
+            return Ok(self.emit_shift(lhs, op, rhs));
+        }
+        let is_pointer = |typ| LLVMGetTypeKind(typ) == LLVMTypeKind::LLVMPointerTypeKind;
+        if let (BinOpType::EqualTo | BinOpType::NotEqualTo, true) = (op, is_pointer(lhs_type) || is_pointer(rhs_type)) {
+            // pointers are compared by their addresses
+            let i64_type = LLVMInt64TypeInContext(self.context);
+            let mut address = |value: Value| if is_pointer(LLVMTypeOf(value.llvm_value)) {
+                LLVMBuildPtrToInt(self.builder, value.llvm_value, i64_type, empty.as_ptr())
+            } else {
+                self.coerce(value, i64_type)
+            };
+            let (l, r) = (address(lhs), address(rhs));
+            let predicate = if let BinOpType::EqualTo = op { LLVMIntPredicate::LLVMIntEQ } else { LLVMIntPredicate::LLVMIntNE };
+            return Ok(Value::new(LLVMBuildICmp(self.builder, predicate, l, r, empty.as_ptr()), None));
+        }
+        let (l, r, typ) = if rhs_literal && !lhs_literal {
+            (lhs.llvm_value, self.coerce(rhs, lhs_type), lhs.typ)
+        } else if lhs_literal && !rhs_literal {
+            (self.coerce(lhs, rhs_type), rhs.llvm_value, rhs.typ)
+        } else if LLVMGetIntTypeWidth(lhs_type) >= LLVMGetIntTypeWidth(rhs_type) {
+            (lhs.llvm_value, self.coerce(rhs, lhs_type), lhs.typ.or(rhs.typ))
+        } else {
+            (self.coerce(lhs, rhs_type), rhs.llvm_value, rhs.typ.or(lhs.typ))
+        };
+
+        let builder = self.builder;
+        let compare = |predicate_signed, predicate_unsigned| {
+            let predicate = if signed { predicate_signed } else { predicate_unsigned };
+            Value::new(LLVMBuildICmp(builder, predicate, l, r, empty.as_ptr()), None)
+        };
+        if let BinOpType::ForwardSlash | BinOpType::Percent = op {
+            self.emit_division_checks(state, index, l, op, r, signed);
+        }
+        Ok(match op {
+            _ if op.overflow().is_some() => Value::new(self.emit_arithmetic(state, index, l, op, r, signed), typ),
+            BinOpType::ForwardSlash => if signed {
+                Value::new(LLVMBuildSDiv(self.builder, l, r, empty.as_ptr()), typ)
+            } else {
+                Value::new(LLVMBuildUDiv(self.builder, l, r, empty.as_ptr()), typ)
+            },
+            BinOpType::LessThan => compare(LLVMIntPredicate::LLVMIntSLT, LLVMIntPredicate::LLVMIntULT),
+            BinOpType::GreaterThan => compare(LLVMIntPredicate::LLVMIntSGT, LLVMIntPredicate::LLVMIntUGT),
+            BinOpType::LessThanEqualTo => compare(LLVMIntPredicate::LLVMIntSLE, LLVMIntPredicate::LLVMIntULE),
+            BinOpType::GreaterThanEqualTo => compare(LLVMIntPredicate::LLVMIntSGE, LLVMIntPredicate::LLVMIntUGE),
+            BinOpType::EqualTo => compare(LLVMIntPredicate::LLVMIntEQ, LLVMIntPredicate::LLVMIntEQ),
+            BinOpType::NotEqualTo => compare(LLVMIntPredicate::LLVMIntNE, LLVMIntPredicate::LLVMIntNE),
+            BinOpType::Percent => if signed {
+                Value::new(LLVMBuildSRem(self.builder, l, r, empty.as_ptr()), typ)
+            } else {
+                Value::new(LLVMBuildURem(self.builder, l, r, empty.as_ptr()), typ)
+            },
+            BinOpType::Ampersand => Value::new(LLVMBuildAnd(self.builder, l, r, empty.as_ptr()), typ),
+            BinOpType::Pipe => Value::new(LLVMBuildOr(self.builder, l, r, empty.as_ptr()), typ),
+            BinOpType::Caret => Value::new(LLVMBuildXor(self.builder, l, r, empty.as_ptr()), typ),
+            BinOpType::And => {
+                let l = self.emit_truthy(l);
+                let r = self.emit_truthy(r);
+                Value::new(LLVMBuildAnd(self.builder, l, r, empty.as_ptr()), None)
+            }
+            BinOpType::Or => {
+                let l = self.emit_truthy(l);
+                let r = self.emit_truthy(r);
+                Value::new(LLVMBuildOr(self.builder, l, r, empty.as_ptr()), None)
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    /// Add, subtract or multiply two integers of the same type, and trap, wrap or saturate like
+    /// the operator says when the result doesn't fit it.
+    unsafe fn emit_arithmetic(&mut self, state: &FunctionState, index: IrInstructionIndex, l: LLVMValueRef, op: BinOpType,
+                              r: LLVMValueRef, signed: bool) -> LLVMValueRef {
+        let empty = cstr("");
+        let (op, overflow) = op.overflow().unwrap();
+        let int_type = LLVMTypeOf(l);
+        let bits = LLVMGetIntTypeWidth(int_type);
+        let sign = if signed { "s" } else { "u" };
+        let operation = match op {
+            BinOpType::Plus => "add",
+            BinOpType::Minus => "sub",
+            _ => "mul",
+        };
+        match overflow {
+            Overflow::Wrap => match op {
+                BinOpType::Plus => LLVMBuildAdd(self.builder, l, r, empty.as_ptr()),
+                BinOpType::Minus => LLVMBuildSub(self.builder, l, r, empty.as_ptr()),
+                _ => LLVMBuildMul(self.builder, l, r, empty.as_ptr()),
+            },
+            Overflow::Saturate if matches!(op, BinOpType::Star) => {
+                // there's no saturating multiplication, but a fixed point one with no fraction bits is
+                let name = format!("llvm.{}mul.fix.sat.i{}", sign, bits);
+                let i32_type = LLVMInt32TypeInContext(self.context);
+                let (function, function_type) = self.runtime_function(&name, int_type, &mut [int_type, int_type, i32_type]);
+                let mut args = [l, r, LLVMConstInt(i32_type, 0, 0)];
+                LLVMBuildCall2(self.builder, function_type, function, args.as_mut_ptr(), 3, empty.as_ptr())
+            }
+            Overflow::Saturate => {
+                let name = format!("llvm.{}{}.sat.i{}", sign, operation, bits);
+                let (function, function_type) = self.runtime_function(&name, int_type, &mut [int_type, int_type]);
+                let mut args = [l, r];
+                LLVMBuildCall2(self.builder, function_type, function, args.as_mut_ptr(), 2, empty.as_ptr())
+            }
+            Overflow::Trap => {
+                let name = format!("llvm.{}{}.with.overflow.i{}", sign, operation, bits);
+                let mut fields = [int_type, LLVMInt1TypeInContext(self.context)];
+                let result_type = LLVMStructTypeInContext(self.context, fields.as_mut_ptr(), 2, 0);
+                let (function, function_type) = self.runtime_function(&name, result_type, &mut [int_type, int_type]);
+                let mut args = [l, r];
+                let result = LLVMBuildCall2(self.builder, function_type, function, args.as_mut_ptr(), 2, empty.as_ptr());
+                let overflowed = LLVMBuildExtractValue(self.builder, result, 1, empty.as_ptr());
+                self.emit_panic_if(state, overflowed, "overflow", &self.module.overflow_message(index, op));
+                LLVMBuildExtractValue(self.builder, result, 0, empty.as_ptr())
+            }
+        }
+    }
+
+    /// Check that an integer division or remainder doesn't divide by zero, or overflow by
+    /// dividing the smallest signed value by -1. Both are undefined behaviour in LLVM.
+    unsafe fn emit_division_checks(&mut self, state: &FunctionState, index: IrInstructionIndex, l: LLVMValueRef, op: BinOpType,
+                                   r: LLVMValueRef, signed: bool) {
+        let empty = cstr("");
+        let int_type = LLVMTypeOf(r);
+        let by_zero = LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntEQ, r, LLVMConstInt(int_type, 0, 0), empty.as_ptr());
+        self.emit_panic_if(state, by_zero, "division_by_zero", &self.module.division_by_zero_message(index, op));
+        if signed {
+            let smallest = LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntEQ, l, self.int_min(int_type), empty.as_ptr());
+            let minus_one = LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntEQ, r, LLVMConstAllOnes(int_type), empty.as_ptr());
+            let overflowed = LLVMBuildAnd(self.builder, smallest, minus_one, empty.as_ptr());
+            self.emit_panic_if(state, overflowed, "overflow", &self.module.overflow_message(index, op));
+        }
+    }
+
+    /// The smallest value of a signed integer type.
+    unsafe fn int_min(&self, int_type: LLVMTypeRef) -> LLVMValueRef {
+        let bits = LLVMGetIntTypeWidth(int_type);
+        LLVMConstInt(int_type, (i64::MIN >> (64 - bits)) as u64, 1)
+    }
+
+    /// Call the panic runtime function with a message when `condition` is true, and carry on in
+    /// a new block otherwise.
+    unsafe fn emit_panic_if(&mut self, state: &FunctionState, condition: LLVMValueRef, name: &str, message: &str) {
+        let panic_block = LLVMAppendBasicBlockInContext(self.context, state.function, cstr(name).as_ptr());
+        let continue_block = LLVMAppendBasicBlockInContext(self.context, state.function, cstr("").as_ptr());
+        LLVMBuildCondBr(self.builder, condition, panic_block, continue_block);
+        LLVMPositionBuilderAtEnd(self.builder, panic_block);
+        self.emit_panic(message);
+        LLVMPositionBuilderAtEnd(self.builder, continue_block);
+    }
+
+    /// Call the panic runtime function with a message, which ends the current block.
+    unsafe fn emit_panic(&mut self, message: &str) {
+        let empty = cstr("");
+        let i8_pointer = LLVMPointerType(LLVMInt8TypeInContext(self.context), 0);
+        let i64_type = LLVMInt64TypeInContext(self.context);
+        let void_type = LLVMVoidTypeInContext(self.context);
+        let (panic_function, panic_type) = self.runtime_function(PANIC_FUNCTION, void_type, &mut [i8_pointer, i64_type]);
+        let bytes = LLVMConstStringInContext(self.context, message.as_ptr() as *const c_char, message.len() as c_uint, 1);
+        let data = self.add_constant(bytes, "panic.message");
+        let mut args = [LLVMConstBitCast(data, i8_pointer), LLVMConstInt(i64_type, message.len() as u64, 0)];
+        LLVMBuildCall2(self.builder, panic_type, panic_function, args.as_mut_ptr(), 2, empty.as_ptr());
+        LLVMBuildUnreachable(self.builder);
+    }
+
+    /// Emit an operator where at least one operand is a float. Both operands are converted to the
+    /// float type, which is the wider one when both are floats unless one is an untyped literal.
+    unsafe fn emit_float_bin_op(&mut self, lhs: Value, op: BinOpType, rhs: Value, lhs_literal: bool, rhs_literal: bool) -> Result<Value, String> {
+        let empty = cstr("");
+        let lhs_type = LLVMTypeOf(lhs.llvm_value);
+        let rhs_type = LLVMTypeOf(rhs.llvm_value);
+        let lhs_wins = match (is_float(lhs_type), is_float(rhs_type)) {
+            (true, true) if lhs_literal != rhs_literal => rhs_literal,
+            (true, true) => float_width(lhs_type) >= float_width(rhs_type),
+            (lhs_float, _) => lhs_float,
+        };
+        let (target, typ) = if lhs_wins { (lhs_type, lhs.typ) } else { (rhs_type, rhs.typ) };
+        let l = self.coerce(lhs, target);
+        let r = self.coerce(rhs, target);
+        // floats don't overflow, they become infinite
+        let op = op.overflow().map_or(op, |(op, _)| op);
+
+        let builder = self.builder;
+        let compare = |predicate| Value::new(LLVMBuildFCmp(builder, predicate, l, r, empty.as_ptr()), None);
+        Ok(match op {
+            BinOpType::Plus => Value::new(LLVMBuildFAdd(self.builder, l, r, empty.as_ptr()), typ),
+            BinOpType::Minus => Value::new(LLVMBuildFSub(self.builder, l, r, empty.as_ptr()), typ),
+            BinOpType::Star => Value::new(LLVMBuildFMul(self.builder, l, r, empty.as_ptr()), typ),
+            BinOpType::ForwardSlash => Value::new(LLVMBuildFDiv(self.builder, l, r, empty.as_ptr()), typ),
+            // comparisons with NaN are false
+            BinOpType::LessThan => compare(LLVMRealPredicate::LLVMRealOLT),
+            BinOpType::GreaterThan => compare(LLVMRealPredicate::LLVMRealOGT),
+            BinOpType::LessThanEqualTo => compare(LLVMRealPredicate::LLVMRealOLE),
+            BinOpType::GreaterThanEqualTo => compare(LLVMRealPredicate::LLVMRealOGE),
+            BinOpType::EqualTo => compare(LLVMRealPredicate::LLVMRealOEQ),
+            // NaN isn't equal to anything, itself included
+            BinOpType::NotEqualTo => compare(LLVMRealPredicate::LLVMRealUNE),
+            BinOpType::Percent => {
+                // `frem` would need `fmod` from the C library, so the remainder is computed directly
+                // and `llvm.trunc` can turn into a call to `trunc` from libm, so the quotient is
+                // truncated by converting it to an integer and back when it's small enough to have a fraction
+                let quotient = LLVMBuildFDiv(self.builder, l, r, empty.as_ptr());
+                let width = float_width(target);
+                let (int_type, limit) = if width == 32 {
+                    (LLVMInt32TypeInContext(self.context), 8388608.0)
+                } else {
+                    (LLVMInt64TypeInContext(self.context), 4503599627370496.0)
+                };
+                let below = LLVMBuildFCmp(self.builder, LLVMRealPredicate::LLVMRealOLT, quotient, LLVMConstReal(target, limit), empty.as_ptr());
+                let above = LLVMBuildFCmp(self.builder, LLVMRealPredicate::LLVMRealOGT, quotient, LLVMConstReal(target, -limit), empty.as_ptr());
+                let fractional = LLVMBuildAnd(self.builder, below, above, empty.as_ptr());
+                let truncated = LLVMBuildFPToSI(self.builder, quotient, int_type, empty.as_ptr());
+                let truncated = LLVMBuildSIToFP(self.builder, truncated, target, empty.as_ptr());
+                let quotient = LLVMBuildSelect(self.builder, fractional, truncated, quotient, empty.as_ptr());
+                let product = LLVMBuildFMul(self.builder, quotient, r, empty.as_ptr());
+                Value::new(LLVMBuildFSub(self.builder, l, product, empty.as_ptr()), typ)
+            }
+            BinOpType::Ampersand | BinOpType::Pipe | BinOpType::Caret | BinOpType::ShiftLeft | BinOpType::ShiftRight => {
+                return Err(format!("can't apply {} to floats", op));
+            }
+            BinOpType::And | BinOpType::Or => {
+                let l = self.emit_truthy(l);
+                let r = self.emit_truthy(r);
+                let result = if let BinOpType::And = op {
+                    LLVMBuildAnd(self.builder, l, r, empty.as_ptr())
+                } else {
+                    LLVMBuildOr(self.builder, l, r, empty.as_ptr())
+                };
+                Value::new(result, None)
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    /// Emit a shift, which has the type of the value that is shifted. The amount is taken modulo
+    /// the width of the type, since shifting by more than that is poison in LLVM.
+    unsafe fn emit_shift(&mut self, lhs: Value, op: BinOpType, rhs: Value) -> Value {
+        let empty = cstr("");
+        let lhs_type = LLVMTypeOf(lhs.llvm_value);
+        let width = LLVMGetIntTypeWidth(lhs_type);
+        let amount = self.coerce(rhs, lhs_type);
+        let amount = LLVMBuildAnd(self.builder, amount, LLVMConstInt(lhs_type, (width - 1) as u64, 0), empty.as_ptr());
+        let shifted = match op {
+            BinOpType::ShiftLeft => LLVMBuildShl(self.builder, lhs.llvm_value, amount, empty.as_ptr()),
+            _ if self.is_unsigned(lhs.typ) => LLVMBuildLShr(self.builder, lhs.llvm_value, amount, empty.as_ptr()),
+            _ => LLVMBuildAShr(self.builder, lhs.llvm_value, amount, empty.as_ptr()),
+        };
+        Value::new(shifted, lhs.typ)
+    }
+
+    unsafe fn emit_unary_op(&mut self, state: &FunctionState, index: IrInstructionIndex, op: UnaryOpType, value: Value) -> Value {
+        let empty = cstr("");
+        match op {
+            UnaryOpType::Minus | UnaryOpType::WrappingMinus if is_float(LLVMTypeOf(value.llvm_value)) => {
+                Value::new(LLVMBuildFNeg(self.builder, value.llvm_value, empty.as_ptr()), value.typ)
+            }
+            UnaryOpType::Minus | UnaryOpType::WrappingMinus => {
+                if let (UnaryOpType::Minus, false) = (op, self.is_unsigned(value.typ)) {
+                    let int_type = LLVMTypeOf(value.llvm_value);
+                    let smallest = LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntEQ, value.llvm_value, self.int_min(int_type), empty.as_ptr());
+                    self.emit_panic_if(state, smallest, "overflow", &self.module.negation_message(index));
+                }
+                Value::new(LLVMBuildNeg(self.builder, value.llvm_value, empty.as_ptr()), value.typ)
+            }
+            UnaryOpType::Not => {
+                let truthy = self.emit_truthy(value.llvm_value);
+                Value::new(LLVMBuildNot(self.builder, truthy, empty.as_ptr()), None)
             }
         }
     }
 
+    /// Lower `value as typ`. Unlike other conversions, floats become unsigned integers when the
+    /// target type is unsigned.
+    unsafe fn emit_cast(&mut self, value: Value, typ: IrTypeIndex) -> Value {
+        let target = self.convert_type(typ);
+        let value_type = LLVMTypeOf(value.llvm_value);
+        let converted = if is_float(value_type) && LLVMGetTypeKind(target) == LLVMTypeKind::LLVMIntegerTypeKind && self.is_unsigned(Some(typ)) {
+            self.emit_float_to_int(value.llvm_value, target, false)
+        } else {
+            self.coerce(value, target)
+        };
+        Value::new(converted, Some(typ))
+    }
+
+    /// Convert a float to an integer type, rounding toward zero and saturating at the bounds of
+    /// the type, with the `llvm.fpto[su]i.sat` intrinsics.
+    unsafe fn emit_float_to_int(&mut self, value: LLVMValueRef, target: LLVMTypeRef, signed: bool) -> LLVMValueRef {
+        let empty = cstr("");
+        let value_type = LLVMTypeOf(value);
+        let name = format!("llvm.fpto{}i.sat.i{}.f{}", if signed { "s" } else { "u" }, LLVMGetIntTypeWidth(target), float_width(value_type));
+        let (function, function_type) = self.runtime_function(&name, target, &mut [value_type]);
+        let mut args = [value];
+        LLVMBuildCall2(self.builder, function_type, function, args.as_mut_ptr(), 1, empty.as_ptr())
+    }
+
+    /// Get a pointer to a place a store writes to or a borrow takes, along with its type. Fields of rows are
+    /// inside the row, fields of structs are inside the struct their pointer points to.
+    unsafe fn emit_place(&mut self, state: &mut FunctionState, index: IrInstructionIndex) -> Result<(LLVMValueRef, Option<IrTypeIndex>), String> {
+        let empty = cstr("");
+        let module = self.module;
+        Ok(match module.instruction(index) {
+            IrInstruction::FieldAccessor { aggregate, value } if state.places.contains(&index) => {
+                let field_name = match module.instruction(*value) {
+                    IrInstruction::Ref(name) => name,
+                    ins => return Err(format!("can't access field {:?}", ins)),
+                };
+                let (pointer, typ) = self.emit_place(state, *aggregate)?;
+                let (field_index, field_type) = self.field(typ, field_name)?;
+                let container = if let Some(IrType::Base(_)) = typ.map(|t| module.typ(t)) {
+                    LLVMBuildLoad2(self.builder, LLVMGetElementType(LLVMTypeOf(pointer)), pointer, empty.as_ptr())
+                } else {
+                    pointer
+                };
+                let struct_type = LLVMGetElementType(LLVMTypeOf(container));
+                let field_pointer = LLVMBuildStructGEP2(self.builder, struct_type, container, field_index as c_uint, empty.as_ptr());
+                (field_pointer, Some(field_type))
+            }
+            IrInstruction::Dereference { pointer } if state.places.contains(&index) => {
+                let pointer = self.operand(state, *pointer)?;
+                let typ = pointer.typ.and_then(|t| match module.typ(t) {
+                    IrType::Reference(inner, _) => Some(*inner),
+                    _ => None,
+                });
+                (pointer.llvm_value, typ)
+            }
+            IrInstruction::Index { base, index: element } if state.places.contains(&index) => self.emit_element(state, *base, *element)?,
+            IrInstruction::Ref(name) if state.locals.contains_key(name) => {
+                let local = state.locals[name];
+                (local.pointer, Some(local.typ))
+            }
+            // a value that isn't stored anywhere, like the result of a call, gets a slot of its own
+            _ => {
+                let value = self.operand(state, index)?;
+                let slot = self.entry_alloca(state, LLVMTypeOf(value.llvm_value), "");
+                LLVMBuildStore(self.builder, value.llvm_value, slot);
+                (slot, value.typ)
+            }
+        })
+    }
+
+    /// Get a pointer to an element of an array or slice, along with its type. The base points to
+    /// the first element.
+    unsafe fn emit_element(&mut self, state: &mut FunctionState, base: IrInstructionIndex, index: IrInstructionIndex) -> Result<(LLVMValueRef, Option<IrTypeIndex>), String> {
+        let empty = cstr("");
+        let base = self.operand(state, base)?;
+        let typ = base.typ.and_then(|t| match self.module.typ(t) {
+            IrType::Array(element, _) | IrType::Reference(element, _) => Some(*element),
+            _ => None,
+        });
+        let index = self.operand(state, index)?;
+        let mut indices = [self.coerce(index, LLVMInt64TypeInContext(self.context))];
+        let element_type = LLVMGetElementType(LLVMTypeOf(base.llvm_value));
+        let pointer = LLVMBuildGEP2(self.builder, element_type, base.llvm_value, indices.as_mut_ptr(), 1, empty.as_ptr());
+        Ok((pointer, typ))
+    }
+
+    /// The index and type of a field of a struct or row type.
+    fn field(&self, typ: Option<IrTypeIndex>, field_name: &str) -> Result<(usize, IrTypeIndex), String> {
+        let fields = match typ.map(|t| self.module.typ(t)) {
+            Some(IrType::Row(fields)) => fields,
+            Some(IrType::Base(name)) => match self.module.find_struct(name) {
+                Some(IrNode::Struct { fields, .. }) => fields,
+                _ => return Err(format!("couldn't find struct {}", name)),
+            },
+            t => return Err(format!("can't access field `{}` of {:?}", field_name, t)),
+        };
+        let field_index = fields.iter().position(|f| f.name == field_name)
+            .ok_or_else(|| format!("couldn't find field `{}`", field_name))?;
+        Ok((field_index, fields[field_index].typ))
+    }
+
+    /// Access a field of a row value or of a struct through its pointer.
+    unsafe fn emit_field_access(&mut self, aggregate: Value, field_name: &str) -> Result<Value, String> {
+        let empty = cstr("");
+        let (field_index, field_type) = self.field(aggregate.typ, field_name)?;
+
+        let aggregate_type = LLVMTypeOf(aggregate.llvm_value);
+        let field_value = if LLVMGetTypeKind(aggregate_type) == LLVMTypeKind::LLVMPointerTypeKind {
+            let struct_type = LLVMGetElementType(aggregate_type);
+            let field_pointer = LLVMBuildStructGEP2(self.builder, struct_type, aggregate.llvm_value, field_index as c_uint, empty.as_ptr());
+            LLVMBuildLoad2(self.builder, LLVMStructGetTypeAtIndex(struct_type, field_index as c_uint), field_pointer, empty.as_ptr())
+        } else {
+            LLVMBuildExtractValue(self.builder, aggregate.llvm_value, field_index as c_uint, empty.as_ptr())
+        };
+        Ok(Value::new(field_value, Some(field_type)))
+    }
+
+    /// Lower `new T in allocator` to a call to the allocator runtime function.
+    /// The type of a pointer to a new `typ` and the type of what it points to.
+    unsafe fn allocation_types(&mut self, typ: IrTypeIndex) -> Result<(LLVMTypeRef, LLVMTypeRef), String> {
+        // nominal types are already pointers to their struct and arrays to their first element,
+        // everything else gets a new pointer
+        let converted = self.convert_type(typ);
+        let (result_type, allocated_type) = if let IrType::Base(_) = self.module.typ(typ) {
+            (converted, LLVMGetElementType(converted))
+        } else if let IrType::Array(_, len) = self.module.typ(typ) {
+            (converted, LLVMArrayType(LLVMGetElementType(converted), *len as c_uint))
+        } else {
+            (LLVMPointerType(converted, 0), converted)
+        };
+        if LLVMGetTypeKind(allocated_type) == LLVMTypeKind::LLVMStructTypeKind && LLVMIsOpaqueStruct(allocated_type) != 0 {
+            return Err(format!("can't allocate {:?} because its size is unknown", self.module.typ(typ)));
+        }
+        Ok((result_type, allocated_type))
+    }
+
+    unsafe fn emit_new(&mut self, typ: IrTypeIndex, allocator: Value) -> Result<Value, String> {
+        let empty = cstr("");
+        let i8_pointer = LLVMPointerType(LLVMInt8TypeInContext(self.context), 0);
+        let i64_type = LLVMInt64TypeInContext(self.context);
+        let (alloc_function, alloc_type) = self.runtime_function(ALLOC_FUNCTION, i8_pointer, &mut [i8_pointer, i64_type]);
+
+        let (result_type, allocated_type) = self.allocation_types(typ)?;
+        let allocator_pointer = if LLVMGetTypeKind(LLVMTypeOf(allocator.llvm_value)) == LLVMTypeKind::LLVMPointerTypeKind {
+            LLVMBuildBitCast(self.builder, allocator.llvm_value, i8_pointer, empty.as_ptr())
+        } else {
+            LLVMBuildIntToPtr(self.builder, allocator.llvm_value, i8_pointer, empty.as_ptr())
+        };
+        let mut args = [allocator_pointer, LLVMSizeOf(allocated_type)];
+        let memory = LLVMBuildCall2(self.builder, alloc_type, alloc_function, args.as_mut_ptr(), 2, empty.as_ptr());
+        let typ = if let IrType::Base(_) | IrType::Array(..) = self.module.typ(typ) { Some(typ) } else { None };
+        Ok(Value::new(LLVMBuildBitCast(self.builder, memory, result_type, empty.as_ptr()), typ))
+    }
+
+    /// Put the bytes of a string literal and the `Str` header that points to them in private
+    /// constants, and return the pointer to the header.
+    unsafe fn emit_string(&mut self, string: &str) -> Value {
+        let str_type = self.module.str_type().expect("string literals have the `Str` type");
+        let header_type = LLVMGetElementType(self.convert_type(str_type));
+        let bytes = LLVMConstStringInContext(self.context, string.as_ptr() as *const c_char, string.len() as c_uint, 1);
+        let data = self.add_constant(bytes, "str.data");
+        let mut fields = [
+            LLVMConstBitCast(data, LLVMPointerType(LLVMInt8TypeInContext(self.context), 0)),
+            LLVMConstInt(LLVMInt64TypeInContext(self.context), string.len() as u64, 0),
+        ];
+        let header = LLVMConstNamedStruct(header_type, fields.as_mut_ptr(), fields.len() as c_uint);
+        Value::new(self.add_constant(header, "str"), Some(str_type))
+    }
+
+    unsafe fn add_constant(&mut self, value: LLVMValueRef, name: &str) -> LLVMValueRef {
+        let name = cstr(name);
+        let global = LLVMAddGlobal(self.llvm_module, LLVMTypeOf(value), name.as_ptr());
+        LLVMSetInitializer(global, value);
+        LLVMSetGlobalConstant(global, 1);
+        LLVMSetLinkage(global, LLVMLinkage::LLVMPrivateLinkage);
+        LLVMSetUnnamedAddress(global, LLVMUnnamedAddr::LLVMGlobalUnnamedAddr);
+        global
+    }
+
+    /// Lower `print` to a call to the print runtime function with the fields of the `Str`.
+    unsafe fn emit_print(&mut self, string: Value) -> Result<(), String> {
+        let empty = cstr("");
+        let i8_pointer = LLVMPointerType(LLVMInt8TypeInContext(self.context), 0);
+        let i64_type = LLVMInt64TypeInContext(self.context);
+        let void_type = LLVMVoidTypeInContext(self.context);
+        let (print_function, print_type) = self.runtime_function(PRINT_FUNCTION, void_type, &mut [i8_pointer, i64_type]);
+        let data = self.emit_field_access(string, "data")?;
+        let len = self.emit_field_access(string, "len")?;
+        let mut args = [self.coerce(data, i8_pointer), self.coerce(len, i64_type)];
+        LLVMBuildCall2(self.builder, print_type, print_function, args.as_mut_ptr(), 2, empty.as_ptr());
+        Ok(())
+    }
+
+    /// Get a function of the runtime, declaring it the first time it's called.
+    unsafe fn runtime_function(&mut self, name: &str, return_type: LLVMTypeRef, params: &mut [LLVMTypeRef]) -> (LLVMValueRef, LLVMTypeRef) {
+        let name = cstr(name);
+        let function_type = LLVMFunctionType(return_type, params.as_mut_ptr(), params.len() as c_uint, 0);
+        let mut function = LLVMGetNamedFunction(self.llvm_module, name.as_ptr());
+        if function.is_null() {
+            function = LLVMAddFunction(self.llvm_module, name.as_ptr(), function_type);
+        }
+        (function, function_type)
+    }
+
+    /// Convert a value to an `i1` by comparing it against zero.
+    unsafe fn emit_truthy(&mut self, value: LLVMValueRef) -> LLVMValueRef {
+        let value_type = LLVMTypeOf(value);
+        if LLVMGetTypeKind(value_type) == LLVMTypeKind::LLVMIntegerTypeKind && LLVMGetIntTypeWidth(value_type) == 1 {
+            return value;
+        }
+        let empty = cstr("");
+        if is_float(value_type) {
+            // NaN is truthy, like any other float that isn't zero
+            return LLVMBuildFCmp(self.builder, LLVMRealPredicate::LLVMRealUNE, value, LLVMConstNull(value_type), empty.as_ptr());
+        }
+        LLVMBuildICmp(self.builder, LLVMIntPredicate::LLVMIntNE, value, LLVMConstNull(value_type), empty.as_ptr())
+    }
+
+    /// Convert a value to the given type. Integers are resized, pointers are cast, and floats are
+    /// converted to and from integers, saturating like `as` does.
+    unsafe fn coerce(&mut self, value: Value, target: LLVMTypeRef) -> LLVMValueRef {
+        let value_type = LLVMTypeOf(value.llvm_value);
+        if value_type == target {
+            return value.llvm_value;
+        }
+        let empty = cstr("");
+        match (LLVMGetTypeKind(value_type), LLVMGetTypeKind(target)) {
+            (LLVMTypeKind::LLVMIntegerTypeKind, LLVMTypeKind::LLVMIntegerTypeKind) => {
+                // booleans are zero extended, everything else follows its signedness
+                let signed = LLVMGetIntTypeWidth(value_type) > 1 && !self.is_unsigned(value.typ);
+                LLVMBuildIntCast2(self.builder, value.llvm_value, target, signed as LLVMBool, empty.as_ptr())
+            }
+            (LLVMTypeKind::LLVMPointerTypeKind, LLVMTypeKind::LLVMPointerTypeKind) => {
+                LLVMBuildBitCast(self.builder, value.llvm_value, target, empty.as_ptr())
+            }
+            (LLVMTypeKind::LLVMIntegerTypeKind, LLVMTypeKind::LLVMPointerTypeKind) => {
+                LLVMBuildIntToPtr(self.builder, value.llvm_value, target, empty.as_ptr())
+            }
+            // rows whose fields have different types are converted field by field
+            (LLVMTypeKind::LLVMStructTypeKind, LLVMTypeKind::LLVMStructTypeKind)
+                if LLVMCountStructElementTypes(value_type) == LLVMCountStructElementTypes(target) => {
+                let field_types = match value.typ.map(|t| self.module.typ(t)) {
+                    Some(IrType::Row(fields)) => fields.iter().map(|field| Some(field.typ)).collect(),
+                    _ => vec![None; LLVMCountStructElementTypes(target) as usize],
+                };
+                let mut converted = LLVMGetUndef(target);
+                for (i, field_type) in field_types.into_iter().enumerate() {
+                    let field = LLVMBuildExtractValue(self.builder, value.llvm_value, i as c_uint, empty.as_ptr());
+                    let field = self.coerce(Value::new(field, field_type), LLVMStructGetTypeAtIndex(target, i as c_uint));
+                    converted = LLVMBuildInsertValue(self.builder, converted, field, i as c_uint, empty.as_ptr());
+                }
+                converted
+            }
+            (LLVMTypeKind::LLVMIntegerTypeKind, _) if is_float(target) => {
+                if LLVMGetIntTypeWidth(value_type) > 1 && !self.is_unsigned(value.typ) {
+                    LLVMBuildSIToFP(self.builder, value.llvm_value, target, empty.as_ptr())
+                } else {
+                    LLVMBuildUIToFP(self.builder, value.llvm_value, target, empty.as_ptr())
+                }
+            }
+            (_, LLVMTypeKind::LLVMIntegerTypeKind) if is_float(value_type) => {
+                if LLVMGetIntTypeWidth(target) == 1 {
+                    self.emit_truthy(value.llvm_value)
+                } else {
+                    self.emit_float_to_int(value.llvm_value, target, true)
+                }
+            }
+            _ if is_float(value_type) && is_float(target) => {
+                LLVMBuildFPCast(self.builder, value.llvm_value, target, empty.as_ptr())
+            }
+            _ => value.llvm_value
+        }
+    }
+
+    fn is_unsigned(&self, typ: Option<IrTypeIndex>) -> bool {
+        match typ.map(|t| self.module.typ(t)) {
+            Some(IrType::UInt(_)) => true,
+            Some(IrType::Refinement(_, inner, _)) => self.is_unsigned(Some(*inner)),
+            _ => false,
+        }
+    }
+
+    /// Get the named LLVM struct for a struct defined in this module. Structs are created before
+    /// their bodies are filled in so they can refer to themselves.
+    unsafe fn convert_struct(&mut self, name: &str) -> LLVMTypeRef {
+        if let Some(typ) = self.struct_cache.get(name) {
+            return *typ;
+        }
+        let struct_name = cstr(name);
+        let struct_type = LLVMStructCreateNamed(self.context, struct_name.as_ptr());
+        self.struct_cache.insert(name.to_string(), struct_type);
+
+        if let Some(IrNode::Struct { fields, .. }) = self.module.find_struct(name) {
+            let mut field_types = Vec::with_capacity(fields.len());
+            for field in fields.clone().iter() {
+                field_types.push(self.convert_type(field.typ));
+            }
+            LLVMStructSetBody(struct_type, field_types.as_mut_ptr(), field_types.len() as c_uint, 0);
+        }
+        struct_type
+    }
+
     unsafe fn convert_type(&mut self, type_index: IrTypeIndex) -> LLVMTypeRef {
         if let Some(typ) = self.type_cache.get(&type_index) {
             return *typ;
@@ -70,18 +1237,92 @@ impl<'c> LLVMBackend<'c> {
                     FloatTy::F128 => LLVMFP128TypeInContext(self.context),
                 }
             }
+            // nominal types are passed around by reference
+            IrType::Base(name) => LLVMPointerType(self.convert_struct(&name.clone()), 0),
+            IrType::Refinement(_, inner, _) => self.convert_type(*inner),
+            IrType::Row(fields) => {
+                // rows are named after their fields and their types, so rows with the same layout
+                // stay distinct and rows with the same fields are the same type
+                let fields = fields.clone();
+                let row_name = self.module.type_name(type_index);
+                if let Some(typ) = self.struct_cache.get(&row_name) {
+                    *typ
+                } else {
+                    let mut field_types = Vec::with_capacity(fields.len());
+                    for field in fields.iter() {
+                        field_types.push(self.convert_type(field.typ));
+                    }
+                    let name = cstr(&row_name);
+                    let row_type = LLVMStructCreateNamed(self.context, name.as_ptr());
+                    LLVMStructSetBody(row_type, field_types.as_mut_ptr(), field_types.len() as c_uint, 0);
+                    self.struct_cache.insert(row_name, row_type);
+                    row_type
+                }
+            }
+            IrType::Reference(inner, _) => LLVMPointerType(self.convert_type(*inner), 0),
+            // arrays are passed around as a pointer to their first element
+            IrType::Array(element, _) => LLVMPointerType(self.convert_type(*element), 0),
+            IrType::Optional(inner) => {
+                // optional pointers use null, other optionals carry a flag
+                let inner = self.convert_type(*inner);
+                if LLVMGetTypeKind(inner) == LLVMTypeKind::LLVMPointerTypeKind {
+                    inner
+                } else {
+                    let mut fields = [LLVMInt1TypeInContext(self.context), inner];
+                    LLVMStructTypeInContext(self.context, fields.as_mut_ptr(), 2, 0)
+                }
+            }
             IrType::Function(f, ret) => {
+                let (f, ret) = (f.clone(), *ret);
                 let mut args = Vec::with_capacity(f.len());
                 for arg in f.iter() {
-                    args.push(self.convert_type(arg.clone()));
+                    args.push(self.convert_type(*arg));
                 }
-                let result_type = self.convert_type(ret.clone());
-                LLVMFunctionType(result_type, args.as_mut_ptr(), args.len() as c_uint, 0)
+                let result_type = self.convert_type(ret);
+                // function values are pointers to functions
+                LLVMPointerType(LLVMFunctionType(result_type, args.as_mut_ptr(), args.len() as c_uint, 0), 0)
             }
-            t => panic!("couldn't convert type {:?}", t)
+            IrType::Void => LLVMVoidTypeInContext(self.context),
+            // integer literals are 64 bits wide, so that's what we use until types are inferred
+            IrType::Unknown => LLVMInt64TypeInContext(self.context),
         };
 
         self.type_cache.insert(type_index, llvm_type);
         llvm_type
     }
 }
+
+impl<'c> Drop for LLVMBackend<'c> {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(debug_info) = &mut self.debug_info {
+                debug_info.dispose();
+            }
+            if !self.target_machine.is_null() {
+                LLVMDisposeTargetMachine(self.target_machine);
+            }
+            LLVMDisposeBuilder(self.builder);
+            // the module is owned by the JIT once it has been handed over
+            if !self.llvm_module.is_null() {
+                LLVMDisposeModule(self.llvm_module);
+            }
+            LLVMOrcDisposeThreadSafeContext(self.thread_safe_context);
+        }
+    }
+}
+
+/// Whether an LLVM type is one of the float types.
+unsafe fn is_float(typ: LLVMTypeRef) -> bool {
+    matches!(LLVMGetTypeKind(typ), LLVMTypeKind::LLVMHalfTypeKind | LLVMTypeKind::LLVMFloatTypeKind
+        | LLVMTypeKind::LLVMDoubleTypeKind | LLVMTypeKind::LLVMFP128TypeKind)
+}
+
+/// The number of bits in an LLVM float type.
+unsafe fn float_width(typ: LLVMTypeRef) -> u32 {
+    match LLVMGetTypeKind(typ) {
+        LLVMTypeKind::LLVMHalfTypeKind => 16,
+        LLVMTypeKind::LLVMFloatTypeKind => 32,
+        LLVMTypeKind::LLVMFP128TypeKind => 128,
+        _ => 64,
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::os::raw::c_uint;
+use std::ptr;
+use llvm_sys::*;
+use llvm_sys::core::*;
+use llvm_sys::debuginfo::*;
+use llvm_sys::prelude::*;
+use llvm_sys::target::*;
+use crate::ast::Span;
+use crate::ir::{FloatTy, IntTy, IrFunction, IrNode, IrType, IrTypeIndex, UIntTy};
+use crate::options::OptLevel;
+use super::{cstr, FunctionState, LLVMBackend};
+
+const DW_ATE_BOOLEAN: LLVMDWARFTypeEncoding = 0x02;
+const DW_ATE_FLOAT: LLVMDWARFTypeEncoding = 0x04;
+const DW_ATE_SIGNED: LLVMDWARFTypeEncoding = 0x05;
+const DW_ATE_UNSIGNED: LLVMDWARFTypeEncoding = 0x08;
+const DW_TAG_STRUCTURE_TYPE: c_uint = 0x13;
+
+/// DWARF state for a module: the compile unit, the file it was parsed from, and the debug
+/// descriptors of the types that have been used so far.
+pub(super) struct DebugInfo {
+    builder: LLVMDIBuilderRef,
+    file: LLVMMetadataRef,
+    compile_unit: LLVMMetadataRef,
+    type_cache: HashMap<IrTypeIndex, LLVMMetadataRef>,
+    struct_cache: HashMap<String, LLVMMetadataRef>,
+}
+
+impl DebugInfo {
+    pub(super) unsafe fn dispose(&mut self) {
+        LLVMDisposeDIBuilder(self.builder);
+    }
+}
+
+impl<'c> LLVMBackend<'c> {
+    /// Start emitting DWARF for the module, with a compile unit for the file it was parsed from.
+    pub(super) unsafe fn create_debug_info(&mut self) {
+        let builder = LLVMCreateDIBuilder(self.llvm_module);
+
+        // debuggers find the source through the directory of the compile unit
+        let source_path = std::path::Path::new(&self.module.source_name);
+        let file_name = source_path.file_name().map_or(self.module.source_name.clone(), |f| f.to_string_lossy().to_string());
+        let directory = source_path.parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .map(|p| p.to_path_buf())
+            .or_else(|| std::env::current_dir().ok())
+            .map(|p| std::fs::canonicalize(&p).unwrap_or(p))
+            .map_or(String::new(), |p| p.to_string_lossy().to_string());
+        let file = LLVMDIBuilderCreateFile(builder, cstr(&file_name).as_ptr(), file_name.len(),
+                                           cstr(&directory).as_ptr(), directory.len());
+
+        let producer = "nuvae";
+        let optimized = self.options.opt_level != OptLevel::O0;
+        let compile_unit = LLVMDIBuilderCreateCompileUnit(
+            builder, LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageC99, file,
+            cstr(producer).as_ptr(), producer.len(), optimized as LLVMBool,
+            cstr("").as_ptr(), 0, 0, cstr("").as_ptr(), 0,
+            LLVMDWARFEmissionKind::LLVMDWARFEmissionKindFull, 0, 0, 0,
+            cstr("").as_ptr(), 0, cstr("").as_ptr(), 0,
+        );
+
+        let i32_type = LLVMInt32TypeInContext(self.context);
+        let version_key = "Debug Info Version";
+        let version = LLVMValueAsMetadata(LLVMConstInt(i32_type, LLVMDebugMetadataVersion() as u64, 0));
+        LLVMAddModuleFlag(self.llvm_module, LLVMModuleFlagBehavior::LLVMModuleFlagBehaviorWarning,
+                          cstr(version_key).as_ptr(), version_key.len(), version);
+        let dwarf_key = "Dwarf Version";
+        let dwarf_version = LLVMValueAsMetadata(LLVMConstInt(i32_type, 4, 0));
+        LLVMAddModuleFlag(self.llvm_module, LLVMModuleFlagBehavior::LLVMModuleFlagBehaviorWarning,
+                          cstr(dwarf_key).as_ptr(), dwarf_key.len(), dwarf_version);
+
+        self.debug_info = Some(DebugInfo {
+            builder,
+            file,
+            compile_unit,
+            type_cache: HashMap::new(),
+            struct_cache: HashMap::new(),
+        });
+    }
+
+    /// Resolve all the debug descriptors, this has to happen before the module is verified.
+    pub(super) unsafe fn finalize_debug_info(&mut self) {
+        if let Some(debug_info) = &self.debug_info {
+            LLVMDIBuilderFinalize(debug_info.builder);
+        }
+    }
+
+    /// Attach a subprogram to a function, returning it so locations can be scoped to it.
+    pub(super) unsafe fn debug_function(&mut self, function: &IrFunction, llvm_function: LLVMValueRef) -> Option<LLVMMetadataRef> {
+        let (builder, file) = match &self.debug_info {
+            Some(debug_info) => (debug_info.builder, debug_info.file),
+            None => return None,
+        };
+
+        // the first element of a subroutine type is its return type
+        let mut types = Vec::with_capacity(function.params.len() + 1);
+        types.push(self.debug_type(function.return_type));
+        for param in function.params.iter() {
+            types.push(self.debug_type(param.typ));
+        }
+        let subroutine_type = LLVMDIBuilderCreateSubroutineType(builder, file, types.as_mut_ptr(), types.len() as c_uint, LLVMDIFlagZero);
+
+        let line = self.debug_line(function.span);
+        let name = &function.name;
+        let linkage_name_ptr = LLVMGetValueName2(llvm_function, &mut 0);
+        let linkage_name = std::ffi::CStr::from_ptr(linkage_name_ptr).to_string_lossy().to_string();
+        let optimized = self.options.opt_level != OptLevel::O0;
+        let subprogram = LLVMDIBuilderCreateFunction(
+            builder, file, cstr(name).as_ptr(), name.len(), cstr(&linkage_name).as_ptr(), linkage_name.len(),
+            file, line, subroutine_type, 0, 1, line, LLVMDIFlagPrototyped, optimized as LLVMBool,
+        );
+        LLVMSetSubprogram(llvm_function, subprogram);
+        Some(subprogram)
+    }
+
+    /// Set the location of the instructions built from now on.
+    pub(super) unsafe fn set_debug_location(&mut self, state: &FunctionState, span: Option<Span>) {
+        if let Some(subprogram) = state.subprogram {
+            let location = self.debug_location(subprogram, span.or(state.span));
+            LLVMSetCurrentDebugLocation2(self.builder, location);
+        }
+    }
+
+    /// Describe a parameter or `let` binding living in the given stack slot.
+    /// Parameters are numbered from one, `let` bindings have no number.
+    pub(super) unsafe fn debug_variable(&mut self, state: &FunctionState, name: &str, typ: IrTypeIndex,
+                                        storage: LLVMValueRef, span: Option<Span>, arg_number: Option<u32>) {
+        let subprogram = match state.subprogram {
+            Some(subprogram) => subprogram,
+            None => return,
+        };
+        let (builder, file) = match &self.debug_info {
+            Some(debug_info) => (debug_info.builder, debug_info.file),
+            None => return,
+        };
+        let span = span.or(state.span);
+        let line = self.debug_line(span);
+        let debug_type = self.debug_type(typ);
+        let variable = match arg_number {
+            Some(arg_number) => LLVMDIBuilderCreateParameterVariable(
+                builder, subprogram, cstr(name).as_ptr(), name.len(), arg_number, file, line, debug_type, 1, LLVMDIFlagZero,
+            ),
+            None => LLVMDIBuilderCreateAutoVariable(
+                builder, subprogram, cstr(name).as_ptr(), name.len(), file, line, debug_type, 1, LLVMDIFlagZero, 0,
+            ),
+        };
+        let expression = LLVMDIBuilderCreateExpression(builder, ptr::null_mut(), 0);
+        let location = self.debug_location(subprogram, span);
+        LLVMDIBuilderInsertDeclareAtEnd(builder, storage, variable, expression, location, LLVMGetInsertBlock(self.builder));
+    }
+
+    unsafe fn debug_line(&self, span: Option<Span>) -> c_uint {
+        span.map_or(0, |span| self.module.location(span.start().to_usize()).0)
+    }
+
+    unsafe fn debug_location(&self, scope: LLVMMetadataRef, span: Option<Span>) -> LLVMMetadataRef {
+        let (line, column) = span.map_or((0, 0), |span| self.module.location(span.start().to_usize()));
+        LLVMDIBuilderCreateDebugLocation(self.context, line, column, scope, ptr::null_mut())
+    }
+
+    unsafe fn debug_type(&mut self, type_index: IrTypeIndex) -> LLVMMetadataRef {
+        let (builder, file) = {
+            let debug_info = self.debug_info.as_ref().unwrap();
+            if let Some(typ) = debug_info.type_cache.get(&type_index) {
+                return *typ;
+            }
+            (debug_info.builder, debug_info.file)
+        };
+        let data_layout = LLVMGetModuleDataLayout(self.llvm_module);
+        let pointer_bits = LLVMPointerSize(data_layout) as u64 * 8;
+
+        let basic = |name: &str, bits: u32, encoding| {
+            LLVMDIBuilderCreateBasicType(builder, cstr(name).as_ptr(), name.len(), bits as u64, encoding, LLVMDIFlagZero)
+        };
+        let typ = self.module.typ(type_index).clone();
+        let debug_type = match &typ {
+            IrType::Bool => basic("Bool", 8, DW_ATE_BOOLEAN),
+            IrType::Int(i) => {
+                let name = match i {
+                    IntTy::ISize => "IntSize".to_string(),
+                    i => format!("Int{}", i.bits()),
+                };
+                basic(&name, i.bits(), DW_ATE_SIGNED)
+            }
+            IrType::UInt(u) => {
+                let name = match u {
+                    UIntTy::USize => "USize".to_string(),
+                    u => format!("UInt{}", u.bits()),
+                };
+                basic(&name, u.bits(), DW_ATE_UNSIGNED)
+            }
+            IrType::Float(f) => {
+                let name = match f {
+                    FloatTy::F16 => "Float16",
+                    FloatTy::F32 => "Float32",
+                    FloatTy::F64 => "Float64",
+                    FloatTy::F128 => "Float128",
+                };
+                basic(name, f.bits(), DW_ATE_FLOAT)
+            }
+            // untyped values are compiled as 64 bit integers
+            IrType::Unknown => basic("Int64", 64, DW_ATE_SIGNED),
+            IrType::Void => ptr::null_mut(),
+            IrType::Base(name) => {
+                let struct_type = self.debug_struct(name, type_index);
+                LLVMDIBuilderCreatePointerType(builder, struct_type, pointer_bits, 0, 0, cstr(name).as_ptr(), name.len())
+            }
+            IrType::Refinement(_, inner, _) => self.debug_type(*inner),
+            IrType::Row(fields) => {
+                let name = format!("{{{}}}", fields.iter().map(|f| f.name.clone()).collect::<Vec<String>>().join(","));
+                let llvm_type = self.convert_type(type_index);
+                let fields = fields.iter().map(|f| (f.name.clone(), f.typ)).collect();
+                self.debug_composite(&name, llvm_type, fields)
+            }
+            // arrays are pointers to their first element
+            IrType::Reference(inner, _) | IrType::Array(inner, _) => {
+                let inner = self.debug_type(*inner);
+                LLVMDIBuilderCreatePointerType(builder, inner, pointer_bits, 0, 0, cstr("").as_ptr(), 0)
+            }
+            IrType::Optional(inner) => {
+                let llvm_inner = self.convert_type(*inner);
+                if LLVMGetTypeKind(llvm_inner) == LLVMTypeKind::LLVMPointerTypeKind {
+                    self.debug_type(*inner)
+                } else {
+                    let name = "Optional";
+                    LLVMDIBuilderCreateUnspecifiedType(builder, cstr(name).as_ptr(), name.len())
+                }
+            }
+            IrType::Function(args, ret) => {
+                let mut types = Vec::with_capacity(args.len() + 1);
+                types.push(self.debug_type(*ret));
+                for arg in args.iter() {
+                    types.push(self.debug_type(*arg));
+                }
+                let subroutine = LLVMDIBuilderCreateSubroutineType(builder, file, types.as_mut_ptr(), types.len() as c_uint, LLVMDIFlagZero);
+                LLVMDIBuilderCreatePointerType(builder, subroutine, pointer_bits, 0, 0, cstr("").as_ptr(), 0)
+            }
+        };
+
+        self.debug_info.as_mut().unwrap().type_cache.insert(type_index, debug_type);
+        debug_type
+    }
+
+    /// Describe the struct behind a nominal type. Structs refer to a forward declaration of
+    /// themselves while their fields are being described.
+    unsafe fn debug_struct(&mut self, name: &str, type_index: IrTypeIndex) -> LLVMMetadataRef {
+        let (builder, file, compile_unit) = {
+            let debug_info = self.debug_info.as_ref().unwrap();
+            if let Some(typ) = debug_info.struct_cache.get(name) {
+                return *typ;
+            }
+            (debug_info.builder, debug_info.file, debug_info.compile_unit)
+        };
+        let forward_decl = LLVMDIBuilderCreateForwardDecl(builder, DW_TAG_STRUCTURE_TYPE, cstr(name).as_ptr(), name.len(),
+                                                          compile_unit, file, 0, 0, 0, 0, cstr("").as_ptr(), 0);
+        self.debug_info.as_mut().unwrap().struct_cache.insert(name.to_string(), forward_decl);
+
+        let fields = match self.module.find_struct(name) {
+            Some(IrNode::Struct { fields, .. }) => fields.iter().map(|f| (f.name.clone(), f.typ)).collect(),
+            _ => return forward_decl,
+        };
+        let llvm_type = LLVMGetElementType(self.convert_type(type_index));
+        let struct_type = self.debug_composite(name, llvm_type, fields);
+        self.debug_info.as_mut().unwrap().struct_cache.insert(name.to_string(), struct_type);
+        struct_type
+    }
+
+    /// Describe a struct or row with the layout of the given LLVM struct type.
+    unsafe fn debug_composite(&mut self, name: &str, llvm_type: LLVMTypeRef, fields: Vec<(String, IrTypeIndex)>) -> LLVMMetadataRef {
+        let (builder, file, compile_unit) = {
+            let debug_info = self.debug_info.as_ref().unwrap();
+            (debug_info.builder, debug_info.file, debug_info.compile_unit)
+        };
+        let data_layout = LLVMGetModuleDataLayout(self.llvm_module);
+        let mut members = Vec::with_capacity(fields.len());
+        for (i, (field_name, field_type)) in fields.iter().enumerate() {
+            let debug_field_type = self.debug_type(*field_type);
+            let llvm_field_type = LLVMStructGetTypeAtIndex(llvm_type, i as c_uint);
+            let size = LLVMSizeOfTypeInBits(data_layout, llvm_field_type);
+            let align = LLVMABIAlignmentOfType(data_layout, llvm_field_type) * 8;
+            let offset = LLVMOffsetOfElement(data_layout, llvm_type, i as c_uint) * 8;
+            members.push(LLVMDIBuilderCreateMemberType(builder, compile_unit, cstr(field_name).as_ptr(), field_name.len(),
+                                                       file, 0, size, align, offset, LLVMDIFlagZero, debug_field_type));
+        }
+        let size = LLVMABISizeOfType(data_layout, llvm_type) * 8;
+        let align = LLVMABIAlignmentOfType(data_layout, llvm_type) * 8;
+        LLVMDIBuilderCreateStructType(builder, compile_unit, cstr(name).as_ptr(), name.len(), file, 0, size, align,
+                                      LLVMDIFlagZero, ptr::null_mut(), members.as_mut_ptr(), members.len() as c_uint,
+                                      0, ptr::null_mut(), cstr(name).as_ptr(), name.len())
+    }
+}
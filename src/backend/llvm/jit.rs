@@ -0,0 +1,254 @@
+use std::alloc::{alloc, Layout};
+use std::io::Write;
+use std::os::raw::c_uint;
+use std::ptr;
+use llvm_sys::*;
+use llvm_sys::core::*;
+use llvm_sys::orc2::*;
+use llvm_sys::orc2::lljit::*;
+use crate::backend::{ALLOC_FUNCTION, PANIC_FUNCTION, PRINT_FUNCTION};
+use super::{cstr, LLVMBackend, Value};
+
+/// Name of the generated function the JIT calls into. Its signature is
+/// `i64 __nuvae_entry(i64* args)` no matter what the entry function looks like.
+const ENTRY_WRAPPER: &str = "__nuvae_entry";
+
+/// `nuvae_alloc` for JIT compiled code, which can't link against the C runtime.
+extern "C" fn jit_alloc(_allocator: *mut u8, size: i64) -> *mut u8 {
+    let layout = Layout::from_size_align(size.max(1) as usize, 16).unwrap();
+    unsafe { alloc(layout) }
+}
+
+/// `nuvae_print` for JIT compiled code. It goes through Rust's standard output, which has its
+/// own buffer, so it has to be flushed for the output to come out in order.
+extern "C" fn jit_print(data: *const u8, len: i64) {
+    let bytes = unsafe { std::slice::from_raw_parts(data, len as usize) };
+    let mut stdout = std::io::stdout();
+    let _ = stdout.write_all(bytes).and_then(|_| stdout.flush());
+}
+
+/// `nuvae_panic` for JIT compiled code. Like the C runtime, it reports the message after
+/// everything that was printed and aborts.
+extern "C" fn jit_panic(message: *const u8, len: i64) {
+    let message = unsafe { std::slice::from_raw_parts(message, len as usize) };
+    let _ = std::io::stdout().flush();
+    eprintln!("panic: {}", String::from_utf8_lossy(message));
+    std::process::abort();
+}
+
+/// A module that has been compiled in-process with LLVM's ORC JIT.
+pub struct Jit {
+    lljit: LLVMOrcLLJITRef,
+    arity: usize,
+}
+
+impl Jit {
+    /// Call the entry function with integer arguments and return its result as an integer.
+    pub fn call(&self, args: &[i64]) -> Result<i64, String> {
+        if args.len() != self.arity {
+            return Err(format!("expected {} arguments but got {}", self.arity, args.len()));
+        }
+        unsafe {
+            let mut address: LLVMOrcExecutorAddress = 0;
+            let error = LLVMOrcLLJITLookup(self.lljit, &mut address, cstr(ENTRY_WRAPPER).as_ptr());
+            if !error.is_null() {
+                return Err(LLVMBackend::take_error(error));
+            }
+            let entry: extern "C" fn(*const i64) -> i64 = std::mem::transmute(address as usize);
+            Ok(entry(args.as_ptr()))
+        }
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMOrcDisposeLLJIT(self.lljit);
+        }
+    }
+}
+
+impl<'c> LLVMBackend<'c> {
+    /// Hand the compiled module over to the JIT, with `entry` as the function `Jit::call` calls.
+    /// The module is verified and optimized first, like it is for the other outputs.
+    pub fn into_jit(mut self, entry: &str) -> Result<Jit, String> {
+        unsafe {
+            let arity = self.emit_entry_wrapper(entry)?;
+            self.verify()?;
+            self.optimize()?;
+
+            let mut lljit: LLVMOrcLLJITRef = ptr::null_mut();
+            let error = LLVMOrcCreateLLJIT(&mut lljit, ptr::null_mut());
+            if !error.is_null() {
+                return Err(Self::take_error(error));
+            }
+            let jit = Jit { lljit, arity };
+            let main_dylib = LLVMOrcLLJITGetMainJITDylib(lljit);
+
+            // the runtime is provided by the compiler itself, everything else comes from the process
+            let symbol = |name: &str, address: usize| LLVMJITCSymbolMapPair {
+                Name: LLVMOrcLLJITMangleAndIntern(lljit, cstr(name).as_ptr()),
+                Sym: LLVMJITEvaluatedSymbol {
+                    Address: address as LLVMOrcExecutorAddress,
+                    Flags: LLVMJITSymbolFlags {
+                        GenericFlags: LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsExported as u8
+                            | LLVMJITSymbolGenericFlags::LLVMJITSymbolGenericFlagsCallable as u8,
+                        TargetFlags: 0,
+                    },
+                },
+            };
+            let mut runtime_symbols = [
+                symbol(ALLOC_FUNCTION, jit_alloc as *const () as usize),
+                symbol(PRINT_FUNCTION, jit_print as *const () as usize),
+                symbol(PANIC_FUNCTION, jit_panic as *const () as usize),
+            ];
+            let error = LLVMOrcJITDylibDefine(main_dylib, LLVMOrcAbsoluteSymbols(runtime_symbols.as_mut_ptr(), runtime_symbols.len()));
+            if !error.is_null() {
+                return Err(Self::take_error(error));
+            }
+            let mut generator: LLVMOrcDefinitionGeneratorRef = ptr::null_mut();
+            let error = LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess(
+                &mut generator, LLVMOrcLLJITGetGlobalPrefix(lljit), None, ptr::null_mut());
+            if !error.is_null() {
+                return Err(Self::take_error(error));
+            }
+            LLVMOrcJITDylibAddGenerator(main_dylib, generator);
+
+            let thread_safe_module = LLVMOrcCreateNewThreadSafeModule(self.llvm_module, self.thread_safe_context);
+            self.llvm_module = ptr::null_mut();
+            let error = LLVMOrcLLJITAddLLVMIRModule(lljit, main_dylib, thread_safe_module);
+            if !error.is_null() {
+                LLVMOrcDisposeThreadSafeModule(thread_safe_module);
+                return Err(Self::take_error(error));
+            }
+            Ok(jit)
+        }
+    }
+
+    /// Emit the wrapper that unpacks integer arguments, calls `entry` and widens its result to
+    /// an integer. Returns the number of arguments `entry` takes.
+    unsafe fn emit_entry_wrapper(&mut self, entry: &str) -> Result<usize, String> {
+        let function = match self.function_names.get(entry) {
+            Some((function, _)) => *function,
+            None => return Err(format!("couldn't find entry function `{}`", entry)),
+        };
+        let function_type = LLVMGlobalGetValueType(function);
+        let arity = LLVMCountParamTypes(function_type) as usize;
+        let mut param_types = vec![ptr::null_mut(); arity];
+        LLVMGetParamTypes(function_type, param_types.as_mut_ptr());
+
+        let i64_type = LLVMInt64TypeInContext(self.context);
+        let mut wrapper_params = [LLVMPointerType(i64_type, 0)];
+        let wrapper_type = LLVMFunctionType(i64_type, wrapper_params.as_mut_ptr(), 1, 0);
+        let wrapper = LLVMAddFunction(self.llvm_module, cstr(ENTRY_WRAPPER).as_ptr(), wrapper_type);
+        let block = LLVMAppendBasicBlockInContext(self.context, wrapper, cstr("").as_ptr());
+        LLVMPositionBuilderAtEnd(self.builder, block);
+        // the wrapper has no debug info, so it can't keep the location of the last function
+        LLVMSetCurrentDebugLocation2(self.builder, ptr::null_mut());
+
+        let empty = cstr("");
+        let args_pointer = LLVMGetParam(wrapper, 0);
+        let mut args = Vec::with_capacity(arity);
+        for (i, param_type) in param_types.into_iter().enumerate() {
+            let mut indices = [LLVMConstInt(i64_type, i as u64, 0)];
+            let arg_pointer = LLVMBuildGEP2(self.builder, i64_type, args_pointer, indices.as_mut_ptr(), 1, empty.as_ptr());
+            let arg = LLVMBuildLoad2(self.builder, i64_type, arg_pointer, empty.as_ptr());
+            let arg = match LLVMGetTypeKind(param_type) {
+                LLVMTypeKind::LLVMPointerTypeKind => LLVMBuildIntToPtr(self.builder, arg, param_type, empty.as_ptr()),
+                LLVMTypeKind::LLVMIntegerTypeKind if LLVMGetIntTypeWidth(param_type) == 1 => self.emit_truthy(arg),
+                _ => self.coerce(Value::new(arg, None), param_type),
+            };
+            args.push(arg);
+        }
+        let result = LLVMBuildCall2(self.builder, function_type, function, args.as_mut_ptr(), args.len() as c_uint, empty.as_ptr());
+
+        let return_type = LLVMGetReturnType(function_type);
+        let result = match LLVMGetTypeKind(return_type) {
+            LLVMTypeKind::LLVMVoidTypeKind => LLVMConstInt(i64_type, 0, 0),
+            LLVMTypeKind::LLVMPointerTypeKind => LLVMBuildPtrToInt(self.builder, result, i64_type, empty.as_ptr()),
+            LLVMTypeKind::LLVMIntegerTypeKind => {
+                let typ = self.function_names.get(entry).map(|(_, typ)| *typ);
+                self.coerce(Value::new(result, typ), i64_type)
+            }
+            _ => return Err(format!("can't return {} from the JIT", entry)),
+        };
+        LLVMBuildRet(self.builder, result);
+        Ok(arity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::Path;
+    use crate::backend::llvm::LLVMBackend;
+    use crate::compiler::Compiler;
+    use crate::options::{OptLevel, Options};
+
+    /// JIT compile `source` and call `entry` with `args`.
+    fn run(source: &str, opt_level: OptLevel, entry: &str, args: &[i64]) -> Result<i64, String> {
+        let mut compiler = Compiler::new(Options { opt_level, ..Options::default() });
+        let module = compiler.parse_module(Path::of("test"), "test.nuv".to_string(), source.to_string())
+            .ok_or("the program doesn't compile")?;
+        compiler.optimize(module)?;
+        let module = compiler.modules.get(module).unwrap();
+        let mut backend = LLVMBackend::new(&compiler.options, module)?;
+        backend.compile()?;
+        backend.into_jit(entry)?.call(args)
+    }
+
+    #[test]
+    fn returns_the_result_of_main() {
+        let source = "fun main(): Int32 { return 42; }";
+        assert_eq!(run(source, OptLevel::O0, "main", &[]), Ok(42));
+    }
+
+    #[test]
+    fn passes_arguments_to_the_entry_function() {
+        let source = "
+            fun max(x: Int32, y: Int32): Int32 {
+                if x > y {
+                    return x;
+                }
+                return y;
+            }
+        ";
+        assert_eq!(run(source, OptLevel::O0, "max", &[3, 9]), Ok(9));
+        assert_eq!(run(source, OptLevel::O0, "max", &[-3, -9]), Ok(-3));
+    }
+
+    #[test]
+    fn widens_results_by_their_signedness() {
+        let source = "
+            fun byte(): UInt8 { return 255; }
+            fun small(): Int8 { return -1; }
+            fun yes(): Bool { return true; }
+            fun nothing() {}
+        ";
+        assert_eq!(run(source, OptLevel::O0, "byte", &[]), Ok(255));
+        assert_eq!(run(source, OptLevel::O0, "small", &[]), Ok(-1));
+        assert_eq!(run(source, OptLevel::O0, "yes", &[]), Ok(1));
+        assert_eq!(run(source, OptLevel::O0, "nothing", &[]), Ok(0));
+    }
+
+    #[test]
+    fn optimized_modules_return_the_same() {
+        let source = "
+            fun sum(k: Int64): Int64 {
+                if k < 1 {
+                    return 0;
+                }
+                return sum(k - 1) + k;
+            }
+        ";
+        for opt_level in [OptLevel::O1, OptLevel::O2, OptLevel::O3, OptLevel::Os] {
+            assert_eq!(run(source, opt_level, "sum", &[100]), Ok(5050));
+        }
+    }
+
+    #[test]
+    fn checks_the_entry_function_and_its_arguments() {
+        let source = "fun id(x: Int64): Int64 { return x; }";
+        assert_eq!(run(source, OptLevel::O0, "main", &[]), Err("couldn't find entry function `main`".to_string()));
+        assert_eq!(run(source, OptLevel::O0, "id", &[]), Err("expected 1 arguments but got 0".to_string()));
+    }
+}
@@ -0,0 +1,8 @@
+// What the LLVM C API that llvm-sys binds doesn't cover, built by build.rs.
+
+#include <llvm-c/Core.h>
+#include <llvm/IR/Instructions.h>
+
+extern "C" void nuvae_set_must_tail(LLVMValueRef call) {
+    llvm::unwrap<llvm::CallInst>(call)->setTailCallKind(llvm::CallInst::TCK_MustTail);
+}
@@ -0,0 +1,1798 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use wasm_encoder::{BlockType, CodeSection, ConstExpr, DataSection, ElementSection, Elements, EntityType, ExportKind, ExportSection, Function, FunctionSection, GlobalSection, GlobalType, ImportSection, IndirectNameMap, Instruction, MemArg, MemorySection, MemoryType, NameMap, NameSection, RefType, TableSection, TableType, TypeSection, ValType};
+use crate::ast::{BinOpType, Overflow, UnaryOpType};
+use crate::backend::{Artifact, Backend, ALLOC_FUNCTION, PANIC_FUNCTION, PRINT_FUNCTION};
+use crate::ir::{Access, FloatTy, IntTy, IrBlockIndex, IrFunction, IrInstruction, IrInstructionIndex, IrNode, IrNodeIndex, IrType, IrTypeIndex, IrTypedName, Module, UIntTy};
+use crate::options::{EmitKind, Options};
+
+/// The shadow stack for locals whose address is taken grows down from here to the reserved
+/// null page. String literals are placed here, and the heap grows up from the end of them.
+const STACK_TOP: u32 = 65536;
+const INITIAL_PAGES: u64 = 2;
+
+const STACK_POINTER: u32 = 0;
+const HEAP_POINTER: u32 = 1;
+
+/// How a value is stored in linear memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Slot {
+    size: u32,
+    val_type: ValType,
+    signed: bool,
+}
+
+impl Slot {
+    fn mem_arg(&self, offset: u32) -> MemArg {
+        // the alignment hint can't be larger than the width of the access
+        let width = match self.val_type {
+            ValType::I32 => self.size.min(4),
+            ValType::F32 => 4,
+            _ => 8,
+        };
+        MemArg { offset: offset as u64, align: width.trailing_zeros(), memory_index: 0 }
+    }
+
+    fn load(&self, offset: u32) -> Instruction<'static> {
+        let arg = self.mem_arg(offset);
+        match (self.val_type, self.size, self.signed) {
+            (ValType::I32, 1, true) => Instruction::I32Load8S(arg),
+            (ValType::I32, 1, false) => Instruction::I32Load8U(arg),
+            (ValType::I32, 2, true) => Instruction::I32Load16S(arg),
+            (ValType::I32, 2, false) => Instruction::I32Load16U(arg),
+            (ValType::I32, _, _) => Instruction::I32Load(arg),
+            (ValType::I64, _, _) => Instruction::I64Load(arg),
+            (ValType::F32, _, _) => Instruction::F32Load(arg),
+            (ValType::F64, _, _) => Instruction::F64Load(arg),
+            (val_type, _, _) => panic!("can't load {:?}", val_type),
+        }
+    }
+
+    fn store(&self, offset: u32) -> Instruction<'static> {
+        let arg = self.mem_arg(offset);
+        match (self.val_type, self.size) {
+            (ValType::I32, 1) => Instruction::I32Store8(arg),
+            (ValType::I32, 2) => Instruction::I32Store16(arg),
+            (ValType::I32, _) => Instruction::I32Store(arg),
+            (ValType::I64, _) => Instruction::I64Store(arg),
+            (ValType::F32, _) => Instruction::F32Store(arg),
+            (ValType::F64, _) => Instruction::F64Store(arg),
+            (val_type, _) => panic!("can't store {:?}", val_type),
+        }
+    }
+}
+
+/// Where the value of an instruction, parameter or `let` binding lives.
+#[derive(Clone, Copy, Debug)]
+enum Place {
+    Local(u32),
+    /// A slot in the function's frame on the shadow stack, for locals whose address is taken.
+    Frame(u32),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    /// A function, by its index in the function table.
+    Function(u32),
+    Void,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Value {
+    place: Place,
+    val_type: Option<ValType>,
+    typ: Option<IrTypeIndex>,
+    /// How to load what a pointer points to, when that isn't clear from its type.
+    pointee: Option<Slot>,
+}
+
+impl Value {
+    fn new(place: Place, val_type: Option<ValType>, typ: Option<IrTypeIndex>) -> Self {
+        Self { place, val_type, typ, pointee: None }
+    }
+}
+
+/// State that only lives while a single function is being emitted.
+struct FunctionState {
+    param_count: u32,
+    locals: Vec<ValType>,
+    local_names: Vec<(u32, String)>,
+    return_type: Option<ValType>,
+    named: HashMap<String, Value>,
+    values: HashMap<IrInstructionIndex, Value>,
+    /// Names of the locals whose address is taken, which have to live in memory.
+    borrowed: HashSet<String>,
+    /// Instructions that are only emitted where they are stored to, borrowed or read through.
+    places: HashSet<IrInstructionIndex>,
+    frame_size: u32,
+    /// Locals holding the frame address and the stack pointer to restore on return.
+    frame: Option<(u32, u32)>,
+    label: u32,
+    block_numbers: HashMap<IrBlockIndex, u32>,
+    current_block: u32,
+    body: Vec<Instruction<'static>>,
+}
+
+impl FunctionState {
+    fn new_local(&mut self, val_type: ValType, name: Option<&str>) -> u32 {
+        let index = self.param_count + self.locals.len() as u32;
+        self.locals.push(val_type);
+        if let Some(name) = name {
+            self.local_names.push((index, name.to_string()));
+        }
+        index
+    }
+
+    fn new_frame_slot(&mut self) -> u32 {
+        self.new_frame_space(8)
+    }
+
+    /// Reserve `size` bytes of the frame, keeping everything after them 8 byte aligned.
+    fn new_frame_space(&mut self, size: u32) -> u32 {
+        let offset = self.frame_size;
+        self.frame_size += size.div_ceil(8) * 8;
+        offset
+    }
+
+    fn ins(&mut self, instruction: Instruction<'static>) {
+        self.body.push(instruction);
+    }
+}
+
+/// Plugs the wasm backend into the compiler. It always produces a binary module,
+/// the text format is printed from it when that's what was asked for.
+pub struct WasmCodegen<'options> {
+    options: &'options Options,
+}
+
+impl<'o> WasmCodegen<'o> {
+    pub fn new(options: &'o Options) -> Self {
+        Self { options }
+    }
+}
+
+impl Backend for WasmCodegen<'_> {
+    fn compile_module(&mut self, module: &Module) -> Result<Artifact, String> {
+        if self.options.target.is_some() {
+            return Err("the wasm backend always compiles for wasm32".to_string());
+        }
+        match self.options.emit {
+            EmitKind::Wasm | EmitKind::Wat | EmitKind::Executable => {
+                let wasm = WasmBackend::new(module).compile()?;
+                // a mistake in the backend would otherwise only show up when the module is loaded
+                wasmparser::Validator::new().validate_all(&wasm)
+                    .map_err(|e| format!("the wasm backend produced an invalid module: {}", e))?;
+                Ok(Artifact::Wasm(wasm))
+            }
+            kind => Err(format!("the wasm backend can't emit {:?}", kind)),
+        }
+    }
+}
+
+/// Backend that lowers a module to WebAssembly with a single linear memory. The allocator is
+/// defined in every module as a bump allocator over linear memory, the other runtime functions
+/// are imported from the `env` module when they're used, with 32 bit pointers.
+pub struct WasmBackend<'module> {
+    module: &'module Module,
+    types: TypeSection,
+    type_indices: HashMap<(Vec<ValType>, Vec<ValType>), u32>,
+    function_section: FunctionSection,
+    code: CodeSection,
+    exports: ExportSection,
+    function_names: NameMap,
+    local_names: IndirectNameMap,
+    imports: ImportSection,
+    /// Index of the imported print function, if the module prints.
+    print_function: Option<u32>,
+    /// Index of the imported panic function, if the module can panic.
+    panic_function: Option<u32>,
+    /// Index of the allocator, which comes right after the imports.
+    alloc_function: u32,
+    /// The bytes of string literals and their `Str`s, which start at `STACK_TOP`.
+    data: Vec<u8>,
+    /// Function and table indices of every function in the module.
+    functions: HashMap<IrNodeIndex, (u32, u32)>,
+    functions_by_name: HashMap<String, IrNodeIndex>,
+}
+
+impl<'m> WasmBackend<'m> {
+    pub fn new(module: &'m Module) -> Self {
+        Self {
+            module,
+            types: TypeSection::new(),
+            type_indices: HashMap::new(),
+            function_section: FunctionSection::new(),
+            code: CodeSection::new(),
+            exports: ExportSection::new(),
+            function_names: NameMap::new(),
+            local_names: IndirectNameMap::new(),
+            imports: ImportSection::new(),
+            print_function: None,
+            panic_function: None,
+            alloc_function: 0,
+            data: Vec::new(),
+            functions: HashMap::new(),
+            functions_by_name: HashMap::new(),
+        }
+    }
+
+    /// Lower every function in the module and return the binary module. Public functions are
+    /// exported under their own names, along with the memory.
+    pub fn compile(mut self) -> Result<Vec<u8>, String> {
+        self.check_types()?;
+        let module = self.module;
+        if prints(module) {
+            let type_index = self.type_index(vec![ValType::I32, ValType::I64], vec![]);
+            self.imports.import("env", PRINT_FUNCTION, EntityType::Function(type_index));
+            self.function_names.append(self.alloc_function, PRINT_FUNCTION);
+            self.print_function = Some(self.alloc_function);
+            self.alloc_function += 1;
+        }
+        if panics(module) {
+            let type_index = self.type_index(vec![ValType::I32, ValType::I64], vec![]);
+            self.imports.import("env", PANIC_FUNCTION, EntityType::Function(type_index));
+            self.function_names.append(self.alloc_function, PANIC_FUNCTION);
+            self.panic_function = Some(self.alloc_function);
+            self.alloc_function += 1;
+        }
+        self.emit_alloc_function();
+        let mut table_size = 0;
+        for (index, function) in module.functions() {
+            let function_index = self.alloc_function + table_size + 1;
+            self.functions.insert(index, (function_index, table_size));
+            self.functions_by_name.entry(function.name.clone()).or_insert(index);
+            table_size += 1;
+        }
+        for (index, function) in module.functions() {
+            self.emit_function(index, function)?;
+        }
+
+        let mut tables = TableSection::new();
+        tables.table(TableType {
+            element_type: RefType::FUNCREF,
+            table64: false,
+            minimum: table_size as u64,
+            maximum: Some(table_size as u64),
+            shared: false,
+        });
+        let data_end = STACK_TOP + self.data.len() as u32;
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType {
+            minimum: INITIAL_PAGES.max((data_end as u64).div_ceil(65536)),
+            maximum: None,
+            memory64: false,
+            shared: false,
+            page_size_log2: None,
+        });
+        let mut globals = GlobalSection::new();
+        let mutable_i32 = GlobalType { val_type: ValType::I32, mutable: true, shared: false };
+        globals.global(mutable_i32, &ConstExpr::i32_const(STACK_TOP as i32));
+        globals.global(mutable_i32, &ConstExpr::i32_const(data_end as i32));
+        self.exports.export("memory", ExportKind::Memory, 0);
+        let mut elements = ElementSection::new();
+        let first_function = self.alloc_function + 1;
+        let table_functions: Vec<u32> = (first_function..first_function + table_size).collect();
+        elements.active(None, &ConstExpr::i32_const(0), Elements::Functions(Cow::Owned(table_functions)));
+        let mut data = DataSection::new();
+        if !self.data.is_empty() {
+            data.active(0, &ConstExpr::i32_const(STACK_TOP as i32), self.data.iter().copied());
+        }
+        let mut names = NameSection::new();
+        names.functions(&self.function_names);
+        names.locals(&self.local_names);
+
+        let mut wasm = wasm_encoder::Module::new();
+        wasm.section(&self.types)
+            .section(&self.imports)
+            .section(&self.function_section)
+            .section(&tables)
+            .section(&memories)
+            .section(&globals)
+            .section(&self.exports)
+            .section(&elements)
+            .section(&self.code)
+            .section(&data)
+            .section(&names);
+        Ok(wasm.finish())
+    }
+
+    fn type_index(&mut self, params: Vec<ValType>, results: Vec<ValType>) -> u32 {
+        if let Some(index) = self.type_indices.get(&(params.clone(), results.clone())) {
+            return *index;
+        }
+        let index = self.type_indices.len() as u32;
+        self.types.ty().function(params.clone(), results.clone());
+        self.type_indices.insert((params, results), index);
+        index
+    }
+
+    fn signature(&mut self, params: &[IrTypeIndex], return_type: IrTypeIndex) -> Result<u32, String> {
+        let params = params.iter().map(|param| self.value_type(*param)).collect::<Result<_, String>>()?;
+        let results = self.val_type(return_type).into_iter().collect();
+        Ok(self.type_index(params, results))
+    }
+
+    /// Emit the bump allocator as the first function after the imports:
+    /// `i32 nuvae_alloc(i32 allocator, i64 size)`. Memory is grown when the heap runs out of it.
+    fn emit_alloc_function(&mut self) {
+        let type_index = self.type_index(vec![ValType::I32, ValType::I64], vec![ValType::I32]);
+        self.function_section.function(type_index);
+        self.function_names.append(self.alloc_function, ALLOC_FUNCTION);
+
+        let (size, result, end) = (1, 2, 3);
+        let mut function = Function::new([(2, ValType::I32)]);
+        for instruction in [
+            // align every allocation to 8 bytes
+            Instruction::GlobalGet(HEAP_POINTER),
+            Instruction::I32Const(7),
+            Instruction::I32Add,
+            Instruction::I32Const(-8),
+            Instruction::I32And,
+            Instruction::LocalTee(result),
+            Instruction::LocalGet(size),
+            Instruction::I32WrapI64,
+            Instruction::I32Add,
+            Instruction::LocalTee(end),
+            Instruction::GlobalSet(HEAP_POINTER),
+            Instruction::Block(BlockType::Empty),
+            Instruction::LocalGet(end),
+            Instruction::MemorySize(0),
+            Instruction::I32Const(16),
+            Instruction::I32Shl,
+            Instruction::I32LeU,
+            Instruction::BrIf(0),
+            // grow by enough pages to fit the end of the allocation
+            Instruction::LocalGet(end),
+            Instruction::MemorySize(0),
+            Instruction::I32Const(16),
+            Instruction::I32Shl,
+            Instruction::I32Sub,
+            Instruction::I32Const(65535),
+            Instruction::I32Add,
+            Instruction::I32Const(16),
+            Instruction::I32ShrU,
+            Instruction::MemoryGrow(0),
+            Instruction::I32Const(-1),
+            Instruction::I32Eq,
+            Instruction::If(BlockType::Empty),
+            Instruction::Unreachable,
+            Instruction::End,
+            Instruction::End,
+            Instruction::LocalGet(result),
+            Instruction::End,
+        ] {
+            function.instruction(&instruction);
+        }
+        self.code.function(&function);
+    }
+
+    fn emit_function(&mut self, index: IrNodeIndex, function: &IrFunction) -> Result<(), String> {
+        let module = self.module;
+        let (function_index, _) = *self.functions.get(&index).unwrap();
+        let params: Vec<IrTypeIndex> = function.params.iter().map(|param| param.typ).collect();
+        let type_index = self.signature(&params, function.return_type)?;
+        self.function_section.function(type_index);
+        self.function_names.append(function_index, &function.name);
+        if let Access::Public = function.access {
+            self.exports.export(&function.name, ExportKind::Func, function_index);
+        }
+
+        let mut state = FunctionState {
+            param_count: params.len() as u32,
+            locals: vec![],
+            local_names: vec![],
+            return_type: self.val_type(function.return_type),
+            named: HashMap::new(),
+            values: HashMap::new(),
+            borrowed: HashSet::new(),
+            places: module.places(function),
+            frame_size: 0,
+            frame: None,
+            label: 0,
+            block_numbers: HashMap::new(),
+            current_block: 0,
+            body: vec![],
+        };
+        state.label = state.new_local(ValType::I32, None);
+        for (i, block) in function.blocks.iter().enumerate() {
+            state.block_numbers.insert(*block, i as u32);
+        }
+
+        // locals whose address is taken and objects on the stack live in a frame on the shadow stack
+        let mut needs_frame = false;
+        for block in function.blocks.iter() {
+            for ins in module.block(*block).instructions.iter() {
+                match module.instruction(*ins) {
+                    IrInstruction::Borrow { value } => {
+                        needs_frame = true;
+                        if let IrInstruction::Ref(name) = module.instruction(*value) {
+                            state.borrowed.insert(name.clone());
+                        }
+                    }
+                    IrInstruction::StackAlloc { .. } => needs_frame = true,
+                    _ => {}
+                }
+            }
+        }
+        if needs_frame {
+            let frame = state.new_local(ValType::I32, None);
+            let saved = state.new_local(ValType::I32, None);
+            state.frame = Some((frame, saved));
+        }
+
+        for (i, param) in function.params.iter().enumerate() {
+            let val_type = self.val_type(param.typ);
+            let value = Value::new(Place::Local(i as u32), val_type, Some(param.typ));
+            state.local_names.push((i as u32, param.name.clone()));
+            if state.borrowed.contains(&param.name) {
+                let spilled = self.spill(&mut state, value)?;
+                state.named.insert(param.name.clone(), spilled);
+            } else {
+                state.named.insert(param.name.clone(), value);
+            }
+        }
+
+        // the blocks are dispatched on the label local by a `br_table` in a loop:
+        // loop { block { ... block { br_table } <block 0> } <block 1> ... }
+        let block_count = function.blocks.len() as u32;
+        state.ins(Instruction::Loop(BlockType::Empty));
+        for _ in 0..block_count {
+            state.ins(Instruction::Block(BlockType::Empty));
+        }
+        state.ins(Instruction::LocalGet(state.label));
+        state.ins(Instruction::BrTable(Cow::Owned((0..block_count).collect()), 0));
+        for (i, block) in function.blocks.iter().enumerate() {
+            state.ins(Instruction::End);
+            state.current_block = i as u32;
+            let mut terminated = false;
+            for ins in module.block(*block).instructions.iter() {
+                self.emit_instruction(&mut state, *ins)?;
+                if module.instruction(*ins).is_terminator() {
+                    terminated = true;
+                    break;
+                }
+            }
+            if !terminated {
+                if state.return_type.is_none() {
+                    self.emit_return(&mut state);
+                } else {
+                    state.ins(Instruction::Unreachable);
+                }
+            }
+        }
+        state.ins(Instruction::End);
+        state.ins(Instruction::Unreachable);
+        state.ins(Instruction::End);
+
+        let mut prologue = vec![];
+        if let Some((frame, saved)) = state.frame {
+            let frame_size = (state.frame_size + 15) & !15;
+            prologue.extend([
+                Instruction::GlobalGet(STACK_POINTER),
+                Instruction::LocalTee(saved),
+                Instruction::I32Const(frame_size as i32),
+                Instruction::I32Sub,
+                Instruction::LocalTee(frame),
+                Instruction::GlobalSet(STACK_POINTER),
+            ]);
+        }
+
+        let mut wasm_function = Function::new_with_locals_types(state.locals.iter().copied());
+        for instruction in prologue.iter().chain(state.body.iter()) {
+            wasm_function.instruction(instruction);
+        }
+        self.code.function(&wasm_function);
+
+        let mut local_names = NameMap::new();
+        state.local_names.sort_by_key(|(index, _)| *index);
+        for (index, name) in state.local_names.iter() {
+            local_names.append(*index, name);
+        }
+        self.local_names.append(function_index, &local_names);
+        Ok(())
+    }
+
+    /// Store a value in a new frame slot and return the value that refers to it.
+    fn spill(&mut self, state: &mut FunctionState, value: Value) -> Result<Value, String> {
+        let (frame, _) = state.frame.unwrap();
+        let val_type = value.val_type.ok_or("can't store a Void value")?;
+        let slot = Slot { size: 8, val_type, signed: false };
+        let offset = state.new_frame_slot();
+        state.ins(Instruction::LocalGet(frame));
+        self.push(state, &value, val_type)?;
+        state.ins(slot.store(offset));
+        Ok(Value { place: Place::Frame(offset), ..value })
+    }
+
+    /// Push an operand on the stack, converted to `target`.
+    fn push(&mut self, state: &mut FunctionState, value: &Value, target: ValType) -> Result<(), String> {
+        match value.place {
+            Place::Int(n) => {
+                match target {
+                    ValType::I32 => state.ins(Instruction::I32Const(n as i32)),
+                    ValType::I64 => state.ins(Instruction::I64Const(n)),
+                    ValType::F32 => state.ins(Instruction::F32Const((n as f32).into())),
+                    ValType::F64 => state.ins(Instruction::F64Const((n as f64).into())),
+                    t => return Err(format!("can't convert an integer to {:?}", t)),
+                }
+                return Ok(());
+            }
+            Place::Float(x) => match target {
+                ValType::F32 => {
+                    state.ins(Instruction::F32Const((x as f32).into()));
+                    return Ok(());
+                }
+                _ => state.ins(Instruction::F64Const(x.into())),
+            },
+            Place::Bool(b) => state.ins(Instruction::I32Const(b as i32)),
+            Place::Function(table_index) => state.ins(Instruction::I32Const(table_index as i32)),
+            Place::Local(local) => state.ins(Instruction::LocalGet(local)),
+            Place::Frame(offset) => {
+                let (frame, _) = state.frame.unwrap();
+                let val_type = value.val_type.unwrap();
+                state.ins(Instruction::LocalGet(frame));
+                state.ins(Slot { size: 8, val_type, signed: false }.load(offset));
+            }
+            Place::Void => return Err("tried to use a Void value".to_string()),
+        }
+        let source = value.val_type.unwrap_or(ValType::I32);
+        let signed = !self.is_unsigned(value.typ) && !matches!(value.place, Place::Bool(_))
+            && !matches!(value.typ.map(|t| self.module.typ(t)), Some(IrType::Bool));
+        self.convert(state, source, target, signed)
+    }
+
+    /// Convert the value on top of the stack. `signed` is the signedness of the integer side, which
+    /// is the source for integers and the target for floats that become integers. Floats saturate
+    /// at the bounds of the integer type.
+    fn convert(&mut self, state: &mut FunctionState, source: ValType, target: ValType, signed: bool) -> Result<(), String> {
+        let conversion = match (source, target) {
+            (s, t) if s == t => return Ok(()),
+            (ValType::I32, ValType::I64) => if signed { Instruction::I64ExtendI32S } else { Instruction::I64ExtendI32U },
+            (ValType::I64, ValType::I32) => Instruction::I32WrapI64,
+            (ValType::I32, ValType::F32) => if signed { Instruction::F32ConvertI32S } else { Instruction::F32ConvertI32U },
+            (ValType::I32, ValType::F64) => if signed { Instruction::F64ConvertI32S } else { Instruction::F64ConvertI32U },
+            (ValType::I64, ValType::F32) => if signed { Instruction::F32ConvertI64S } else { Instruction::F32ConvertI64U },
+            (ValType::I64, ValType::F64) => if signed { Instruction::F64ConvertI64S } else { Instruction::F64ConvertI64U },
+            (ValType::F32, ValType::I32) => if signed { Instruction::I32TruncSatF32S } else { Instruction::I32TruncSatF32U },
+            (ValType::F64, ValType::I32) => if signed { Instruction::I32TruncSatF64S } else { Instruction::I32TruncSatF64U },
+            (ValType::F32, ValType::I64) => if signed { Instruction::I64TruncSatF32S } else { Instruction::I64TruncSatF32U },
+            (ValType::F64, ValType::I64) => if signed { Instruction::I64TruncSatF64S } else { Instruction::I64TruncSatF64U },
+            (ValType::F32, ValType::F64) => Instruction::F64PromoteF32,
+            (ValType::F64, ValType::F32) => Instruction::F32DemoteF64,
+            (s, t) => return Err(format!("can't convert {:?} to {:?}", s, t)),
+        };
+        state.ins(conversion);
+        Ok(())
+    }
+
+    /// Push a value as an `i32` condition.
+    fn push_truthy(&mut self, state: &mut FunctionState, value: &Value) -> Result<(), String> {
+        match value.val_type {
+            Some(ValType::I64) => {
+                self.push(state, value, ValType::I64)?;
+                state.ins(Instruction::I64Const(0));
+                state.ins(Instruction::I64Ne);
+            }
+            // NaN is truthy, like any other float that isn't zero
+            Some(ValType::F32) => {
+                self.push(state, value, ValType::F32)?;
+                state.ins(Instruction::F32Const(0.0f32.into()));
+                state.ins(Instruction::F32Ne);
+            }
+            Some(ValType::F64) => {
+                self.push(state, value, ValType::F64)?;
+                state.ins(Instruction::F64Const(0.0f64.into()));
+                state.ins(Instruction::F64Ne);
+            }
+            _ => {
+                self.push(state, value, ValType::I32)?;
+                if !matches!(value.place, Place::Bool(_)) {
+                    state.ins(Instruction::I32Const(0));
+                    state.ins(Instruction::I32Ne);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep the value on top of the stack in a new local.
+    fn store_result(&mut self, state: &mut FunctionState, val_type: ValType, typ: Option<IrTypeIndex>) -> Value {
+        let local = state.new_local(val_type, None);
+        state.ins(Instruction::LocalSet(local));
+        Value::new(Place::Local(local), Some(val_type), typ)
+    }
+
+    /// Get the value of an instruction operand. `Ref`s and literals are pushed at their use,
+    /// every other instruction has already been emitted into a local.
+    fn operand(&mut self, state: &mut FunctionState, index: IrInstructionIndex) -> Result<Value, String> {
+        if let Some(value) = state.values.get(&index) {
+            return Ok(*value);
+        }
+        Ok(match self.module.instruction(index) {
+            IrInstruction::Ref(name) => {
+                if let Some(value) = state.named.get(name) {
+                    *value
+                } else if let Some(function) = self.functions_by_name.get(name) {
+                    let (_, table_index) = *self.functions.get(function).unwrap();
+                    Value::new(Place::Function(table_index), Some(ValType::I32), None)
+                } else {
+                    return Err(format!("couldn't find `{}`", name));
+                }
+            }
+            // `check_types` reports 128-bit integers, so the low 64 bits are all a literal has
+            IrInstruction::NatLiteral(n) => Value::new(Place::Int(*n as i64), Some(ValType::I64), None),
+            IrInstruction::FloatLiteral(n) => Value::new(Place::Float(*n), Some(ValType::F64), None),
+            IrInstruction::CharLiteral(c) => Value::new(Place::Int(*c as i64), Some(ValType::I64), None),
+            IrInstruction::BoolLiteral(b) => Value::new(Place::Bool(*b), Some(ValType::I32), None),
+            ins => return Err(format!("instruction {:?} was used before it was emitted", ins)),
+        })
+    }
+
+    fn emit_instruction(&mut self, state: &mut FunctionState, index: IrInstructionIndex) -> Result<(), String> {
+        let module = self.module;
+        if state.places.contains(&index) {
+            return Ok(());
+        }
+        let value = match module.instruction(index) {
+            // these are pushed where they are used
+            IrInstruction::Ref(_) | IrInstruction::NatLiteral(_) | IrInstruction::FloatLiteral(_)
+            | IrInstruction::BoolLiteral(_) | IrInstruction::CharLiteral(_) => return Ok(()),
+            IrInstruction::StrLiteral(string) => {
+                let str_type = module.str_type().expect("string literals have the `Str` type");
+                let address = self.define_string(str_type, string)?;
+                Value::new(Place::Int(address as i64), Some(ValType::I32), Some(str_type))
+            }
+            IrInstruction::Print { value } => {
+                let value = self.operand(state, *value)?;
+                let data = self.emit_field_access(state, value, "data")?;
+                let len = self.emit_field_access(state, value, "len")?;
+                self.push(state, &data, ValType::I32)?;
+                self.push(state, &len, ValType::I64)?;
+                state.ins(Instruction::Call(self.print_function.unwrap()));
+                return Ok(());
+            }
+            IrInstruction::BinOp(lhs, op, rhs) => {
+                let lhs = self.operand(state, *lhs)?;
+                let rhs = self.operand(state, *rhs)?;
+                self.emit_bin_op(state, index, lhs, *op, rhs)?
+            }
+            IrInstruction::UnaryOp(op, value) => {
+                let value = self.operand(state, *value)?;
+                self.emit_unary_op(state, index, *op, value)?
+            }
+            IrInstruction::Cast { value, typ } => {
+                let value = self.operand(state, *value)?;
+                self.emit_cast(state, value, *typ)?
+            }
+            IrInstruction::FieldAccessor { aggregate, value } => {
+                let field_name = self.field_name(*value)?;
+                let aggregate = self.operand(state, *aggregate)?;
+                self.emit_field_access(state, aggregate, &field_name)?
+            }
+            IrInstruction::FunctionCall { function, args } => {
+                let callee = self.operand(state, *function)?;
+                self.emit_call(state, callee, args)?
+            }
+            IrInstruction::New { typ, allocator } => {
+                let allocator = self.operand(state, *allocator)?;
+                self.emit_new(state, *typ, allocator)?
+            }
+            IrInstruction::StackAlloc { typ } => self.emit_stack_alloc(state, *typ)?,
+            IrInstruction::RowLiteral { typ, values } => {
+                let values = values.iter().map(|value| self.operand(state, *value)).collect::<Result<Vec<Value>, String>>()?;
+                self.emit_row(state, *typ, &values)?
+            }
+            IrInstruction::Dereference { pointer } => {
+                let pointer = self.operand(state, *pointer)?;
+                self.emit_dereference(state, pointer)?
+            }
+            IrInstruction::Index { base, index } => self.emit_index(state, *base, *index)?,
+            IrInstruction::BoundsCheck { index, len } => {
+                let i = self.operand(state, *index)?;
+                let len = self.operand(state, *len)?;
+                // negative indices are past the end once they're unsigned
+                self.push(state, &i, ValType::I64)?;
+                self.push(state, &len, ValType::I64)?;
+                state.ins(Instruction::I64GeU);
+                let message = module.bounds_message(*index);
+                self.emit_panic_if(state, &message);
+                return Ok(());
+            }
+            IrInstruction::Denull { optional } => {
+                let optional = self.operand(state, *optional)?;
+                let typ = optional.typ.and_then(|t| match module.typ(t) {
+                    IrType::Optional(inner) => Some(*inner),
+                    _ => None,
+                });
+                match typ {
+                    // non-pointer optionals point to their value, or are null
+                    Some(inner) if !self.is_pointer(inner) => {
+                        let slot = self.slot(inner)?;
+                        self.push(state, &optional, ValType::I32)?;
+                        state.ins(slot.load(0));
+                        self.store_result(state, slot.val_type, Some(inner))
+                    }
+                    _ => Value { typ: typ.or(optional.typ), ..optional },
+                }
+            }
+            // borrowing a field, element or pointee gives the address of that place
+            IrInstruction::Borrow { value } if state.places.contains(value) => {
+                let slot = self.push_place_address(state, *value)?;
+                let mut result = self.store_result(state, ValType::I32, None);
+                result.pointee = Some(slot);
+                result
+            }
+            IrInstruction::Borrow { value } => {
+                // borrowing a local gives the address of its frame slot, anything else is spilled first
+                let borrowed = self.operand(state, *value)?;
+                let borrowed = match borrowed.place {
+                    Place::Frame(_) => borrowed,
+                    _ => self.spill(state, borrowed)?,
+                };
+                let offset = match borrowed.place {
+                    Place::Frame(offset) => offset,
+                    _ => unreachable!(),
+                };
+                let (frame, _) = state.frame.unwrap();
+                state.ins(Instruction::LocalGet(frame));
+                state.ins(Instruction::I32Const(offset as i32));
+                state.ins(Instruction::I32Add);
+                let mut result = self.store_result(state, ValType::I32, None);
+                let val_type = borrowed.val_type.ok_or("can't borrow a Void value")?;
+                result.pointee = Some(Slot { size: 8, val_type, signed: false });
+                result
+            }
+            IrInstruction::Let { name, value } => {
+                let value = self.operand(state, *value)?;
+                // untyped bindings take the type of their value
+                let typ = match (module.typ(name.typ), value.typ) {
+                    (IrType::Unknown, Some(value_type)) => Some(value_type),
+                    (IrType::Unknown, None) if matches!(value.place, Place::Float(_)) => module.float_literal_type(),
+                    (IrType::Unknown, None) if !matches!(value.place, Place::Int(_)) => None,
+                    _ => Some(name.typ),
+                };
+                let val_type = match typ {
+                    Some(typ) => self.val_type(typ),
+                    None => value.val_type,
+                }.ok_or("can't bind a Void value")?;
+                self.push(state, &value, val_type)?;
+                let local = state.new_local(val_type, Some(&name.name));
+                state.ins(Instruction::LocalSet(local));
+                let mut local_value = Value { place: Place::Local(local), val_type: Some(val_type), typ, pointee: value.pointee };
+                if state.borrowed.contains(&name.name) {
+                    local_value = self.spill(state, local_value)?;
+                }
+                state.named.insert(name.name.clone(), local_value);
+                return Ok(());
+            }
+            IrInstruction::Assign { name, value } => {
+                let value = self.operand(state, *value)?;
+                let local = *state.named.get(name).ok_or_else(|| format!("couldn't find `{}`", name))?;
+                self.store_local(state, &local, &value)?;
+                return Ok(());
+            }
+            IrInstruction::Store { place, value } => {
+                let value = self.operand(state, *value)?;
+                self.emit_store(state, *place, value)?;
+                return Ok(());
+            }
+            IrInstruction::Branch { condition, true_branch, false_branch } => {
+                let condition = self.operand(state, *condition)?;
+                self.push_truthy(state, &condition)?;
+                let true_block = *state.block_numbers.get(true_branch).unwrap();
+                let false_block = *state.block_numbers.get(false_branch).unwrap();
+                state.ins(Instruction::If(BlockType::Result(ValType::I32)));
+                state.ins(Instruction::I32Const(true_block as i32));
+                state.ins(Instruction::Else);
+                state.ins(Instruction::I32Const(false_block as i32));
+                state.ins(Instruction::End);
+                state.ins(Instruction::LocalSet(state.label));
+                self.emit_dispatch(state);
+                return Ok(());
+            }
+            IrInstruction::Jump { target } => {
+                let target = *state.block_numbers.get(target).unwrap();
+                state.ins(Instruction::I32Const(target as i32));
+                state.ins(Instruction::LocalSet(state.label));
+                self.emit_dispatch(state);
+                return Ok(());
+            }
+            IrInstruction::Return { value } => {
+                let value = self.operand(state, *value)?;
+                if let Some(return_type) = state.return_type {
+                    self.push(state, &value, return_type)?;
+                }
+                self.emit_return(state);
+                return Ok(());
+            }
+            IrInstruction::Error => return Err("tried to emit an error instruction".to_string()),
+        };
+        state.values.insert(index, value);
+        Ok(())
+    }
+
+    /// Branch back to the dispatch loop from the current block.
+    fn emit_dispatch(&mut self, state: &mut FunctionState) {
+        // the current block's code is nested in the blocks of all the blocks after it
+        let depth = state.block_numbers.len() as u32 - 1 - state.current_block;
+        state.ins(Instruction::Br(depth));
+    }
+
+    fn emit_return(&mut self, state: &mut FunctionState) {
+        if let Some((_, saved)) = state.frame {
+            state.ins(Instruction::LocalGet(saved));
+            state.ins(Instruction::GlobalSet(STACK_POINTER));
+        }
+        state.ins(Instruction::Return);
+    }
+
+    fn emit_call(&mut self, state: &mut FunctionState, callee: Value, args: &[IrInstructionIndex]) -> Result<Value, String> {
+        let direct = match callee.place {
+            Place::Function(table_index) => self.functions.iter()
+                .find(|(_, (_, table))| *table == table_index)
+                .map(|(index, (function_index, _))| (*index, *function_index)),
+            _ => None,
+        };
+        let (params, return_type) = match direct {
+            Some((index, _)) => match self.module.node(index) {
+                IrNode::Function(function) => (function.params.iter().map(|param| param.typ).collect(), function.return_type),
+                _ => unreachable!(),
+            },
+            None => match callee.typ.map(|t| self.module.typ(t)) {
+                Some(IrType::Function(params, return_type)) => (params.clone(), *return_type),
+                _ => return Err("tried to call a value that isn't a function".to_string()),
+            },
+        };
+        if params.len() != args.len() {
+            return Err(format!("expected {} arguments but got {}", params.len(), args.len()));
+        }
+        for (arg, param) in args.iter().zip(params.iter()) {
+            let arg = self.operand(state, *arg)?;
+            let param_type = self.value_type(*param)?;
+            self.push(state, &arg, param_type)?;
+        }
+        match direct {
+            Some((_, function_index)) => state.ins(Instruction::Call(function_index)),
+            None => {
+                let type_index = self.signature(&params, return_type)?;
+                self.push(state, &callee, ValType::I32)?;
+                state.ins(Instruction::CallIndirect { type_index, table_index: 0 });
+            }
+        }
+        Ok(match self.val_type(return_type) {
+            Some(val_type) => self.store_result(state, val_type, Some(return_type)),
+            None => Value::new(Place::Void, None, Some(return_type)),
+        })
+    }
+
+    fn emit_bin_op(&mut self, state: &mut FunctionState, index: IrInstructionIndex, lhs: Value, op: BinOpType, rhs: Value) -> Result<Value, String> {
+        if let BinOpType::And | BinOpType::Or = op {
+            self.push_truthy(state, &lhs)?;
+            self.push_truthy(state, &rhs)?;
+            state.ins(if let BinOpType::And = op { Instruction::I32And } else { Instruction::I32Or });
+            return Ok(self.store_result(state, ValType::I32, None));
+        }
+
+        if op.is_shift() {
+            return self.emit_shift(state, lhs, op, rhs);
+        }
+
+        // integers are converted to the type of a float operand, untyped literals take the type of
+        // the other operand, otherwise operands are widened to the larger of the two types
+        let width = |value: &Value| match value.val_type {
+            Some(ValType::I64) | Some(ValType::F64) => 64,
+            _ => 32,
+        };
+        let is_float = |value: &Value| matches!(value.val_type, Some(ValType::F32) | Some(ValType::F64));
+        let lhs_literal = matches!(lhs.place, Place::Int(_) | Place::Float(_));
+        let rhs_literal = matches!(rhs.place, Place::Int(_) | Place::Float(_));
+        let (val_type, typ) = if is_float(&lhs) != is_float(&rhs) {
+            if is_float(&lhs) { (lhs.val_type, lhs.typ) } else { (rhs.val_type, rhs.typ) }
+        } else if rhs_literal && !lhs_literal {
+            (lhs.val_type, lhs.typ)
+        } else if lhs_literal && !rhs_literal {
+            (rhs.val_type, rhs.typ)
+        } else if width(&lhs) >= width(&rhs) {
+            (lhs.val_type, lhs.typ.or(rhs.typ))
+        } else {
+            (rhs.val_type, rhs.typ.or(lhs.typ))
+        };
+        let val_type = val_type.unwrap_or(ValType::I64);
+        let signed = !(self.is_unsigned(lhs.typ) || self.is_unsigned(rhs.typ));
+        let float = matches!(val_type, ValType::F32 | ValType::F64);
+        if !float && op.overflow().is_some() {
+            return self.emit_arithmetic(state, index, op, (lhs, rhs), val_type, typ);
+        }
+        // floats don't overflow, they become infinite
+        let op = op.overflow().map_or(op, |(op, _)| op);
+        if let (true, BinOpType::Percent) = (float, op) {
+            // there's no float remainder instruction, so it's `l - trunc(l / r) * r`
+            let wide = val_type == ValType::F64;
+            self.push(state, &lhs, val_type)?;
+            self.push(state, &lhs, val_type)?;
+            self.push(state, &rhs, val_type)?;
+            state.ins(Self::float_bin_op(BinOpType::ForwardSlash, wide)?);
+            state.ins(if wide { Instruction::F64Trunc } else { Instruction::F32Trunc });
+            self.push(state, &rhs, val_type)?;
+            state.ins(Self::float_bin_op(BinOpType::Star, wide)?);
+            state.ins(Self::float_bin_op(BinOpType::Minus, wide)?);
+            return Ok(self.store_result(state, val_type, typ));
+        }
+        if let (false, BinOpType::ForwardSlash | BinOpType::Percent) = (float, op) {
+            self.emit_division_checks(state, index, (&lhs, &rhs), op, val_type, typ)?;
+        }
+        self.push(state, &lhs, val_type)?;
+        self.push(state, &rhs, val_type)?;
+
+        if float {
+            state.ins(Self::float_bin_op(op, val_type == ValType::F64)?);
+            return Ok(if op.is_comparison() {
+                self.store_result(state, ValType::I32, None)
+            } else {
+                self.store_result(state, val_type, typ)
+            });
+        }
+
+        let wide = val_type == ValType::I64;
+        let pick = |narrow: Instruction<'static>, wide_ins: Instruction<'static>| if wide { wide_ins } else { narrow };
+        let instruction = match (op, signed) {
+            (BinOpType::ForwardSlash, true) => pick(Instruction::I32DivS, Instruction::I64DivS),
+            (BinOpType::ForwardSlash, false) => pick(Instruction::I32DivU, Instruction::I64DivU),
+            (BinOpType::LessThan, true) => pick(Instruction::I32LtS, Instruction::I64LtS),
+            (BinOpType::LessThan, false) => pick(Instruction::I32LtU, Instruction::I64LtU),
+            (BinOpType::GreaterThan, true) => pick(Instruction::I32GtS, Instruction::I64GtS),
+            (BinOpType::GreaterThan, false) => pick(Instruction::I32GtU, Instruction::I64GtU),
+            (BinOpType::LessThanEqualTo, true) => pick(Instruction::I32LeS, Instruction::I64LeS),
+            (BinOpType::LessThanEqualTo, false) => pick(Instruction::I32LeU, Instruction::I64LeU),
+            (BinOpType::GreaterThanEqualTo, true) => pick(Instruction::I32GeS, Instruction::I64GeS),
+            (BinOpType::GreaterThanEqualTo, false) => pick(Instruction::I32GeU, Instruction::I64GeU),
+            (BinOpType::EqualTo, _) => pick(Instruction::I32Eq, Instruction::I64Eq),
+            (BinOpType::NotEqualTo, _) => pick(Instruction::I32Ne, Instruction::I64Ne),
+            (BinOpType::Percent, true) => pick(Instruction::I32RemS, Instruction::I64RemS),
+            (BinOpType::Percent, false) => pick(Instruction::I32RemU, Instruction::I64RemU),
+            (BinOpType::Ampersand, _) => pick(Instruction::I32And, Instruction::I64And),
+            (BinOpType::Pipe, _) => pick(Instruction::I32Or, Instruction::I64Or),
+            (BinOpType::Caret, _) => pick(Instruction::I32Xor, Instruction::I64Xor),
+            _ => unreachable!(),
+        };
+        state.ins(instruction);
+        if op.is_comparison() {
+            Ok(self.store_result(state, ValType::I32, None))
+        } else {
+            if let Some(typ) = typ {
+                self.emit_wrap(state, typ);
+            }
+            Ok(self.store_result(state, val_type, typ))
+        }
+    }
+
+    /// Add, subtract or multiply two integers, and trap, wrap or saturate like the operator says
+    /// when the result doesn't fit `typ`. Results that fit in 32 bits are computed exactly in 64
+    /// bits and compared against the limits of their type, for 64 bit ones the overflow is worked
+    /// out from the operands and the wrapped result.
+    fn emit_arithmetic(&mut self, state: &mut FunctionState, index: IrInstructionIndex, op: BinOpType, operands: (Value, Value),
+                       val_type: ValType, typ: Option<IrTypeIndex>) -> Result<Value, String> {
+        let (op, overflow) = op.overflow().unwrap();
+        let (lhs, rhs) = operands;
+        let signed = !(self.is_unsigned(lhs.typ) || self.is_unsigned(rhs.typ));
+        let wide = val_type == ValType::I64;
+        let pick = |narrow: Instruction<'static>, wide_ins: Instruction<'static>| if wide { wide_ins } else { narrow };
+        let instruction = match op {
+            BinOpType::Plus => pick(Instruction::I32Add, Instruction::I64Add),
+            BinOpType::Minus => pick(Instruction::I32Sub, Instruction::I64Sub),
+            _ => pick(Instruction::I32Mul, Instruction::I64Mul),
+        };
+        if overflow == Overflow::Wrap {
+            self.push(state, &lhs, val_type)?;
+            self.push(state, &rhs, val_type)?;
+            state.ins(instruction);
+            if let Some(typ) = typ {
+                self.emit_wrap(state, typ);
+            }
+            return Ok(self.store_result(state, val_type, typ));
+        }
+
+        if wide {
+            self.push(state, &lhs, val_type)?;
+            self.push(state, &rhs, val_type)?;
+            state.ins(instruction);
+            let result = self.store_result(state, val_type, typ);
+            let overflowed = self.emit_wide_overflow(state, op, signed, (lhs, rhs), result)?;
+            if overflow == Overflow::Trap {
+                self.emit_overflow_trap(state, index, op, overflowed)?;
+                return Ok(result);
+            }
+            self.emit_wide_limit(state, op, signed, (lhs, rhs))?;
+            self.push(state, &result, val_type)?;
+            self.push(state, &overflowed, ValType::I32)?;
+            state.ins(Instruction::Select);
+            return Ok(self.store_result(state, val_type, typ));
+        }
+
+        let extend = if signed { Instruction::I64ExtendI32S } else { Instruction::I64ExtendI32U };
+        self.push(state, &lhs, val_type)?;
+        state.ins(extend.clone());
+        self.push(state, &rhs, val_type)?;
+        state.ins(extend);
+        state.ins(match op {
+            BinOpType::Plus => Instruction::I64Add,
+            BinOpType::Minus => Instruction::I64Sub,
+            _ => Instruction::I64Mul,
+        });
+        let exact = self.store_result(state, ValType::I64, None);
+        let bits = typ.and_then(|typ| self.narrow_bits(typ)).unwrap_or(32) as i64;
+        let (min, max) = if signed { (-(1 << (bits - 1)), (1 << (bits - 1)) - 1) } else { (0, (1 << bits) - 1) };
+        if signed {
+            self.push(state, &exact, ValType::I64)?;
+            state.ins(Instruction::I64Const(min));
+            state.ins(Instruction::I64LtS);
+            self.push(state, &exact, ValType::I64)?;
+            state.ins(Instruction::I64Const(max));
+            state.ins(Instruction::I64GtS);
+            state.ins(Instruction::I32Or);
+        } else {
+            // an unsigned result below zero is far above the maximum when it's compared unsigned
+            self.push(state, &exact, ValType::I64)?;
+            state.ins(Instruction::I64Const(max));
+            state.ins(Instruction::I64GtU);
+        }
+        let overflowed = self.store_result(state, ValType::I32, None);
+        if overflow == Overflow::Trap {
+            self.emit_overflow_trap(state, index, op, overflowed)?;
+            self.push(state, &exact, ValType::I64)?;
+        } else {
+            if signed {
+                state.ins(Instruction::I64Const(min));
+                state.ins(Instruction::I64Const(max));
+                self.push(state, &exact, ValType::I64)?;
+                state.ins(Instruction::I64Const(min));
+                state.ins(Instruction::I64LtS);
+                state.ins(Instruction::Select);
+            } else {
+                // unsigned subtraction can only overflow below zero, the others only above the maximum
+                state.ins(Instruction::I64Const(if let BinOpType::Minus = op { 0 } else { max }));
+            }
+            self.push(state, &exact, ValType::I64)?;
+            self.push(state, &overflowed, ValType::I32)?;
+            state.ins(Instruction::Select);
+        }
+        state.ins(Instruction::I32WrapI64);
+        Ok(self.store_result(state, val_type, typ))
+    }
+
+    /// Work out whether 64 bit arithmetic overflowed from its operands and wrapped result.
+    fn emit_wide_overflow(&mut self, state: &mut FunctionState, op: BinOpType, signed: bool, operands: (Value, Value), result: Value) -> Result<Value, String> {
+        let (lhs, rhs) = operands;
+        let i64 = ValType::I64;
+        match (op, signed) {
+            // a sum overflowed when its sign differs from the signs of both operands
+            (BinOpType::Plus, true) => {
+                self.push(state, &lhs, i64)?;
+                self.push(state, &result, i64)?;
+                state.ins(Instruction::I64Xor);
+                self.push(state, &rhs, i64)?;
+                self.push(state, &result, i64)?;
+                state.ins(Instruction::I64Xor);
+                state.ins(Instruction::I64And);
+                state.ins(Instruction::I64Const(0));
+                state.ins(Instruction::I64LtS);
+            }
+            // a difference overflowed when the operands have different signs and the result has
+            // the sign of the right one
+            (BinOpType::Minus, true) => {
+                self.push(state, &lhs, i64)?;
+                self.push(state, &rhs, i64)?;
+                state.ins(Instruction::I64Xor);
+                self.push(state, &lhs, i64)?;
+                self.push(state, &result, i64)?;
+                state.ins(Instruction::I64Xor);
+                state.ins(Instruction::I64And);
+                state.ins(Instruction::I64Const(0));
+                state.ins(Instruction::I64LtS);
+            }
+            (BinOpType::Plus, false) => {
+                self.push(state, &result, i64)?;
+                self.push(state, &lhs, i64)?;
+                state.ins(Instruction::I64LtU);
+            }
+            (BinOpType::Minus, false) => {
+                self.push(state, &lhs, i64)?;
+                self.push(state, &rhs, i64)?;
+                state.ins(Instruction::I64LtU);
+            }
+            // a product overflowed when dividing it by the left operand doesn't give the right one
+            // back. Dividing by zero would trap, and so would dividing the smallest integer by -1,
+            // so those are divided by 1 instead and checked on their own.
+            (_, true) => {
+                state.ins(Instruction::I64Const(1));
+                self.push(state, &lhs, i64)?;
+                self.push(state, &lhs, i64)?;
+                state.ins(Instruction::I64Eqz);
+                self.push(state, &lhs, i64)?;
+                state.ins(Instruction::I64Const(-1));
+                state.ins(Instruction::I64Eq);
+                state.ins(Instruction::I32Or);
+                state.ins(Instruction::Select);
+                let divisor = self.store_result(state, i64, None);
+                // -1 times anything but the smallest integer fits
+                self.push(state, &rhs, i64)?;
+                state.ins(Instruction::I64Const(i64::MIN));
+                state.ins(Instruction::I64Eq);
+                state.ins(Instruction::I32Const(0));
+                self.push(state, &result, i64)?;
+                self.push(state, &divisor, i64)?;
+                state.ins(Instruction::I64DivS);
+                self.push(state, &rhs, i64)?;
+                state.ins(Instruction::I64Ne);
+                self.push(state, &lhs, i64)?;
+                state.ins(Instruction::I64Eqz);
+                state.ins(Instruction::Select);
+                self.push(state, &lhs, i64)?;
+                state.ins(Instruction::I64Const(-1));
+                state.ins(Instruction::I64Eq);
+                state.ins(Instruction::Select);
+            }
+            (_, false) => {
+                state.ins(Instruction::I64Const(1));
+                self.push(state, &lhs, i64)?;
+                self.push(state, &lhs, i64)?;
+                state.ins(Instruction::I64Eqz);
+                state.ins(Instruction::Select);
+                let divisor = self.store_result(state, i64, None);
+                state.ins(Instruction::I32Const(0));
+                self.push(state, &result, i64)?;
+                self.push(state, &divisor, i64)?;
+                state.ins(Instruction::I64DivU);
+                self.push(state, &rhs, i64)?;
+                state.ins(Instruction::I64Ne);
+                self.push(state, &lhs, i64)?;
+                state.ins(Instruction::I64Eqz);
+                state.ins(Instruction::Select);
+            }
+        }
+        Ok(self.store_result(state, ValType::I32, None))
+    }
+
+    /// Push the limit 64 bit saturating arithmetic that overflowed is clamped to. A signed result
+    /// overflows towards the sign of the right operand, or for a product the sign the exact result
+    /// has, and unsigned ones overflow above the maximum except for subtraction.
+    fn emit_wide_limit(&mut self, state: &mut FunctionState, op: BinOpType, signed: bool, operands: (Value, Value)) -> Result<(), String> {
+        let (lhs, rhs) = operands;
+        if !signed {
+            state.ins(Instruction::I64Const(if let BinOpType::Minus = op { 0 } else { -1 }));
+            return Ok(());
+        }
+        let (when_negative, otherwise) = if let BinOpType::Minus = op { (i64::MAX, i64::MIN) } else { (i64::MIN, i64::MAX) };
+        state.ins(Instruction::I64Const(when_negative));
+        state.ins(Instruction::I64Const(otherwise));
+        self.push(state, &rhs, ValType::I64)?;
+        if let BinOpType::Star = op {
+            self.push(state, &lhs, ValType::I64)?;
+            state.ins(Instruction::I64Xor);
+        }
+        state.ins(Instruction::I64Const(0));
+        state.ins(Instruction::I64LtS);
+        state.ins(Instruction::Select);
+        Ok(())
+    }
+
+    /// Check that an integer division or remainder doesn't divide by zero, or overflow by
+    /// dividing the smallest value of a signed type by -1. Wasm would trap without saying why, and
+    /// wouldn't notice the overflow for narrow types.
+    fn emit_division_checks(&mut self, state: &mut FunctionState, index: IrInstructionIndex, operands: (&Value, &Value), op: BinOpType,
+                            val_type: ValType, typ: Option<IrTypeIndex>) -> Result<(), String> {
+        let (lhs, rhs) = operands;
+        let signed = !(self.is_unsigned(lhs.typ) || self.is_unsigned(rhs.typ));
+        let wide = val_type == ValType::I64;
+        self.push(state, rhs, val_type)?;
+        state.ins(if wide { Instruction::I64Eqz } else { Instruction::I32Eqz });
+        let message = self.module.division_by_zero_message(index, op);
+        self.emit_panic_if(state, &message);
+        if signed {
+            let min = self.int_min(val_type, typ);
+            self.push(state, lhs, val_type)?;
+            self.push(state, &Value::new(Place::Int(min), Some(val_type), None), val_type)?;
+            state.ins(if wide { Instruction::I64Eq } else { Instruction::I32Eq });
+            self.push(state, rhs, val_type)?;
+            self.push(state, &Value::new(Place::Int(-1), Some(val_type), None), val_type)?;
+            state.ins(if wide { Instruction::I64Eq } else { Instruction::I32Eq });
+            state.ins(Instruction::I32And);
+            let message = self.module.overflow_message(index, op);
+            self.emit_panic_if(state, &message);
+        }
+        Ok(())
+    }
+
+    /// The smallest value of a signed integer type kept in a local of type `val_type`.
+    fn int_min(&self, val_type: ValType, typ: Option<IrTypeIndex>) -> i64 {
+        let bits = typ.and_then(|typ| self.narrow_bits(typ))
+            .unwrap_or(if val_type == ValType::I64 { 64 } else { 32 });
+        i64::MIN >> (64 - bits)
+    }
+
+    /// Call the panic function when an overflow flag is set, with the message for `op`.
+    fn emit_overflow_trap(&mut self, state: &mut FunctionState, index: IrInstructionIndex, op: BinOpType, overflowed: Value) -> Result<(), String> {
+        let message = self.module.overflow_message(index, op);
+        self.push(state, &overflowed, ValType::I32)?;
+        self.emit_panic_if(state, &message);
+        Ok(())
+    }
+
+    /// Call the panic function with a message when the condition on top of the stack is true.
+    fn emit_panic_if(&mut self, state: &mut FunctionState, message: &str) {
+        let address = STACK_TOP + self.data.len() as u32;
+        self.data.extend_from_slice(message.as_bytes());
+        state.ins(Instruction::If(BlockType::Empty));
+        state.ins(Instruction::I32Const(address as i32));
+        state.ins(Instruction::I64Const(message.len() as i64));
+        state.ins(Instruction::Call(self.panic_function.expect("the panic function is imported when the module can panic")));
+        state.ins(Instruction::Unreachable);
+        state.ins(Instruction::End);
+    }
+
+    /// Emit a shift, which has the type of the value that is shifted. Wasm takes the amount modulo
+    /// 32 or 64, so for narrower types it's masked to their width first.
+    fn emit_shift(&mut self, state: &mut FunctionState, lhs: Value, op: BinOpType, rhs: Value) -> Result<Value, String> {
+        let val_type = lhs.val_type.unwrap_or(ValType::I64);
+        let wide = match val_type {
+            ValType::I32 => false,
+            ValType::I64 => true,
+            t => return Err(format!("can't shift {:?}", t)),
+        };
+        self.push(state, &lhs, val_type)?;
+        self.push(state, &rhs, val_type)?;
+        if let Some(bits) = lhs.typ.and_then(|typ| self.narrow_bits(typ)) {
+            state.ins(Instruction::I32Const(bits as i32 - 1));
+            state.ins(Instruction::I32And);
+        }
+        let pick = |narrow: Instruction<'static>, wide_ins: Instruction<'static>| if wide { wide_ins } else { narrow };
+        state.ins(match op {
+            BinOpType::ShiftLeft => pick(Instruction::I32Shl, Instruction::I64Shl),
+            _ if self.is_unsigned(lhs.typ) => pick(Instruction::I32ShrU, Instruction::I64ShrU),
+            _ => pick(Instruction::I32ShrS, Instruction::I64ShrS),
+        });
+        if let Some(typ) = lhs.typ {
+            self.emit_wrap(state, typ);
+        }
+        Ok(self.store_result(state, val_type, lhs.typ))
+    }
+
+    fn emit_unary_op(&mut self, state: &mut FunctionState, index: IrInstructionIndex, op: UnaryOpType, value: Value) -> Result<Value, String> {
+        match op {
+            UnaryOpType::Minus | UnaryOpType::WrappingMinus => {
+                let val_type = value.val_type.unwrap_or(ValType::I64);
+                match val_type {
+                    ValType::F32 | ValType::F64 => {
+                        self.push(state, &value, val_type)?;
+                        state.ins(if val_type == ValType::F64 { Instruction::F64Neg } else { Instruction::F32Neg });
+                    }
+                    _ => {
+                        if let (UnaryOpType::Minus, false) = (op, self.is_unsigned(value.typ)) {
+                            let min = self.int_min(val_type, value.typ);
+                            self.push(state, &value, val_type)?;
+                            self.push(state, &Value::new(Place::Int(min), Some(val_type), None), val_type)?;
+                            state.ins(if val_type == ValType::I64 { Instruction::I64Eq } else { Instruction::I32Eq });
+                            let message = self.module.negation_message(index);
+                            self.emit_panic_if(state, &message);
+                        }
+                        // integers are negated by subtracting them from zero
+                        self.push(state, &Value::new(Place::Int(0), Some(val_type), None), val_type)?;
+                        self.push(state, &value, val_type)?;
+                        state.ins(if val_type == ValType::I64 { Instruction::I64Sub } else { Instruction::I32Sub });
+                        if let Some(typ) = value.typ {
+                            self.emit_wrap(state, typ);
+                        }
+                    }
+                }
+                Ok(self.store_result(state, val_type, value.typ))
+            }
+            UnaryOpType::Not => {
+                self.push_truthy(state, &value)?;
+                state.ins(Instruction::I32Eqz);
+                Ok(self.store_result(state, ValType::I32, None))
+            }
+        }
+    }
+
+    /// The instruction for an operator on two `f32`s, or two `f64`s when `wide`.
+    fn float_bin_op(op: BinOpType, wide: bool) -> Result<Instruction<'static>, String> {
+        let pick = |narrow: Instruction<'static>, wide_ins: Instruction<'static>| if wide { wide_ins } else { narrow };
+        Ok(match op {
+            BinOpType::Plus => pick(Instruction::F32Add, Instruction::F64Add),
+            BinOpType::Minus => pick(Instruction::F32Sub, Instruction::F64Sub),
+            BinOpType::Star => pick(Instruction::F32Mul, Instruction::F64Mul),
+            BinOpType::ForwardSlash => pick(Instruction::F32Div, Instruction::F64Div),
+            BinOpType::LessThan => pick(Instruction::F32Lt, Instruction::F64Lt),
+            BinOpType::GreaterThan => pick(Instruction::F32Gt, Instruction::F64Gt),
+            BinOpType::LessThanEqualTo => pick(Instruction::F32Le, Instruction::F64Le),
+            BinOpType::GreaterThanEqualTo => pick(Instruction::F32Ge, Instruction::F64Ge),
+            BinOpType::EqualTo => pick(Instruction::F32Eq, Instruction::F64Eq),
+            // NaN isn't equal to anything, itself included
+            BinOpType::NotEqualTo => pick(Instruction::F32Ne, Instruction::F64Ne),
+            BinOpType::Ampersand | BinOpType::Pipe | BinOpType::Caret | BinOpType::ShiftLeft | BinOpType::ShiftRight => {
+                return Err(format!("can't apply {} to floats", op));
+            }
+            _ => unreachable!(),
+        })
+    }
+
+    /// Lower `value as typ`. Integers are wrapped to the width of `typ`, and floats are clamped to
+    /// its bounds first when it's narrower than 32 bits, since there are only saturating
+    /// conversions to `i32` and `i64`.
+    fn emit_cast(&mut self, state: &mut FunctionState, value: Value, typ: IrTypeIndex) -> Result<Value, String> {
+        let val_type = self.value_type(typ)?;
+        match value.val_type {
+            Some(source @ (ValType::F32 | ValType::F64)) if matches!(val_type, ValType::I32 | ValType::I64) => {
+                self.push(state, &value, source)?;
+                let bounds = match self.module.typ(typ) {
+                    IrType::Int(IntTy::I8) => Some((i8::MIN as f64, i8::MAX as f64)),
+                    IrType::Int(IntTy::I16) => Some((i16::MIN as f64, i16::MAX as f64)),
+                    IrType::UInt(UIntTy::U8) => Some((0.0, u8::MAX as f64)),
+                    IrType::UInt(UIntTy::U16) => Some((0.0, u16::MAX as f64)),
+                    _ => None,
+                };
+                if let Some((min, max)) = bounds {
+                    // the bounds are exact in both float types, and NaN stays NaN
+                    if source == ValType::F32 {
+                        state.ins(Instruction::F32Const((min as f32).into()));
+                        state.ins(Instruction::F32Max);
+                        state.ins(Instruction::F32Const((max as f32).into()));
+                        state.ins(Instruction::F32Min);
+                    } else {
+                        state.ins(Instruction::F64Const(min.into()));
+                        state.ins(Instruction::F64Max);
+                        state.ins(Instruction::F64Const(max.into()));
+                        state.ins(Instruction::F64Min);
+                    }
+                }
+                let signed = !self.is_unsigned(Some(typ));
+                self.convert(state, source, val_type, signed)?;
+            }
+            _ => {
+                self.push(state, &value, val_type)?;
+                self.emit_wrap(state, typ);
+            }
+        }
+        Ok(self.store_result(state, val_type, Some(typ)))
+    }
+
+    /// The width of `typ` when it's an integer narrower than the `i32` it's kept in.
+    fn narrow_bits(&self, typ: IrTypeIndex) -> Option<u32> {
+        match self.module.typ(typ) {
+            IrType::Int(IntTy::I8) | IrType::UInt(UIntTy::U8) => Some(8),
+            IrType::Int(IntTy::I16) | IrType::UInt(UIntTy::U16) => Some(16),
+            IrType::Refinement(_, inner, _) => self.narrow_bits(*inner),
+            _ => None,
+        }
+    }
+
+    /// Wrap the integer on top of the stack to the width of `typ` when it's narrower than its local.
+    fn emit_wrap(&mut self, state: &mut FunctionState, typ: IrTypeIndex) {
+        match self.module.typ(typ) {
+            IrType::Int(IntTy::I8) => state.ins(Instruction::I32Extend8S),
+            IrType::Int(IntTy::I16) => state.ins(Instruction::I32Extend16S),
+            IrType::UInt(UIntTy::U8) => {
+                state.ins(Instruction::I32Const(0xff));
+                state.ins(Instruction::I32And);
+            }
+            IrType::UInt(UIntTy::U16) => {
+                state.ins(Instruction::I32Const(0xffff));
+                state.ins(Instruction::I32And);
+            }
+            IrType::Refinement(_, inner, _) => self.emit_wrap(state, *inner),
+            _ => {}
+        }
+    }
+
+    /// Access a field of a row or struct through its pointer.
+    fn emit_field_access(&mut self, state: &mut FunctionState, aggregate: Value, field_name: &str) -> Result<Value, String> {
+        let (offset, field_type) = self.field(aggregate.typ, field_name)?;
+        let slot = self.slot(field_type)?;
+        self.push(state, &aggregate, ValType::I32)?;
+        state.ins(slot.load(offset));
+        Ok(self.store_result(state, slot.val_type, Some(field_type)))
+    }
+
+    /// The offset and type of a field of a struct or row type.
+    fn field(&mut self, typ: Option<IrTypeIndex>, field_name: &str) -> Result<(u32, IrTypeIndex), String> {
+        let fields = self.aggregate_fields(typ)
+            .ok_or_else(|| format!("can't access field `{}` of {:?}", field_name, typ))?;
+        let (offsets, _) = self.layout(&fields)?;
+        let field_index = fields.iter().position(|f| f.name == field_name)
+            .ok_or_else(|| format!("couldn't find field `{}`", field_name))?;
+        Ok((offsets[field_index], fields[field_index].typ))
+    }
+
+    fn store_local(&mut self, state: &mut FunctionState, local: &Value, value: &Value) -> Result<(), String> {
+        let val_type = local.val_type.unwrap();
+        match local.place {
+            Place::Local(index) => {
+                self.push(state, value, val_type)?;
+                state.ins(Instruction::LocalSet(index));
+            }
+            Place::Frame(offset) => {
+                let (frame, _) = state.frame.unwrap();
+                state.ins(Instruction::LocalGet(frame));
+                self.push(state, value, val_type)?;
+                state.ins(Slot { size: 8, val_type, signed: false }.store(offset));
+            }
+            place => return Err(format!("can't assign to {:?}", place)),
+        }
+        Ok(())
+    }
+
+    /// The type a pointer points to, and how to load it.
+    fn pointee(&mut self, pointer: &Value) -> Result<(Option<IrTypeIndex>, Slot), String> {
+        let typ = pointer.typ.and_then(|t| match self.module.typ(t) {
+            IrType::Reference(inner, _) => Some(*inner),
+            _ => None,
+        });
+        let slot = match typ {
+            Some(typ) => self.slot(typ)?,
+            None => pointer.pointee.ok_or_else(|| format!("can't dereference {:?}", pointer))?,
+        };
+        Ok((typ, slot))
+    }
+
+    fn emit_dereference(&mut self, state: &mut FunctionState, pointer: Value) -> Result<Value, String> {
+        let (typ, slot) = self.pointee(&pointer)?;
+        self.push(state, &pointer, ValType::I32)?;
+        state.ins(slot.load(0));
+        Ok(self.store_result(state, slot.val_type, typ))
+    }
+
+    /// Push the address of an element of an array or slice, whose base points to the first
+    /// element, and return the element's type and how to load it.
+    fn push_element(&mut self, state: &mut FunctionState, base: IrInstructionIndex, index: IrInstructionIndex) -> Result<(Option<IrTypeIndex>, Slot), String> {
+        let base = self.operand(state, base)?;
+        let index = self.operand(state, index)?;
+        let typ = base.typ.and_then(|t| match self.module.typ(t) {
+            IrType::Array(element, _) | IrType::Reference(element, _) => Some(*element),
+            _ => None,
+        });
+        let slot = match typ {
+            Some(typ) => self.slot(typ)?,
+            None => base.pointee.ok_or_else(|| format!("can't index {:?}", base))?,
+        };
+        self.push(state, &base, ValType::I32)?;
+        self.push(state, &index, ValType::I32)?;
+        state.ins(Instruction::I32Const(slot.size as i32));
+        state.ins(Instruction::I32Mul);
+        state.ins(Instruction::I32Add);
+        Ok((typ, slot))
+    }
+
+    fn emit_index(&mut self, state: &mut FunctionState, base: IrInstructionIndex, index: IrInstructionIndex) -> Result<Value, String> {
+        let (typ, slot) = self.push_element(state, base, index)?;
+        state.ins(slot.load(0));
+        Ok(self.store_result(state, slot.val_type, typ))
+    }
+
+    fn field_name(&self, field: IrInstructionIndex) -> Result<String, String> {
+        match self.module.instruction(field) {
+            IrInstruction::Ref(name) => Ok(name.clone()),
+            ins => Err(format!("can't access field {:?}", ins)),
+        }
+    }
+
+    /// Read a place, which isn't emitted on its own. Anything else is an ordinary operand.
+    fn place_value(&mut self, state: &mut FunctionState, index: IrInstructionIndex) -> Result<Value, String> {
+        if !state.places.contains(&index) {
+            return self.operand(state, index);
+        }
+        match self.module.instruction(index) {
+            IrInstruction::FieldAccessor { aggregate, value } => {
+                let field_name = self.field_name(*value)?;
+                let aggregate = self.place_value(state, *aggregate)?;
+                self.emit_field_access(state, aggregate, &field_name)
+            }
+            IrInstruction::Dereference { pointer } => {
+                let pointer = self.operand(state, *pointer)?;
+                self.emit_dereference(state, pointer)
+            }
+            IrInstruction::Index { base, index } => self.emit_index(state, *base, *index),
+            ins => Err(format!("{:?} isn't a place", ins)),
+        }
+    }
+
+    /// Push the address of a place a borrow takes, and return how to load what's there. Fields of
+    /// structs are inside the struct their pointer points to.
+    fn push_place_address(&mut self, state: &mut FunctionState, index: IrInstructionIndex) -> Result<Slot, String> {
+        match self.module.instruction(index) {
+            IrInstruction::FieldAccessor { aggregate, value } => {
+                let field_name = self.field_name(*value)?;
+                let container = self.place_value(state, *aggregate)?;
+                let (offset, field_type) = self.field(container.typ, &field_name)?;
+                let slot = self.slot(field_type)?;
+                self.push(state, &container, ValType::I32)?;
+                state.ins(Instruction::I32Const(offset as i32));
+                state.ins(Instruction::I32Add);
+                Ok(slot)
+            }
+            IrInstruction::Dereference { pointer } => {
+                let pointer = self.operand(state, *pointer)?;
+                let (_, slot) = self.pointee(&pointer)?;
+                self.push(state, &pointer, ValType::I32)?;
+                Ok(slot)
+            }
+            IrInstruction::Index { base, index } => Ok(self.push_element(state, *base, *index)?.1),
+            ins => Err(format!("{:?} isn't a place", ins)),
+        }
+    }
+
+    /// Write a value to a local, a pointee or a field. Rows are values that may be shared, so a
+    /// field of one is written by copying the row and storing the copy where the row came from.
+    fn emit_store(&mut self, state: &mut FunctionState, place: IrInstructionIndex, value: Value) -> Result<(), String> {
+        match self.module.instruction(place) {
+            IrInstruction::Ref(name) => {
+                let local = *state.named.get(name).ok_or_else(|| format!("couldn't find `{}`", name))?;
+                self.store_local(state, &local, &value)?;
+            }
+            IrInstruction::Dereference { pointer } => {
+                let pointer = self.operand(state, *pointer)?;
+                let (_, slot) = self.pointee(&pointer)?;
+                self.push(state, &pointer, ValType::I32)?;
+                self.push(state, &value, slot.val_type)?;
+                state.ins(slot.store(0));
+            }
+            IrInstruction::Index { base, index } => {
+                let (_, slot) = self.push_element(state, *base, *index)?;
+                self.push(state, &value, slot.val_type)?;
+                state.ins(slot.store(0));
+            }
+            IrInstruction::FieldAccessor { aggregate, value: field } => {
+                let field_name = self.field_name(*field)?;
+                let container = self.place_value(state, *aggregate)?;
+                let fields = self.aggregate_fields(container.typ)
+                    .ok_or_else(|| format!("can't access field `{}` of {:?}", field_name, container.typ))?;
+                let (offsets, size) = self.layout(&fields)?;
+                let field_index = fields.iter().position(|f| f.name == field_name)
+                    .ok_or_else(|| format!("couldn't find field `{}`", field_name))?;
+                if !self.is_row(container.typ.unwrap()) {
+                    let slot = self.slot(fields[field_index].typ)?;
+                    self.push(state, &container, ValType::I32)?;
+                    self.push(state, &value, slot.val_type)?;
+                    state.ins(slot.store(offsets[field_index]));
+                    return Ok(());
+                }
+                state.ins(Instruction::I32Const(0));
+                state.ins(Instruction::I64Const(size as i64));
+                state.ins(Instruction::Call(self.alloc_function));
+                let copy = self.store_result(state, ValType::I32, container.typ);
+                for (i, field) in fields.iter().enumerate() {
+                    let slot = self.slot(field.typ)?;
+                    self.push(state, &copy, ValType::I32)?;
+                    if i == field_index {
+                        self.push(state, &value, slot.val_type)?;
+                    } else {
+                        self.push(state, &container, ValType::I32)?;
+                        state.ins(slot.load(offsets[i]));
+                    }
+                    state.ins(slot.store(offsets[i]));
+                }
+                self.emit_store(state, *aggregate, copy)?;
+            }
+            // anything else is a temporary, so nothing can see the write
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Rows are kept in memory from the default allocator, like `new` objects.
+    fn emit_row(&mut self, state: &mut FunctionState, typ: IrTypeIndex, values: &[Value]) -> Result<Value, String> {
+        let fields = self.aggregate_fields(Some(typ)).expect("row literals have row types");
+        let (offsets, size) = self.layout(&fields)?;
+        state.ins(Instruction::I32Const(0));
+        state.ins(Instruction::I64Const(size as i64));
+        state.ins(Instruction::Call(self.alloc_function));
+        let row = self.store_result(state, ValType::I32, Some(typ));
+        for (i, (field, value)) in fields.iter().zip(values).enumerate() {
+            let slot = self.slot(field.typ)?;
+            self.push(state, &row, ValType::I32)?;
+            self.push(state, value, slot.val_type)?;
+            state.ins(slot.store(offsets[i]));
+        }
+        Ok(row)
+    }
+
+    /// Lower `new T in allocator` to a call to the allocator.
+    /// The size of a new `typ`, the type of the pointer to it and how to load what that points
+    /// to, when that isn't clear from the pointer's type.
+    fn allocation(&mut self, typ: IrTypeIndex) -> Result<(u32, Option<IrTypeIndex>, Option<Slot>), String> {
+        // nominal types are already pointers to their struct, everything else gets a new pointer
+        Ok(match self.module.typ(typ) {
+            IrType::Base(name) => match self.module.find_struct(name) {
+                Some(IrNode::Struct { fields, .. }) => {
+                    let fields = fields.clone();
+                    (self.layout(&fields)?.1, Some(typ), None)
+                }
+                _ => return Err(format!("can't allocate {:?} because its size is unknown", self.module.typ(typ))),
+            },
+            // arrays are already pointers to their first element, and their elements follow it
+            IrType::Array(element, len) => {
+                let (element, len) = (*element, *len);
+                (self.slot(element)?.size * len as u32, Some(typ), None)
+            }
+            _ => {
+                let slot = self.slot(typ)?;
+                (slot.size, None, Some(slot))
+            }
+        })
+    }
+
+    fn emit_new(&mut self, state: &mut FunctionState, typ: IrTypeIndex, allocator: Value) -> Result<Value, String> {
+        let (size, result_type, pointee) = self.allocation(typ)?;
+        self.push(state, &allocator, ValType::I32)?;
+        state.ins(Instruction::I64Const(size as i64));
+        state.ins(Instruction::Call(self.alloc_function));
+        let mut result = self.store_result(state, ValType::I32, result_type);
+        result.pointee = pointee;
+        Ok(result)
+    }
+
+    fn emit_stack_alloc(&mut self, state: &mut FunctionState, typ: IrTypeIndex) -> Result<Value, String> {
+        let (size, result_type, pointee) = self.allocation(typ)?;
+        let offset = state.new_frame_space(size);
+        let (frame, _) = state.frame.unwrap();
+        // objects start out zeroed, like those the interpreter allocates, the frame space is a
+        // multiple of 8 bytes
+        for word in (offset..offset + size).step_by(8) {
+            state.ins(Instruction::LocalGet(frame));
+            state.ins(Instruction::I64Const(0));
+            state.ins(Instruction::I64Store(MemArg { offset: word as u64, align: 3, memory_index: 0 }));
+        }
+        state.ins(Instruction::LocalGet(frame));
+        state.ins(Instruction::I32Const(offset as i32));
+        state.ins(Instruction::I32Add);
+        let mut result = self.store_result(state, ValType::I32, result_type);
+        result.pointee = pointee;
+        Ok(result)
+    }
+
+    /// Add the bytes of a string literal and the `Str` that points to them to the data segment,
+    /// returning the address of the `Str`.
+    fn define_string(&mut self, str_type: IrTypeIndex, string: &str) -> Result<u32, String> {
+        let bytes = STACK_TOP + self.data.len() as u32;
+        self.data.extend_from_slice(string.as_bytes());
+        let fields = self.aggregate_fields(Some(str_type)).unwrap();
+        let (offsets, size) = self.layout(&fields)?;
+        let len_size = self.slot(fields[1].typ)?.size as usize;
+        self.data.resize(self.data.len().div_ceil(8) * 8, 0);
+        let header = self.data.len();
+        self.data.resize(header + size as usize, 0);
+        let data_offset = header + offsets[0] as usize;
+        self.data[data_offset..data_offset + 4].copy_from_slice(&bytes.to_le_bytes());
+        let len_offset = header + offsets[1] as usize;
+        self.data[len_offset..len_offset + len_size].copy_from_slice(&(string.len() as u64).to_le_bytes()[..len_size]);
+        Ok(STACK_TOP + header as u32)
+    }
+
+    fn aggregate_fields(&self, typ: Option<IrTypeIndex>) -> Option<Vec<IrTypedName>> {
+        match typ.map(|t| self.module.typ(t)) {
+            Some(IrType::Row(fields)) => Some(fields.clone()),
+            Some(IrType::Base(name)) => match self.module.find_struct(name) {
+                Some(IrNode::Struct { fields, .. }) => Some(fields.clone()),
+                _ => None,
+            },
+            Some(IrType::Refinement(_, inner, _)) => self.aggregate_fields(Some(*inner)),
+            _ => None,
+        }
+    }
+
+    /// Lay out fields with their natural alignment, returning their offsets and the total size.
+    fn layout(&mut self, fields: &[IrTypedName]) -> Result<(Vec<u32>, u32), String> {
+        let mut offsets = Vec::with_capacity(fields.len());
+        let mut size: u32 = 0;
+        let mut align = 1;
+        for field in fields {
+            let slot = self.slot(field.typ)?;
+            size = size.div_ceil(slot.size) * slot.size;
+            offsets.push(size);
+            size += slot.size;
+            align = align.max(slot.size);
+        }
+        Ok((offsets, size.div_ceil(align) * align))
+    }
+
+    /// How a value of the given type is stored in memory.
+    fn slot(&mut self, typ: IrTypeIndex) -> Result<Slot, String> {
+        let (size, signed) = match self.module.typ(typ) {
+            IrType::Bool => (1, false),
+            IrType::Int(i) => (i.bits() / 8, true),
+            IrType::UInt(u) => (u.bits() / 8, false),
+            IrType::Float(f) => (f.bits() / 8, true),
+            IrType::Refinement(_, inner, _) => return self.slot(*inner),
+            IrType::Unknown => (8, true),
+            _ => (4, false),
+        };
+        let val_type = self.value_type(typ)?;
+        Ok(Slot { size, val_type, signed })
+    }
+
+    fn is_pointer(&self, typ: IrTypeIndex) -> bool {
+        match self.module.typ(typ) {
+            IrType::Base(_) | IrType::Reference(_, _) | IrType::Row(_) | IrType::Optional(_) | IrType::Function(_, _) => true,
+            IrType::Array(..) => true,
+            IrType::Refinement(_, inner, _) => self.is_pointer(*inner),
+            _ => false,
+        }
+    }
+
+    fn is_row(&self, typ: IrTypeIndex) -> bool {
+        match self.module.typ(typ) {
+            IrType::Row(_) => true,
+            IrType::Refinement(_, inner, _) => self.is_row(*inner),
+            _ => false,
+        }
+    }
+
+    fn is_unsigned(&self, typ: Option<IrTypeIndex>) -> bool {
+        match typ.map(|t| self.module.typ(t)) {
+            Some(IrType::UInt(_)) => true,
+            Some(IrType::Refinement(_, inner, _)) => self.is_unsigned(Some(*inner)),
+            _ => false,
+        }
+    }
+
+    fn check_types(&self) -> Result<(), String> {
+        for (_, typ) in self.module.module_arena.type_arena.iter() {
+            match typ {
+                IrType::Int(i) if i.bits() > 64 => return Err("128-bit integers aren't supported by the wasm backend".to_string()),
+                IrType::UInt(u) if u.bits() > 64 => return Err("128-bit integers aren't supported by the wasm backend".to_string()),
+                IrType::Float(f) if !matches!(f, FloatTy::F32 | FloatTy::F64) => {
+                    return Err(format!("{}-bit floats aren't supported by the wasm backend", f.bits()));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The wasm type of a type that has values, like that of a parameter or a field.
+    fn value_type(&self, typ: IrTypeIndex) -> Result<ValType, String> {
+        self.val_type(typ).ok_or_else(|| format!("can't use a value of type `{}`", self.module.type_name(typ)))
+    }
+
+    /// The wasm type values of `typ` are kept in. Structs, rows and optionals that aren't
+    /// pointers already are pointers into linear memory, functions are indices into the table.
+    fn val_type(&self, typ: IrTypeIndex) -> Option<ValType> {
+        Some(match self.module.typ(typ) {
+            IrType::Bool => ValType::I32,
+            IrType::Int(i) if i.bits() <= 32 => ValType::I32,
+            IrType::UInt(u) if u.bits() <= 32 => ValType::I32,
+            IrType::Int(i) if i.bits() <= 64 => ValType::I64,
+            IrType::UInt(u) if u.bits() <= 64 => ValType::I64,
+            IrType::Int(_) | IrType::UInt(_) => unreachable!("`check_types` reports 128-bit integers"),
+            IrType::Float(FloatTy::F32) => ValType::F32,
+            IrType::Float(FloatTy::F64) => ValType::F64,
+            IrType::Float(f) => unreachable!("`check_types` reports {}-bit floats", f.bits()),
+            IrType::Refinement(_, inner, _) => return self.val_type(*inner),
+            IrType::Base(_) | IrType::Row(_) | IrType::Reference(_, _) | IrType::Optional(_) | IrType::Function(_, _) => ValType::I32,
+            IrType::Array(..) => ValType::I32,
+            IrType::Void => return None,
+            IrType::Unknown => ValType::I64,
+        })
+    }
+}
+
+/// Whether any arithmetic in the module traps on overflow, divides or any index is checked, which
+/// needs the panic function imported.
+fn panics(module: &Module) -> bool {
+    module.functions()
+        .flat_map(|(_, function)| function.blocks.iter())
+        .flat_map(|block| module.block(*block).instructions.iter())
+        .any(|ins| match module.instruction(*ins) {
+            IrInstruction::BinOp(_, op, _) => matches!(op.overflow(), Some((_, Overflow::Trap)))
+                || matches!(op, BinOpType::ForwardSlash | BinOpType::Percent),
+            IrInstruction::UnaryOp(UnaryOpType::Minus, _) => true,
+            IrInstruction::BoundsCheck { .. } => true,
+            _ => false,
+        })
+}
+
+/// Whether any function in the module uses `print`, which needs the print function imported.
+fn prints(module: &Module) -> bool {
+    module.functions()
+        .flat_map(|(_, function)| function.blocks.iter())
+        .flat_map(|block| module.block(*block).instructions.iter())
+        .any(|ins| matches!(module.instruction(*ins), IrInstruction::Print { .. }))
+}
@@ -1,29 +1,169 @@
-use generational_arena::Arena;
-use crate::ast::Path;
+use std::path::Path;
+use generational_arena::{Arena, Index};
+use crate::ast;
+use crate::backend::{Artifact, Backend};
+use crate::backend::c::CCodegen;
+use crate::backend::cranelift::CraneliftCodegen;
+use crate::backend::interpreter::Interpreter;
+use crate::backend::linker;
+#[cfg(feature = "llvm")]
+use crate::backend::llvm::{LLVMBackend, LlvmCodegen};
+use crate::backend::wasm::WasmCodegen;
 use crate::ir::Module;
+use crate::ir::passes::{report_escapes, PassManager};
 use crate::ir::translate::IrBuilder;
+use crate::options::{BackendKind, EmitKind, Options};
 use crate::parser::Parser;
 
 pub struct Compiler {
+    pub options: Options,
     pub modules: Arena<Module>,
     ir_builder: IrBuilder,
 }
 
 impl Compiler {
-    pub fn new() -> Compiler {
+    pub fn new(options: Options) -> Compiler {
         Compiler {
+            ir_builder: IrBuilder::new(options.overflow_checks()),
+            options,
             modules: Default::default(),
-            ir_builder: IrBuilder::new(),
         }
     }
 
-    pub fn parse_module(&mut self, path: Path, file_name: String, code: String) {
+    pub fn parse_module(&mut self, path: ast::Path, file_name: String, code: String) -> Option<Index> {
         let mut parser = Parser::new();
         let parsed_program = parser.parse(path, file_name, code);
+        let module = parsed_program.map(|program| self.ir_builder.convert(program, &mut parser.diagnostics));
         parser.diagnostics.emit_errors();
-        if let Some(mut program) = parsed_program {
-            let module = self.ir_builder.convert(program);
-            self.modules.insert(module);
+        if parser.diagnostics.has_errors() {
+            return None;
         }
+        module.map(|module| self.modules.insert(module))
+    }
+
+    /// Run the IR passes for the optimization level, or the ones given with `--passes`, over a module.
+    pub fn optimize(&mut self, module: Index) -> Result<(), String> {
+        let options = &self.options;
+        let mut pass_manager = match &options.passes {
+            Some(passes) => PassManager::from_names(passes)?,
+            None => PassManager::for_opt_level(options.opt_level),
+        };
+        for pass in options.print_after.iter() {
+            pass_manager.print_after(pass)?;
+        }
+        let module = self.modules.get_mut(module).unwrap();
+        pass_manager.run(module);
+        if options.time_passes {
+            eprint!("{}", pass_manager.report());
+        }
+        if options.report_escapes {
+            eprint!("{}", report_escapes(module));
+        }
+        Ok(())
+    }
+
+    /// Call the entry function with the program arguments, either by JIT compiling the module
+    /// or by interpreting its IR.
+    pub fn run(&self, module: Index) -> Result<i64, String> {
+        let mut args = Vec::with_capacity(self.options.program_args.len());
+        for arg in self.options.program_args.iter() {
+            args.push(arg.parse::<i64>().map_err(|_| format!("expected an integer argument but got `{}`", arg))?);
+        }
+        let module = self.modules.get(module).unwrap();
+        match self.options.backend {
+            BackendKind::Interpreter => Interpreter::new(module).run(&self.options.entry, &args),
+            #[cfg(feature = "llvm")]
+            BackendKind::Llvm => {
+                let mut backend = LLVMBackend::new(&self.options, module)?;
+                backend.compile()?;
+                let jit = backend.into_jit(&self.options.entry)?;
+                jit.call(&args)
+            }
+            #[cfg(not(feature = "llvm"))]
+            BackendKind::Llvm => Err("nuvae was built without LLVM, use --backend=interpreter to `run` modules".to_string()),
+            BackendKind::Cranelift => Err("the Cranelift backend can't `run` modules, use --backend=interpreter".to_string()),
+            BackendKind::C => Err("the C backend can't `run` modules".to_string()),
+            BackendKind::Wasm => Err("the wasm backend can't `run` modules".to_string()),
+        }
+    }
+
+    /// Compile a module with the selected backend and write whatever `--emit` asked for to the output path.
+    pub fn emit(&self, module: Index) -> Result<(), String> {
+        let module = self.modules.get(module).unwrap();
+        let artifact = self.backend()?.compile_module(module)?;
+        self.write_artifact(module, artifact)
+    }
+
+    fn backend(&self) -> Result<Box<dyn Backend + '_>, String> {
+        let options = &self.options;
+        match options.backend {
+            #[cfg(feature = "llvm")]
+            BackendKind::Llvm => Ok(Box::new(LlvmCodegen::new(options))),
+            #[cfg(not(feature = "llvm"))]
+            BackendKind::Llvm => Err("nuvae was built without LLVM, use --backend=cranelift".to_string()),
+            BackendKind::Cranelift => Ok(Box::new(CraneliftCodegen::new(options))),
+            BackendKind::C => Ok(Box::new(CCodegen::new(options))),
+            BackendKind::Wasm => Ok(Box::new(WasmCodegen::new(options))),
+            BackendKind::Interpreter => Err("the interpreter can only `run` modules".to_string()),
+        }
+    }
+
+    /// Write an artifact to the output path. Objects are linked and C source is compiled
+    /// by the system tools when `--emit` asked for more than the backend produced.
+    fn write_artifact(&self, module: &Module, artifact: Artifact) -> Result<(), String> {
+        let options = &self.options;
+        let output = options.output_path();
+        let write = |path: &Path, contents: &[u8]| {
+            std::fs::write(path, contents).map_err(|e| format!("couldn't write {}: {}", path.display(), e))
+        };
+        match (artifact, options.emit) {
+            (Artifact::Object(object), EmitKind::Executable) => {
+                self.in_scratch_dir(|scratch_dir| {
+                    let object_path = scratch_dir.join(format!("{}.o", module.name));
+                    write(&object_path, &object)?;
+                    linker::link(&options.linker, &[object_path], &output, scratch_dir)
+                })
+            }
+            (Artifact::C(source), EmitKind::Assembly | EmitKind::Object | EmitKind::Executable) => {
+                let mut flags = vec!["-std=c99", options.opt_level.flag()];
+                if options.debug_info {
+                    flags.push("-g");
+                }
+                match options.emit {
+                    EmitKind::Assembly => flags.push("-S"),
+                    EmitKind::Object => flags.push("-c"),
+                    _ => {}
+                }
+                self.in_scratch_dir(|scratch_dir| {
+                    let source_path = scratch_dir.join(format!("{}.c", module.name));
+                    write(&source_path, source.as_bytes())?;
+                    linker::compile_c(&options.linker, &flags, &source_path, &output, scratch_dir)
+                })
+            }
+            (Artifact::Wasm(wasm), EmitKind::Wat) => {
+                let wat = wasmprinter::print_bytes(&wasm).map_err(|e| format!("couldn't print the module: {}", e))?;
+                write(&output, wat.as_bytes())
+            }
+            // the binary and text formats are written next to each other
+            (Artifact::Wasm(wasm), EmitKind::Executable) => {
+                let wat = wasmprinter::print_bytes(&wasm).map_err(|e| format!("couldn't print the module: {}", e))?;
+                write(&output.with_extension("wasm"), &wasm)?;
+                write(&output.with_extension("wat"), wat.as_bytes())
+            }
+            (Artifact::Object(bytes), _) | (Artifact::Wasm(bytes), _) => write(&output, &bytes),
+            #[cfg(feature = "llvm")]
+            (Artifact::Assembly(text), _) | (Artifact::LlvmIr(text), _) => write(&output, text.as_bytes()),
+            (Artifact::C(text), _) => write(&output, text.as_bytes()),
+        }
+    }
+
+    /// Run `f` with a temporary directory for intermediate files, which is removed afterwards.
+    fn in_scratch_dir(&self, f: impl FnOnce(&Path) -> Result<(), String>) -> Result<(), String> {
+        let scratch_dir = std::env::temp_dir().join(format!("nuvae-{}", std::process::id()));
+        std::fs::create_dir_all(&scratch_dir)
+            .map_err(|e| format!("couldn't create {}: {}", scratch_dir.display(), e))?;
+        let result = f(&scratch_dir);
+        let _ = std::fs::remove_dir_all(&scratch_dir);
+        result
     }
 }
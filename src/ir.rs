@@ -1,9 +1,12 @@
+use std::collections::{HashMap, HashSet};
 use generational_arena::{Arena, Index};
-use crate::ast::{BinOpType, ExpressionIndex, Path, TypeName};
+use crate::ast::{BinOpType, Path, Span, TypeName, UnaryOpType};
 use crate::ir::FloatTy::*;
 use crate::ir::IntTy::*;
 use crate::ir::UIntTy::*;
 
+pub(crate) mod passes;
+mod print;
 pub(crate) mod translate;
 
 pub type IrTypeIndex = Index;
@@ -11,11 +14,17 @@ pub type IrNodeIndex = Index;
 pub type IrBlockIndex = Index;
 pub type IrInstructionIndex = Index;
 
+/// Name of the built-in struct string literals are, `Str { data: &UInt8, len: USize }`. Its
+/// bytes are UTF-8 and aren't followed by a zero byte.
+pub const STR_TYPE: &str = "Str";
+
 pub struct ModuleArena {
     pub type_arena: Arena<IrType>,
     pub node_arena: Arena<IrNode>,
     pub block_arena: Arena<IrBlock>,
     pub instruction_arena: Arena<IrInstruction>,
+    /// Source locations of the instructions that came from source code.
+    pub instruction_spans: HashMap<IrInstructionIndex, Span>,
 }
 
 impl ModuleArena {
@@ -25,6 +34,7 @@ impl ModuleArena {
             node_arena: Arena::new(),
             block_arena: Arena::new(),
             instruction_arena: Arena::new(),
+            instruction_spans: HashMap::new(),
         }
     }
 
@@ -32,11 +42,25 @@ impl ModuleArena {
         let index = self.instruction_arena.insert(ins);
         block.instructions.push(index);
     }
+
+    /// Remove a block along with all of its instructions.
+    pub fn remove_block(&mut self, index: IrBlockIndex) {
+        if let Some(block) = self.block_arena.remove(index) {
+            for ins in block.instructions {
+                self.instruction_arena.remove(ins);
+                self.instruction_spans.remove(&ins);
+            }
+        }
+    }
 }
 
 pub struct Module {
     pub path: Path,
     pub name: String,
+    /// Name of the source file this module was parsed from.
+    pub source_name: String,
+    /// Byte offsets of the start of each line in the source file.
+    pub line_starts: Vec<usize>,
     pub imports: Vec<Path>,
     pub module_arena: ModuleArena,
 }
@@ -45,6 +69,198 @@ impl Module {
     pub fn typ(&self, index: IrTypeIndex) -> &IrType {
         self.module_arena.type_arena.get(index).unwrap()
     }
+
+    pub fn node(&self, index: IrNodeIndex) -> &IrNode {
+        self.module_arena.node_arena.get(index).unwrap()
+    }
+
+    pub fn block(&self, index: IrBlockIndex) -> &IrBlock {
+        self.module_arena.block_arena.get(index).unwrap()
+    }
+
+    pub fn instruction(&self, index: IrInstructionIndex) -> &IrInstruction {
+        self.module_arena.instruction_arena.get(index).unwrap()
+    }
+
+    pub fn instruction_span(&self, index: IrInstructionIndex) -> Option<Span> {
+        self.module_arena.instruction_spans.get(&index).copied()
+    }
+
+    /// Get the one-based line and column of a byte offset in the source file.
+    pub fn location(&self, offset: usize) -> (u32, u32) {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next_line) => next_line - 1,
+        };
+        let column = offset - self.line_starts[line];
+        (line as u32 + 1, column as u32 + 1)
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item=(IrNodeIndex, &IrFunction)> {
+        self.module_arena.node_arena.iter().filter_map(|(index, node)| match node {
+            IrNode::Function(function) => Some((index, function)),
+            _ => None,
+        })
+    }
+
+    pub fn function(&self, index: IrNodeIndex) -> &IrFunction {
+        match self.node(index) {
+            IrNode::Function(function) => function,
+            _ => panic!("node {:?} isn't a function", index),
+        }
+    }
+
+    pub fn function_mut(&mut self, index: IrNodeIndex) -> &mut IrFunction {
+        match self.module_arena.node_arena.get_mut(index) {
+            Some(IrNode::Function(function)) => function,
+            _ => panic!("node {:?} isn't a function", index),
+        }
+    }
+
+    /// Get the index of every function, so they can be changed while going through them.
+    pub fn function_indices(&self) -> Vec<IrNodeIndex> {
+        self.functions().map(|(index, _)| index).collect()
+    }
+
+    /// Add an instruction to the end of a block, with the span of the source it came from.
+    pub fn push_instruction(&mut self, block: IrBlockIndex, ins: IrInstruction, span: Option<Span>) -> IrInstructionIndex {
+        let index = self.module_arena.instruction_arena.insert(ins);
+        self.module_arena.block_arena.get_mut(block).unwrap().instructions.push(index);
+        if let Some(span) = span {
+            self.module_arena.instruction_spans.insert(index, span);
+        }
+        index
+    }
+
+    /// Names of the parameters and locals of a function, which shadow functions with the same name.
+    pub fn local_names(&self, function: &IrFunction) -> HashSet<String> {
+        let mut names: HashSet<String> = function.params.iter().map(|param| param.name.clone()).collect();
+        for block in function.blocks.iter() {
+            for ins in self.block(*block).instructions.iter() {
+                match self.instruction(*ins) {
+                    IrInstruction::Let { name, .. } => {
+                        names.insert(name.name.clone());
+                    }
+                    IrInstruction::Assign { name, .. } => {
+                        names.insert(name.clone());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        names
+    }
+
+    /// Get the call a block ends with when the block returns its result right away.
+    pub fn tail_call(&self, block: IrBlockIndex) -> Option<IrInstructionIndex> {
+        match self.block(block).instructions.as_slice() {
+            [.., call, ret] => match (self.instruction(*call), self.instruction(*ret)) {
+                (IrInstruction::FunctionCall { .. }, IrInstruction::Return { value }) if value == call => Some(*call),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether a function borrows anything. The borrowed value lives in the function's frame,
+    /// so a tail call can't reuse it.
+    pub fn has_borrows(&self, function: &IrFunction) -> bool {
+        function.blocks.iter()
+            .flat_map(|block| self.block(*block).instructions.iter())
+            .any(|ins| matches!(self.instruction(*ins), IrInstruction::Borrow { .. }))
+    }
+
+    /// The `FieldAccessor`s, `Dereference`s and `Index`es stores write to or borrows take the
+    /// address of, and the places those are fields of, since a field of a row is stored in the
+    /// row. Backends don't read these, they work out where they are when they emit the store or
+    /// the borrow.
+    pub fn places(&self, function: &IrFunction) -> HashSet<IrInstructionIndex> {
+        let mut places = HashSet::new();
+        for ins in function.blocks.iter().flat_map(|block| self.block(*block).instructions.iter()) {
+            let mut place = match self.instruction(*ins) {
+                IrInstruction::Store { place, .. } => *place,
+                IrInstruction::Borrow { value } if matches!(self.instruction(*value),
+                    IrInstruction::FieldAccessor { .. } | IrInstruction::Dereference { .. } | IrInstruction::Index { .. }) => *value,
+                _ => continue,
+            };
+            places.insert(place);
+            while let IrInstruction::FieldAccessor { aggregate, .. } = self.instruction(place) {
+                match self.instruction(*aggregate) {
+                    IrInstruction::FieldAccessor { .. } | IrInstruction::Dereference { .. } | IrInstruction::Index { .. } => {
+                        place = *aggregate;
+                        places.insert(place);
+                    }
+                    _ => break,
+                }
+            }
+        }
+        places
+    }
+
+    /// Get the `Str` type string literals have, if the module uses strings.
+    pub fn str_type(&self) -> Option<IrTypeIndex> {
+        self.module_arena.type_arena.iter()
+            .find(|(_, typ)| matches!(typ, IrType::Base(name) if name == STR_TYPE))
+            .map(|(index, _)| index)
+    }
+
+    /// Get the `Float64` type float literals have when nothing else gives them a type, if the
+    /// module has float literals.
+    pub fn float_literal_type(&self) -> Option<IrTypeIndex> {
+        self.module_arena.type_arena.iter()
+            .find(|(_, typ)| matches!(typ, IrType::Float(FloatTy::F64)))
+            .map(|(index, _)| index)
+    }
+
+    /// The message a program panics with when the arithmetic of an instruction overflows, which
+    /// starts with where the instruction is in the source.
+    pub fn overflow_message(&self, index: IrInstructionIndex, op: BinOpType) -> String {
+        let action = match op.overflow().map_or(op, |(op, _)| op) {
+            BinOpType::Plus => "add",
+            BinOpType::Minus => "subtract",
+            BinOpType::Star => "multiply",
+            BinOpType::ForwardSlash => "divide",
+            _ => "calculate the remainder",
+        };
+        self.located(index, &format!("attempt to {} with overflow", action))
+    }
+
+    /// The message a program panics with when it negates the smallest value of a signed type.
+    pub fn negation_message(&self, index: IrInstructionIndex) -> String {
+        self.located(index, "attempt to negate with overflow")
+    }
+
+    /// The message a program panics with when an integer division or remainder divides by zero.
+    pub fn division_by_zero_message(&self, index: IrInstructionIndex, op: BinOpType) -> String {
+        match op {
+            BinOpType::ForwardSlash => self.located(index, "attempt to divide by zero"),
+            _ => self.located(index, "attempt to calculate the remainder with a divisor of zero"),
+        }
+    }
+
+    /// The message a program panics with when it indexes an array or slice out of its bounds,
+    /// which starts with where the index is in the source.
+    pub fn bounds_message(&self, index: IrInstructionIndex) -> String {
+        self.located(index, "index out of bounds")
+    }
+
+    /// Put where an instruction is in the source in front of a message.
+    fn located(&self, index: IrInstructionIndex, message: &str) -> String {
+        match self.instruction_span(index) {
+            Some(span) => {
+                let (line, column) = self.location(span.start().to_usize());
+                format!("{}:{}:{}: {}", self.source_name, line, column, message)
+            }
+            None => format!("{}: {}", self.source_name, message),
+        }
+    }
+
+    /// Find the struct node with the given name, if this module defines one.
+    pub fn find_struct(&self, struct_name: &str) -> Option<&IrNode> {
+        self.module_arena.node_arena.iter()
+            .map(|(_, node)| node)
+            .find(|node| matches!(node, IrNode::Struct { name, .. } if name == struct_name))
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -63,6 +279,36 @@ impl Access {
     }
 }
 
+/// Attributes that change how a function is compiled, written like `@inline` before `fun`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Attribute {
+    /// Always inline the function where it's called, whatever it costs.
+    Inline,
+    /// Never inline the function.
+    NoInline,
+    /// Make it an error when a call to the function from itself can't become a jump.
+    TailRec,
+}
+
+impl Attribute {
+    pub fn from<Str: AsRef<str>>(name: Str) -> Option<Self> {
+        match name.as_ref() {
+            "inline" => Some(Attribute::Inline),
+            "noinline" => Some(Attribute::NoInline),
+            "tailrec" => Some(Attribute::TailRec),
+            &_ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Attribute::Inline => "inline",
+            Attribute::NoInline => "noinline",
+            Attribute::TailRec => "tailrec",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Copy)]
 pub enum IntTy {
     ISize,
@@ -86,6 +332,17 @@ impl IntTy {
         }
     }
 
+    pub fn name(&self) -> &'static str {
+        match self {
+            ISize => "IntSize",
+            I8 => "Int8",
+            I16 => "Int16",
+            I32 => "Int32",
+            I64 => "Int64",
+            I128 => "Int128",
+        }
+    }
+
     pub fn bits(&self) -> u32 {
         match self {
             ISize => 64, // todo
@@ -121,6 +378,17 @@ impl UIntTy {
         }
     }
 
+    pub fn name(&self) -> &'static str {
+        match self {
+            USize => "USize",
+            U8 => "UInt8",
+            U16 => "UInt16",
+            U32 => "UInt32",
+            U64 => "UInt64",
+            U128 => "UInt128",
+        }
+    }
+
     pub fn bits(&self) -> u32 {
         match self {
             USize => 64, // todo
@@ -164,8 +432,8 @@ impl FloatTy {
 
 #[derive(Clone, Debug)]
 pub struct IrTypedName {
-    typ: IrTypeIndex,
-    name: String,
+    pub typ: IrTypeIndex,
+    pub name: String,
 }
 
 #[derive(Clone, Debug)]
@@ -180,6 +448,9 @@ pub enum IrType {
     Reference(IrTypeIndex, bool),
     Optional(IrTypeIndex),
     Function(Vec<IrTypeIndex>, IrTypeIndex),
+    /// A fixed number of values of a type, which is a pointer to the first of them like a struct
+    /// is a pointer to its fields. Slices are `{data: &mut T, len: USize}` rows.
+    Array(IrTypeIndex, u64),
     Void,
     Unknown,
 }
@@ -187,24 +458,45 @@ pub enum IrType {
 #[derive(Clone, Debug)]
 pub struct IrFunction {
     pub access: Access,
+    pub attributes: Vec<Attribute>,
     pub name: String,
     pub type_params: Vec<IrTypedName>,
+    pub params: Vec<IrTypedName>,
     pub return_type: IrTypeIndex,
     pub blocks: Vec<IrBlockIndex>,
+    pub span: Option<Span>,
+}
+
+impl IrFunction {
+    pub fn has_attribute(&self, attribute: Attribute) -> bool {
+        self.attributes.contains(&attribute)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IrEnumVariant {
+    pub name: String,
+    pub fields: Vec<IrTypedName>,
 }
 
 #[derive(Clone, Debug)]
 pub enum IrNode {
     Function(IrFunction),
     Struct {
+        name: String,
+        fields: Vec<IrTypedName>,
         nodes: Vec<IrNodeIndex>,
     },
+    Enum {
+        name: String,
+        variants: Vec<IrEnumVariant>,
+    },
     Error,
 }
 
 #[derive(Clone, Debug)]
 pub struct IrBlock {
-    instructions: Vec<IrInstructionIndex>,
+    pub instructions: Vec<IrInstructionIndex>,
 }
 
 impl IrBlock {
@@ -216,9 +508,17 @@ impl IrBlock {
 #[derive(Clone, Debug)]
 pub enum IrInstruction {
     Ref(String),
-    NatLiteral(i64),
+    /// An integer literal. `UInt128`s that don't fit an i128 are kept as its bits.
+    NatLiteral(i128),
+    /// A float literal, which takes the float type of whatever it's used with, or `Float64`.
+    FloatLiteral(f64),
     BoolLiteral(bool),
+    /// A `Str` whose header and bytes are constant data.
+    StrLiteral(String),
+    /// A character, which is the integer literal of its Unicode scalar value.
+    CharLiteral(char),
     BinOp(IrInstructionIndex, BinOpType, IrInstructionIndex),
+    UnaryOp(UnaryOpType, IrInstructionIndex),
     FieldAccessor {
         aggregate: IrInstructionIndex,
         value: IrInstructionIndex,
@@ -231,22 +531,117 @@ pub enum IrInstruction {
         typ: IrTypeIndex,
         allocator: IrInstructionIndex,
     },
+    /// A `New` that escape analysis found never outlives its function, in the function's frame.
+    StackAlloc {
+        typ: IrTypeIndex,
+    },
+    /// A row made from the values of its fields, which are in the order of its type.
+    RowLiteral {
+        typ: IrTypeIndex,
+        values: Vec<IrInstructionIndex>,
+    },
     Dereference {
         pointer: IrInstructionIndex,
     },
+    /// The element at `index` of an array, or of the elements the `data` of a slice points to.
+    /// The index isn't checked, the `BoundsCheck` before it does that.
+    Index {
+        base: IrInstructionIndex,
+        index: IrInstructionIndex,
+    },
+    /// Panic unless `0 <= index < len`. See `Module::bounds_message`.
+    BoundsCheck {
+        index: IrInstructionIndex,
+        len: IrInstructionIndex,
+    },
     Denull {
         optional: IrInstructionIndex,
     },
     Borrow {
         value: IrInstructionIndex,
     },
+    /// Convert a number to an integer or float type. Integers are wrapped to the width of the
+    /// type, and floats become integers by rounding toward zero, saturating at the bounds of the
+    /// type, with NaN becoming zero.
+    Cast {
+        value: IrInstructionIndex,
+        typ: IrTypeIndex,
+    },
+    /// Write the bytes of a `Str` to standard output, for the built-in `print`.
+    Print {
+        value: IrInstructionIndex,
+    },
+    Let {
+        name: IrTypedName,
+        value: IrInstructionIndex,
+    },
+    Assign {
+        name: String,
+        value: IrInstructionIndex,
+    },
+    /// Write a value to a place, which is the `FieldAccessor`, `Dereference` or `Index` that
+    /// would read it. See `Module::places`.
+    Store {
+        place: IrInstructionIndex,
+        value: IrInstructionIndex,
+    },
     Branch {
         condition: IrInstructionIndex,
         true_branch: IrBlockIndex,
         false_branch: IrBlockIndex,
     },
+    Jump {
+        target: IrBlockIndex,
+    },
     Return {
         value: IrInstructionIndex,
     },
     Error,
 }
+
+impl IrInstruction {
+    /// Terminators end a block; nothing after them in the same block is executed.
+    pub fn is_terminator(&self) -> bool {
+        matches!(self, IrInstruction::Branch { .. } | IrInstruction::Jump { .. } | IrInstruction::Return { .. })
+    }
+
+    /// The instructions whose values this instruction uses.
+    pub fn operands(&self) -> Vec<IrInstructionIndex> {
+        match self {
+            IrInstruction::Ref(_) | IrInstruction::NatLiteral(_) | IrInstruction::BoolLiteral(_) => vec![],
+            IrInstruction::StrLiteral(_) | IrInstruction::CharLiteral(_) | IrInstruction::FloatLiteral(_) => vec![],
+            IrInstruction::BinOp(lhs, _, rhs) => vec![*lhs, *rhs],
+            IrInstruction::UnaryOp(_, value) => vec![*value],
+            IrInstruction::FieldAccessor { aggregate, value } => vec![*aggregate, *value],
+            IrInstruction::FunctionCall { function, args } => {
+                let mut operands = vec![*function];
+                operands.extend(args.iter().copied());
+                operands
+            }
+            IrInstruction::New { allocator, .. } => vec![*allocator],
+            IrInstruction::StackAlloc { .. } => vec![],
+            IrInstruction::RowLiteral { values, .. } => values.clone(),
+            IrInstruction::Dereference { pointer } => vec![*pointer],
+            IrInstruction::Index { base, index } => vec![*base, *index],
+            IrInstruction::BoundsCheck { index, len } => vec![*index, *len],
+            IrInstruction::Denull { optional } => vec![*optional],
+            IrInstruction::Borrow { value } | IrInstruction::Print { value } => vec![*value],
+            IrInstruction::Cast { value, .. } => vec![*value],
+            IrInstruction::Let { value, .. } | IrInstruction::Assign { value, .. } => vec![*value],
+            IrInstruction::Store { place, value } => vec![*place, *value],
+            IrInstruction::Branch { condition, .. } => vec![*condition],
+            IrInstruction::Jump { .. } => vec![],
+            IrInstruction::Return { value } => vec![*value],
+            IrInstruction::Error => vec![],
+        }
+    }
+
+    /// The blocks a terminator can continue in.
+    pub fn successors(&self) -> Vec<IrBlockIndex> {
+        match self {
+            IrInstruction::Branch { true_branch, false_branch, .. } => vec![*true_branch, *false_branch],
+            IrInstruction::Jump { target } => vec![*target],
+            _ => vec![],
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::{Duration, Instant};
+use crate::ir::Module;
+use crate::options::OptLevel;
+use bounds::BoundsCheckElimination;
+use constant_propagation::ConstantPropagation;
+use dead_code::DeadCodeElimination;
+use escape::EscapeAnalysis;
+use inline::Inliner;
+use simplify_cfg::SimplifyCfg;
+use tail_calls::TailCallElimination;
+
+pub use escape::report_escapes;
+
+mod bounds;
+mod constant_propagation;
+mod dead_code;
+mod escape;
+mod inline;
+mod simplify_cfg;
+mod tail_calls;
+
+/// An optimization over the IR of a module. Passes run before the module is handed to a
+/// backend, so they can use what nuvae knows about refinements and allocators.
+pub trait Pass {
+    /// Name of the pass, used by `--passes` and `--print-after`.
+    fn name(&self) -> &'static str;
+
+    /// Run the pass over the module and return whether it changed anything.
+    fn run(&mut self, module: &mut Module) -> bool;
+}
+
+type PassConstructor = fn() -> Box<dyn Pass>;
+
+/// Every pass that can be created by name.
+const PASSES: &[(&str, PassConstructor)] = &[
+    ("sccp", || Box::new(ConstantPropagation)),
+    ("simplify-cfg", || Box::new(SimplifyCfg)),
+    ("dce", || Box::new(DeadCodeElimination)),
+    ("inline", || Box::new(Inliner)),
+    ("tailcall", || Box::new(TailCallElimination)),
+    ("escape", || Box::new(EscapeAnalysis)),
+    ("bounds", || Box::new(BoundsCheckElimination)),
+];
+
+/// Passes that run at every optimization level, and with `--passes` too. Recursion is how nuvae
+/// loops, so tail calls are turned into jumps even without optimizations, and `@tailrec` relies on it.
+const REQUIRED_PIPELINE: &[&str] = &["tailcall"];
+
+/// Passes that run after the required ones when optimizations are enabled.
+/// Control flow is simplified before inlining so that functions where every path returns can be
+/// inlined, and again after `dce` since it can leave blocks that only jump. Escape analysis runs
+/// after inlining, which can show that an object a callee returns doesn't outlive the caller.
+/// Bounds checks are removed once constants are folded, so a literal index is known.
+const DEFAULT_PIPELINE: &[&str] = &["simplify-cfg", "inline", "sccp", "bounds", "escape", "simplify-cfg", "dce", "simplify-cfg"];
+
+/// Create a pass from its name.
+pub fn create(name: &str) -> Option<Box<dyn Pass>> {
+    PASSES.iter().find(|(pass_name, _)| *pass_name == name).map(|(_, create)| create())
+}
+
+/// How long a pass took and whether it changed the module.
+#[derive(Clone, Debug)]
+pub struct PassTiming {
+    pub name: &'static str,
+    pub duration: Duration,
+    pub changed: bool,
+}
+
+/// Runs passes over a module in the order they were added.
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    /// Names of the passes after which the IR is printed.
+    print_after: Vec<String>,
+    timings: Vec<PassTiming>,
+}
+
+impl PassManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a pass manager with the default pipeline for an optimization level.
+    pub fn for_opt_level(opt_level: OptLevel) -> Self {
+        let mut manager = Self::new();
+        for name in REQUIRED_PIPELINE {
+            manager.add(create(name).unwrap());
+        }
+        if opt_level == OptLevel::O0 {
+            return manager;
+        }
+        for name in DEFAULT_PIPELINE {
+            manager.add(create(name).unwrap());
+        }
+        manager
+    }
+
+    /// Create a pass manager that runs the named passes in the given order. The required passes
+    /// that aren't named run first, so they can be moved but not left out.
+    pub fn from_names<Str: AsRef<str>>(names: &[Str]) -> Result<Self, String> {
+        let mut manager = Self::new();
+        for required in REQUIRED_PIPELINE {
+            if !names.iter().any(|name| name.as_ref() == *required) {
+                manager.add(create(required).unwrap());
+            }
+        }
+        for name in names {
+            let pass = create(name.as_ref()).ok_or(format!("unknown pass `{}`", name.as_ref()))?;
+            manager.add(pass);
+        }
+        Ok(manager)
+    }
+
+    pub fn add(&mut self, pass: Box<dyn Pass>) {
+        self.passes.push(pass);
+    }
+
+    /// Print the module to stderr every time the named pass has run.
+    pub fn print_after(&mut self, name: &str) -> Result<(), String> {
+        if create(name).is_none() {
+            return Err(format!("unknown pass `{}`", name));
+        }
+        self.print_after.push(name.to_string());
+        Ok(())
+    }
+
+    /// Run every pass over the module, returning whether any of them changed it.
+    pub fn run(&mut self, module: &mut Module) -> bool {
+        let mut changed = false;
+        for pass in self.passes.iter_mut() {
+            let start = Instant::now();
+            let pass_changed = pass.run(module);
+            self.timings.push(PassTiming { name: pass.name(), duration: start.elapsed(), changed: pass_changed });
+            changed |= pass_changed;
+            if self.print_after.iter().any(|name| name == pass.name()) {
+                eprintln!("; IR after {}\n{}", pass.name(), module);
+            }
+        }
+        changed
+    }
+
+    /// Format the timings as a table, with the total at the bottom.
+    pub fn report(&self) -> String {
+        let mut report = format!("{:<24} {:>12} {:>8}\n", "pass", "time (ms)", "changed");
+        let mut total = Duration::ZERO;
+        for timing in self.timings.iter() {
+            total += timing.duration;
+            report.push_str(&format!("{:<24} {:>12.3} {:>8}\n", timing.name,
+                                     timing.duration.as_secs_f64() * 1000.0, timing.changed));
+        }
+        report.push_str(&format!("{:<24} {:>12.3}\n", "total", total.as_secs_f64() * 1000.0));
+        report
+    }
+}
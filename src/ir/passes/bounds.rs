@@ -0,0 +1,296 @@
+use std::collections::{HashMap, HashSet};
+use crate::ast::{BinOpType, UnaryOpType};
+use crate::ir::{IrBlockIndex, IrFunction, IrInstruction, IrInstructionIndex, IrNodeIndex, IrType, IrTypeIndex, Module};
+use crate::ir::passes::Pass;
+
+/// Removes the bounds checks of indices that are known to be in bounds, which is when
+/// `0 <= index` and `index < len` follow from:
+///
+/// - constants, like a literal index into an array, whose length is part of its type,
+/// - the types of variables: unsigned integers aren't negative,
+/// - the conditions of the branches that have to be taken to reach the check, like the
+///   `i < s.len` of `if i < s.len { ... s[i] ... }` or the `i >= s.len` that returns before it.
+///
+/// Refinements like `(i: Int32 where i >= 0 and i < 8)` aren't checked where values are bound
+/// or passed, so what they say about a variable can't be relied on here.
+///
+/// Values are compared by how they're computed, so the `s.len` of a condition is the length the
+/// check compares against unless `s` is assigned in between. Fields and dereferences can be
+/// changed by any store or call, so facts about them don't survive those.
+pub struct BoundsCheckElimination;
+
+impl Pass for BoundsCheckElimination {
+    fn name(&self) -> &'static str {
+        "bounds"
+    }
+
+    fn run(&mut self, module: &mut Module) -> bool {
+        let mut changed = false;
+        for index in module.function_indices() {
+            changed |= self.run_function(module, index);
+        }
+        changed
+    }
+}
+
+impl BoundsCheckElimination {
+    fn run_function(&mut self, module: &mut Module, index: IrNodeIndex) -> bool {
+        let function = module.function(index);
+        let prover = Prover::new(module, function);
+        let mut proven = vec![];
+        for block in function.blocks.iter() {
+            for (position, ins) in module.block(*block).instructions.iter().enumerate() {
+                if let IrInstruction::BoundsCheck { index, len } = module.instruction(*ins) {
+                    let facts = prover.facts_before(*block, position, &mut HashSet::new());
+                    if prover.in_bounds(&facts, *index, *len) {
+                        proven.push((*block, *ins));
+                    }
+                }
+            }
+        }
+
+        let arena = &mut module.module_arena;
+        for (block, ins) in proven.iter() {
+            arena.block_arena.get_mut(*block).unwrap().instructions.retain(|i| i != ins);
+            arena.instruction_arena.remove(*ins);
+            arena.instruction_spans.remove(ins);
+        }
+        !proven.is_empty()
+    }
+}
+
+/// A value as far as it can be told apart from others by how it's computed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Term {
+    Constant(i128),
+    Local(String),
+    Field(Box<Term>, String),
+    Dereference(Box<Term>),
+}
+
+impl Term {
+    fn mentions(&self, name: &str) -> bool {
+        match self {
+            Term::Constant(_) => false,
+            Term::Local(local) => local == name,
+            Term::Field(aggregate, _) | Term::Dereference(aggregate) => aggregate.mentions(name),
+        }
+    }
+
+    /// Whether the term reads memory, which stores and calls can change.
+    fn reads_memory(&self) -> bool {
+        matches!(self, Term::Field(..) | Term::Dereference(_))
+    }
+}
+
+/// `lhs < rhs`, or `lhs <= rhs` when it isn't strict.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Fact {
+    lhs: Term,
+    strict: bool,
+    rhs: Term,
+}
+
+impl Fact {
+    fn mentions(&self, name: &str) -> bool {
+        self.lhs.mentions(name) || self.rhs.mentions(name)
+    }
+
+    fn reads_memory(&self) -> bool {
+        self.lhs.reads_memory() || self.rhs.reads_memory()
+    }
+}
+
+struct Prover<'m> {
+    module: &'m Module,
+    /// The block the function starts in, which is entered from its callers too.
+    entry: Option<IrBlockIndex>,
+    predecessors: HashMap<IrBlockIndex, Vec<IrBlockIndex>>,
+    /// The types every parameter and `let` of a name has.
+    types: HashMap<String, Vec<IrTypeIndex>>,
+    /// Locals that are borrowed, which anything can change through the reference.
+    borrowed: HashSet<String>,
+    /// The `Ref`s that are the names of fields.
+    fields: HashSet<IrInstructionIndex>,
+}
+
+impl<'m> Prover<'m> {
+    fn new(module: &'m Module, function: &IrFunction) -> Self {
+        let mut predecessors: HashMap<IrBlockIndex, Vec<IrBlockIndex>> = HashMap::new();
+        let mut types: HashMap<String, Vec<IrTypeIndex>> = HashMap::new();
+        let mut borrowed = HashSet::new();
+        let mut fields = HashSet::new();
+        for param in function.params.iter() {
+            types.entry(param.name.clone()).or_default().push(param.typ);
+        }
+        for block in function.blocks.iter() {
+            for ins in module.block(*block).instructions.iter() {
+                let instruction = module.instruction(*ins);
+                for successor in instruction.successors() {
+                    predecessors.entry(successor).or_default().push(*block);
+                }
+                match instruction {
+                    IrInstruction::Let { name, .. } => types.entry(name.name.clone()).or_default().push(name.typ),
+                    IrInstruction::Borrow { value } => {
+                        if let IrInstruction::Ref(name) = module.instruction(*value) {
+                            borrowed.insert(name.clone());
+                        }
+                    }
+                    IrInstruction::FieldAccessor { value, .. } => {
+                        fields.insert(*value);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Self { module, entry: function.blocks.first().copied(), predecessors, types, borrowed, fields }
+    }
+
+    /// The facts that hold right before the instruction at `position` in a block.
+    fn facts_before(&self, block: IrBlockIndex, position: usize, visited: &mut HashSet<IrBlockIndex>) -> Vec<Fact> {
+        let mut facts = self.entry_facts(block, visited);
+        for ins in self.module.block(block).instructions[..position].iter() {
+            self.kill(&mut facts, *ins);
+        }
+        facts
+    }
+
+    /// The facts that hold when a block is entered. Only blocks with a single predecessor know
+    /// anything, which is what held at the end of it and the condition of its branch. The block
+    /// a function starts in is entered from its callers as well, even when tail calls jump to it.
+    fn entry_facts(&self, block: IrBlockIndex, visited: &mut HashSet<IrBlockIndex>) -> Vec<Fact> {
+        let predecessor = match self.predecessors.get(&block).map(Vec::as_slice) {
+            Some([predecessor]) if Some(block) != self.entry && visited.insert(block) => *predecessor,
+            _ => return vec![],
+        };
+        let instructions = &self.module.block(predecessor).instructions;
+        match instructions.last().map(|ins| self.module.instruction(*ins)) {
+            Some(IrInstruction::Branch { condition, true_branch, false_branch }) if true_branch != false_branch => {
+                // the condition is evaluated where it is, things can change after that
+                let position = match instructions.iter().position(|ins| ins == condition) {
+                    Some(position) => position,
+                    None => return vec![],
+                };
+                let mut facts = self.facts_before(predecessor, position, visited);
+                self.condition_facts(*condition, block == *true_branch, &mut facts);
+                for ins in instructions[position + 1..].iter() {
+                    self.kill(&mut facts, *ins);
+                }
+                facts
+            }
+            Some(IrInstruction::Jump { .. }) => self.facts_before(predecessor, instructions.len(), visited),
+            _ => vec![],
+        }
+    }
+
+    /// Drop the facts an instruction can make untrue.
+    fn kill(&self, facts: &mut Vec<Fact>, ins: IrInstructionIndex) {
+        match self.module.instruction(ins) {
+            IrInstruction::Let { name: typed_name, .. } => facts.retain(|fact| !fact.mentions(&typed_name.name)),
+            IrInstruction::Assign { name, .. } => facts.retain(|fact| !fact.mentions(name)),
+            IrInstruction::Store { .. } | IrInstruction::FunctionCall { .. } => facts.retain(|fact| !fact.reads_memory()),
+            _ => {}
+        }
+    }
+
+    /// Add what a condition says when it's `true` or `false`.
+    fn condition_facts(&self, condition: IrInstructionIndex, value: bool, facts: &mut Vec<Fact>) {
+        let (lhs, op, rhs) = match self.module.instruction(condition) {
+            IrInstruction::UnaryOp(UnaryOpType::Not, inner) => return self.condition_facts(*inner, !value, facts),
+            IrInstruction::BinOp(lhs, op, rhs) => (*lhs, *op, *rhs),
+            _ => return,
+        };
+        match (op, value) {
+            (BinOpType::And, true) | (BinOpType::Or, false) => {
+                self.condition_facts(lhs, value, facts);
+                self.condition_facts(rhs, value, facts);
+                return;
+            }
+            (BinOpType::And | BinOpType::Or, _) => return,
+            _ => {}
+        }
+        let (lhs, rhs) = match (self.term(lhs), self.term(rhs)) {
+            (Some(lhs), Some(rhs)) => (lhs, rhs),
+            _ => return,
+        };
+        let fact = |lhs: &Term, strict, rhs: &Term| Fact { lhs: lhs.clone(), strict, rhs: rhs.clone() };
+        match (op, value) {
+            (BinOpType::LessThan, true) | (BinOpType::GreaterThanEqualTo, false) => facts.push(fact(&lhs, true, &rhs)),
+            (BinOpType::LessThanEqualTo, true) | (BinOpType::GreaterThan, false) => facts.push(fact(&lhs, false, &rhs)),
+            (BinOpType::GreaterThan, true) | (BinOpType::LessThanEqualTo, false) => facts.push(fact(&rhs, true, &lhs)),
+            (BinOpType::GreaterThanEqualTo, true) | (BinOpType::LessThan, false) => facts.push(fact(&rhs, false, &lhs)),
+            (BinOpType::EqualTo, true) | (BinOpType::NotEqualTo, false) => {
+                facts.push(fact(&lhs, false, &rhs));
+                facts.push(fact(&rhs, false, &lhs));
+            }
+            _ => {}
+        }
+    }
+
+    /// The term for the value of an instruction, if it can be told apart by how it's computed.
+    fn term(&self, ins: IrInstructionIndex) -> Option<Term> {
+        match self.module.instruction(ins) {
+            IrInstruction::NatLiteral(n) => Some(Term::Constant(*n)),
+            IrInstruction::CharLiteral(c) => Some(Term::Constant(*c as i128)),
+            IrInstruction::Ref(name) if !self.borrowed.contains(name) && !self.fields.contains(&ins) => Some(Term::Local(name.clone())),
+            IrInstruction::FieldAccessor { aggregate, value } => match self.module.instruction(*value) {
+                IrInstruction::Ref(field) => Some(Term::Field(Box::new(self.term(*aggregate)?), field.clone())),
+                _ => None,
+            },
+            IrInstruction::Dereference { pointer } => Some(Term::Dereference(Box::new(self.term(*pointer)?))),
+            _ => None,
+        }
+    }
+
+    /// Whether `0 <= index < len` follows from the facts and what's known about the index.
+    fn in_bounds(&self, facts: &[Fact], index: IrInstructionIndex, len: IrInstructionIndex) -> bool {
+        let (index, len) = match (self.term(index), self.term(len)) {
+            (Some(index), Some(len)) => (index, len),
+            _ => return false,
+        };
+        self.not_negative(facts, &index) && self.less_than(facts, &index, &len)
+    }
+
+    fn not_negative(&self, facts: &[Fact], term: &Term) -> bool {
+        match term {
+            Term::Constant(n) => *n >= 0,
+            Term::Local(name) if self.unsigned(name) => true,
+            _ => facts.iter().any(|fact| fact.rhs == *term && match fact.lhs {
+                Term::Constant(n) => n >= 0 || (fact.strict && n == -1),
+                _ => false,
+            }),
+        }
+    }
+
+    fn less_than(&self, facts: &[Fact], term: &Term, len: &Term) -> bool {
+        if let (Term::Constant(n), Term::Constant(len)) = (term, len) {
+            return n < len;
+        }
+        facts.iter().any(|fact| {
+            if fact.lhs != *term {
+                return false;
+            }
+            if fact.strict && fact.rhs == *len {
+                return true;
+            }
+            // a bound that's a constant proves it against a constant length
+            match (&fact.rhs, len) {
+                (Term::Constant(bound), Term::Constant(len)) => if fact.strict { bound <= len } else { bound < len },
+                _ => false,
+            }
+        })
+    }
+
+    /// Whether every binding of a local has an unsigned integer type.
+    fn unsigned(&self, name: &str) -> bool {
+        self.types.get(name).is_some_and(|types| types.iter().all(|typ| self.unsigned_type(*typ)))
+    }
+
+    fn unsigned_type(&self, typ: IrTypeIndex) -> bool {
+        match self.module.typ(typ) {
+            IrType::UInt(_) => true,
+            IrType::Refinement(_, inner, _) => self.unsigned_type(*inner),
+            _ => false,
+        }
+    }
+}
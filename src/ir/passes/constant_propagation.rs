@@ -0,0 +1,477 @@
+use std::collections::{HashMap, HashSet};
+use crate::ast::{BinOpType, Overflow, UnaryOpType};
+use crate::ir::{IrBlockIndex, IrInstruction, IrInstructionIndex, IrNode, IrNodeIndex, IrType, IrTypeIndex, Module};
+use crate::ir::passes::Pass;
+
+/// How the bits of a constant are interpreted, following the rules the backends use. Constants
+/// are kept like the interpreter keeps integers: signed ones sign extended, unsigned ones zero
+/// extended and 128 bit ones as they are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ConstType {
+    /// An integer literal, which takes the type of whatever it's used with.
+    Literal,
+    Bool,
+    Int { bits: u32, signed: bool },
+}
+
+const INT64: ConstType = ConstType::Int { bits: 64, signed: true };
+
+impl ConstType {
+    fn of(module: &Module, typ: IrTypeIndex) -> Option<Self> {
+        match module.typ(typ) {
+            IrType::Bool => Some(ConstType::Bool),
+            IrType::Int(i) => Some(ConstType::Int { bits: i.bits(), signed: true }),
+            IrType::UInt(u) => Some(ConstType::Int { bits: u.bits(), signed: false }),
+            IrType::Refinement(_, inner, _) => Self::of(module, *inner),
+            _ => None,
+        }
+    }
+
+    fn bits(&self) -> u32 {
+        match self {
+            ConstType::Literal => 64,
+            ConstType::Bool => 1,
+            ConstType::Int { bits, .. } => *bits,
+        }
+    }
+
+    fn is_unsigned(&self) -> bool {
+        matches!(self, ConstType::Int { signed: false, .. })
+    }
+
+    /// The smallest value of this type if it's signed.
+    fn min(&self) -> i128 {
+        i128::MIN >> (128 - self.bits())
+    }
+
+    /// Wrap a value to the width of this type. Literals are wrapped to 64 bits, which is what
+    /// arithmetic on them is done in.
+    fn wrap(&self, n: i128) -> i128 {
+        match *self {
+            ConstType::Bool => (n != 0) as i128,
+            _ if self.bits() == 128 => n,
+            _ if self.is_unsigned() => n & ((1 << self.bits()) - 1),
+            _ => (n << (128 - self.bits())) >> (128 - self.bits()),
+        }
+    }
+}
+
+/// What is known about a value: nothing yet, that it's always the same constant,
+/// or that it can't be known at compile time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Lattice {
+    Top,
+    Const(i128, ConstType),
+    Bottom,
+}
+
+impl Lattice {
+    fn meet(self, other: Lattice) -> Lattice {
+        match (self, other) {
+            (Lattice::Top, x) | (x, Lattice::Top) => x,
+            (a, b) if a == b => a,
+            _ => Lattice::Bottom,
+        }
+    }
+
+    /// Convert a constant to the type of the variable it's stored in.
+    fn convert(self, target: Option<ConstType>) -> Lattice {
+        match (self, target) {
+            (Lattice::Const(n, ConstType::Bool), Some(ConstType::Bool)) => Lattice::Const(n, ConstType::Bool),
+            (Lattice::Const(_, ConstType::Bool), _) | (Lattice::Const(_, _), Some(ConstType::Bool)) => Lattice::Bottom,
+            (Lattice::Const(n, _), Some(target)) => Lattice::Const(target.wrap(n), target),
+            (Lattice::Const(_, _), None) => Lattice::Bottom,
+            (value, _) => value,
+        }
+    }
+}
+
+type Env = HashMap<String, Lattice>;
+
+/// Sparse conditional constant propagation. Constants are followed through `let` bindings and
+/// assignments along the edges that can actually be taken, so a branch on a constant makes the
+/// other side unreachable and what is only assigned there doesn't count. Arithmetic and
+/// comparisons on constants are folded, branches on constants become jumps, and blocks that can't
+/// be reached are removed. Refinement predicates are folded as well.
+pub struct ConstantPropagation;
+
+impl Pass for ConstantPropagation {
+    fn name(&self) -> &'static str {
+        "sccp"
+    }
+
+    fn run(&mut self, module: &mut Module) -> bool {
+        let mut changed = false;
+        for index in module.function_indices() {
+            changed |= self.run_function(module, index);
+        }
+        let predicates: Vec<IrBlockIndex> = module.module_arena.type_arena.iter()
+            .filter_map(|(_, typ)| match typ {
+                IrType::Refinement(_, _, predicate) => Some(*predicate),
+                _ => None,
+            })
+            .collect();
+        for predicate in predicates {
+            // predicates refer to the refined value and parameters, which are never constant
+            let mut analysis = Analysis::new(module);
+            analysis.executable.insert(predicate);
+            analysis.visit(predicate, Env::new());
+            let rewrites = analysis.rewrites(&[predicate]);
+            changed |= apply(module, rewrites);
+        }
+        changed
+    }
+}
+
+impl ConstantPropagation {
+    fn run_function(&mut self, module: &mut Module, index: IrNodeIndex) -> bool {
+        let function = match module.node(index) {
+            IrNode::Function(function) => function,
+            _ => unreachable!(),
+        };
+        let blocks = function.blocks.clone();
+        let entry = match blocks.first() {
+            Some(entry) => *entry,
+            None => return false,
+        };
+
+        let mut analysis = Analysis::new(module);
+        for block in blocks.iter() {
+            for ins in module.block(*block).instructions.iter() {
+                match module.instruction(*ins) {
+                    IrInstruction::Let { name, .. } => {
+                        let declared = match module.typ(name.typ) {
+                            // untyped bindings take the type of their value, which is only known for the `let` itself
+                            IrType::Unknown => None,
+                            _ => ConstType::of(module, name.typ),
+                        };
+                        let previous = analysis.declared.insert(name.name.clone(), declared);
+                        if previous.is_some() && previous != Some(declared) {
+                            analysis.declared.insert(name.name.clone(), None);
+                        }
+                    }
+                    IrInstruction::FieldAccessor { value, .. } => {
+                        analysis.fields.insert(*value);
+                    }
+                    IrInstruction::Borrow { value } => {
+                        // anything can be stored through the reference
+                        if let IrInstruction::Ref(name) = module.instruction(*value) {
+                            analysis.borrowed.insert(name.clone());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let entry_env: Env = function.params.iter().map(|param| (param.name.clone(), Lattice::Bottom)).collect();
+        analysis.add_edge(entry, &entry_env);
+        while let Some(block) = analysis.worklist.pop() {
+            let env = analysis.entry_envs.get(&block).unwrap().clone();
+            analysis.visit(block, env);
+        }
+
+        let unreachable: Vec<IrBlockIndex> = blocks.iter()
+            .filter(|block| !analysis.executable.contains(block))
+            .copied()
+            .collect();
+        let rewrites = analysis.rewrites(&blocks);
+        let mut changed = apply(module, rewrites);
+        if !unreachable.is_empty() {
+            module.function_mut(index).blocks.retain(|block| !unreachable.contains(block));
+            for block in unreachable {
+                module.module_arena.remove_block(block);
+            }
+            changed = true;
+        }
+        changed
+    }
+}
+
+struct Analysis<'m> {
+    module: &'m Module,
+    values: HashMap<IrInstructionIndex, Lattice>,
+    executable: HashSet<IrBlockIndex>,
+    entry_envs: HashMap<IrBlockIndex, Env>,
+    worklist: Vec<IrBlockIndex>,
+    /// Types of the `let` bindings in the function, when they're declared as a type constants can have.
+    declared: HashMap<String, Option<ConstType>>,
+    /// Names of the locals whose address is taken, which can change without an assignment.
+    borrowed: HashSet<String>,
+    /// The `Ref`s that name fields rather than locals.
+    fields: HashSet<IrInstructionIndex>,
+}
+
+impl<'m> Analysis<'m> {
+    fn new(module: &'m Module) -> Self {
+        Self {
+            module,
+            values: HashMap::new(),
+            executable: HashSet::new(),
+            entry_envs: HashMap::new(),
+            worklist: vec![],
+            declared: HashMap::new(),
+            borrowed: HashSet::new(),
+            fields: HashSet::new(),
+        }
+    }
+
+    /// Mark an edge as taken, merging what is known at its end with what is known at the target.
+    fn add_edge(&mut self, target: IrBlockIndex, env: &Env) {
+        if self.executable.insert(target) {
+            self.entry_envs.insert(target, env.clone());
+            self.worklist.push(target);
+            return;
+        }
+        let target_env = self.entry_envs.get_mut(&target).unwrap();
+        let mut changed = false;
+        let names: HashSet<String> = target_env.keys().chain(env.keys()).cloned().collect();
+        for name in names {
+            let old = target_env.get(&name).copied().unwrap_or(Lattice::Top);
+            let new = old.meet(env.get(&name).copied().unwrap_or(Lattice::Top));
+            if new != old {
+                target_env.insert(name, new);
+                changed = true;
+            }
+        }
+        if changed && !self.worklist.contains(&target) {
+            self.worklist.push(target);
+        }
+    }
+
+    fn value(&self, index: IrInstructionIndex) -> Lattice {
+        self.values.get(&index).copied().unwrap_or(Lattice::Top)
+    }
+
+    fn visit(&mut self, block: IrBlockIndex, mut env: Env) {
+        let module = self.module;
+        for ins in module.block(block).instructions.iter() {
+            let value = match module.instruction(*ins) {
+                IrInstruction::NatLiteral(n) => Lattice::Const(*n, ConstType::Literal),
+                IrInstruction::BoolLiteral(b) => Lattice::Const(*b as i128, ConstType::Bool),
+                IrInstruction::CharLiteral(c) => Lattice::Const(*c as i128, ConstType::Literal),
+                IrInstruction::Ref(name) => {
+                    if self.borrowed.contains(name) || self.fields.contains(ins) {
+                        Lattice::Bottom
+                    } else if let Some(value) = env.get(name) {
+                        *value
+                    } else if self.declared.contains_key(name) {
+                        // a local that hasn't been bound on any path that was taken so far
+                        Lattice::Top
+                    } else {
+                        Lattice::Bottom
+                    }
+                }
+                IrInstruction::BinOp(lhs, op, rhs) => fold(self.value(*lhs), *op, self.value(*rhs)),
+                IrInstruction::UnaryOp(op, value) => fold_unary(*op, self.value(*value)),
+                // float types have no constants, so converting to them isn't folded
+                IrInstruction::Cast { value, typ } => self.value(*value).convert(ConstType::of(module, *typ)),
+                IrInstruction::Let { name, value } => {
+                    let value = match (module.typ(name.typ), self.value(*value)) {
+                        (_, _) if self.borrowed.contains(&name.name) => Lattice::Bottom,
+                        // literals bound without a type are 64-bit integers
+                        (IrType::Unknown, Lattice::Const(n, ConstType::Literal)) => Lattice::Const(n, INT64),
+                        (IrType::Unknown, value) => value,
+                        (_, value) => value.convert(ConstType::of(module, name.typ)),
+                    };
+                    env.insert(name.name.clone(), value);
+                    continue;
+                }
+                IrInstruction::Assign { name, value } => {
+                    let declared = self.declared.get(name).copied().flatten();
+                    let value = if self.borrowed.contains(name) || declared.is_none() {
+                        Lattice::Bottom
+                    } else {
+                        self.value(*value).convert(declared)
+                    };
+                    env.insert(name.clone(), value);
+                    continue;
+                }
+                IrInstruction::Branch { condition, true_branch, false_branch } => {
+                    match self.value(*condition) {
+                        Lattice::Const(n, _) => {
+                            let target = if n != 0 { *true_branch } else { *false_branch };
+                            self.add_edge(target, &env);
+                        }
+                        // a condition that is still unknown at this point is treated like any other value
+                        _ => {
+                            self.add_edge(*true_branch, &env);
+                            self.add_edge(*false_branch, &env);
+                        }
+                    }
+                    return;
+                }
+                IrInstruction::Jump { target } => {
+                    self.add_edge(*target, &env);
+                    return;
+                }
+                IrInstruction::Return { .. } => return,
+                _ => Lattice::Bottom,
+            };
+            self.values.insert(*ins, value);
+        }
+    }
+
+    /// Find the instructions in the given blocks that can be replaced with a constant.
+    fn rewrites(&self, blocks: &[IrBlockIndex]) -> Vec<(IrInstructionIndex, IrInstruction)> {
+        let module = self.module;
+        let mut rewrites = vec![];
+        for block in blocks.iter().filter(|block| self.executable.contains(block)) {
+            for ins in module.block(*block).instructions.iter() {
+                let instruction = module.instruction(*ins);
+                let rewrite = match (instruction, self.value(*ins)) {
+                    (IrInstruction::Branch { condition, true_branch, false_branch }, _) => match self.value(*condition) {
+                        Lattice::Const(n, _) => Some(IrInstruction::Jump { target: if n != 0 { *true_branch } else { *false_branch } }),
+                        _ => None,
+                    },
+                    // only untyped constants can be replaced with literals, typed ones would lose their type
+                    (IrInstruction::BinOp(..) | IrInstruction::UnaryOp(..) | IrInstruction::Ref(_), Lattice::Const(n, ConstType::Literal)) => {
+                        Some(IrInstruction::NatLiteral(n))
+                    }
+                    (IrInstruction::BinOp(..) | IrInstruction::UnaryOp(..) | IrInstruction::Ref(_), Lattice::Const(n, ConstType::Bool)) => {
+                        Some(IrInstruction::BoolLiteral(n != 0))
+                    }
+                    _ => None,
+                };
+                if let Some(rewrite) = rewrite {
+                    rewrites.push((*ins, rewrite));
+                }
+                if instruction.is_terminator() {
+                    break;
+                }
+            }
+        }
+        rewrites
+    }
+}
+
+fn apply(module: &mut Module, rewrites: Vec<(IrInstructionIndex, IrInstruction)>) -> bool {
+    let changed = !rewrites.is_empty();
+    for (index, instruction) in rewrites {
+        *module.module_arena.instruction_arena.get_mut(index).unwrap() = instruction;
+    }
+    changed
+}
+
+/// Fold a binary operation on two constants the way the backends would compute it.
+fn fold(lhs: Lattice, op: BinOpType, rhs: Lattice) -> Lattice {
+    let ((a, lhs_type), (b, rhs_type)) = match (lhs, rhs) {
+        (Lattice::Const(a, lhs_type), Lattice::Const(b, rhs_type)) => ((a, lhs_type), (b, rhs_type)),
+        (Lattice::Bottom, _) | (_, Lattice::Bottom) => return Lattice::Bottom,
+        _ => return Lattice::Top,
+    };
+    if let BinOpType::And | BinOpType::Or = op {
+        let (a, b) = (a != 0, b != 0);
+        let result = if let BinOpType::And = op { a && b } else { a || b };
+        return Lattice::Const(result as i128, ConstType::Bool);
+    }
+    if lhs_type == ConstType::Bool && rhs_type == ConstType::Bool {
+        return match op {
+            BinOpType::EqualTo => Lattice::Const((a == b) as i128, ConstType::Bool),
+            BinOpType::NotEqualTo => Lattice::Const((a != b) as i128, ConstType::Bool),
+            _ => Lattice::Bottom,
+        };
+    }
+    if lhs_type == ConstType::Bool || rhs_type == ConstType::Bool {
+        return Lattice::Bottom;
+    }
+    if op.is_shift() {
+        return fold_shift(a, lhs_type, op, b);
+    }
+
+    // literals take the type of the other operand, otherwise the wider type wins
+    let typ = match (lhs_type, rhs_type) {
+        (ConstType::Literal, typ) | (typ, ConstType::Literal) => typ,
+        (lhs_type, rhs_type) if lhs_type.bits() >= rhs_type.bits() => lhs_type,
+        (_, rhs_type) => rhs_type,
+    };
+    let unsigned = lhs_type.is_unsigned() || rhs_type.is_unsigned();
+    let (a, b) = (typ.wrap(a), typ.wrap(b));
+    let compare = |signed: bool, unsigned_result: bool| {
+        Lattice::Const(if unsigned { unsigned_result } else { signed } as i128, ConstType::Bool)
+    };
+    if op.overflow().is_some() {
+        return fold_arithmetic(a, op, b, typ, unsigned);
+    }
+    let (ua, ub) = (a as u128, b as u128);
+    let result = match op {
+        // division by zero and division that overflows are left for the program to fail on
+        BinOpType::ForwardSlash | BinOpType::Percent if b == 0 || (!unsigned && b == -1 && a == typ.min()) => return Lattice::Bottom,
+        BinOpType::ForwardSlash if unsigned => (ua / ub) as i128,
+        BinOpType::ForwardSlash => a.wrapping_div(b),
+        BinOpType::Percent if unsigned => (ua % ub) as i128,
+        BinOpType::Percent => a.wrapping_rem(b),
+        BinOpType::Ampersand => a & b,
+        BinOpType::Pipe => a | b,
+        BinOpType::Caret => a ^ b,
+        BinOpType::LessThan => return compare(a < b, ua < ub),
+        BinOpType::GreaterThan => return compare(a > b, ua > ub),
+        BinOpType::LessThanEqualTo => return compare(a <= b, ua <= ub),
+        BinOpType::GreaterThanEqualTo => return compare(a >= b, ua >= ub),
+        BinOpType::EqualTo => return compare(a == b, ua == ub),
+        BinOpType::NotEqualTo => return compare(a != b, ua != ub),
+        _ => unreachable!(),
+    };
+    Lattice::Const(typ.wrap(result), typ)
+}
+
+/// Fold addition, subtraction or multiplication. Arithmetic that overflows and traps is left
+/// for the program to fail on.
+fn fold_arithmetic(a: i128, op: BinOpType, b: i128, typ: ConstType, unsigned: bool) -> Lattice {
+    let (op, overflow) = op.overflow().unwrap();
+    let bits = typ.bits();
+    // the exact result when it fits the type, and the result saturated at its bounds
+    let (exact, saturated) = if unsigned {
+        let (a, b) = (a as u128, b as u128);
+        let (exact, saturated) = match op {
+            BinOpType::Plus => (a.checked_add(b), a.saturating_add(b)),
+            BinOpType::Minus => (a.checked_sub(b), a.saturating_sub(b)),
+            _ => (a.checked_mul(b), a.saturating_mul(b)),
+        };
+        let max = u128::MAX >> (128 - bits);
+        (exact.filter(|exact| *exact <= max).map(|exact| exact as i128), saturated.min(max) as i128)
+    } else {
+        let (exact, saturated) = match op {
+            BinOpType::Plus => (a.checked_add(b), a.saturating_add(b)),
+            BinOpType::Minus => (a.checked_sub(b), a.saturating_sub(b)),
+            _ => (a.checked_mul(b), a.saturating_mul(b)),
+        };
+        let (min, max) = (i128::MIN >> (128 - bits), i128::MAX >> (128 - bits));
+        (exact.filter(|exact| (min..=max).contains(exact)), saturated.clamp(min, max))
+    };
+    let result = match (exact, overflow) {
+        (Some(exact), _) => exact,
+        (None, Overflow::Trap) => return Lattice::Bottom,
+        (None, Overflow::Wrap) => match op {
+            BinOpType::Plus => a.wrapping_add(b),
+            BinOpType::Minus => a.wrapping_sub(b),
+            _ => a.wrapping_mul(b),
+        },
+        (None, Overflow::Saturate) => saturated,
+    };
+    Lattice::Const(typ.wrap(result), typ)
+}
+
+/// Fold a shift, which has the type of the value that is shifted.
+fn fold_shift(a: i128, typ: ConstType, op: BinOpType, amount: i128) -> Lattice {
+    let a = typ.wrap(a);
+    let amount = (amount & (typ.bits() as i128 - 1)) as u32;
+    let result = match op {
+        BinOpType::ShiftLeft => a.wrapping_shl(amount),
+        // narrow values are already zero or sign extended, so shifting them as 128 bit values works
+        BinOpType::ShiftRight if typ.is_unsigned() => ((a as u128) >> amount) as i128,
+        BinOpType::ShiftRight => a >> amount,
+        _ => unreachable!(),
+    };
+    Lattice::Const(typ.wrap(result), typ)
+}
+
+fn fold_unary(op: UnaryOpType, value: Lattice) -> Lattice {
+    match (op, value) {
+        (UnaryOpType::Not, Lattice::Const(n, _)) => Lattice::Const((n == 0) as i128, ConstType::Bool),
+        (UnaryOpType::Minus | UnaryOpType::WrappingMinus, Lattice::Const(_, ConstType::Bool)) => Lattice::Bottom,
+        // negating the smallest signed value traps, which is left for the program to fail on
+        (UnaryOpType::Minus, Lattice::Const(n, typ)) if !typ.is_unsigned() && n == typ.min() => Lattice::Bottom,
+        (UnaryOpType::Minus | UnaryOpType::WrappingMinus, Lattice::Const(n, typ)) => Lattice::Const(typ.wrap(n.wrapping_neg()), typ),
+        (_, value) => value,
+    }
+}
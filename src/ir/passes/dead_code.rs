@@ -0,0 +1,110 @@
+use std::collections::HashSet;
+use crate::ast::{BinOpType, UnaryOpType};
+use crate::ir::{IrBlockIndex, IrInstruction, IrInstructionIndex, IrNodeIndex, IrType, Module};
+use crate::ir::passes::Pass;
+
+/// Removes instructions whose results are never used and that don't do anything else, along with
+/// `let` bindings and assignments of locals that are never read. Removing one instruction can make
+/// the ones it used dead as well, so this repeats until nothing changes.
+///
+/// Calls, allocations and denulls are always kept, as is division by anything but a nonzero
+/// literal, since those can have effects or fail at runtime.
+pub struct DeadCodeElimination;
+
+impl Pass for DeadCodeElimination {
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+
+    fn run(&mut self, module: &mut Module) -> bool {
+        // predicates aren't run, but the locals they mention shouldn't disappear from under them
+        let predicate_names: HashSet<String> = module.module_arena.type_arena.iter()
+            .filter_map(|(_, typ)| match typ {
+                IrType::Refinement(_, _, predicate) => Some(*predicate),
+                _ => None,
+            })
+            .flat_map(|predicate| referenced_names(module, &[predicate]))
+            .collect();
+
+        let mut changed = false;
+        for index in module.function_indices() {
+            while self.run_function(module, index, &predicate_names) {
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+impl DeadCodeElimination {
+    /// Remove the instructions that are dead right now, returning whether there were any.
+    fn run_function(&mut self, module: &mut Module, index: IrNodeIndex, predicate_names: &HashSet<String>) -> bool {
+        let blocks = module.function_mut(index).blocks.clone();
+        let mut used: HashSet<IrInstructionIndex> = HashSet::new();
+        for block in blocks.iter() {
+            for ins in module.block(*block).instructions.iter() {
+                used.extend(module.instruction(*ins).operands());
+            }
+        }
+        let mut read = referenced_names(module, &blocks);
+        read.extend(predicate_names.iter().cloned());
+
+        let mut dead: Vec<(IrBlockIndex, IrInstructionIndex)> = vec![];
+        for block in blocks.iter() {
+            for ins in module.block(*block).instructions.iter() {
+                let is_dead = match module.instruction(*ins) {
+                    IrInstruction::Let { name, .. } => !read.contains(&name.name),
+                    IrInstruction::Assign { name, .. } => !read.contains(name),
+                    instruction => !used.contains(ins) && !has_side_effects(module, instruction),
+                };
+                if is_dead {
+                    dead.push((*block, *ins));
+                }
+            }
+        }
+
+        let arena = &mut module.module_arena;
+        for (block, ins) in dead.iter() {
+            arena.block_arena.get_mut(*block).unwrap().instructions.retain(|i| i != ins);
+            arena.instruction_arena.remove(*ins);
+            arena.instruction_spans.remove(ins);
+        }
+        !dead.is_empty()
+    }
+}
+
+/// Names of the locals read in the given blocks. A borrow reads its local through a `Ref` too.
+fn referenced_names(module: &Module, blocks: &[IrBlockIndex]) -> HashSet<String> {
+    blocks.iter()
+        .flat_map(|block| module.block(*block).instructions.iter())
+        .filter_map(|ins| match module.instruction(*ins) {
+            IrInstruction::Ref(name) => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn has_side_effects(module: &Module, instruction: &IrInstruction) -> bool {
+    match instruction {
+        IrInstruction::Ref(_) | IrInstruction::NatLiteral(_) | IrInstruction::BoolLiteral(_) => false,
+        IrInstruction::StrLiteral(_) | IrInstruction::CharLiteral(_) | IrInstruction::FloatLiteral(_) => false,
+        // only dividing by zero or by -1 traps
+        IrInstruction::BinOp(_, BinOpType::ForwardSlash | BinOpType::Percent, divisor) => {
+            !matches!(module.instruction(*divisor), IrInstruction::NatLiteral(n) if *n != 0 && *n != -1)
+        }
+        // arithmetic that traps on overflow stays, since the program has to stop when it does
+        IrInstruction::BinOp(_, BinOpType::Plus | BinOpType::Minus | BinOpType::Star, _) => true,
+        IrInstruction::UnaryOp(UnaryOpType::Minus, _) => true,
+        IrInstruction::BinOp(..) | IrInstruction::UnaryOp(..) | IrInstruction::Cast { .. } => false,
+        IrInstruction::FieldAccessor { .. } | IrInstruction::Dereference { .. } | IrInstruction::Borrow { .. } => false,
+        // the check before an element is read stops the program when it's out of bounds
+        IrInstruction::Index { .. } => false,
+        IrInstruction::BoundsCheck { .. } => true,
+        IrInstruction::StackAlloc { .. } | IrInstruction::RowLiteral { .. } => false,
+        IrInstruction::FunctionCall { .. } | IrInstruction::New { .. } | IrInstruction::Denull { .. } => true,
+        IrInstruction::Print { .. } => true,
+        IrInstruction::Let { .. } | IrInstruction::Assign { .. } | IrInstruction::Store { .. } => true,
+        IrInstruction::Branch { .. } | IrInstruction::Jump { .. } | IrInstruction::Return { .. } => true,
+        IrInstruction::Error => true,
+    }
+}
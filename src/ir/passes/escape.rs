@@ -0,0 +1,235 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use crate::ast::Span;
+use crate::ir::{IrBlockIndex, IrFunction, IrInstruction, IrInstructionIndex, IrNodeIndex, IrType, IrTypedName, Module};
+use crate::ir::passes::Pass;
+
+/// Moves objects made with `new T in allocator` into the frame of the function that makes them
+/// when they can't outlive it, so the allocator is never called. An object escapes when it's
+/// returned, passed to a call, borrowed, used as an allocator, stored in a field, an element or
+/// through a pointer, either directly or through the locals and rows that hold it, or when a field
+/// or element inside it is borrowed. Closures hold their environment in a row, so it stays in the
+/// frame unless the closure escapes.
+///
+/// Tail recursion that became a loop reuses the frame for every iteration, so an object made in a
+/// loop that's assigned to a local, like a parameter for the next iteration, escapes as well.
+pub struct EscapeAnalysis;
+
+impl Pass for EscapeAnalysis {
+    fn name(&self) -> &'static str {
+        "escape"
+    }
+
+    fn run(&mut self, module: &mut Module) -> bool {
+        let mut changed = false;
+        for index in module.function_indices() {
+            changed |= self.run_function(module, index);
+        }
+        changed
+    }
+}
+
+impl EscapeAnalysis {
+    fn run_function(&mut self, module: &mut Module, index: IrNodeIndex) -> bool {
+        let function = module.function(index);
+        let escaping: HashSet<IrInstructionIndex> = escapes(module, function).iter()
+            .map(|escape| escape.allocation)
+            .collect();
+        let allocations: Vec<IrInstructionIndex> = allocations(module, function).into_iter()
+            .filter(|allocation| !escaping.contains(allocation))
+            .collect();
+        for allocation in allocations.iter() {
+            let instruction = module.module_arena.instruction_arena.get_mut(*allocation).unwrap();
+            if let IrInstruction::New { typ, .. } = instruction {
+                *instruction = IrInstruction::StackAlloc { typ: *typ };
+            }
+        }
+        !allocations.is_empty()
+    }
+}
+
+/// A reason an allocation can outlive the function that makes it.
+pub struct Escape {
+    /// The `New` instruction that made the object.
+    pub allocation: IrInstructionIndex,
+    pub reason: String,
+    /// Where the object escapes, if that's known.
+    pub span: Option<Span>,
+}
+
+/// Find every way the objects a function allocates escape from it.
+pub fn escapes(module: &Module, function: &IrFunction) -> Vec<Escape> {
+    // the names after a `.` are fields, not locals that could hold an object
+    let field_names: HashSet<IrInstructionIndex> = instructions(module, function)
+        .filter_map(|(_, ins)| match module.instruction(ins) {
+            IrInstruction::FieldAccessor { value, .. } => Some(*value),
+            _ => None,
+        })
+        .collect();
+
+    let mut escapes = vec![];
+    for allocation in allocations(module, function) {
+        let values = aliases(module, function, allocation, &field_names);
+        let interior = interior(module, function, &values);
+        let in_loop = in_cycle(module, block_of(module, function, allocation));
+        for (_, ins) in instructions(module, function) {
+            let reason = match module.instruction(ins) {
+                IrInstruction::Return { value } if values.contains(value) => "it's returned".to_string(),
+                IrInstruction::FunctionCall { function: callee, args } => {
+                    // the code of a closure only reads the environment it's called with
+                    let closure_call = !matches!(module.instruction(*callee), IrInstruction::Ref(_));
+                    if !args.iter().skip(closure_call as usize).any(|arg| values.contains(arg)) {
+                        continue;
+                    }
+                    match module.instruction(*callee) {
+                        IrInstruction::Ref(name) => format!("it's passed to `{}`", name),
+                        _ => "it's passed to a function".to_string(),
+                    }
+                }
+                IrInstruction::Borrow { value } if values.contains(value) => "it's borrowed".to_string(),
+                IrInstruction::Borrow { value } if interior.contains(value) => "a place inside it is borrowed".to_string(),
+                IrInstruction::New { allocator, .. } if values.contains(allocator) => "it's used as an allocator".to_string(),
+                IrInstruction::Store { place, value } if values.contains(value) => match module.instruction(*place) {
+                    IrInstruction::Dereference { .. } => "it's stored through a pointer".to_string(),
+                    IrInstruction::Index { .. } => "it's stored in an array".to_string(),
+                    _ => "it's stored in a field".to_string(),
+                },
+                IrInstruction::Assign { name, value } if in_loop && values.contains(value) => {
+                    format!("it's assigned to `{}` in a loop, whose next iteration reuses the stack", name)
+                }
+                _ => continue,
+            };
+            escapes.push(Escape { allocation, reason, span: module.instruction_span(ins) });
+        }
+    }
+    escapes
+}
+
+/// Explain why every allocation that's still left to an allocator can outlive its function.
+pub fn report_escapes(module: &Module) -> String {
+    let location = |span: Span| module.location(span.start().to_usize());
+    let mut report = String::new();
+    for (_, function) in module.functions() {
+        let escapes = escapes(module, function);
+        for allocation in allocations(module, function) {
+            let typ = match module.instruction(allocation) {
+                IrInstruction::New { typ, .. } => module.type_name(*typ),
+                _ => unreachable!(),
+            };
+            let (line, column) = module.instruction_span(allocation).map_or((0, 0), location);
+            write!(report, "{}:{}:{}: `new {}` in `{}`", module.source_name, line, column, typ, function.name).unwrap();
+            let reasons: Vec<&Escape> = escapes.iter().filter(|escape| escape.allocation == allocation).collect();
+            if reasons.is_empty() {
+                writeln!(report, " doesn't escape, the `escape` pass would put it on the stack").unwrap();
+                continue;
+            }
+            writeln!(report, " escapes").unwrap();
+            for escape in reasons {
+                match escape.span.map(location) {
+                    Some((line, column)) => writeln!(report, "    {}:{}: {}", line, column, escape.reason).unwrap(),
+                    None => writeln!(report, "    {}", escape.reason).unwrap(),
+                }
+            }
+        }
+    }
+    report
+}
+
+fn instructions<'m>(module: &'m Module, function: &'m IrFunction) -> impl Iterator<Item=(IrBlockIndex, IrInstructionIndex)> + 'm {
+    function.blocks.iter()
+        .flat_map(move |block| module.block(*block).instructions.iter().map(move |ins| (*block, *ins)))
+}
+
+fn allocations(module: &Module, function: &IrFunction) -> Vec<IrInstructionIndex> {
+    instructions(module, function)
+        .filter(|(_, ins)| matches!(module.instruction(*ins), IrInstruction::New { .. }))
+        .map(|(_, ins)| ins)
+        .collect()
+}
+
+/// The instructions whose value can be the object an allocation made, or a row holding it. Locals
+/// either is bound to are followed by name, wherever they're bound, and the fields of a row that
+/// hold the object give it back.
+fn aliases(module: &Module, function: &IrFunction, allocation: IrInstructionIndex,
+           field_names: &HashSet<IrInstructionIndex>) -> HashSet<IrInstructionIndex> {
+    let mut values = HashSet::from([allocation]);
+    let mut names = HashSet::new();
+    let mut rows = HashSet::new();
+    let mut row_names = HashSet::new();
+    let mut fields = HashSet::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (_, ins) in instructions(module, function) {
+            changed |= match module.instruction(ins) {
+                IrInstruction::Let { name: IrTypedName { name, .. }, value } | IrInstruction::Assign { name, value } if values.contains(value) => {
+                    let row = rows.contains(value) && row_names.insert(name.clone());
+                    names.insert(name.clone()) | row
+                }
+                IrInstruction::Ref(name) if names.contains(name) && !field_names.contains(&ins) => {
+                    let row = row_names.contains(name) && rows.insert(ins);
+                    values.insert(ins) | row
+                }
+                IrInstruction::Denull { optional } if values.contains(optional) => values.insert(ins),
+                IrInstruction::RowLiteral { typ, values: row } if row.iter().any(|value| values.contains(value)) => {
+                    let mut holds = false;
+                    if let IrType::Row(types) = module.typ(*typ) {
+                        for (field, value) in types.iter().zip(row) {
+                            holds |= values.contains(value) && fields.insert(field.name.clone());
+                        }
+                    }
+                    holds | rows.insert(ins) | values.insert(ins)
+                }
+                IrInstruction::FieldAccessor { aggregate, value } if rows.contains(aggregate) => {
+                    let holds = matches!(module.instruction(*value), IrInstruction::Ref(field) if fields.contains(field));
+                    holds && values.insert(ins)
+                }
+                _ => false,
+            };
+        }
+    }
+    values
+}
+
+/// The fields, elements and pointees inside an object or reached through places inside it, whose
+/// address is an address in the object when they're borrowed.
+fn interior(module: &Module, function: &IrFunction, values: &HashSet<IrInstructionIndex>) -> HashSet<IrInstructionIndex> {
+    let mut places = HashSet::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (_, ins) in instructions(module, function) {
+            let container = match module.instruction(ins) {
+                IrInstruction::FieldAccessor { aggregate, .. } => aggregate,
+                IrInstruction::Index { base, .. } => base,
+                IrInstruction::Dereference { pointer } => pointer,
+                _ => continue,
+            };
+            if values.contains(container) || places.contains(container) {
+                changed |= places.insert(ins);
+            }
+        }
+    }
+    places
+}
+
+fn block_of(module: &Module, function: &IrFunction, ins: IrInstructionIndex) -> IrBlockIndex {
+    instructions(module, function).find(|(_, i)| *i == ins).map(|(block, _)| block).unwrap()
+}
+
+/// Whether a block can be reached again once it's left.
+fn in_cycle(module: &Module, block: IrBlockIndex) -> bool {
+    let successors = |block: IrBlockIndex| module.block(block).instructions.last()
+        .map_or(vec![], |terminator| module.instruction(*terminator).successors());
+    let mut seen = HashSet::new();
+    let mut worklist = successors(block);
+    while let Some(next) = worklist.pop() {
+        if next == block {
+            return true;
+        }
+        if seen.insert(next) {
+            worklist.extend(successors(next));
+        }
+    }
+    false
+}
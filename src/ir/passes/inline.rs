@@ -0,0 +1,443 @@
+use std::collections::{HashMap, HashSet};
+use crate::ir::{Attribute, IntTy, IrBlock, IrBlockIndex, IrFunction, IrInstruction, IrInstructionIndex, IrNodeIndex, IrType, IrTypeIndex, IrTypedName, Module};
+use crate::ir::passes::Pass;
+
+/// Functions that cost at most this much are inlined even without `@inline`.
+const INLINE_THRESHOLD: usize = 16;
+
+/// Replaces calls to small functions with a copy of their body. Arguments are bound to the
+/// callee's parameters with `let`s at the call site, using the parameter types so refinements
+/// stay attached to the values, and every `return` becomes a jump to the code after the call.
+///
+/// Functions are inlined when their cost is below the threshold or they're marked `@inline`, and
+/// never when they're marked `@noinline`. Functions that can end up calling themselves, like
+/// `sum`, aren't inlined at all since there's no end to where that would stop.
+pub struct Inliner;
+
+impl Pass for Inliner {
+    fn name(&self) -> &'static str {
+        "inline"
+    }
+
+    fn run(&mut self, module: &mut Module) -> bool {
+        let graph = CallGraph::new(module);
+        let mut changed = false;
+        // callees first, so what gets inlined has already had its own calls inlined
+        for caller in graph.bottom_up() {
+            changed |= self.run_function(module, &graph, caller);
+        }
+        changed
+    }
+}
+
+impl Inliner {
+    fn run_function(&mut self, module: &mut Module, graph: &CallGraph, caller: IrNodeIndex) -> bool {
+        // calls that were looked at already, including the ones copied in with an inlined body
+        let mut visited: HashSet<IrInstructionIndex> = HashSet::new();
+        let mut changed = false;
+        loop {
+            let locals = module.local_names(module.function(caller));
+            let site = module.function(caller).blocks.iter().find_map(|block| {
+                module.block(*block).instructions.iter()
+                    .position(|ins| !visited.contains(ins) && graph.call_target(module, &locals, *ins).is_some())
+                    .map(|position| (*block, position))
+            });
+            let (block, position) = match site {
+                Some(site) => site,
+                None => return changed,
+            };
+            let call = module.block(block).instructions[position];
+            visited.insert(call);
+            let callee = graph.call_target(module, &locals, call).unwrap();
+            if self.should_inline(module, graph, caller, callee, call, &locals) {
+                visited.extend(inline(module, caller, block, position, callee));
+                changed = true;
+            }
+        }
+    }
+
+    fn should_inline(&self, module: &Module, graph: &CallGraph, caller: IrNodeIndex, callee: IrNodeIndex,
+                     call: IrInstructionIndex, locals: &HashSet<String>) -> bool {
+        let function = module.function(callee);
+        if callee == caller || graph.is_recursive(callee) || function.has_attribute(Attribute::NoInline) {
+            return false;
+        }
+        let arg_count = match module.instruction(call) {
+            IrInstruction::FunctionCall { args, .. } => args.len(),
+            _ => unreachable!(),
+        };
+        let binding = match result_binding(module, function) {
+            Some(binding) if arg_count == function.params.len() => binding,
+            _ => return false,
+        };
+        // a block without a terminator runs off the end of the function, which only returns
+        // from functions that don't return a value
+        if !matches!(binding, ResultBinding::Void) && !function.blocks.iter().all(|block| terminated(module, *block)) {
+            return false;
+        }
+        // the functions the callee refers to by name could be shadowed by the caller's locals
+        let callee_locals = module.local_names(function);
+        let mut instructions = function.blocks.iter().flat_map(|block| module.block(*block).instructions.iter());
+        let shadowed = instructions.any(|ins| match module.instruction(*ins) {
+            IrInstruction::Ref(name) => !callee_locals.contains(name) && locals.contains(name),
+            _ => false,
+        });
+        if shadowed {
+            return false;
+        }
+        function.has_attribute(Attribute::Inline) || cost(module, function) <= INLINE_THRESHOLD
+    }
+}
+
+/// Which functions call which, by name.
+struct CallGraph {
+    /// Functions by name, leaving out names that more than one function has.
+    functions: HashMap<String, IrNodeIndex>,
+    callees: HashMap<IrNodeIndex, Vec<IrNodeIndex>>,
+    order: Vec<IrNodeIndex>,
+}
+
+impl CallGraph {
+    fn new(module: &Module) -> Self {
+        let mut functions = HashMap::new();
+        let mut duplicates = HashSet::new();
+        for (index, function) in module.functions() {
+            if functions.insert(function.name.clone(), index).is_some() {
+                duplicates.insert(function.name.clone());
+            }
+        }
+        for name in duplicates {
+            functions.remove(&name);
+        }
+
+        let mut graph = CallGraph { functions, callees: HashMap::new(), order: module.function_indices() };
+        for (index, function) in module.functions() {
+            let locals = module.local_names(function);
+            let callees = function.blocks.iter()
+                .flat_map(|block| module.block(*block).instructions.iter())
+                .filter_map(|ins| graph.call_target(module, &locals, *ins))
+                .collect();
+            graph.callees.insert(index, callees);
+        }
+        graph
+    }
+
+    /// The function a call calls, if it calls one of the module's functions by its name.
+    fn call_target(&self, module: &Module, locals: &HashSet<String>, ins: IrInstructionIndex) -> Option<IrNodeIndex> {
+        match module.instruction(ins) {
+            IrInstruction::FunctionCall { function, .. } => match module.instruction(*function) {
+                IrInstruction::Ref(name) if !locals.contains(name) => self.functions.get(name).copied(),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether a function can end up calling itself.
+    fn is_recursive(&self, function: IrNodeIndex) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = self.callees[&function].clone();
+        while let Some(callee) = stack.pop() {
+            if callee == function {
+                return true;
+            }
+            if seen.insert(callee) {
+                stack.extend(self.callees[&callee].iter().copied());
+            }
+        }
+        false
+    }
+
+    /// Every function, ordered so that callees come before their callers as far as recursion allows.
+    fn bottom_up(&self) -> Vec<IrNodeIndex> {
+        let mut order = vec![];
+        let mut visited = HashSet::new();
+        for function in self.order.iter() {
+            self.post_order(*function, &mut visited, &mut order);
+        }
+        order
+    }
+
+    fn post_order(&self, function: IrNodeIndex, visited: &mut HashSet<IrNodeIndex>, order: &mut Vec<IrNodeIndex>) {
+        if !visited.insert(function) {
+            return;
+        }
+        for callee in self.callees[&function].iter() {
+            self.post_order(*callee, visited, order);
+        }
+        order.push(function);
+    }
+}
+
+/// Estimate how much code a function turns into. `Ref`s and literals are free since the
+/// backends materialize them where they're used.
+fn cost(module: &Module, function: &IrFunction) -> usize {
+    function.blocks.iter()
+        .flat_map(|block| module.block(*block).instructions.iter())
+        .filter(|ins| !matches!(module.instruction(**ins),
+            IrInstruction::Ref(_) | IrInstruction::NatLiteral(_) | IrInstruction::FloatLiteral(_)
+            | IrInstruction::BoolLiteral(_) | IrInstruction::CharLiteral(_)))
+        .count()
+}
+
+/// How the value returned by an inlined body gets to the code after the call.
+enum ResultBinding {
+    /// There is no value, the call is just removed.
+    Void,
+    /// Declared with a zero value before the body, every `return` assigns to it.
+    Declared(IrInstruction),
+    /// Bound with a `let` by the only `return` in the body.
+    Single,
+}
+
+fn result_binding(module: &Module, function: &IrFunction) -> Option<ResultBinding> {
+    if let IrType::Void = module.typ(function.return_type) {
+        return Some(ResultBinding::Void);
+    }
+    if let Some(zero) = zero(module, function.return_type) {
+        return Some(ResultBinding::Declared(zero));
+    }
+    let returns = function.blocks.iter()
+        .flat_map(|block| module.block(*block).instructions.iter())
+        .filter(|ins| matches!(module.instruction(**ins), IrInstruction::Return { .. }))
+        .count();
+    if returns == 1 {
+        Some(ResultBinding::Single)
+    } else {
+        None
+    }
+}
+
+fn terminated(module: &Module, block: IrBlockIndex) -> bool {
+    module.block(block).instructions.last().is_some_and(|ins| module.instruction(*ins).is_terminator())
+}
+
+/// A literal a variable of the type can start out with, if there is one.
+fn zero(module: &Module, typ: IrTypeIndex) -> Option<IrInstruction> {
+    match module.typ(typ) {
+        // values of unknown types are 64-bit integers in the backends
+        IrType::Int(_) | IrType::UInt(_) | IrType::Unknown => Some(IrInstruction::NatLiteral(0)),
+        IrType::Float(_) => Some(IrInstruction::FloatLiteral(0.0)),
+        IrType::Bool => Some(IrInstruction::BoolLiteral(false)),
+        IrType::Refinement(_, inner, _) => zero(module, *inner),
+        _ => None,
+    }
+}
+
+/// Names of locals start with a letter, so names starting with `_n_` can't clash with them.
+/// Find an `n` that wasn't used for another call inlined into the same function.
+fn fresh_prefix(module: &Module, caller: IrNodeIndex, callee: &str) -> String {
+    let names = module.local_names(module.function(caller));
+    let mut n = 0;
+    while names.iter().any(|name| name.starts_with(&format!("_{}_", n))) {
+        n += 1;
+    }
+    format!("_{}_{}", n, callee)
+}
+
+/// Replace the call at `position` in `block` with a copy of the callee's body, returning the
+/// instructions that were copied from the callee.
+fn inline(module: &mut Module, caller: IrNodeIndex, block: IrBlockIndex, position: usize, callee: IrNodeIndex) -> Vec<IrInstructionIndex> {
+    let function = module.function(callee).clone();
+    let call = module.block(block).instructions[position];
+    let args = match module.instruction(call) {
+        IrInstruction::FunctionCall { args, .. } => args.clone(),
+        _ => unreachable!(),
+    };
+    let span = module.instruction_span(call);
+    let binding = result_binding(module, &function).unwrap();
+
+    let prefix = fresh_prefix(module, caller, &function.name);
+    let renames = module.local_names(&function).into_iter()
+        .map(|name| {
+            let renamed = format!("{}_{}", prefix, name);
+            (name, renamed)
+        })
+        .collect();
+    let mut copier = Copier { renames, bound: None, instructions: HashMap::new(), blocks: HashMap::new() };
+    for old in function.blocks.iter() {
+        copier.blocks.insert(*old, module.module_arena.block_arena.insert(IrBlock::new()));
+    }
+
+    // the call becomes a reference to the result, at the start of the block after the body
+    let continuation = module.module_arena.block_arena.insert(IrBlock::new());
+    let mut tail = module.module_arena.block_arena.get_mut(block).unwrap().instructions.split_off(position);
+    if let ResultBinding::Void = binding {
+        tail.remove(0);
+        module.module_arena.instruction_arena.remove(call);
+        module.module_arena.instruction_spans.remove(&call);
+    } else {
+        *module.module_arena.instruction_arena.get_mut(call).unwrap() = IrInstruction::Ref(prefix.clone());
+    }
+    module.module_arena.block_arena.get_mut(continuation).unwrap().instructions = tail;
+
+    for (param, arg) in function.params.iter().zip(args) {
+        let typ = match module.typ(param.typ) {
+            // parameters without a type are 64-bit integers in the backends
+            IrType::Unknown => int64(module),
+            _ => copier.typ(module, param.typ),
+        };
+        let name = IrTypedName { name: copier.rename(&param.name), typ };
+        module.push_instruction(block, IrInstruction::Let { name, value: arg }, span);
+    }
+    let result = match binding {
+        ResultBinding::Void => None,
+        _ => Some(IrTypedName { name: prefix, typ: copier.typ(module, function.return_type) }),
+    };
+    if let (ResultBinding::Declared(zero), Some(result)) = (&binding, &result) {
+        let zero = module.push_instruction(block, zero.clone(), span);
+        module.push_instruction(block, IrInstruction::Let { name: result.clone(), value: zero }, span);
+    }
+    module.push_instruction(block, IrInstruction::Jump { target: copier.blocks[&function.blocks[0]] }, span);
+
+    let exit = Exit { result, declared: matches!(binding, ResultBinding::Declared(_)), continuation };
+    for old in function.blocks.iter() {
+        let new = copier.blocks[old];
+        copier.copy_block(module, *old, new, Some(&exit));
+        // running off the end of a function that doesn't return a value returns from it
+        if !terminated(module, new) {
+            module.push_instruction(new, IrInstruction::Jump { target: continuation }, span);
+        }
+    }
+
+    let mut blocks: Vec<IrBlockIndex> = function.blocks.iter().map(|old| copier.blocks[old]).collect();
+    blocks.push(continuation);
+    let caller_blocks = &mut module.function_mut(caller).blocks;
+    let after = caller_blocks.iter().position(|b| *b == block).unwrap() + 1;
+    caller_blocks.splice(after..after, blocks);
+    copier.instructions.into_values().collect()
+}
+
+fn int64(module: &mut Module) -> IrTypeIndex {
+    let existing = module.module_arena.type_arena.iter()
+        .find(|(_, typ)| matches!(typ, IrType::Int(IntTy::I64)))
+        .map(|(index, _)| index);
+    existing.unwrap_or_else(|| module.module_arena.type_arena.insert(IrType::Int(IntTy::I64)))
+}
+
+/// Copies the blocks of a function being inlined, renaming its locals so they don't clash
+/// with the caller's.
+struct Copier {
+    renames: HashMap<String, String>,
+    /// The variable of the refinement predicate being copied, which keeps its name.
+    bound: Option<String>,
+    instructions: HashMap<IrInstructionIndex, IrInstructionIndex>,
+    blocks: HashMap<IrBlockIndex, IrBlockIndex>,
+}
+
+/// Where a `return` in an inlined body goes.
+struct Exit {
+    /// The variable the returned value is bound to, unless the callee doesn't return one.
+    result: Option<IrTypedName>,
+    /// Whether the result was declared before the body, so returns assign to it.
+    declared: bool,
+    continuation: IrBlockIndex,
+}
+
+impl Copier {
+    fn rename(&self, name: &str) -> String {
+        match self.renames.get(name) {
+            Some(renamed) if self.bound.as_deref() != Some(name) => renamed.clone(),
+            _ => name.to_string(),
+        }
+    }
+
+    /// Refinement predicates can mention the callee's parameters, which have new names at the
+    /// call site, so the predicate is copied with the new names.
+    fn typ(&mut self, module: &mut Module, typ: IrTypeIndex) -> IrTypeIndex {
+        let (var, inner, predicate) = match module.typ(typ) {
+            IrType::Refinement(var, inner, predicate) => (var.clone(), *inner, *predicate),
+            _ => return typ,
+        };
+        let mentions_locals = module.block(predicate).instructions.iter().any(|ins| match module.instruction(*ins) {
+            IrInstruction::Ref(name) => *name != var && self.renames.contains_key(name),
+            _ => false,
+        });
+        if !mentions_locals {
+            return typ;
+        }
+        let copy = module.module_arena.block_arena.insert(IrBlock::new());
+        let outer = self.bound.replace(var.clone());
+        self.copy_block(module, predicate, copy, None);
+        self.bound = outer;
+        module.module_arena.type_arena.insert(IrType::Refinement(var, inner, copy))
+    }
+
+    /// Copy the instructions of one block to the end of another.
+    fn copy_block(&mut self, module: &mut Module, from: IrBlockIndex, to: IrBlockIndex, exit: Option<&Exit>) {
+        // field names are `Ref`s too, but they don't name locals
+        let fields: HashSet<IrInstructionIndex> = module.block(from).instructions.iter()
+            .filter_map(|ins| match module.instruction(*ins) {
+                IrInstruction::FieldAccessor { value, .. } => Some(*value),
+                _ => None,
+            })
+            .collect();
+        for ins in module.block(from).instructions.clone() {
+            let span = module.instruction_span(ins);
+            let instruction = module.instruction(ins).clone();
+            // the type of a `let` can have a predicate that needs copying too
+            let let_type = match &instruction {
+                IrInstruction::Let { name, .. } => Some(self.typ(module, name.typ)),
+                _ => None,
+            };
+            let operand = |index: &IrInstructionIndex| self.instructions[index];
+            let copied = match instruction {
+                IrInstruction::Ref(name) if fields.contains(&ins) => IrInstruction::Ref(name),
+                IrInstruction::Ref(name) => IrInstruction::Ref(self.rename(&name)),
+                IrInstruction::NatLiteral(n) => IrInstruction::NatLiteral(n),
+                IrInstruction::FloatLiteral(n) => IrInstruction::FloatLiteral(n),
+                IrInstruction::BoolLiteral(b) => IrInstruction::BoolLiteral(b),
+                IrInstruction::StrLiteral(string) => IrInstruction::StrLiteral(string),
+                IrInstruction::CharLiteral(c) => IrInstruction::CharLiteral(c),
+                IrInstruction::BinOp(lhs, op, rhs) => IrInstruction::BinOp(operand(&lhs), op, operand(&rhs)),
+                IrInstruction::UnaryOp(op, value) => IrInstruction::UnaryOp(op, operand(&value)),
+                IrInstruction::FieldAccessor { aggregate, value } => {
+                    IrInstruction::FieldAccessor { aggregate: operand(&aggregate), value: operand(&value) }
+                }
+                IrInstruction::FunctionCall { function, args } => {
+                    IrInstruction::FunctionCall { function: operand(&function), args: args.iter().map(operand).collect() }
+                }
+                IrInstruction::New { typ, allocator } => IrInstruction::New { typ, allocator: operand(&allocator) },
+                IrInstruction::StackAlloc { typ } => IrInstruction::StackAlloc { typ },
+                IrInstruction::RowLiteral { typ, values } => {
+                    IrInstruction::RowLiteral { typ, values: values.iter().map(operand).collect() }
+                }
+                IrInstruction::Dereference { pointer } => IrInstruction::Dereference { pointer: operand(&pointer) },
+                IrInstruction::Index { base, index } => IrInstruction::Index { base: operand(&base), index: operand(&index) },
+                IrInstruction::BoundsCheck { index, len } => IrInstruction::BoundsCheck { index: operand(&index), len: operand(&len) },
+                IrInstruction::Denull { optional } => IrInstruction::Denull { optional: operand(&optional) },
+                IrInstruction::Borrow { value } => IrInstruction::Borrow { value: operand(&value) },
+                IrInstruction::Cast { value, typ } => IrInstruction::Cast { value: operand(&value), typ },
+                IrInstruction::Print { value } => IrInstruction::Print { value: operand(&value) },
+                IrInstruction::Let { name, value } => {
+                    let typ = let_type.unwrap();
+                    IrInstruction::Let { name: IrTypedName { name: self.rename(&name.name), typ }, value: operand(&value) }
+                }
+                IrInstruction::Assign { name, value } => IrInstruction::Assign { name: self.rename(&name), value: operand(&value) },
+                IrInstruction::Store { place, value } => IrInstruction::Store { place: operand(&place), value: operand(&value) },
+                IrInstruction::Branch { condition, true_branch, false_branch } => IrInstruction::Branch {
+                    condition: operand(&condition),
+                    true_branch: self.blocks[&true_branch],
+                    false_branch: self.blocks[&false_branch],
+                },
+                IrInstruction::Jump { target } => IrInstruction::Jump { target: self.blocks[&target] },
+                IrInstruction::Return { value } => {
+                    let exit = exit.expect("predicates don't return");
+                    let value = operand(&value);
+                    let result = match &exit.result {
+                        Some(result) if exit.declared => Some(IrInstruction::Assign { name: result.name.clone(), value }),
+                        Some(result) => Some(IrInstruction::Let { name: result.clone(), value }),
+                        None => None,
+                    };
+                    if let Some(result) = result {
+                        module.push_instruction(to, result, span);
+                    }
+                    IrInstruction::Jump { target: exit.continuation }
+                }
+                IrInstruction::Error => IrInstruction::Error,
+            };
+            let index = module.push_instruction(to, copied, span);
+            self.instructions.insert(ins, index);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::{HashMap, HashSet};
+use crate::ir::{IrBlockIndex, IrInstruction, IrNodeIndex, Module};
+use crate::ir::passes::Pass;
+
+/// Cleans up the control flow of functions. Instructions after a block's terminator are dropped,
+/// branches on `true` or `false` become jumps, branches and jumps to blocks that do nothing but
+/// jump somewhere else go straight to where those blocks would have gone, and blocks that can't be
+/// reached from the entry are removed. This gets rid of the empty `false_branch` of an `if`
+/// without an `else`, the merge block of an `if` where both sides return, and everything that
+/// follows a `return`.
+pub struct SimplifyCfg;
+
+impl Pass for SimplifyCfg {
+    fn name(&self) -> &'static str {
+        "simplify-cfg"
+    }
+
+    fn run(&mut self, module: &mut Module) -> bool {
+        let mut changed = false;
+        for index in module.function_indices() {
+            changed |= self.run_function(module, index);
+        }
+        changed
+    }
+}
+
+impl SimplifyCfg {
+    fn run_function(&mut self, module: &mut Module, index: IrNodeIndex) -> bool {
+        let blocks = module.function_mut(index).blocks.clone();
+        let entry = match blocks.first() {
+            Some(entry) => *entry,
+            None => return false,
+        };
+        let mut changed = false;
+        for block in blocks.iter() {
+            changed |= truncate_after_terminator(module, *block);
+        }
+
+        // blocks that only jump somewhere else, the entry block has to stay where it is
+        let forwards: HashMap<IrBlockIndex, IrBlockIndex> = blocks.iter()
+            .skip(1)
+            .filter_map(|block| match module.block(*block).instructions.as_slice() {
+                [ins] => match module.instruction(*ins) {
+                    IrInstruction::Jump { target } => Some((*block, *target)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        let resolve = |mut block: IrBlockIndex| {
+            let mut seen = HashSet::new();
+            while let Some(target) = forwards.get(&block) {
+                // blocks that jump to each other forever are left alone
+                if !seen.insert(block) {
+                    break;
+                }
+                block = *target;
+            }
+            block
+        };
+
+        for block in blocks.iter() {
+            let terminator = match module.block(*block).instructions.last() {
+                Some(terminator) => *terminator,
+                None => continue,
+            };
+            let rewrite = match module.instruction(terminator) {
+                IrInstruction::Branch { condition, true_branch, false_branch } => {
+                    let (true_target, false_target) = (resolve(*true_branch), resolve(*false_branch));
+                    if let IrInstruction::BoolLiteral(b) = module.instruction(*condition) {
+                        // like the `true` an `else` is translated to
+                        Some(IrInstruction::Jump { target: if *b { true_target } else { false_target } })
+                    } else if true_target == false_target {
+                        // the condition is still evaluated, only the choice goes away
+                        Some(IrInstruction::Jump { target: true_target })
+                    } else if true_target != *true_branch || false_target != *false_branch {
+                        Some(IrInstruction::Branch { condition: *condition, true_branch: true_target, false_branch: false_target })
+                    } else {
+                        None
+                    }
+                }
+                IrInstruction::Jump { target } if resolve(*target) != *target => {
+                    Some(IrInstruction::Jump { target: resolve(*target) })
+                }
+                _ => None,
+            };
+            if let Some(rewrite) = rewrite {
+                *module.module_arena.instruction_arena.get_mut(terminator).unwrap() = rewrite;
+                changed = true;
+            }
+        }
+
+        let mut reachable = HashSet::new();
+        let mut worklist = vec![entry];
+        while let Some(block) = worklist.pop() {
+            if !reachable.insert(block) {
+                continue;
+            }
+            if let Some(terminator) = module.block(block).instructions.last() {
+                worklist.extend(module.instruction(*terminator).successors());
+            }
+        }
+        let unreachable: Vec<IrBlockIndex> = blocks.iter()
+            .filter(|block| !reachable.contains(block))
+            .copied()
+            .collect();
+        if !unreachable.is_empty() {
+            module.function_mut(index).blocks.retain(|block| reachable.contains(block));
+            for block in unreachable {
+                module.module_arena.remove_block(block);
+            }
+            changed = true;
+        }
+        changed
+    }
+}
+
+/// Remove the instructions that follow the first terminator of a block.
+fn truncate_after_terminator(module: &mut Module, block: IrBlockIndex) -> bool {
+    let instructions = &module.block(block).instructions;
+    let end = match instructions.iter().position(|ins| module.instruction(*ins).is_terminator()) {
+        Some(position) if position + 1 < instructions.len() => position + 1,
+        _ => return false,
+    };
+    let arena = &mut module.module_arena;
+    let removed = arena.block_arena.get_mut(block).unwrap().instructions.split_off(end);
+    for ins in removed {
+        arena.instruction_arena.remove(ins);
+        arena.instruction_spans.remove(&ins);
+    }
+    true
+}
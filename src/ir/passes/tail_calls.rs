@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+use crate::ir::{IrInstruction, IrNodeIndex, IrTypedName, Module};
+use crate::ir::passes::Pass;
+
+/// Turns calls a function makes to itself in tail position, like `return loop(n, i + 1, c, f)`,
+/// into assignments to its parameters and a jump back to its first block, so recursion that
+/// stands in for a loop runs in constant stack space.
+///
+/// The new arguments are bound to temporaries before any parameter is assigned, since they can
+/// refer to parameters themselves. Functions that borrow anything are left alone, their frame
+/// can't be reused while something may still point into it.
+pub struct TailCallElimination;
+
+impl Pass for TailCallElimination {
+    fn name(&self) -> &'static str {
+        "tailcall"
+    }
+
+    fn run(&mut self, module: &mut Module) -> bool {
+        let mut names = HashSet::new();
+        let mut duplicates = HashSet::new();
+        for (_, function) in module.functions() {
+            if !names.insert(function.name.clone()) {
+                duplicates.insert(function.name.clone());
+            }
+        }
+
+        let mut changed = false;
+        for index in module.function_indices() {
+            // which function a name refers to isn't clear when more than one has it
+            if !duplicates.contains(&module.function(index).name) {
+                changed |= self.run_function(module, index);
+            }
+        }
+        changed
+    }
+}
+
+impl TailCallElimination {
+    fn run_function(&mut self, module: &mut Module, index: IrNodeIndex) -> bool {
+        let function = module.function(index).clone();
+        if module.has_borrows(&function) {
+            return false;
+        }
+        let locals = module.local_names(&function);
+        let entry = function.blocks[0];
+        let mut changed = false;
+        for block in function.blocks.iter() {
+            let call = match module.tail_call(*block) {
+                Some(call) => call,
+                None => continue,
+            };
+            let args = match module.instruction(call) {
+                IrInstruction::FunctionCall { function: callee, args } => match module.instruction(*callee) {
+                    IrInstruction::Ref(name) if *name == function.name && !locals.contains(name) => args.clone(),
+                    _ => continue,
+                },
+                _ => unreachable!(),
+            };
+            if args.len() != function.params.len() {
+                continue;
+            }
+
+            let span = module.instruction_span(call);
+            // drop the call and the return after it
+            let instructions = &mut module.module_arena.block_arena.get_mut(*block).unwrap().instructions;
+            let removed = instructions.split_off(instructions.len() - 2);
+            for ins in removed {
+                module.module_arena.instruction_arena.remove(ins);
+                module.module_arena.instruction_spans.remove(&ins);
+            }
+
+            let mut assigned = vec![];
+            for (param, arg) in function.params.iter().zip(args) {
+                // passing a parameter on unchanged doesn't need an assignment
+                if matches!(module.instruction(arg), IrInstruction::Ref(name) if *name == param.name) {
+                    continue;
+                }
+                let temporary = format!("_tail_{}", param.name);
+                module.push_instruction(*block, IrInstruction::Let { name: IrTypedName { name: temporary.clone(), typ: param.typ }, value: arg }, span);
+                assigned.push((param.name.clone(), temporary));
+            }
+            for (param, temporary) in assigned {
+                let value = module.push_instruction(*block, IrInstruction::Ref(temporary), span);
+                module.push_instruction(*block, IrInstruction::Assign { name: param, value }, span);
+            }
+            module.push_instruction(*block, IrInstruction::Jump { target: entry }, span);
+            changed = true;
+        }
+        changed
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use crate::ir::*;
+
+/// Instructions print as `%n` and blocks as `bbn`, numbered by their slot in the arena
+/// so the same instruction has the same name before and after a pass.
+fn slot(index: Index) -> usize {
+    index.into_raw_parts().0
+}
+
+impl Module {
+    /// Get a readable name for a type, like `(v: Int32 where bb4)` or `&mut ?X`.
+    pub fn type_name(&self, typ: IrTypeIndex) -> String {
+        match self.typ(typ) {
+            IrType::Bool => "Bool".to_string(),
+            IrType::Int(IntTy::ISize) => "IntSize".to_string(),
+            IrType::Int(i) => format!("Int{}", i.bits()),
+            IrType::UInt(UIntTy::USize) => "USize".to_string(),
+            IrType::UInt(u) => format!("UInt{}", u.bits()),
+            IrType::Float(f) => format!("Float{}", f.bits()),
+            IrType::Base(name) => name.clone(),
+            IrType::Refinement(var, inner, predicate) => {
+                format!("({}: {} where bb{})", var, self.type_name(*inner), slot(*predicate))
+            }
+            IrType::Row(fields) => format!("{{{}}}", self.typed_names(fields)),
+            IrType::Reference(inner, true) => format!("&mut {}", self.type_name(*inner)),
+            IrType::Reference(inner, false) => format!("&{}", self.type_name(*inner)),
+            IrType::Optional(inner) => format!("?{}", self.type_name(*inner)),
+            IrType::Function(params, ret) => {
+                let params: Vec<String> = params.iter().map(|param| self.type_name(*param)).collect();
+                format!("({}) -> {}", params.join(", "), self.type_name(*ret))
+            }
+            IrType::Array(element, len) => format!("[{}; {}]", self.type_name(*element), len),
+            IrType::Void => "Void".to_string(),
+            IrType::Unknown => "_".to_string(),
+        }
+    }
+
+    fn typed_names(&self, names: &[IrTypedName]) -> String {
+        let names: Vec<String> = names.iter()
+            .map(|name| format!("{}: {}", name.name, self.type_name(name.typ)))
+            .collect();
+        names.join(", ")
+    }
+
+    fn fmt_instruction(&self, f: &mut Formatter<'_>, index: IrInstructionIndex) -> fmt::Result {
+        let name = |index: &IrInstructionIndex| format!("%{}", slot(*index));
+        let block = |index: &IrBlockIndex| format!("bb{}", slot(*index));
+        write!(f, "    ")?;
+        match self.instruction(index) {
+            IrInstruction::Let { name: binding, value } => {
+                return writeln!(f, "let {}: {} = {}", binding.name, self.type_name(binding.typ), name(value));
+            }
+            IrInstruction::Assign { name: assigned, value } => return writeln!(f, "{} = {}", assigned, name(value)),
+            IrInstruction::Store { place, value } => return writeln!(f, "store {} to {}", name(value), name(place)),
+            IrInstruction::Branch { condition, true_branch, false_branch } => {
+                return writeln!(f, "branch {}, {}, {}", name(condition), block(true_branch), block(false_branch));
+            }
+            IrInstruction::Jump { target } => return writeln!(f, "jump {}", block(target)),
+            IrInstruction::Return { value } => return writeln!(f, "return {}", name(value)),
+            IrInstruction::Print { value } => return writeln!(f, "print {}", name(value)),
+            IrInstruction::BoundsCheck { index, len } => return writeln!(f, "check {} < {}", name(index), name(len)),
+            _ => write!(f, "{} = ", name(&index))?,
+        }
+        match self.instruction(index) {
+            IrInstruction::Ref(referenced) => writeln!(f, "ref {}", referenced),
+            IrInstruction::NatLiteral(n) => writeln!(f, "{}", n),
+            IrInstruction::FloatLiteral(n) => writeln!(f, "{:?}", n),
+            IrInstruction::BoolLiteral(b) => writeln!(f, "{}", b),
+            IrInstruction::StrLiteral(string) => writeln!(f, "{:?}", string),
+            IrInstruction::CharLiteral(c) => writeln!(f, "{:?}", c),
+            IrInstruction::BinOp(lhs, op, rhs) => writeln!(f, "{} {} {}", name(lhs), op, name(rhs)),
+            IrInstruction::UnaryOp(op, value) => writeln!(f, "{} {}", op, name(value)),
+            IrInstruction::FieldAccessor { aggregate, value } => writeln!(f, "{}.{}", name(aggregate), name(value)),
+            IrInstruction::FunctionCall { function, args } => {
+                let args: Vec<String> = args.iter().map(name).collect();
+                writeln!(f, "call {}({})", name(function), args.join(", "))
+            }
+            IrInstruction::New { typ, allocator } => writeln!(f, "new {} in {}", self.type_name(*typ), name(allocator)),
+            IrInstruction::StackAlloc { typ } => writeln!(f, "new {} on stack", self.type_name(*typ)),
+            IrInstruction::RowLiteral { typ, values } => {
+                let values: Vec<String> = values.iter().map(name).collect();
+                writeln!(f, "{} {{{}}}", self.type_name(*typ), values.join(", "))
+            }
+            IrInstruction::Dereference { pointer } => writeln!(f, "{}.*", name(pointer)),
+            IrInstruction::Index { base, index } => writeln!(f, "{}[{}]", name(base), name(index)),
+            IrInstruction::Denull { optional } => writeln!(f, "{}.?", name(optional)),
+            IrInstruction::Borrow { value } => writeln!(f, "{}.&", name(value)),
+            IrInstruction::Cast { value, typ } => writeln!(f, "{} as {}", name(value), self.type_name(*typ)),
+            IrInstruction::Error => writeln!(f, "error"),
+            _ => unreachable!(),
+        }
+    }
+
+    fn fmt_block(&self, f: &mut Formatter<'_>, index: IrBlockIndex) -> fmt::Result {
+        writeln!(f, "  bb{}:", slot(index))?;
+        for ins in self.block(index).instructions.iter() {
+            self.fmt_instruction(f, *ins)?;
+        }
+        Ok(())
+    }
+}
+
+/// Print the module's IR as text, for `--print-after`.
+impl Display for Module {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "module {}", self.name)?;
+        for (_, node) in self.module_arena.node_arena.iter() {
+            writeln!(f)?;
+            match node {
+                IrNode::Function(function) => {
+                    for attribute in function.attributes.iter() {
+                        write!(f, "@{} ", attribute.name())?;
+                    }
+                    let access = if let Access::Public = function.access { "public " } else { "" };
+                    writeln!(f, "{}fun {}({}): {} {{", access, function.name,
+                             self.typed_names(&function.params), self.type_name(function.return_type))?;
+                    for block in function.blocks.iter() {
+                        self.fmt_block(f, *block)?;
+                    }
+                    writeln!(f, "}}")?;
+                }
+                IrNode::Struct { name, fields, .. } => writeln!(f, "struct {} {{{}}}", name, self.typed_names(fields))?,
+                IrNode::Enum { name, variants } => {
+                    let variants: Vec<String> = variants.iter()
+                        .map(|variant| format!("{}({})", variant.name, self.typed_names(&variant.fields)))
+                        .collect();
+                    writeln!(f, "enum {} {{{}}}", name, variants.join(", "))?;
+                }
+                IrNode::Error => writeln!(f, "error")?,
+            }
+        }
+
+        // refinement predicates live in blocks that don't belong to any function
+        for (index, typ) in self.module_arena.type_arena.iter() {
+            if let IrType::Refinement(_, _, predicate) = typ {
+                writeln!(f, "\npredicate of {}", self.type_name(index))?;
+                self.fmt_block(f, *predicate)?;
+            }
+        }
+        Ok(())
+    }
+}
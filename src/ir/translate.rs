@@ -1,77 +1,256 @@
 use std::borrow::Borrow;
-use crate::ast::{AstFunction, Expression, Node, Program, ProgramArena, Statement, StatementIndex, Type, TypedName, TypeIndex};
+use std::collections::{HashMap, HashSet};
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+use crate::ast::{AstFunction, BinOpType, EnumVariant, Expression, ExpressionIndex, Node, NodeIndex, Program, ProgramArena, Receiver, Span, Statement, StatementIndex, Type, TypedName, TypeIndex, UnaryOpType};
+use crate::diagnostic::{DiagnosticManager, FileId};
 use crate::ir;
 use crate::ir::*;
 
+/// The receiver, parameters, return type and body of a function.
+type Signature<'p> = (Option<Receiver>, &'p [TypedName], TypeIndex, Option<&'p [StatementIndex]>);
+
+/// The parameter the code of a closure gets its environment as, which no variable can be called.
+const ENVIRONMENT: &str = "closure::env";
+
 pub struct IrBuilderContext<'ctx> {
     program: &'ctx Program,
     module_arena: ModuleArena,
     void_index: IrTypeIndex,
     unknown_index: IrTypeIndex,
+    /// The `Str` type, once a string literal or the type name is used.
+    str_index: Option<IrTypeIndex>,
+    /// The `Float64` type of untyped float literals, once one is used.
+    float_index: Option<IrTypeIndex>,
+    /// Blocks belonging to the function currently being built.
+    function_blocks: Vec<IrBlockIndex>,
+    /// Whether the function currently being built returns a value.
+    returns_value: bool,
+    /// The types AST types have been built as, so each is only built once.
+    ast_types: HashMap<TypeIndex, IrTypeIndex>,
+    /// Return type of the function currently being built.
+    return_type: Option<IrTypeIndex>,
+    /// Types of the parameters and locals of the function currently being built, as far as
+    /// they're known.
+    local_types: HashMap<String, IrTypeIndex>,
+    /// Names of the parameters and locals of the function currently being built, whether or not
+    /// their types are known.
+    locals: HashSet<String>,
+    /// Untyped locals of the function currently being built that are bound to an integer
+    /// literal, so they're numbers even though their type isn't known.
+    integer_locals: HashSet<String>,
+    /// Name of the function currently being built, which the lambdas in it are named after.
+    function_name: String,
+    /// Number of lambdas built so far in the function currently being built.
+    lambdas: usize,
+    /// Functions that have been used as values, whose closure code has been built.
+    function_values: HashSet<String>,
+    /// Type the expression about to be built is used as, which integer literals have to fit and
+    /// row literals take.
+    expected_type: Option<IrTypeIndex>,
+    /// Span of the source code instructions are currently being built for.
+    current_span: Option<Span>,
+    diagnostics: &'ctx mut DiagnosticManager,
+    file_id: Option<FileId>,
 }
 
 impl<'ctx> IrBuilderContext<'ctx> {
-    pub fn new(program: &'ctx Program) -> IrBuilderContext {
+    pub fn new(program: &'ctx Program, diagnostics: &'ctx mut DiagnosticManager) -> IrBuilderContext<'ctx> {
         let mut module_arena = ModuleArena::new();
 
         let void_index = module_arena.type_arena.insert(IrType::Void);
         let unknown_index = module_arena.type_arena.insert(IrType::Unknown);
+        let file_id = diagnostics.get_file_id(&program.source_name);
 
         IrBuilderContext {
             program,
-            module_arena: ModuleArena::new(),
+            module_arena,
             void_index,
             unknown_index,
+            str_index: None,
+            float_index: None,
+            function_blocks: vec![],
+            returns_value: false,
+            ast_types: HashMap::new(),
+            return_type: None,
+            local_types: HashMap::new(),
+            locals: HashSet::new(),
+            integer_locals: HashSet::new(),
+            function_name: String::new(),
+            lambdas: 0,
+            function_values: HashSet::new(),
+            expected_type: None,
+            current_span: None,
+            diagnostics,
+            file_id,
         }
     }
 
+    fn error(&mut self, message: &str, span: Option<Span>, label: &str) {
+        let mut diagnostic = Diagnostic::error().with_message(message);
+        if let (Some(file_id), Some(span)) = (self.file_id, span) {
+            diagnostic = diagnostic.with_labels(vec![Label::primary(file_id, span).with_message(label)]);
+        }
+        self.diagnostics.add_diagnostic(diagnostic);
+    }
+
+    fn str_type(&mut self) -> IrTypeIndex {
+        let module_arena = &mut self.module_arena;
+        *self.str_index.get_or_insert_with(|| module_arena.type_arena.insert(IrType::Base(STR_TYPE.to_string())))
+    }
+
+    fn float_literal_type(&mut self) -> IrTypeIndex {
+        let module_arena = &mut self.module_arena;
+        *self.float_index.get_or_insert_with(|| module_arena.type_arena.insert(IrType::Float(FloatTy::F64)))
+    }
+
+    /// Warn about an attribute nuvae doesn't know, which is ignored.
+    fn warn_unknown_attribute(&mut self, attribute: &str, span: Span) {
+        let file_id = match self.file_id {
+            Some(file_id) => file_id,
+            None => return,
+        };
+        self.diagnostics.add_diagnostic(Diagnostic::warning()
+            .with_message(format!("unknown attribute `@{}`", attribute))
+            .with_labels(vec![Label::primary(file_id, span).with_message("this attribute is ignored")]));
+    }
+
+    /// Warn about a statement that can never run because it follows a `return`, or an `if` whose
+    /// branches all return.
+    fn warn_unreachable(&mut self, statement: StatementIndex, previous: Option<StatementIndex>) {
+        let (file_id, span) = match (self.file_id, self.program.statement_span(statement)) {
+            (Some(file_id), Some(span)) => (file_id, span),
+            _ => return,
+        };
+        let mut labels = vec![Label::primary(file_id, span).with_message("unreachable statement")];
+        if let Some(previous_span) = previous.and_then(|s| self.program.statement_span(s)) {
+            let message = match self.program.statement(previous.unwrap()) {
+                Statement::If { .. } => "every branch of this `if` returns",
+                _ => "any code following this `return` is unreachable",
+            };
+            labels.push(Label::secondary(file_id, previous_span).with_message(message));
+        }
+        self.diagnostics.add_diagnostic(Diagnostic::warning()
+            .with_message("unreachable statement")
+            .with_labels(labels));
+    }
+
+    /// Create a new block that is part of the function currently being built.
     pub fn new_block(&mut self) -> IrBlockIndex {
+        let index = self.new_detached_block();
+        self.function_blocks.push(index);
+        index
+    }
+
+    /// Create a new block that doesn't belong to any function, like the predicate of a refinement.
+    pub fn new_detached_block(&mut self) -> IrBlockIndex {
         self.module_arena.block_arena.insert(IrBlock::new())
     }
 
+    pub fn is_terminated(&self, block: IrBlockIndex) -> bool {
+        let block = self.module_arena.block_arena.get(block).unwrap();
+        block.instructions.iter().any(|ins| self.module_arena.instruction_arena.get(*ins).unwrap().is_terminator())
+    }
+
+    /// Whether `block` can be reached from the start of the function currently being built.
+    /// `else` is built as an `if true`, whose false branch is never taken.
+    pub fn is_reached(&self, block: IrBlockIndex) -> bool {
+        let mut seen = HashSet::new();
+        let mut pending: Vec<IrBlockIndex> = self.function_blocks.first().copied().into_iter().collect();
+        while let Some(from) = pending.pop() {
+            if from == block {
+                return true;
+            }
+            if !seen.insert(from) {
+                continue;
+            }
+            for ins in self.module_arena.block_arena.get(from).unwrap().instructions.iter() {
+                match self.module_arena.instruction_arena.get(*ins).unwrap() {
+                    IrInstruction::Branch { condition, true_branch, .. }
+                        if matches!(self.module_arena.instruction_arena.get(*condition), Some(IrInstruction::BoolLiteral(true))) => {
+                        pending.push(*true_branch);
+                    }
+                    ins => pending.extend(ins.successors()),
+                }
+            }
+        }
+        false
+    }
+
     /// Insert an instruction into the instruction arena and add its index to the provided block.
     /// This ensures that all IrInstructions are allocated into some IrBlock.
     /// The returned index can be used in other instructions.
     pub fn ins(&mut self, block: IrBlockIndex, ins: IrInstruction) -> IrInstructionIndex {
         let index = self.module_arena.instruction_arena.insert(ins);
         self.module_arena.block_arena.get_mut(block).unwrap().instructions.push(index);
+        if let Some(span) = self.current_span {
+            self.module_arena.instruction_spans.insert(index, span);
+        }
         index
     }
 }
 
-pub struct IrBuilder {}
+pub struct IrBuilder {
+    /// Whether `+`, `-` and `*` trap on overflow, otherwise they're built as their wrapping versions.
+    overflow_checks: bool,
+}
 
 impl IrBuilder {
-    pub fn new() -> IrBuilder {
-        IrBuilder {}
+    pub fn new(overflow_checks: bool) -> IrBuilder {
+        IrBuilder { overflow_checks }
     }
 
-    pub fn convert(&self, program: Program) -> Module {
-        let mut ctx = IrBuilderContext::new(&program);
-        for (_index, node) in program.program_arena.node_arena.iter() {
+    pub fn convert(&self, program: Program, diagnostics: &mut DiagnosticManager) -> Module {
+        let mut ctx = IrBuilderContext::new(&program, diagnostics);
+        for (index, node) in program.program_arena.node_arena.iter() {
             match node {
                 Node::TypeAlias { .. } => {}
                 Node::Variable { .. } => {}
+                // methods are built with their struct
+                Node::Function(_) if program.node_owner(index).is_some() => {}
                 Node::Function(ast_function) => {
-                    let node = self.build_function(&mut ctx, ast_function);
+                    let node = self.build_function(&mut ctx, ast_function, program.node_span(index), None);
                     ctx.module_arena.node_arena.insert(node);
                 }
                 Node::FunctionPrototype { .. } => {}
-                Node::Struct { .. } => {}
-                Node::Enum { .. } => {}
+                Node::Struct { name, children, .. } if name == STR_TYPE => {
+                    ctx.error("`Str` is a built-in type", program.node_span(index), "`Str` can't be redefined");
+                }
+                Node::Struct { name, children, .. } => {
+                    let node = self.build_struct(&mut ctx, name, children);
+                    ctx.module_arena.node_arena.insert(node);
+                }
+                Node::Enum { name, variants, .. } => {
+                    let node = self.build_enum(&mut ctx, name, variants);
+                    ctx.module_arena.node_arena.insert(node);
+                }
                 Node::Interface { .. } => {}
                 Node::Error => {}
             }
         }
+        if ctx.str_index.is_some() {
+            let node = self.build_str_struct(&mut ctx);
+            ctx.module_arena.node_arena.insert(node);
+        }
         Module {
             path: program.path.clone(),
             name: program.file_name.clone(),
+            source_name: program.source_name.clone(),
+            line_starts: codespan_reporting::files::line_starts(&program.source).collect(),
             imports: program.imports.clone(),
             module_arena: ctx.module_arena,
         }
     }
 
     fn build_type(&self, ctx: &mut IrBuilderContext, ast_type: &TypeIndex) -> IrTypeIndex {
+        if let Some(typ) = ctx.ast_types.get(ast_type) {
+            return *typ;
+        }
+        let typ = self.build_new_type(ctx, ast_type);
+        ctx.ast_types.insert(*ast_type, typ);
+        typ
+    }
+
+    fn build_new_type(&self, ctx: &mut IrBuilderContext, ast_type: &TypeIndex) -> IrTypeIndex {
         if let Some(ast_type) = ctx.program.program_arena.type_arena.get(*ast_type) {
             match ast_type {
                 Type::Base(name) => {
@@ -81,17 +260,51 @@ impl IrBuilder {
                         ctx.module_arena.type_arena.insert(IrType::UInt(int_type))
                     } else if let Some(float_type) = FloatTy::from(&name.name) {
                         ctx.module_arena.type_arena.insert(IrType::Float(float_type))
+                    } else if "Bool" == name.name {
+                        ctx.module_arena.type_arena.insert(IrType::Bool)
                     } else if "Void" == name.name {
                         ctx.void_index
+                    } else if STR_TYPE == name.name {
+                        ctx.str_type()
                     } else {
-                        ctx.unknown_index
+                        ctx.module_arena.type_arena.insert(IrType::Base(name.name.clone()))
+                    }
+                }
+                Type::Refinement(var, inner, predicate) => {
+                    let inner_type = self.build_type(ctx, inner);
+                    let mut predicate_block = ctx.new_detached_block();
+                    self.build_expression(ctx, predicate, &mut predicate_block);
+                    ctx.module_arena.type_arena.insert(IrType::Refinement(var.clone(), inner_type, predicate_block))
+                }
+                Type::Row(fields) => {
+                    let fields = fields.iter().map(|field| self.build_typed_name(ctx, field)).collect();
+                    ctx.module_arena.type_arena.insert(IrType::Row(fields))
+                }
+                Type::Reference(inner, mutable) => {
+                    let inner_type = self.build_type(ctx, inner);
+                    ctx.module_arena.type_arena.insert(IrType::Reference(inner_type, *mutable))
+                }
+                Type::Optional(inner) => {
+                    let inner_type = self.build_type(ctx, inner);
+                    ctx.module_arena.type_arena.insert(IrType::Optional(inner_type))
+                }
+                Type::Function(args, ret) => {
+                    let args = args.iter().map(|arg| self.build_type(ctx, arg)).collect();
+                    let ret = self.build_type(ctx, ret);
+                    self.closure_type(ctx, args, ret)
+                }
+                Type::Array(element, len) => {
+                    let element = self.build_type(ctx, element);
+                    if *len > u64::MAX as u128 {
+                        let span = ctx.current_span;
+                        ctx.error(&format!("array length `{}` doesn't fit in 64 bits", len), span, "arrays can't be this long");
                     }
+                    ctx.module_arena.type_arena.insert(IrType::Array(element, *len as u64))
+                }
+                Type::Slice(element) => {
+                    let element = self.build_type(ctx, element);
+                    self.slice_type(ctx, element)
                 }
-                Type::Refinement(_, _, _) => ctx.unknown_index,
-                Type::Row(_) => ctx.unknown_index,
-                Type::Reference(_, _) => ctx.unknown_index,
-                Type::Optional(_) => ctx.unknown_index,
-                Type::Function(_, _) => ctx.unknown_index,
             }
         } else {
             ctx.unknown_index
@@ -100,36 +313,181 @@ impl IrBuilder {
 
     fn build_typed_name(&self, ctx: &mut IrBuilderContext, ast_typed_name: &TypedName) -> IrTypedName {
         IrTypedName {
-            typ: ast_typed_name.typ.map_or(ctx.void_index, |ty| self.build_type(ctx, &ty)),
+            typ: ast_typed_name.typ.map_or(ctx.unknown_index, |ty| self.build_type(ctx, &ty)),
             name: ast_typed_name.name.clone(),
         }
     }
 
-    fn build_function(&self, ctx: &mut IrBuilderContext, func: &AstFunction) -> IrNode {
-        let mut blocks = vec![];
+    /// Build a struct and its methods, which become functions named after both.
+    fn build_struct(&self, ctx: &mut IrBuilderContext, name: &str, children: &[NodeIndex]) -> IrNode {
+        let mut fields = vec![];
+        let mut nodes = vec![];
+        for child in children {
+            match ctx.program.program_arena.node_arena.get(*child) {
+                Some(Node::Variable { name, .. }) => fields.push(self.build_typed_name(ctx, name)),
+                Some(Node::Function(function)) => {
+                    let method = self.build_function(ctx, function, ctx.program.node_span(*child), Some(name));
+                    nodes.push(ctx.module_arena.node_arena.insert(method));
+                }
+                _ => {}
+            }
+        }
+        IrNode::Struct {
+            name: name.to_string(),
+            fields,
+            nodes,
+        }
+    }
+
+    /// The struct behind `Str`, which only modules that use strings get.
+    fn build_str_struct(&self, ctx: &mut IrBuilderContext) -> IrNode {
+        let byte = ctx.module_arena.type_arena.insert(IrType::UInt(UIntTy::U8));
+        let data = ctx.module_arena.type_arena.insert(IrType::Reference(byte, false));
+        let len = ctx.module_arena.type_arena.insert(IrType::UInt(UIntTy::USize));
+        IrNode::Struct {
+            name: STR_TYPE.to_string(),
+            fields: vec![
+                IrTypedName { typ: data, name: "data".to_string() },
+                IrTypedName { typ: len, name: "len".to_string() },
+            ],
+            nodes: vec![],
+        }
+    }
+
+    fn build_enum(&self, ctx: &mut IrBuilderContext, name: &str, variants: &[EnumVariant]) -> IrNode {
+        let variants = variants.iter().map(|variant| IrEnumVariant {
+            name: variant.name.clone(),
+            fields: variant.params.iter().map(|param| self.build_typed_name(ctx, param)).collect(),
+        }).collect();
+        IrNode::Enum {
+            name: name.to_string(),
+            variants,
+        }
+    }
+
+    /// Build a function, or a method of the struct `owner`, which is named `Owner::name` and
+    /// takes its receiver as the parameter `self`.
+    fn build_function(&self, ctx: &mut IrBuilderContext, func: &AstFunction, span: Option<Span>, owner: Option<&str>) -> IrNode {
+        ctx.function_blocks = vec![];
+        ctx.returns_value = false;
+        ctx.current_span = span;
+        ctx.return_type = Some(self.build_type(ctx, &func.return_type));
+        let name = match owner {
+            Some(owner) => format!("{}::{}", owner, func.name),
+            None => func.name.clone(),
+        };
+        let mut params = vec![];
+        match (func.receiver, owner) {
+            (Some(receiver), Some(owner)) => params.push(IrTypedName {
+                typ: self.receiver_type(ctx, owner, receiver),
+                name: "self".to_string(),
+            }),
+            (Some(_), None) => ctx.error("only methods take `self`", span, "this function isn't declared inside a struct"),
+            (None, _) => {}
+        }
+        params.extend(func.params.iter().map(|param| self.build_typed_name(ctx, param)));
+        ctx.local_types = params.iter()
+            .filter(|param| param.typ != ctx.unknown_index)
+            .map(|param| (param.name.clone(), param.typ))
+            .collect();
+        ctx.locals = params.iter().map(|param| param.name.clone()).collect();
+        ctx.integer_locals.clear();
+        ctx.function_name = name.clone();
+        ctx.lambdas = 0;
+        let mut attributes = vec![];
+        for attribute in func.attributes.iter() {
+            match ir::Attribute::from(&attribute.name) {
+                Some(attribute) => attributes.push(attribute),
+                None => ctx.warn_unknown_attribute(&attribute.name, attribute.span),
+            }
+        }
         let mut current_block = ctx.new_block();
-        blocks.push(current_block.clone());
+        self.build_statements(ctx, &func.statements, &mut current_block);
 
-        for s_index in &func.statements {
-            self.build_statement(ctx, func, s_index, &mut current_block);
+        // functions without a return type that return a value have their return type inferred
+        let mut return_type = self.build_type(ctx, &func.return_type);
+        if return_type == ctx.void_index && ctx.returns_value {
+            return_type = ctx.unknown_index;
+        }
+        if attributes.contains(&ir::Attribute::TailRec) {
+            self.check_tail_recursion(ctx, &name, &params);
         }
+
         IrNode::Function(IrFunction {
             access: Access::from(func.access),
-            name: func.name.clone(),
+            attributes,
+            name,
             type_params: vec![],
-            return_type: self.build_type(ctx, &func.return_type),
-            blocks,
+            params,
+            return_type,
+            blocks: std::mem::take(&mut ctx.function_blocks),
+            span,
         })
     }
 
-    fn build_statement(&self, ctx: &mut IrBuilderContext, func: &AstFunction, s_index: &StatementIndex, current_block: &mut IrBlockIndex) {
-        let stmt = ctx.program.statement(s_index.clone());
+    /// Report what keeps the calls a `@tailrec` function makes to itself from becoming jumps:
+    /// calls whose result is used for something else than returning it, and borrows, which
+    /// could point into the frame the jump reuses.
+    fn check_tail_recursion(&self, ctx: &mut IrBuilderContext, name: &str, params: &[IrTypedName]) {
+        let arena = &ctx.module_arena;
+        let instruction = |index: &IrInstructionIndex| arena.instruction_arena.get(*index).unwrap();
+        let mut locals: Vec<&String> = params.iter().map(|param| &param.name).collect();
+        let mut problems = vec![];
+        for block in ctx.function_blocks.iter() {
+            let instructions = &arena.block_arena.get(*block).unwrap().instructions;
+            for (i, ins) in instructions.iter().enumerate() {
+                let span = arena.instruction_spans.get(ins).copied();
+                match instruction(ins) {
+                    IrInstruction::Let { name, .. } => locals.push(&name.name),
+                    IrInstruction::Borrow { .. } => {
+                        problems.push(("`@tailrec` functions can't borrow values", span, "the frame can't be reused while this could point into it"));
+                    }
+                    IrInstruction::FunctionCall { function, args } => {
+                        let recursive = matches!(instruction(function), IrInstruction::Ref(callee) if callee == name && !locals.contains(&callee));
+                        let returned = matches!(instructions.get(i + 1).map(instruction), Some(IrInstruction::Return { value }) if value == ins);
+                        if recursive && !returned {
+                            problems.push(("recursive call isn't in tail position", span, "the result of this call is used after it returns"));
+                        } else if recursive && args.len() != params.len() {
+                            problems.push(("recursive call has the wrong number of arguments", span, "this call can't be turned into a jump"));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for (message, span, label) in problems {
+            ctx.error(message, span, label);
+        }
+    }
+
+    /// Build a list of statements, warning about the first one that follows a `return` or an `if`
+    /// whose branches all return. Statements that can't run are still built, the passes remove them.
+    fn build_statements(&self, ctx: &mut IrBuilderContext, statements: &[StatementIndex], current_block: &mut IrBlockIndex) {
+        let mut previous = None;
+        let mut warned = false;
+        for s_index in statements {
+            // only an `if` leaves a block nothing jumps to behind it
+            let after_if = previous.is_some_and(|s| matches!(ctx.program.statement(s), Statement::If { .. }));
+            if !warned && (ctx.is_terminated(*current_block) || (after_if && !ctx.is_reached(*current_block))) {
+                ctx.warn_unreachable(*s_index, previous);
+                warned = true;
+            }
+            self.build_statement(ctx, s_index, current_block);
+            previous = Some(*s_index);
+        }
+    }
+
+    fn build_statement(&self, ctx: &mut IrBuilderContext, s_index: &StatementIndex, current_block: &mut IrBlockIndex) {
+        let stmt = ctx.program.statement(*s_index);
+        let outer_span = ctx.current_span;
+        ctx.current_span = ctx.program.statement_span(*s_index).or(outer_span);
         match stmt {
             Statement::If { condition, body, else_if } => {
-                let cond_ins = self.build_expression(ctx, func, stmt, condition, current_block);
+                let cond_ins = self.build_expression(ctx, condition, current_block);
                 // make the blocks we can branch to
                 let true_branch = ctx.new_block();
                 let false_branch = ctx.new_block();
+                let merge_block = ctx.new_block();
 
                 // add the branch ins to the current block
                 let branch = IrInstruction::Branch {
@@ -141,92 +499,1415 @@ impl IrBuilder {
 
                 // build the true block
                 *current_block = true_branch;
-                for stmt in body {
-                    self.build_statement(ctx, func, stmt, current_block);
+                self.build_statements(ctx, body, current_block);
+                if !ctx.is_terminated(*current_block) {
+                    ctx.ins(*current_block, IrInstruction::Jump { target: merge_block });
                 }
 
                 // build the false block
                 *current_block = false_branch;
                 if let Some(stmt) = else_if {
-                    self.build_statement(ctx, func, stmt, current_block);
+                    self.build_statement(ctx, stmt, current_block);
+                }
+                if !ctx.is_terminated(*current_block) {
+                    ctx.ins(*current_block, IrInstruction::Jump { target: merge_block });
                 }
+
+                // continue after the if
+                *current_block = merge_block;
             }
             Statement::Call { function, args } => {
-                let fun_ins = self.build_expression(ctx, func, stmt, function, current_block);
-                let mut arg_insx = Vec::with_capacity(args.len());
-                for arg in args {
-                    let arg_ins = self.build_expression(ctx, func, stmt, arg, current_block);
-                    arg_insx.push(arg_ins);
-                }
-                ctx.ins(*current_block, IrInstruction::FunctionCall {
-                    function: fun_ins,
-                    args: arg_insx,
+                let call = self.build_call(ctx, function, args, current_block);
+                ctx.ins(*current_block, call);
+            }
+            Statement::Expression { value } => {
+                self.build_expression(ctx, value, current_block);
+            }
+            Statement::Let { name, value } => {
+                let typ = match name.typ {
+                    Some(typ) => Some(self.build_type(ctx, &typ)),
+                    None => self.static_type(ctx, value),
+                };
+                let value_ins = self.build_expected(ctx, value, typ, current_block);
+                match typ {
+                    Some(typ) => ctx.local_types.insert(name.name.clone(), typ),
+                    None => ctx.local_types.remove(&name.name),
+                };
+                ctx.locals.insert(name.name.clone());
+                let integer = matches!(ctx.program.expression(*value), Expression::NatLiteral(_) | Expression::CharLiteral(_));
+                if typ.is_none() && integer {
+                    ctx.integer_locals.insert(name.name.clone());
+                } else {
+                    ctx.integer_locals.remove(&name.name);
+                }
+                // untyped bindings get the type of their value when it's known
+                let name = IrTypedName {
+                    typ: typ.unwrap_or(ctx.unknown_index),
+                    name: name.name.clone(),
+                };
+                ctx.ins(*current_block, IrInstruction::Let {
+                    name,
+                    value: value_ins,
                 });
             }
-            Statement::Let { .. } => {}
-            Statement::Assign { .. } => {}
+            Statement::Assign { place, value } => match ctx.program.expression(*place) {
+                Expression::Ref(name) => {
+                    let typ = ctx.local_types.get(name).copied();
+                    let value_ins = self.build_expected(ctx, value, typ, current_block);
+                    ctx.ins(*current_block, IrInstruction::Assign {
+                        name: name.clone(),
+                        value: value_ins,
+                    });
+                }
+                Expression::FieldAccessor { value: field, .. } if matches!(ctx.program.expression(*field), Expression::Ref(_)) => {
+                    self.build_store(ctx, place, value, current_block);
+                }
+                Expression::Dereference { .. } | Expression::Index { .. } => self.build_store(ctx, place, value, current_block),
+                _ => {
+                    let span = ctx.program.expression_span(*place);
+                    ctx.error("can't assign to this", span, "only variables, fields, elements and dereferenced pointers can be assigned to");
+                }
+            },
             Statement::Return { value } => {
-                let value_ins = self.build_expression(ctx, func, stmt, value, current_block);
+                let typ = ctx.return_type;
+                let value_ins = self.build_expected(ctx, value, typ, current_block);
+                ctx.returns_value = true;
                 ctx.ins(*current_block, IrInstruction::Return {
                     value: value_ins
                 });
             }
         }
+        ctx.current_span = outer_span;
     }
 
-    fn build_expression(&self, ctx: &mut IrBuilderContext, func: &AstFunction,
-                        stmt: &Statement, exp: &ExpressionIndex, current_block: &mut IrBlockIndex) -> IrInstructionIndex {
-        let exp = ctx.program.expression(*exp);
-        // let todo = IrInstruction::Ref("TODO".to_string());
-        let ins = match exp {
-            Expression::Ref(s) => IrInstruction::Ref(s.clone()),
-            Expression::NatLiteral(i) => IrInstruction::NatLiteral(i.clone()),
-            Expression::BoolLiteral(b) => IrInstruction::BoolLiteral(b.clone()),
-            Expression::BinOp(lhs, op, rhs) => {
-                let lhs_ins = self.build_expression(ctx, func, stmt, lhs, current_block);
-                let rhs_ins = self.build_expression(ctx, func, stmt, rhs, current_block);
-                IrInstruction::BinOp(lhs_ins, op.clone(), rhs_ins)
-            }
+    /// Whether `exp` is known not to be a reference, because its type is known and isn't one or
+    /// it's an integer.
+    fn not_reference(&self, ctx: &mut IrBuilderContext, exp: &ExpressionIndex) -> bool {
+        match ctx.program.expression(*exp) {
+            Expression::NatLiteral(_) | Expression::CharLiteral(_) => return true,
+            Expression::Ref(name) if ctx.integer_locals.contains(name) => return true,
+            _ => {}
+        }
+        let typ = self.static_type(ctx, exp);
+        typ.is_some_and(|typ| !matches!(ctx.module_arena.type_arena.get(typ), Some(IrType::Reference(..))))
+    }
+
+    /// Whether `exp` is somewhere a borrow can point to. Fields of rows aren't, since a row is
+    /// copied when one of its fields is written, and neither are temporaries like the results of
+    /// calls, which don't outlive the expression.
+    fn is_borrowable(&self, ctx: &mut IrBuilderContext, exp: &ExpressionIndex) -> bool {
+        match ctx.program.expression(*exp) {
+            Expression::Ref(name) => ctx.locals.contains(name),
+            Expression::Dereference { .. } | Expression::Index { .. } => true,
             Expression::FieldAccessor { aggregate, value } => {
-                let agg_ins = self.build_expression(ctx, func, stmt, aggregate, current_block);
-                let value_ins = self.build_expression(ctx, func, stmt, value, current_block);
-                IrInstruction::FieldAccessor {
-                    aggregate: agg_ins,
-                    value: value_ins,
+                matches!(ctx.program.expression(*value), Expression::Ref(_)) && self.struct_behind(ctx, aggregate).is_some()
+            }
+            _ => false,
+        }
+    }
+
+    /// Build an assignment to a field or through a pointer. The value is built first, then the
+    /// place it's stored to.
+    fn build_store(&self, ctx: &mut IrBuilderContext, place: &ExpressionIndex, value: &ExpressionIndex,
+                   current_block: &mut IrBlockIndex) {
+        self.check_writable(ctx, place, "assign");
+        let typ = self.static_type(ctx, place);
+        let value_ins = self.build_expected(ctx, value, typ, current_block);
+        let place_ins = self.build_expression(ctx, place, current_block);
+        ctx.ins(*current_block, IrInstruction::Store {
+            place: place_ins,
+            value: value_ins,
+        });
+    }
+
+    /// Report a store or a `&mut` borrow through a `&` reference, which only lets what it points
+    /// to be read. `action` says which it is.
+    fn check_writable(&self, ctx: &mut IrBuilderContext, place: &ExpressionIndex, action: &str) {
+        let pointer = match ctx.program.expression(*place) {
+            Expression::Dereference { pointer } => *pointer,
+            // the elements of arrays and slices are behind a pointer, like the fields of structs,
+            // so they're only writable through a dereferenced pointer when it is
+            Expression::Index { aggregate, .. } => {
+                let aggregate = *aggregate;
+                if matches!(ctx.program.expression(aggregate), Expression::Dereference { .. }) {
+                    return self.check_writable(ctx, &aggregate, action);
                 }
+                aggregate
             }
-            Expression::FunctionCall { function, args } => {
-                let fun_ins = self.build_expression(ctx, func, stmt, function, current_block);
-                let mut arg_insx = Vec::with_capacity(args.len());
-                for arg in args {
-                    let arg_ins = self.build_expression(ctx, func, stmt, arg, current_block);
-                    arg_insx.push(arg_ins);
+            Expression::FieldAccessor { aggregate, .. } => {
+                let aggregate = *aggregate;
+                let typ = self.static_type(ctx, &aggregate);
+                // rows are stored where they are, so that place has to be writable
+                if matches!(typ.and_then(|typ| ctx.module_arena.type_arena.get(typ)), Some(IrType::Row(_))) {
+                    return self.check_writable(ctx, &aggregate, action);
                 }
-                IrInstruction::FunctionCall {
-                    function: fun_ins,
-                    args: arg_insx,
+                aggregate
+            }
+            _ => return,
+        };
+        let typ = self.static_type(ctx, &pointer);
+        if matches!(typ.and_then(|typ| ctx.module_arena.type_arena.get(typ)), Some(IrType::Reference(_, false))) {
+            let span = ctx.program.expression_span(pointer);
+            ctx.error(&format!("can't {} through a `&` reference", action), span,
+                      "this only lets what it points to be read, it would have to be `&mut`");
+        }
+    }
+
+    /// Build an expression that's used as a value of the type `expected`. Arrays used as slices
+    /// become slices of all of their elements, and `&` references can't be used as `&mut` ones.
+    fn build_expected(&self, ctx: &mut IrBuilderContext, exp: &ExpressionIndex, expected: Option<IrTypeIndex>,
+                      current_block: &mut IrBlockIndex) -> IrInstructionIndex {
+        if let Some((slice, element)) = expected.and_then(|typ| Some((typ, self.slice_element(ctx, typ)?))) {
+            let array = self.static_type(ctx, exp).and_then(|typ| match ctx.module_arena.type_arena.get(typ) {
+                Some(IrType::Array(array_element, len)) => Some((*array_element, *len)),
+                _ => None,
+            });
+            if let Some((array_element, len)) = array {
+                // array literals take the element type of the slice
+                let literal = matches!(ctx.program.expression(*exp), Expression::ArrayLiteral { .. });
+                if !literal && !self.same_type(ctx, array_element, element) {
+                    let span = ctx.program.expression_span(*exp);
+                    ctx.error("array can't be used as a slice of another element type", span,
+                              "the elements of this array have a different type than those of the slice");
                 }
+                ctx.expected_type = expected;
+                let array = self.build_expression(ctx, exp, current_block);
+                let len = ctx.ins(*current_block, IrInstruction::NatLiteral(len as i128));
+                return ctx.ins(*current_block, IrInstruction::RowLiteral { typ: slice, values: vec![array, len] });
             }
+        }
+        let reference = |ctx: &IrBuilderContext, typ: Option<IrTypeIndex>| match typ.and_then(|typ| ctx.module_arena.type_arena.get(typ)) {
+            Some(IrType::Reference(_, mutable)) => Some(*mutable),
+            _ => None,
+        };
+        let actual = self.static_type(ctx, exp);
+        if reference(ctx, expected) == Some(true) && reference(ctx, actual) == Some(false) {
+            let span = ctx.program.expression_span(*exp);
+            ctx.error("expected a `&mut` reference", span, "this is a `&` reference, which can't be changed through");
+        }
+        ctx.expected_type = expected;
+        self.build_expression(ctx, exp, current_block)
+    }
+
+    fn build_expression(&self, ctx: &mut IrBuilderContext, exp: &ExpressionIndex, current_block: &mut IrBlockIndex) -> IrInstructionIndex {
+        let outer_span = ctx.current_span;
+        ctx.current_span = ctx.program.expression_span(*exp).or(outer_span);
+        // the expected type is only this expression's, it's up to each kind of expression to pass
+        // it on to its operands
+        let expected = ctx.expected_type.take();
+        let ins = match ctx.program.expression(*exp) {
+            Expression::Ref(s) if !ctx.locals.contains(s) && self.defines_function(ctx.program, s) => {
+                self.build_function_value(ctx, s, current_block)
+            }
+            Expression::Ref(s) => IrInstruction::Ref(s.clone()),
+            Expression::NatLiteral(n) => IrInstruction::NatLiteral(self.check_literal(ctx, false, *n, expected)),
+            Expression::FloatLiteral(n) => {
+                ctx.float_literal_type();
+                IrInstruction::FloatLiteral(*n)
+            }
+            Expression::BoolLiteral(b) => IrInstruction::BoolLiteral(*b),
+            Expression::StrLiteral(string) => {
+                ctx.str_type();
+                IrInstruction::StrLiteral(string.clone())
+            }
+            Expression::CharLiteral(c) => IrInstruction::CharLiteral(*c),
+            Expression::BinOp(lhs, op, rhs) => {
+                // operands have the type of the other operand, and arithmetic passes on the type
+                // its result is used as
+                let (lhs_expected, rhs_expected) = match op {
+                    BinOpType::And | BinOpType::Or => (None, None),
+                    _ if op.is_shift() => (self.static_type(ctx, lhs).or(expected), None),
+                    _ => {
+                        let expected = if op.is_comparison() { None } else { expected };
+                        (self.static_type(ctx, rhs).or(expected), self.static_type(ctx, lhs).or(expected))
+                    }
+                };
+                let mut lhs_ins = self.build_expected(ctx, lhs, lhs_expected, current_block);
+                let mut rhs_ins = self.build_expected(ctx, rhs, rhs_expected, current_block);
+                // arithmetic on literals alone would be done in 64 bits and only then narrowed, so
+                // it wouldn't overflow in the type its result is used as
+                let arithmetic = !op.is_comparison() && !matches!(op, BinOpType::And | BinOpType::Or);
+                let untyped = self.static_type(ctx, lhs).is_none() && (op.is_shift() || self.static_type(ctx, rhs).is_none());
+                if let Some(typ) = expected.filter(|typ| arithmetic && untyped && self.is_integer(ctx, *typ)) {
+                    lhs_ins = ctx.ins(*current_block, IrInstruction::Cast { value: lhs_ins, typ });
+                    if !op.is_shift() {
+                        rhs_ins = ctx.ins(*current_block, IrInstruction::Cast { value: rhs_ins, typ });
+                    }
+                }
+                let op = if self.overflow_checks { *op } else { op.wrapping() };
+                IrInstruction::BinOp(lhs_ins, op, rhs_ins)
+            }
+            Expression::UnaryOp(op, value) => match (op, ctx.program.expression(*value)) {
+                // negative literals stay literals, so they still take the type of what they're used with
+                (UnaryOpType::Minus, Expression::NatLiteral(n)) => IrInstruction::NatLiteral(self.check_literal(ctx, true, *n, expected)),
+                (UnaryOpType::Minus, Expression::Suffixed { literal, typ }) if matches!(ctx.program.expression(*literal), Expression::NatLiteral(_)) => {
+                    let Expression::NatLiteral(n) = ctx.program.expression(*literal) else { unreachable!() };
+                    let typ = self.build_type(ctx, typ);
+                    let n = self.check_literal(ctx, true, *n, Some(typ));
+                    let value = ctx.ins(*current_block, IrInstruction::NatLiteral(n));
+                    IrInstruction::Cast { value, typ }
+                }
+                (UnaryOpType::Minus, Expression::FloatLiteral(n)) => {
+                    ctx.float_literal_type();
+                    IrInstruction::FloatLiteral(-n)
+                }
+                (UnaryOpType::Minus, _) => {
+                    let value_ins = self.build_expected(ctx, value, expected, current_block);
+                    let op = if self.overflow_checks { *op } else { op.wrapping() };
+                    IrInstruction::UnaryOp(op, value_ins)
+                }
+                _ => {
+                    let value_ins = self.build_expression(ctx, value, current_block);
+                    IrInstruction::UnaryOp(*op, value_ins)
+                }
+            },
+            Expression::FieldAccessor { aggregate, value } => match ctx.program.expression(*value) {
+                Expression::FunctionCall { function, args } if matches!(ctx.program.expression(*function), Expression::Ref(_)) => {
+                    let Expression::Ref(method) = ctx.program.expression(*function) else { unreachable!() };
+                    match self.field_closure(ctx, aggregate, method) {
+                        // a field that holds a closure is called like one
+                        Some((param_types, _)) if !self.check_argument_count(ctx, &format!("`{}`", method), param_types.len(), args.len()) => {
+                            IrInstruction::Error
+                        }
+                        Some((param_types, _)) => {
+                            let field = self.build_field(ctx, aggregate, function, current_block);
+                            let closure = ctx.ins(*current_block, field);
+                            self.build_closure_call(ctx, closure, &param_types, args, current_block)
+                        }
+                        None => self.build_method_call(ctx, aggregate, method, args, current_block),
+                    }
+                }
+                _ => self.build_field(ctx, aggregate, value, current_block),
+            },
+            Expression::FunctionCall { function, args } => self.build_call(ctx, function, args, current_block),
             Expression::New { typ, allocator } => {
-                let alloc_ins = self.build_expression(ctx, func, stmt, allocator, current_block);
+                let alloc_ins = self.build_expression(ctx, allocator, current_block);
                 IrInstruction::New {
                     typ: self.build_type(ctx, typ),
                     allocator: alloc_ins,
                 }
             }
+            Expression::StructLiteral { typ, fields, allocator } => {
+                match self.build_struct_literal(ctx, typ, fields, allocator, current_block) {
+                    Some(object) => {
+                        ctx.current_span = outer_span;
+                        return object;
+                    }
+                    None => IrInstruction::Error,
+                }
+            }
+            Expression::RowLiteral { fields } => self.build_row_literal(ctx, fields, expected, current_block),
+            Expression::ArrayLiteral { values } => {
+                match self.build_array_literal(ctx, values, expected, current_block) {
+                    Some(array) => {
+                        ctx.current_span = outer_span;
+                        return array;
+                    }
+                    None => IrInstruction::Error,
+                }
+            }
+            Expression::Index { aggregate, index } => self.build_index(ctx, aggregate, index, current_block),
             Expression::Dereference { pointer } => {
-                let pointer_ins = self.build_expression(ctx, func, stmt, pointer, current_block);
-                IrInstruction::Dereference { pointer: pointer_ins }
+                if self.not_reference(ctx, pointer) {
+                    let span = ctx.program.expression_span(*pointer);
+                    ctx.error("can't dereference this", span, "only references can be dereferenced");
+                    IrInstruction::Error
+                } else {
+                    let pointer_ins = self.build_expression(ctx, pointer, current_block);
+                    IrInstruction::Dereference { pointer: pointer_ins }
+                }
             }
             Expression::Denull { optional } => {
-                let optional_ins = self.build_expression(ctx, func, stmt, optional, current_block);
+                let optional_ins = self.build_expression(ctx, optional, current_block);
                 IrInstruction::Denull { optional: optional_ins }
             }
-            Expression::Borrow { value } => {
-                let value_ins = self.build_expression(ctx, func, stmt, value, current_block);
+            Expression::Borrow { value, .. } if !self.is_borrowable(ctx, value) => {
+                let span = ctx.program.expression_span(*value);
+                ctx.error("can't borrow this", span, "only variables, fields of structs, elements and dereferenced pointers can be borrowed");
+                IrInstruction::Error
+            }
+            Expression::Borrow { value, mutable } => {
+                if *mutable {
+                    self.check_writable(ctx, value, "borrow `&mut`");
+                }
+                let value_ins = self.build_expression(ctx, value, current_block);
                 IrInstruction::Borrow { value: value_ins }
             }
+            Expression::Cast { value, typ } => {
+                let value_ins = self.build_expression(ctx, value, current_block);
+                let typ = self.build_type(ctx, typ);
+                if matches!(ctx.module_arena.type_arena.get(typ), Some(IrType::Int(_) | IrType::UInt(_) | IrType::Float(_))) {
+                    IrInstruction::Cast { value: value_ins, typ }
+                } else {
+                    let span = ctx.current_span;
+                    ctx.error("`as` only converts between integer and float types", span, "this isn't converted to a number type");
+                    IrInstruction::Error
+                }
+            }
+            // the literal is built as one of its suffix type, and converted to it like with `as`
+            Expression::Suffixed { literal, typ } => {
+                let typ = self.build_type(ctx, typ);
+                let value = self.build_expected(ctx, literal, Some(typ), current_block);
+                IrInstruction::Cast { value, typ }
+            }
+            Expression::Lambda { .. } => self.build_lambda(ctx, exp, expected, current_block),
+        };
+        let index = ctx.ins(*current_block, ins);
+        ctx.current_span = outer_span;
+        index
+    }
+
+    /// Build `aggregate.field`, dereferencing the aggregate when it's a reference to a struct.
+    fn build_field(&self, ctx: &mut IrBuilderContext, aggregate: &ExpressionIndex, field: &ExpressionIndex,
+                   current_block: &mut IrBlockIndex) -> IrInstruction {
+        let mut agg_ins = self.build_expression(ctx, aggregate, current_block);
+        // fields are reached through references, like those of `self` in a `&self` method
+        if self.struct_behind(ctx, aggregate).is_some_and(|(_, reference)| reference.is_some()) {
+            agg_ins = ctx.ins(*current_block, IrInstruction::Dereference { pointer: agg_ins });
+        }
+        // the length of an array is part of its type
+        if let Some((_, Some(len))) = self.sequence_behind(ctx, aggregate) {
+            return match ctx.program.expression(*field) {
+                Expression::Ref(name) if name == "len" => IrInstruction::NatLiteral(len as i128),
+                _ => {
+                    let span = ctx.program.expression_span(*field);
+                    ctx.error("arrays only have a `len`", span, "there's no field with this name");
+                    IrInstruction::Error
+                }
+            };
+        }
+        if let Expression::Ref(name) = ctx.program.expression(*field) {
+            let aggregate_type = self.static_type(ctx, aggregate);
+            let owner = match aggregate_type.and_then(|typ| ctx.module_arena.type_arena.get(typ)) {
+                Some(IrType::Row(_)) => Some("row".to_string()),
+                _ => self.struct_behind(ctx, aggregate).map(|(owner, _)| format!("struct `{}`", owner)),
+            };
+            if let (Some(owner), Some(typ)) = (owner, aggregate_type) {
+                if self.field_type(ctx, typ, name).is_none() {
+                    let span = ctx.program.expression_span(*field);
+                    ctx.error(&format!("{} has no field `{}`", owner, name), span, "there's no field with this name");
+                    return IrInstruction::Error;
+                }
+            }
+        }
+        let field_ins = match ctx.program.expression(*field) {
+            // a field that has the name of a function is still a field
+            Expression::Ref(name) => ctx.ins(*current_block, IrInstruction::Ref(name.clone())),
+            _ => self.build_expression(ctx, field, current_block),
         };
-        ctx.ins(*current_block, ins)
+        IrInstruction::FieldAccessor {
+            aggregate: agg_ins,
+            value: field_ins,
+        }
+    }
+
+    /// Build `aggregate[index]`, which checks that the index is less than the length of the array
+    /// or slice first. An index can't be negative either, which the check finds out with an
+    /// unsigned comparison.
+    fn build_index(&self, ctx: &mut IrBuilderContext, aggregate: &ExpressionIndex, index: &ExpressionIndex,
+                   current_block: &mut IrBlockIndex) -> IrInstruction {
+        let (reference, len) = match self.sequence_behind(ctx, aggregate) {
+            Some((reference, len)) => (reference, len),
+            None => {
+                let span = ctx.program.expression_span(*aggregate);
+                ctx.error("can't index this", span, "only arrays and slices can be indexed");
+                return IrInstruction::Error;
+            }
+        };
+        let index_type = self.static_type(ctx, index);
+        let value_type = self.value_type(ctx, index);
+        if value_type != ctx.unknown_index && !self.is_integer(ctx, value_type) {
+            let span = ctx.program.expression_span(*index);
+            ctx.error("index isn't an integer", span, "arrays and slices are indexed with integers");
+            return IrInstruction::Error;
+        }
+        if let (Expression::NatLiteral(n), Some(len)) = (ctx.program.expression(*index), len) {
+            if *n >= len as u128 {
+                let span = ctx.program.expression_span(*index);
+                ctx.error(&format!("index out of bounds: the length is {} but the index is {}", len, n), span,
+                          "this is past the end of the array");
+                return IrInstruction::Error;
+            }
+        }
+
+        let mut agg_ins = self.build_expression(ctx, aggregate, current_block);
+        if reference {
+            agg_ins = ctx.ins(*current_block, IrInstruction::Dereference { pointer: agg_ins });
+        }
+        let usize_type = ctx.module_arena.type_arena.insert(IrType::UInt(UIntTy::USize));
+        let index_ins = self.build_expected(ctx, index, index_type.or(Some(usize_type)), current_block);
+        // arrays point to their elements, slices point to them with their `data`
+        let (base, len) = match len {
+            Some(len) => (agg_ins, ctx.ins(*current_block, IrInstruction::NatLiteral(len as i128))),
+            None => {
+                let data = ctx.ins(*current_block, IrInstruction::Ref("data".to_string()));
+                let base = ctx.ins(*current_block, IrInstruction::FieldAccessor { aggregate: agg_ins, value: data });
+                let len = ctx.ins(*current_block, IrInstruction::Ref("len".to_string()));
+                (base, ctx.ins(*current_block, IrInstruction::FieldAccessor { aggregate: agg_ins, value: len }))
+            }
+        };
+        ctx.ins(*current_block, IrInstruction::BoundsCheck { index: index_ins, len });
+        IrInstruction::Index { base, index: index_ins }
+    }
+
+    /// Build a call, or the instruction for a built-in function when nothing in the program
+    /// has its name. Anything but a function called by its name is a closure.
+    fn build_call(&self, ctx: &mut IrBuilderContext, function: &ExpressionIndex, args: &[ExpressionIndex],
+                  current_block: &mut IrBlockIndex) -> IrInstruction {
+        let name = match ctx.program.expression(*function) {
+            Expression::Ref(name) if !ctx.locals.contains(name) => name,
+            _ => {
+                let param_types = match self.static_type(ctx, function).and_then(|typ| self.closure_signature(ctx, typ)) {
+                    Some((param_types, _)) => {
+                        let callee = match ctx.program.expression(*function) {
+                            Expression::Ref(name) => format!("`{}`", name),
+                            _ => "this closure".to_string(),
+                        };
+                        if !self.check_argument_count(ctx, &callee, param_types.len(), args.len()) {
+                            return IrInstruction::Error;
+                        }
+                        param_types
+                    }
+                    None => vec![],
+                };
+                let closure = self.build_expression(ctx, function, current_block);
+                return self.build_closure_call(ctx, closure, &param_types, args, current_block);
+            }
+        };
+        let builtin = if self.defines_function(ctx.program, name) { None } else { Some(name.as_str()) };
+        match builtin {
+            Some("print") => {
+                if !self.check_argument_count(ctx, "`print`", 1, args.len()) {
+                    return IrInstruction::Error;
+                }
+                // integer and character literals don't have a type yet, but they're never strings
+                let value_type = self.value_type(ctx, &args[0]);
+                let literal = matches!(ctx.program.expression(args[0]), Expression::NatLiteral(_) | Expression::CharLiteral(_));
+                if literal || (value_type != ctx.unknown_index && value_type != ctx.str_type()) {
+                    let span = ctx.program.expression_span(args[0]);
+                    ctx.error("`print` takes a `Str`", span, "this isn't a `Str`");
+                    return IrInstruction::Error;
+                }
+                let value = self.build_expression(ctx, &args[0], current_block);
+                return IrInstruction::Print { value };
+            }
+            Some(name) => {
+                let span = ctx.current_span;
+                ctx.error(&format!("unknown function `{}`", name), span, "there's no function with this name");
+                return IrInstruction::Error;
+            }
+            None => {}
+        }
+
+        let param_types = self.param_types(ctx, name);
+        if !self.check_argument_count(ctx, &format!("`{}`", name), param_types.len(), args.len()) {
+            return IrInstruction::Error;
+        }
+        let fun_ins = ctx.ins(*current_block, IrInstruction::Ref(name.clone()));
+        let mut arg_insx = Vec::with_capacity(args.len());
+        for (i, arg) in args.iter().enumerate() {
+            let arg_ins = self.build_expected(ctx, arg, param_types.get(i).cloned().flatten(), current_block);
+            arg_insx.push(arg_ins);
+        }
+        IrInstruction::FunctionCall {
+            function: fun_ins,
+            args: arg_insx,
+        }
+    }
+
+    /// Report a call to `callee` with another number of arguments than the `params` it takes,
+    /// returning whether the number was right.
+    fn check_argument_count(&self, ctx: &mut IrBuilderContext, callee: &str, params: usize, args: usize) -> bool {
+        if params == args {
+            return true;
+        }
+        let count = |n: usize| if n == 1 { "1 argument".to_string() } else { format!("{} arguments", n) };
+        let span = ctx.current_span;
+        ctx.error(&format!("{} takes {}", callee, count(params)), span, &format!("this call has {}", count(args)));
+        false
+    }
+
+    /// Build a call to a closure, which calls its code with its environment before the arguments.
+    fn build_closure_call(&self, ctx: &mut IrBuilderContext, closure: IrInstructionIndex, param_types: &[IrTypeIndex],
+                          args: &[ExpressionIndex], current_block: &mut IrBlockIndex) -> IrInstruction {
+        let code_field = ctx.ins(*current_block, IrInstruction::Ref("code".to_string()));
+        let code = ctx.ins(*current_block, IrInstruction::FieldAccessor { aggregate: closure, value: code_field });
+        let env_field = ctx.ins(*current_block, IrInstruction::Ref("env".to_string()));
+        let env = ctx.ins(*current_block, IrInstruction::FieldAccessor { aggregate: closure, value: env_field });
+        let mut arg_insx = Vec::with_capacity(args.len() + 1);
+        arg_insx.push(env);
+        for (i, arg) in args.iter().enumerate() {
+            let arg_ins = self.build_expected(ctx, arg, param_types.get(i).copied(), current_block);
+            arg_insx.push(arg_ins);
+        }
+        IrInstruction::FunctionCall {
+            function: code,
+            args: arg_insx,
+        }
+    }
+
+    /// Build a lambda as a closure. Its body becomes a function of its own, which gets the
+    /// environment before its parameters and starts by reading the variables the lambda
+    /// captures out of it. The environment is a struct of those variables, copied into it when
+    /// the closure is made. It's made with an allocator like any other object, so it stays on
+    /// the stack unless the closure can outlive the function that makes it.
+    fn build_lambda(&self, ctx: &mut IrBuilderContext, lambda: &ExpressionIndex, expected: Option<IrTypeIndex>,
+                    current_block: &mut IrBlockIndex) -> IrInstruction {
+        let Expression::Lambda { params, return_type, statements, allocator } = ctx.program.expression(*lambda) else { unreachable!() };
+        let span = ctx.current_span;
+        let name = format!("{}::lambda{}", ctx.function_name, ctx.lambdas);
+        ctx.lambdas += 1;
+        let captures: Vec<IrTypedName> = self.captures(ctx, params, statements).into_iter()
+            .map(|capture| IrTypedName {
+                typ: ctx.local_types.get(&capture).copied().unwrap_or(ctx.unknown_index),
+                name: capture,
+            })
+            .collect();
+
+        // what the lambda is used as gives the types its parameters and result aren't given
+        let (expected_params, expected_return) = match expected.and_then(|typ| self.closure_signature(ctx, typ)) {
+            Some((params, ret)) => (params, Some(ret)),
+            None => (vec![], None),
+        };
+        let mut param_types = Vec::with_capacity(params.len());
+        for (i, param) in params.iter().enumerate() {
+            let typ = match param.typ {
+                Some(typ) => Some(self.build_type(ctx, &typ)),
+                None => expected_params.get(i).copied().filter(|typ| *typ != ctx.unknown_index),
+            };
+            // closures of different types can't be called the same way, so these have to be known
+            if typ.is_none() {
+                ctx.error(&format!("can't tell the type of parameter `{}`", param.name), span,
+                          "give it a type, or use the lambda where a function type is expected");
+            }
+            param_types.push(typ.unwrap_or(ctx.unknown_index));
+        }
+        let return_type = match return_type {
+            Some(typ) => self.build_type(ctx, typ),
+            None => match expected_return {
+                Some(typ) => typ,
+                None => {
+                    let params: Vec<(String, IrTypeIndex)> = params.iter().map(|param| param.name.clone()).zip(param_types.iter().copied()).collect();
+                    self.lambda_return_type(ctx, &params, statements)
+                }
+            },
+        };
+        let environment = ctx.module_arena.type_arena.insert(IrType::Base(format!("{}::Env", name)));
+        let mut lifted_params = vec![IrTypedName { typ: environment, name: ENVIRONMENT.to_string() }];
+        lifted_params.extend(params.iter().zip(param_types.iter())
+            .map(|(param, typ)| IrTypedName { typ: *typ, name: param.name.clone() }));
+
+        // the body is built like a function, with the state of the one the lambda is in put aside
+        let outer_blocks = std::mem::take(&mut ctx.function_blocks);
+        let outer_returns_value = std::mem::replace(&mut ctx.returns_value, false);
+        let outer_return_type = ctx.return_type.replace(return_type);
+        let outer_local_types = std::mem::take(&mut ctx.local_types);
+        let outer_integer_locals = std::mem::take(&mut ctx.integer_locals);
+        let outer_locals = std::mem::take(&mut ctx.locals);
+        let outer_name = std::mem::replace(&mut ctx.function_name, name.clone());
+        let outer_lambdas = std::mem::replace(&mut ctx.lambdas, 0);
+        for param in lifted_params.iter() {
+            if param.typ != ctx.unknown_index {
+                ctx.local_types.insert(param.name.clone(), param.typ);
+            }
+            ctx.locals.insert(param.name.clone());
+        }
+        let mut body = ctx.new_block();
+        for capture in captures.iter() {
+            let env = ctx.ins(body, IrInstruction::Ref(ENVIRONMENT.to_string()));
+            let field = ctx.ins(body, IrInstruction::Ref(capture.name.clone()));
+            let value = ctx.ins(body, IrInstruction::FieldAccessor { aggregate: env, value: field });
+            ctx.ins(body, IrInstruction::Let { name: capture.clone(), value });
+            if capture.typ != ctx.unknown_index {
+                ctx.local_types.insert(capture.name.clone(), capture.typ);
+            }
+            ctx.locals.insert(capture.name.clone());
+        }
+        self.build_statements(ctx, statements, &mut body);
+        let lifted = IrFunction {
+            access: Access::Generated,
+            attributes: vec![],
+            name: name.clone(),
+            type_params: vec![],
+            params: lifted_params,
+            return_type,
+            blocks: std::mem::replace(&mut ctx.function_blocks, outer_blocks),
+            span,
+        };
+        ctx.returns_value = outer_returns_value;
+        ctx.return_type = outer_return_type;
+        ctx.local_types = outer_local_types;
+        ctx.integer_locals = outer_integer_locals;
+        ctx.locals = outer_locals;
+        ctx.function_name = outer_name;
+        ctx.lambdas = outer_lambdas;
+        ctx.module_arena.node_arena.insert(IrNode::Function(lifted));
+
+        // a lambda that captures nothing doesn't need an environment
+        let allocator = allocator.map(|allocator| self.build_expression(ctx, &allocator, current_block));
+        let env = if captures.is_empty() {
+            ctx.ins(*current_block, IrInstruction::NatLiteral(0))
+        } else {
+            let allocator = allocator.unwrap_or_else(|| ctx.ins(*current_block, IrInstruction::NatLiteral(0)));
+            let env = ctx.ins(*current_block, IrInstruction::New { typ: environment, allocator });
+            for capture in captures.iter() {
+                let value = ctx.ins(*current_block, IrInstruction::Ref(capture.name.clone()));
+                let field = ctx.ins(*current_block, IrInstruction::Ref(capture.name.clone()));
+                let place = ctx.ins(*current_block, IrInstruction::FieldAccessor { aggregate: env, value: field });
+                ctx.ins(*current_block, IrInstruction::Store { place, value });
+            }
+            env
+        };
+        ctx.module_arena.node_arena.insert(IrNode::Struct {
+            name: format!("{}::Env", name),
+            fields: captures,
+            nodes: vec![],
+        });
+        let code = ctx.ins(*current_block, IrInstruction::Ref(name));
+        IrInstruction::RowLiteral {
+            typ: self.closure_type(ctx, param_types, return_type),
+            values: vec![code, env],
+        }
+    }
+
+    /// The variables of the function being built that a lambda uses, in the order it first uses
+    /// them. Lambdas in the lambda capture what they use through it.
+    fn captures(&self, ctx: &IrBuilderContext, params: &[TypedName], statements: &[StatementIndex]) -> Vec<String> {
+        let bound = params.iter().map(|param| param.name.clone()).collect();
+        let mut captures = vec![];
+        self.statement_captures(ctx, statements, &bound, &mut captures);
+        captures
+    }
+
+    /// Find the captures in statements, where `bound` are the names the lambda defines itself.
+    fn statement_captures(&self, ctx: &IrBuilderContext, statements: &[StatementIndex], bound: &HashSet<String>,
+                          captures: &mut Vec<String>) {
+        let mut bound = bound.clone();
+        for statement in statements {
+            match ctx.program.statement(*statement) {
+                Statement::If { condition, body, else_if } => {
+                    self.expression_captures(ctx, condition, &bound, captures);
+                    self.statement_captures(ctx, body, &bound, captures);
+                    if let Some(else_if) = else_if {
+                        self.statement_captures(ctx, &[*else_if], &bound, captures);
+                    }
+                }
+                Statement::Call { function, args } => {
+                    self.expression_captures(ctx, function, &bound, captures);
+                    for arg in args {
+                        self.expression_captures(ctx, arg, &bound, captures);
+                    }
+                }
+                Statement::Expression { value } | Statement::Return { value } => self.expression_captures(ctx, value, &bound, captures),
+                Statement::Let { name, value } => {
+                    self.expression_captures(ctx, value, &bound, captures);
+                    bound.insert(name.name.clone());
+                }
+                Statement::Assign { place, value } => {
+                    self.expression_captures(ctx, place, &bound, captures);
+                    self.expression_captures(ctx, value, &bound, captures);
+                }
+            }
+        }
+    }
+
+    fn expression_captures(&self, ctx: &IrBuilderContext, exp: &ExpressionIndex, bound: &HashSet<String>, captures: &mut Vec<String>) {
+        let mut find = |exp: &ExpressionIndex| self.expression_captures(ctx, exp, bound, captures);
+        match ctx.program.expression(*exp) {
+            Expression::Ref(name) => {
+                if ctx.locals.contains(name) && !bound.contains(name) && !captures.contains(name) {
+                    captures.push(name.clone());
+                }
+            }
+            Expression::BinOp(lhs, _, rhs) => {
+                find(lhs);
+                find(rhs);
+            }
+            Expression::UnaryOp(_, value) | Expression::Dereference { pointer: value } | Expression::Denull { optional: value }
+            | Expression::Borrow { value, .. } | Expression::Cast { value, .. } => find(value),
+            // the names after a `.` are fields and methods
+            Expression::FieldAccessor { aggregate, value } => {
+                find(aggregate);
+                if let Expression::FunctionCall { args, .. } = ctx.program.expression(*value) {
+                    args.iter().for_each(find);
+                }
+            }
+            Expression::FunctionCall { function, args } => {
+                find(function);
+                args.iter().for_each(find);
+            }
+            Expression::New { allocator, .. } => find(allocator),
+            Expression::StructLiteral { fields, allocator, .. } => {
+                fields.iter().for_each(|(_, value)| find(value));
+                allocator.iter().for_each(find);
+            }
+            Expression::RowLiteral { fields } => fields.iter().for_each(|(_, value)| find(value)),
+            Expression::ArrayLiteral { values } => values.iter().for_each(find),
+            Expression::Index { aggregate, index } => {
+                find(aggregate);
+                find(index);
+            }
+            Expression::Lambda { params, statements, allocator, .. } => {
+                allocator.iter().for_each(find);
+                let mut bound = bound.clone();
+                bound.extend(params.iter().map(|param| param.name.clone()));
+                self.statement_captures(ctx, statements, &bound, captures);
+            }
+            Expression::NatLiteral(_) | Expression::FloatLiteral(_) | Expression::BoolLiteral(_) | Expression::StrLiteral(_)
+            | Expression::CharLiteral(_) | Expression::Suffixed { .. } => {}
+        }
+    }
+
+    /// Build the function called `name` used as a value. It's a closure without an environment,
+    /// whose code calls the function.
+    fn build_function_value(&self, ctx: &mut IrBuilderContext, name: &str, current_block: &mut IrBlockIndex) -> IrInstruction {
+        let typ = self.function_value_type(ctx, name);
+        let (param_types, return_type) = self.closure_signature(ctx, typ).unwrap();
+        let code = format!("{}::closure", name);
+        if ctx.function_values.insert(name.to_string()) {
+            let env_type = match ctx.module_arena.type_arena.get(typ) {
+                Some(IrType::Row(fields)) => fields[1].typ,
+                _ => unreachable!(),
+            };
+            let (receiver, params, ..) = Self::signature(ctx.program, name).unwrap();
+            let names = receiver.map(|_| "self".to_string()).into_iter()
+                .chain(params.iter().map(|param| param.name.clone()));
+            let mut code_params = vec![IrTypedName { typ: env_type, name: ENVIRONMENT.to_string() }];
+            code_params.extend(names.zip(param_types).map(|(name, typ)| IrTypedName { typ, name }));
+
+            let block = ctx.new_detached_block();
+            let function = ctx.ins(block, IrInstruction::Ref(name.to_string()));
+            let args = code_params[1..].iter().map(|param| ctx.ins(block, IrInstruction::Ref(param.name.clone()))).collect();
+            let value = ctx.ins(block, IrInstruction::FunctionCall { function, args });
+            if return_type != ctx.void_index {
+                ctx.ins(block, IrInstruction::Return { value });
+            }
+            ctx.module_arena.node_arena.insert(IrNode::Function(IrFunction {
+                access: Access::Generated,
+                attributes: vec![],
+                name: code.clone(),
+                type_params: vec![],
+                params: code_params,
+                return_type,
+                blocks: vec![block],
+                span: ctx.current_span,
+            }));
+        }
+        let code = ctx.ins(*current_block, IrInstruction::Ref(code));
+        let env = ctx.ins(*current_block, IrInstruction::NatLiteral(0));
+        IrInstruction::RowLiteral { typ, values: vec![code, env] }
+    }
+
+    /// The type of the function called `name` used as a value.
+    fn function_value_type(&self, ctx: &mut IrBuilderContext, name: &str) -> IrTypeIndex {
+        let params = self.param_types(ctx, name).into_iter()
+            .map(|typ| typ.unwrap_or(ctx.unknown_index))
+            .collect();
+        let return_type = match self.return_type(ctx, name) {
+            Some(typ) => typ,
+            // functions without a return type that return a value have their return type inferred
+            None if Self::signature(ctx.program, name).and_then(|signature| signature.3)
+                .is_some_and(|statements| self.returns_value(ctx.program, statements)) => ctx.unknown_index,
+            None => ctx.void_index,
+        };
+        self.closure_type(ctx, params, return_type)
+    }
+
+    /// Whether any of the statements returns a value.
+    fn returns_value(&self, program: &Program, statements: &[StatementIndex]) -> bool {
+        self.first_return(program, statements).is_some()
+    }
+
+    /// The value the first `return` in the statements returns.
+    fn first_return(&self, program: &Program, statements: &[StatementIndex]) -> Option<ExpressionIndex> {
+        statements.iter().find_map(|statement| match program.statement(*statement) {
+            Statement::Return { value } => Some(*value),
+            Statement::If { body, else_if, .. } => {
+                self.first_return(program, body).or_else(|| else_if.and_then(|else_if| self.first_return(program, &[else_if])))
+            }
+            _ => None,
+        })
+    }
+
+    /// The type a lambda without a return type returns, which is that of the first value it
+    /// returns as far as it's known with the parameters in scope.
+    fn lambda_return_type(&self, ctx: &mut IrBuilderContext, params: &[(String, IrTypeIndex)], statements: &[StatementIndex]) -> IrTypeIndex {
+        let value = match self.first_return(ctx.program, statements) {
+            Some(value) => value,
+            None => return ctx.void_index,
+        };
+        let outer_local_types = ctx.local_types.clone();
+        for (name, typ) in params {
+            if *typ == ctx.unknown_index {
+                ctx.local_types.remove(name);
+            } else {
+                ctx.local_types.insert(name.clone(), *typ);
+            }
+        }
+        let typ = self.static_type(ctx, &value);
+        ctx.local_types = outer_local_types;
+        typ.unwrap_or(ctx.unknown_index)
+    }
+
+    /// The type of closures that take `params` and return `return_type`: a row of their code,
+    /// which takes a pointer to the environment before the parameters, and that pointer.
+    fn closure_type(&self, ctx: &mut IrBuilderContext, params: Vec<IrTypeIndex>, return_type: IrTypeIndex) -> IrTypeIndex {
+        let byte = ctx.module_arena.type_arena.insert(IrType::UInt(UIntTy::U8));
+        let env = ctx.module_arena.type_arena.insert(IrType::Reference(byte, false));
+        let mut code_params = vec![env];
+        code_params.extend(params);
+        let code = ctx.module_arena.type_arena.insert(IrType::Function(code_params, return_type));
+        ctx.module_arena.type_arena.insert(IrType::Row(vec![
+            IrTypedName { typ: code, name: "code".to_string() },
+            IrTypedName { typ: env, name: "env".to_string() },
+        ]))
+    }
+
+    /// The parameter and return types of the closure in the field `name` of an aggregate, if it
+    /// has a field that holds one.
+    fn field_closure(&self, ctx: &mut IrBuilderContext, aggregate: &ExpressionIndex, name: &str) -> Option<(Vec<IrTypeIndex>, IrTypeIndex)> {
+        let aggregate = self.static_type(ctx, aggregate)?;
+        let field = self.field_type(ctx, aggregate, name)?;
+        self.closure_signature(ctx, field)
+    }
+
+    /// The parameter and return types of a closure type, without the environment.
+    fn closure_signature(&self, ctx: &IrBuilderContext, typ: IrTypeIndex) -> Option<(Vec<IrTypeIndex>, IrTypeIndex)> {
+        let code = match ctx.module_arena.type_arena.get(typ)? {
+            IrType::Row(fields) if fields.len() == 2 && fields[0].name == "code" && fields[1].name == "env" => fields[0].typ,
+            _ => return None,
+        };
+        match ctx.module_arena.type_arena.get(code)? {
+            IrType::Function(params, return_type) => Some((params[1..].to_vec(), *return_type)),
+            _ => None,
+        }
+    }
+
+    /// Build `receiver.method(args)` as a call to the method of the receiver's struct, which
+    /// gets the receiver as `self`: borrowed for `&self` and `&mut self` methods, and
+    /// dereferenced for `self` ones when it's a reference.
+    fn build_method_call(&self, ctx: &mut IrBuilderContext, receiver: &ExpressionIndex, method: &str, args: &[ExpressionIndex],
+                         current_block: &mut IrBlockIndex) -> IrInstruction {
+        let span = ctx.current_span;
+        let (owner, reference) = match self.struct_behind(ctx, receiver) {
+            Some(owner) => owner,
+            None => {
+                let receiver_span = ctx.program.expression_span(*receiver);
+                ctx.error(&format!("can't call method `{}` here", method), receiver_span, "this isn't known to be a struct");
+                return IrInstruction::Error;
+            }
+        };
+        let name = format!("{}::{}", owner, method);
+        let kind = match Self::signature(ctx.program, &name) {
+            Some((Some(kind), ..)) => kind,
+            Some((None, ..)) => {
+                ctx.error(&format!("`{}` doesn't take `self`", name), span, &format!("it's called as `{}(...)`", name));
+                return IrInstruction::Error;
+            }
+            None => {
+                ctx.error(&format!("struct `{}` has no method `{}`", owner, method), span, "there's no method with this name");
+                return IrInstruction::Error;
+            }
+        };
+        if kind == Receiver::RefMut && reference == Some(false) {
+            let receiver_span = ctx.program.expression_span(*receiver);
+            ctx.error(&format!("`{}` takes `&mut self`", name), receiver_span, "this is a `&` reference, which can't be changed through");
+            return IrInstruction::Error;
+        }
+
+        let param_types = self.param_types(ctx, &name);
+        if !self.check_argument_count(ctx, &format!("`{}`", name), param_types.len() - 1, args.len()) {
+            return IrInstruction::Error;
+        }
+        let mut receiver_ins = self.build_expression(ctx, receiver, current_block);
+        receiver_ins = match (kind, reference) {
+            (Receiver::Value, Some(_)) => ctx.ins(*current_block, IrInstruction::Dereference { pointer: receiver_ins }),
+            (Receiver::Ref | Receiver::RefMut, None) => ctx.ins(*current_block, IrInstruction::Borrow { value: receiver_ins }),
+            _ => receiver_ins,
+        };
+        let fun_ins = ctx.ins(*current_block, IrInstruction::Ref(name));
+        let mut arg_insx = Vec::with_capacity(args.len() + 1);
+        arg_insx.push(receiver_ins);
+        for (i, arg) in args.iter().enumerate() {
+            let arg_ins = self.build_expected(ctx, arg, param_types.get(i + 1).cloned().flatten(), current_block);
+            arg_insx.push(arg_ins);
+        }
+        IrInstruction::FunctionCall {
+            function: fun_ins,
+            args: arg_insx,
+        }
+    }
+
+    /// The struct an expression is, or is a reference to, and whether that reference is `&mut`.
+    fn struct_behind(&self, ctx: &mut IrBuilderContext, exp: &ExpressionIndex) -> Option<(String, Option<bool>)> {
+        let typ = self.static_type(ctx, exp)?;
+        let (typ, reference) = match ctx.module_arena.type_arena.get(typ)? {
+            IrType::Reference(inner, mutable) => (*inner, Some(*mutable)),
+            _ => (typ, None),
+        };
+        let name = match ctx.module_arena.type_arena.get(typ)? {
+            IrType::Base(name) => name.clone(),
+            _ => return None,
+        };
+        self.struct_fields(ctx, &name)?;
+        Some((name, reference))
+    }
+
+    /// The type of the `self` of a method of `owner`.
+    fn receiver_type(&self, ctx: &mut IrBuilderContext, owner: &str, receiver: Receiver) -> IrTypeIndex {
+        let typ = ctx.module_arena.type_arena.insert(IrType::Base(owner.to_string()));
+        match receiver {
+            Receiver::Value => typ,
+            Receiver::Ref => ctx.module_arena.type_arena.insert(IrType::Reference(typ, false)),
+            Receiver::RefMut => ctx.module_arena.type_arena.insert(IrType::Reference(typ, true)),
+        }
+    }
+
+    /// The types of the parameters of the function called `name`, starting with `self` for
+    /// methods, as far as they're known.
+    fn param_types(&self, ctx: &mut IrBuilderContext, name: &str) -> Vec<Option<IrTypeIndex>> {
+        let mut param_types = vec![];
+        let (receiver, params) = match Self::signature(ctx.program, name) {
+            Some((receiver, params, ..)) => (receiver, params),
+            None => return param_types,
+        };
+        if let (Some(receiver), Some((owner, _))) = (receiver, name.split_once("::")) {
+            param_types.push(Some(self.receiver_type(ctx, owner, receiver)));
+        }
+        for param in params {
+            param_types.push(param.typ.map(|typ| self.build_type(ctx, &typ)));
+        }
+        param_types
+    }
+
+    fn defines_function(&self, program: &Program, name: &str) -> bool {
+        Self::signature(program, name).is_some()
+    }
+
+    /// The receiver, parameters and return type of the function or prototype called `name`, and
+    /// the body of functions. Methods and other functions declared in a struct are called
+    /// `Owner::name`.
+    fn signature<'p>(program: &'p Program, name: &str) -> Option<Signature<'p>> {
+        let (owner, name) = match name.split_once("::") {
+            Some((owner, name)) => (Some(owner), name),
+            None => (None, name),
+        };
+        program.program_arena.node_arena.iter()
+            .filter(|(index, _)| program.node_owner(*index) == owner)
+            .find_map(|(_, node)| match node {
+                Node::Function(function) if function.name == name => {
+                    Some((function.receiver, function.params.as_slice(), function.return_type, Some(function.statements.as_slice())))
+                }
+                Node::FunctionPrototype { name: prototype, receiver, params, return_type, .. } if prototype == name => {
+                    Some((*receiver, params.as_slice(), *return_type, None))
+                }
+                _ => None,
+            })
+    }
+
+    /// The fields of the struct called `name`, if the program defines one.
+    fn struct_fields(&self, ctx: &mut IrBuilderContext, name: &str) -> Option<Vec<IrTypedName>> {
+        let children = ctx.program.program_arena.node_arena.iter().find_map(|(_, node)| match node {
+            Node::Struct { name: struct_name, children, .. } if struct_name == name => Some(children),
+            _ => None,
+        })?;
+        let mut fields = vec![];
+        for child in children {
+            if let Some(Node::Variable { name, .. }) = ctx.program.program_arena.node_arena.get(*child) {
+                fields.push(self.build_typed_name(ctx, name));
+            }
+        }
+        Some(fields)
+    }
+
+    /// The type of the field `name` of a struct or row type.
+    fn field_type(&self, ctx: &mut IrBuilderContext, typ: IrTypeIndex, name: &str) -> Option<IrTypeIndex> {
+        let fields = match ctx.module_arena.type_arena.get(typ)? {
+            IrType::Row(fields) => fields.clone(),
+            IrType::Reference(inner, _) => {
+                let inner = *inner;
+                return self.field_type(ctx, inner, name);
+            }
+            IrType::Base(struct_name) => {
+                let struct_name = struct_name.clone();
+                self.struct_fields(ctx, &struct_name)?
+            }
+            IrType::Array(..) if name == "len" => return Some(ctx.module_arena.type_arena.insert(IrType::UInt(UIntTy::USize))),
+            _ => return None,
+        };
+        fields.iter().find(|field| field.name == name).map(|field| field.typ)
+    }
+
+    /// Report the field names of a literal that are given twice, returning whether there were any.
+    fn check_duplicate_fields(&self, ctx: &mut IrBuilderContext, fields: &[(String, ExpressionIndex)]) -> bool {
+        let mut duplicates = false;
+        for (i, (name, value)) in fields.iter().enumerate() {
+            if fields[..i].iter().any(|(other, _)| other == name) {
+                let span = ctx.program.expression_span(*value);
+                ctx.error(&format!("field `{}` is given more than once", name), span, "this is its second value");
+                duplicates = true;
+            }
+        }
+        duplicates
+    }
+
+    /// Build a struct literal as a `New` of the struct and a store to each of its fields, which
+    /// have to be given exactly once. Without an allocator the object is made with the default
+    /// one, so it can be moved to the stack like any other. Returns the object, if the literal
+    /// is valid.
+    fn build_struct_literal(&self, ctx: &mut IrBuilderContext, typ: &TypeIndex, fields: &[(String, ExpressionIndex)],
+                            allocator: &Option<ExpressionIndex>, current_block: &mut IrBlockIndex) -> Option<IrInstructionIndex> {
+        let span = ctx.current_span;
+        let typ = self.build_type(ctx, typ);
+        let name = match ctx.module_arena.type_arena.get(typ) {
+            Some(IrType::Base(name)) => name.clone(),
+            _ => unreachable!("struct literals name their struct"),
+        };
+        let declared = match self.struct_fields(ctx, &name) {
+            Some(declared) => declared,
+            None => {
+                ctx.error(&format!("unknown struct `{}`", name), span, "there's no struct with this name");
+                return None;
+            }
+        };
+        let mut valid = !self.check_duplicate_fields(ctx, fields);
+        for (field, value) in fields.iter() {
+            if !declared.iter().any(|declared| declared.name == *field) {
+                let span = ctx.program.expression_span(*value);
+                ctx.error(&format!("struct `{}` has no field `{}`", name, field), span, "there's no field with this name");
+                valid = false;
+            }
+        }
+        let missing: Vec<String> = declared.iter()
+            .filter(|declared| !fields.iter().any(|(field, _)| *field == declared.name))
+            .map(|declared| format!("`{}`", declared.name))
+            .collect();
+        if !missing.is_empty() {
+            ctx.error(&format!("missing fields {} in `{}` literal", missing.join(", "), name), span,
+                      "every field has to be given a value");
+            valid = false;
+        }
+        if !valid {
+            return None;
+        }
+
+        let mut values = Vec::with_capacity(fields.len());
+        for (field, value) in fields.iter() {
+            let field_type = declared.iter().find(|declared| declared.name == *field).map(|declared| declared.typ);
+            values.push(self.build_expected(ctx, value, field_type, current_block));
+        }
+        let allocator = match allocator {
+            Some(allocator) => self.build_expression(ctx, allocator, current_block),
+            None => ctx.ins(*current_block, IrInstruction::NatLiteral(0)),
+        };
+        let object = ctx.ins(*current_block, IrInstruction::New { typ, allocator });
+        for ((field, _), value) in fields.iter().zip(values) {
+            let field = ctx.ins(*current_block, IrInstruction::Ref(field.clone()));
+            let place = ctx.ins(*current_block, IrInstruction::FieldAccessor { aggregate: object, value: field });
+            ctx.ins(*current_block, IrInstruction::Store { place, value });
+        }
+        Some(object)
+    }
+
+    /// Build an array literal, which is made with the default allocator and gets its elements
+    /// stored after they're all built, like a struct literal.
+    fn build_array_literal(&self, ctx: &mut IrBuilderContext, values: &[ExpressionIndex], expected: Option<IrTypeIndex>,
+                           current_block: &mut IrBlockIndex) -> Option<IrInstructionIndex> {
+        let typ = self.array_type(ctx, values, expected)?;
+        let element = match ctx.module_arena.type_arena.get(typ) {
+            Some(IrType::Array(element, _)) => *element,
+            _ => unreachable!(),
+        };
+        let mut elements = Vec::with_capacity(values.len());
+        for value in values {
+            elements.push(self.build_expected(ctx, value, Some(element), current_block));
+        }
+        let allocator = ctx.ins(*current_block, IrInstruction::NatLiteral(0));
+        let array = ctx.ins(*current_block, IrInstruction::New { typ, allocator });
+        for (i, value) in elements.into_iter().enumerate() {
+            let index = ctx.ins(*current_block, IrInstruction::NatLiteral(i as i128));
+            let place = ctx.ins(*current_block, IrInstruction::Index { base: array, index });
+            ctx.ins(*current_block, IrInstruction::Store { place, value });
+        }
+        Some(array)
+    }
+
+    /// The type of an array literal, which is the array type it's used as, an array of the
+    /// elements of the slice type it's used as, or otherwise an array of the type of its first
+    /// value.
+    fn array_type(&self, ctx: &mut IrBuilderContext, values: &[ExpressionIndex], expected: Option<IrTypeIndex>) -> Option<IrTypeIndex> {
+        let span = ctx.current_span;
+        match expected.and_then(|typ| self.sequence_type(ctx, typ)) {
+            Some((_, Some(len))) if len != values.len() as u64 => {
+                ctx.error(&format!("array literal has {} elements but its type has {}", values.len(), len), span,
+                          &format!("expected {} elements", len));
+                return None;
+            }
+            Some((_, Some(_))) => return expected,
+            Some((element, None)) => return Some(ctx.module_arena.type_arena.insert(IrType::Array(element, values.len() as u64))),
+            None => {}
+        }
+        let element = match values.first() {
+            Some(first) => self.value_type(ctx, first),
+            None => {
+                ctx.error("can't tell the type of the elements of an empty array", span,
+                          "give it a type, or use it where an array or slice type is expected");
+                return None;
+            }
+        };
+        Some(ctx.module_arena.type_arena.insert(IrType::Array(element, values.len() as u64)))
+    }
+
+    /// The type of slices of `element`, a row of a pointer to the first element and the number
+    /// of elements.
+    fn slice_type(&self, ctx: &mut IrBuilderContext, element: IrTypeIndex) -> IrTypeIndex {
+        let data = ctx.module_arena.type_arena.insert(IrType::Reference(element, true));
+        let len = ctx.module_arena.type_arena.insert(IrType::UInt(UIntTy::USize));
+        ctx.module_arena.type_arena.insert(IrType::Row(vec![
+            IrTypedName { typ: data, name: "data".to_string() },
+            IrTypedName { typ: len, name: "len".to_string() },
+        ]))
+    }
+
+    /// The element type of a slice type.
+    fn slice_element(&self, ctx: &IrBuilderContext, typ: IrTypeIndex) -> Option<IrTypeIndex> {
+        match self.sequence_type(ctx, typ)? {
+            (element, None) => Some(element),
+            _ => None,
+        }
+    }
+
+    /// The element type of an array or slice type, and the length of arrays.
+    fn sequence_type(&self, ctx: &IrBuilderContext, typ: IrTypeIndex) -> Option<(IrTypeIndex, Option<u64>)> {
+        match ctx.module_arena.type_arena.get(typ)? {
+            IrType::Array(element, len) => Some((*element, Some(*len))),
+            IrType::Row(fields) if fields.len() == 2 && fields[0].name == "data" && fields[1].name == "len" => {
+                match ctx.module_arena.type_arena.get(fields[0].typ)? {
+                    IrType::Reference(element, _) => Some((*element, None)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether an expression is an array or slice, or a reference to one, and the length of
+    /// arrays.
+    fn sequence_behind(&self, ctx: &mut IrBuilderContext, exp: &ExpressionIndex) -> Option<(bool, Option<u64>)> {
+        let typ = self.static_type(ctx, exp)?;
+        let (typ, reference) = match ctx.module_arena.type_arena.get(typ)? {
+            IrType::Reference(inner, _) => (*inner, true),
+            _ => (typ, false),
+        };
+        let (_, len) = self.sequence_type(ctx, typ)?;
+        Some((reference, len))
+    }
+
+    fn is_integer(&self, ctx: &IrBuilderContext, typ: IrTypeIndex) -> bool {
+        match ctx.module_arena.type_arena.get(typ) {
+            Some(IrType::Int(_) | IrType::UInt(_)) => true,
+            Some(IrType::Refinement(_, inner, _)) => self.is_integer(ctx, *inner),
+            _ => false,
+        }
+    }
+
+    /// Whether two types are the same, so values of one can be used where the other is
+    /// expected without being converted.
+    fn same_type(&self, ctx: &IrBuilderContext, a: IrTypeIndex, b: IrTypeIndex) -> bool {
+        if a == b {
+            return true;
+        }
+        let arena = &ctx.module_arena.type_arena;
+        match (arena.get(a), arena.get(b)) {
+            (Some(IrType::Bool), Some(IrType::Bool)) => true,
+            (Some(IrType::Int(a)), Some(IrType::Int(b))) => a.name() == b.name(),
+            (Some(IrType::UInt(a)), Some(IrType::UInt(b))) => a.name() == b.name(),
+            (Some(IrType::Float(a)), Some(IrType::Float(b))) => a.bits() == b.bits(),
+            (Some(IrType::Base(a)), Some(IrType::Base(b))) => a == b,
+            (Some(IrType::Reference(a, a_mutable)), Some(IrType::Reference(b, b_mutable))) => {
+                a_mutable == b_mutable && self.same_type(ctx, *a, *b)
+            }
+            (Some(IrType::Optional(a)), Some(IrType::Optional(b))) => self.same_type(ctx, *a, *b),
+            (Some(IrType::Array(a, a_len)), Some(IrType::Array(b, b_len))) => a_len == b_len && self.same_type(ctx, *a, *b),
+            (Some(IrType::Row(a)), Some(IrType::Row(b))) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.name == b.name && self.same_type(ctx, a.typ, b.typ))
+            }
+            _ => false,
+        }
+    }
+
+    /// Build a row literal, whose values are built in the order they're written and passed in
+    /// the order of the row type.
+    fn build_row_literal(&self, ctx: &mut IrBuilderContext, fields: &[(String, ExpressionIndex)], expected: Option<IrTypeIndex>,
+                         current_block: &mut IrBlockIndex) -> IrInstruction {
+        if self.check_duplicate_fields(ctx, fields) {
+            return IrInstruction::Error;
+        }
+        let typ = self.row_type(ctx, fields, expected);
+        let types = match ctx.module_arena.type_arena.get(typ) {
+            Some(IrType::Row(types)) => types.clone(),
+            _ => unreachable!(),
+        };
+        let mut values = HashMap::new();
+        for (field, value) in fields.iter() {
+            let field_type = types.iter().find(|typ| typ.name == *field).map(|typ| typ.typ);
+            values.insert(field, self.build_expected(ctx, value, field_type, current_block));
+        }
+        IrInstruction::RowLiteral {
+            typ,
+            values: types.iter().map(|typ| values[&typ.name]).collect(),
+        }
+    }
+
+    /// The type of a row literal, which is the row type it's used as when there is one, and
+    /// otherwise made from the types of its values.
+    fn row_type(&self, ctx: &mut IrBuilderContext, fields: &[(String, ExpressionIndex)], expected: Option<IrTypeIndex>) -> IrTypeIndex {
+        if let Some(expected) = expected {
+            if let Some(IrType::Row(types)) = ctx.module_arena.type_arena.get(expected) {
+                let same_fields = types.len() == fields.len()
+                    && fields.iter().all(|(field, _)| types.iter().any(|typ| typ.name == *field));
+                if same_fields {
+                    return expected;
+                }
+                let names: Vec<String> = types.iter().map(|typ| format!("`{}`", typ.name)).collect();
+                let span = ctx.current_span;
+                ctx.error("row literal doesn't have the fields of its type", span,
+                          &format!("expected a value for each of {}", names.join(", ")));
+            }
+        }
+        let mut types = Vec::with_capacity(fields.len());
+        for (field, value) in fields.iter() {
+            types.push(IrTypedName { name: field.clone(), typ: self.value_type(ctx, value) });
+        }
+        ctx.module_arena.type_arena.insert(IrType::Row(types))
+    }
+
+    /// The type a value has when nothing it's used with gives it one, which is its static type
+    /// or the type of its kind of literal.
+    fn value_type(&self, ctx: &mut IrBuilderContext, exp: &ExpressionIndex) -> IrTypeIndex {
+        if let Some(typ) = self.static_type(ctx, exp) {
+            return typ;
+        }
+        match ctx.program.expression(*exp) {
+            Expression::FloatLiteral(_) => ctx.float_literal_type(),
+            Expression::UnaryOp(UnaryOpType::Minus, value) if matches!(ctx.program.expression(*value), Expression::FloatLiteral(_)) => {
+                ctx.float_literal_type()
+            }
+            Expression::StrLiteral(_) => ctx.str_type(),
+            Expression::BoolLiteral(_) | Expression::UnaryOp(UnaryOpType::Not, _) => ctx.module_arena.type_arena.insert(IrType::Bool),
+            Expression::BinOp(_, op, _) if op.is_comparison() || matches!(op, BinOpType::And | BinOpType::Or) => {
+                ctx.module_arena.type_arena.insert(IrType::Bool)
+            }
+            _ => ctx.unknown_index,
+        }
+    }
+
+    /// The type an expression has without looking at what it's used with, if it has one.
+    /// Literals only get theirs from what they're used with.
+    fn static_type(&self, ctx: &mut IrBuilderContext, exp: &ExpressionIndex) -> Option<IrTypeIndex> {
+        match ctx.program.expression(*exp) {
+            Expression::Ref(name) if !ctx.locals.contains(name) && self.defines_function(ctx.program, name) => {
+                Some(self.function_value_type(ctx, name))
+            }
+            Expression::Ref(name) => ctx.local_types.get(name).copied(),
+            Expression::Cast { typ, .. } | Expression::Suffixed { typ, .. } => Some(self.build_type(ctx, typ)),
+            Expression::BinOp(_, BinOpType::And | BinOpType::Or, _) => None,
+            Expression::BinOp(_, op, _) if op.is_comparison() => None,
+            Expression::BinOp(lhs, op, _) if op.is_shift() => self.static_type(ctx, lhs),
+            Expression::BinOp(lhs, _, rhs) => self.static_type(ctx, lhs).or_else(|| self.static_type(ctx, rhs)),
+            Expression::UnaryOp(UnaryOpType::Minus, value) => self.static_type(ctx, value),
+            Expression::FunctionCall { function, .. } => match ctx.program.expression(*function) {
+                Expression::Ref(name) if !ctx.locals.contains(name) => self.return_type(ctx, name),
+                _ => {
+                    let closure = self.static_type(ctx, function)?;
+                    let (_, return_type) = self.closure_signature(ctx, closure)?;
+                    Some(return_type).filter(|typ| *typ != ctx.void_index && *typ != ctx.unknown_index)
+                }
+            },
+            Expression::StructLiteral { typ, .. } => Some(self.build_type(ctx, typ)),
+            Expression::RowLiteral { fields } => Some(self.row_type(ctx, fields, None)),
+            Expression::ArrayLiteral { values } => {
+                let element = self.value_type(ctx, values.first()?);
+                Some(ctx.module_arena.type_arena.insert(IrType::Array(element, values.len() as u64)))
+            }
+            Expression::Index { aggregate, .. } => {
+                let mut typ = self.static_type(ctx, aggregate)?;
+                if let IrType::Reference(inner, _) = ctx.module_arena.type_arena.get(typ)? {
+                    typ = *inner;
+                }
+                self.sequence_type(ctx, typ).map(|(element, _)| element)
+            }
+            Expression::FieldAccessor { aggregate, value } => match ctx.program.expression(*value) {
+                Expression::Ref(field) => {
+                    let aggregate = self.static_type(ctx, aggregate)?;
+                    self.field_type(ctx, aggregate, field)
+                }
+                Expression::FunctionCall { function, .. } => match ctx.program.expression(*function) {
+                    Expression::Ref(method) => {
+                        if let Some((_, return_type)) = self.field_closure(ctx, aggregate, method) {
+                            return Some(return_type).filter(|typ| *typ != ctx.void_index && *typ != ctx.unknown_index);
+                        }
+                        let (owner, _) = self.struct_behind(ctx, aggregate)?;
+                        self.return_type(ctx, &format!("{}::{}", owner, method))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            },
+            Expression::Borrow { value, mutable } => {
+                let mutable = *mutable;
+                let value = self.static_type(ctx, value)?;
+                Some(ctx.module_arena.type_arena.insert(IrType::Reference(value, mutable)))
+            }
+            Expression::Dereference { pointer } => {
+                let pointer = self.static_type(ctx, pointer)?;
+                match ctx.module_arena.type_arena.get(pointer)? {
+                    IrType::Reference(inner, _) => Some(*inner),
+                    _ => None,
+                }
+            }
+            // the types of parameters and results that aren't given aren't known
+            Expression::Lambda { params, return_type, statements, .. } => {
+                let params: Vec<(String, IrTypeIndex)> = params.iter()
+                    .map(|param| (param.name.clone(), param.typ.map_or(ctx.unknown_index, |typ| self.build_type(ctx, &typ))))
+                    .collect();
+                let return_type = match return_type {
+                    Some(typ) => self.build_type(ctx, typ),
+                    None => self.lambda_return_type(ctx, &params, statements),
+                };
+                Some(self.closure_type(ctx, params.into_iter().map(|(_, typ)| typ).collect(), return_type))
+            }
+            _ => None,
+        }
+    }
+
+    /// The type the function called `name` returns, if it returns a value.
+    fn return_type(&self, ctx: &mut IrBuilderContext, name: &str) -> Option<IrTypeIndex> {
+        let typ = self.build_type(ctx, &Self::signature(ctx.program, name)?.2);
+        Some(typ).filter(|typ| *typ != ctx.void_index)
+    }
+
+    /// Check that the integer literal `-magnitude` or `magnitude` fits the integer type it's used
+    /// as, or in 64 bits when that isn't known, and get the bits it's built with.
+    fn check_literal(&self, ctx: &mut IrBuilderContext, negative: bool, magnitude: u128, expected: Option<IrTypeIndex>) -> i128 {
+        let (min_magnitude, max, name) = match expected.and_then(|typ| ctx.module_arena.type_arena.get(typ)) {
+            Some(IrType::Int(typ)) => (1u128 << (typ.bits() - 1), (1u128 << (typ.bits() - 1)) - 1, Some(typ.name())),
+            Some(IrType::UInt(typ)) => (0, u128::MAX >> (128 - typ.bits()), Some(typ.name())),
+            _ => (1u128 << 63, u64::MAX as u128, None),
+        };
+        let value = if negative { format!("-{}", magnitude) } else { magnitude.to_string() };
+        let span = ctx.current_span;
+        let fits = if negative { magnitude <= min_magnitude } else { magnitude <= max };
+        if !fits {
+            let min = if min_magnitude == 0 { "0".to_string() } else { format!("-{}", min_magnitude) };
+            match name {
+                Some(name) => ctx.error(&format!("integer literal `{}` is out of range for `{}`", value, name), span,
+                                        &format!("`{}` holds `{}` to `{}`", name, min, max)),
+                None => ctx.error(&format!("integer literal `{}` is out of range", value), span,
+                                  "integer literals have to fit in 64 bits when their type isn't known"),
+            }
+        }
+        let bits = magnitude as i128;
+        if negative { bits.wrapping_neg() } else { bits }
     }
 }
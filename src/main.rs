@@ -1,5 +1,7 @@
+use std::path::Path;
+use std::process::exit;
 use crate::compiler::Compiler;
-use crate::ir::IrNode;
+use crate::options::Options;
 
 mod ast;
 mod parser;
@@ -7,8 +9,21 @@ mod diagnostic;
 mod ir;
 mod backend;
 mod compiler;
+mod options;
 
 fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, options::USAGE);
+            exit(1);
+        }
+    };
+    if options.input.is_some() {
+        compile_file(options);
+        return;
+    }
+
     let test_file = "test.nuv";
     let test_source = "\
 
@@ -139,7 +154,7 @@ fn main() {
 
     ".to_string();
 
-    let mut compiler = Compiler::new();
+    let mut compiler = Compiler::new(options);
     compiler.parse_module(ast::Path::of("test"), test_file.to_string(), test_source);
     for (_index, module) in compiler.modules.iter() {
         for (_node_index, node) in module.module_arena.node_arena.iter() {
@@ -158,3 +173,39 @@ fn main() {
 
     println!("parse complete!")
 }
+
+/// Compile the input file to whatever `--emit` asked for, or run it with `run`.
+fn compile_file(options: Options) {
+    let file = options.input.clone().unwrap();
+    let source = match std::fs::read_to_string(&file) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("couldn't read {}: {}", file, error);
+            exit(1);
+        }
+    };
+    let module_name = Path::new(&file).file_stem().unwrap().to_string_lossy().to_string();
+
+    let mut compiler = Compiler::new(options);
+    let module = match compiler.parse_module(ast::Path::of(&module_name), file, source) {
+        Some(module) => module,
+        None => exit(1),
+    };
+    if let Err(error) = compiler.optimize(module) {
+        eprintln!("{}", error);
+        exit(1);
+    }
+
+    if compiler.options.run {
+        match compiler.run(module) {
+            Ok(result) => println!("{}", result),
+            Err(error) => {
+                eprintln!("{}", error);
+                exit(1);
+            }
+        }
+    } else if let Err(error) = compiler.emit(module) {
+        eprintln!("{}", error);
+        exit(1);
+    }
+}
@@ -0,0 +1,270 @@
+use std::path::{Path, PathBuf};
+
+/// What the compiler should produce for the input file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmitKind {
+    C,
+    LlvmIr,
+    Assembly,
+    Object,
+    Executable,
+    Wasm,
+    Wat,
+}
+
+impl EmitKind {
+    pub fn from<Str: AsRef<str>>(name: Str) -> Option<Self> {
+        match name.as_ref() {
+            "c" => Some(EmitKind::C),
+            "llvm-ir" => Some(EmitKind::LlvmIr),
+            "asm" => Some(EmitKind::Assembly),
+            "obj" => Some(EmitKind::Object),
+            "exe" => Some(EmitKind::Executable),
+            "wasm" => Some(EmitKind::Wasm),
+            "wat" => Some(EmitKind::Wat),
+            &_ => None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            EmitKind::C => "c",
+            EmitKind::LlvmIr => "ll",
+            EmitKind::Assembly => "s",
+            EmitKind::Object => "o",
+            EmitKind::Executable => "",
+            EmitKind::Wasm => "wasm",
+            EmitKind::Wat => "wat",
+        }
+    }
+}
+
+/// Which backend compiles or runs the input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    Llvm,
+    Cranelift,
+    C,
+    Wasm,
+    Interpreter,
+}
+
+impl BackendKind {
+    pub fn from<Str: AsRef<str>>(name: Str) -> Option<Self> {
+        match name.as_ref() {
+            "llvm" => Some(BackendKind::Llvm),
+            "cranelift" => Some(BackendKind::Cranelift),
+            "c" => Some(BackendKind::C),
+            "wasm" => Some(BackendKind::Wasm),
+            "interpreter" => Some(BackendKind::Interpreter),
+            &_ => None
+        }
+    }
+}
+
+impl Default for BackendKind {
+    /// LLVM when nuvae was built with it, otherwise Cranelift.
+    fn default() -> Self {
+        if cfg!(feature = "llvm") {
+            BackendKind::Llvm
+        } else {
+            BackendKind::Cranelift
+        }
+    }
+}
+
+/// How hard LLVM should try to optimize the generated code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+    O3,
+    Os,
+}
+
+impl OptLevel {
+    pub fn from<Str: AsRef<str>>(name: Str) -> Option<Self> {
+        match name.as_ref() {
+            "-O0" => Some(OptLevel::O0),
+            "-O1" => Some(OptLevel::O1),
+            "-O2" => Some(OptLevel::O2),
+            "-O3" => Some(OptLevel::O3),
+            "-Os" => Some(OptLevel::Os),
+            &_ => None
+        }
+    }
+
+    /// The flag for this level, which C compilers understand as well.
+    pub fn flag(&self) -> &'static str {
+        match self {
+            OptLevel::O0 => "-O0",
+            OptLevel::O1 => "-O1",
+            OptLevel::O2 => "-O2",
+            OptLevel::O3 => "-O3",
+            OptLevel::Os => "-Os",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub input: Option<String>,
+    pub output: Option<String>,
+    pub emit: EmitKind,
+    pub backend: BackendKind,
+    pub opt_level: OptLevel,
+    /// Whether to emit DWARF debug info.
+    pub debug_info: bool,
+    /// Target triple to compile for, defaults to the host.
+    pub target: Option<String>,
+    pub cpu: Option<String>,
+    pub features: Option<String>,
+    /// Command used to link object files into an executable, and to compile the output of the C backend.
+    pub linker: String,
+    /// Whether to JIT compile and run the input instead of writing output.
+    pub run: bool,
+    /// Function that `run` calls.
+    pub entry: String,
+    /// Integer arguments passed to the entry function by `run`.
+    pub program_args: Vec<String>,
+    /// IR passes to run in order, instead of the default pipeline for the optimization level.
+    /// The required passes run as well.
+    pub passes: Option<Vec<String>>,
+    /// Passes after which the IR is printed.
+    pub print_after: Vec<String>,
+    /// Whether to print how long each IR pass took.
+    pub time_passes: bool,
+    /// Whether to explain why the allocations left after optimizing escape.
+    pub report_escapes: bool,
+    /// Whether integer arithmetic traps on overflow instead of wrapping, by default only with `-O0`.
+    pub overflow_checks: Option<bool>,
+}
+
+pub const USAGE: &str = "\
+usage: nuvae [options] <file.nuv>
+       nuvae run [options] <file.nuv> [args...]
+
+options:
+    -o <file>               write output to <file>
+    --emit=<kind>           c, llvm-ir, asm, obj, wasm, wat or exe (default)
+    --backend=<name>        llvm (default), cranelift, c, wasm or interpreter, which can only `run`
+    -O0, -O1, -O2, -O3, -Os optimization level, defaults to -O0
+    -g                      emit debug info
+    --target=<triple>       target triple to compile for, defaults to the host
+    --cpu=<name>            target cpu
+    --features=<list>       target features, like +neon,-fp-armv8
+    --linker=<command>      command used to link executables, defaults to cc
+    --entry=<name>          function called by `run`, defaults to main
+    --passes=<list>         IR passes to run in order, instead of the default for the optimization level,
+                            tailcall runs first unless the list has it
+    --print-after=<pass>    print the IR to stderr after <pass> runs
+    --time-passes           print how long each IR pass took
+    --report-escapes        explain why allocations couldn't be moved to the stack
+    --overflow-checks=<on|off>
+                            whether integer overflow panics or wraps, defaults to on with -O0";
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            input: None,
+            output: None,
+            emit: EmitKind::Executable,
+            backend: BackendKind::default(),
+            opt_level: OptLevel::O0,
+            debug_info: false,
+            target: None,
+            cpu: None,
+            features: None,
+            linker: "cc".to_string(),
+            run: false,
+            entry: "main".to_string(),
+            program_args: vec![],
+            passes: None,
+            print_after: vec![],
+            time_passes: false,
+            report_escapes: false,
+            overflow_checks: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item=String>>(args: I) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.peekable();
+        if args.peek().map(|arg| arg.as_str()) == Some("run") {
+            args.next();
+            options.run = true;
+        }
+        while let Some(arg) = args.next() {
+            // everything after the input file is passed to the program that's being run
+            if options.run && options.input.is_some() {
+                options.program_args.push(arg);
+                continue;
+            }
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg.clone(), None),
+            };
+            // flags take their value either after `=` or as the next argument
+            let take_value = |args: &mut std::iter::Peekable<I>| value.clone().or_else(|| args.next())
+                .ok_or(format!("expected a value after {}", flag));
+            match flag.as_str() {
+                "-o" => options.output = Some(take_value(&mut args)?),
+                "--emit" => {
+                    let kind = take_value(&mut args)?;
+                    options.emit = EmitKind::from(&kind).ok_or(format!("unknown output kind `{}`", kind))?;
+                }
+                "--backend" => {
+                    let name = take_value(&mut args)?;
+                    options.backend = BackendKind::from(&name).ok_or(format!("unknown backend `{}`", name))?;
+                }
+                "--target" => options.target = Some(take_value(&mut args)?),
+                "--cpu" => options.cpu = Some(take_value(&mut args)?),
+                "--features" => options.features = Some(take_value(&mut args)?),
+                "--linker" => options.linker = take_value(&mut args)?,
+                "-g" => options.debug_info = true,
+                "--entry" => options.entry = take_value(&mut args)?,
+                "--passes" => {
+                    let passes = take_value(&mut args)?;
+                    options.passes = Some(passes.split(',').filter(|pass| !pass.is_empty()).map(str::to_string).collect());
+                }
+                "--print-after" => options.print_after.push(take_value(&mut args)?),
+                "--time-passes" => options.time_passes = true,
+                "--report-escapes" => options.report_escapes = true,
+                "--overflow-checks" => {
+                    options.overflow_checks = match take_value(&mut args)?.as_str() {
+                        "on" => Some(true),
+                        "off" => Some(false),
+                        value => return Err(format!("expected on or off after --overflow-checks but got `{}`", value)),
+                    };
+                }
+                f if OptLevel::from(f).is_some() => options.opt_level = OptLevel::from(f).unwrap(),
+                f if f.starts_with('-') => return Err(format!("unknown option `{}`", f)),
+                _ => {
+                    if options.input.is_some() {
+                        return Err(format!("unexpected argument `{}`", arg));
+                    }
+                    options.input = Some(arg);
+                }
+            }
+        }
+        Ok(options)
+    }
+
+    /// Whether arithmetic that overflows traps, which it does for unoptimized builds unless
+    /// `--overflow-checks` says otherwise.
+    pub fn overflow_checks(&self) -> bool {
+        self.overflow_checks.unwrap_or(self.opt_level == OptLevel::O0)
+    }
+
+    /// The file to write output to, which is named after the input file unless `-o` was given.
+    pub fn output_path(&self) -> PathBuf {
+        if let Some(output) = &self.output {
+            return PathBuf::from(output);
+        }
+        let input = Path::new(self.input.as_deref().unwrap_or("out.nuv"));
+        input.with_extension(self.emit.extension())
+    }
+}
@@ -0,0 +1,617 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+/// Words that are keywords rather than names.
+const KEYWORDS: &[&str] = &[
+    "and", "as", "else", "enum", "false", "fun", "if", "import", "in", "interface", "let", "new", "not", "or",
+    "public", "return", "self", "struct", "true", "type", "unique", "where",
+];
+
+/// Punctuation, with the longer ones before the ones they start with.
+const PUNCTUATION: &[&str] = &[
+    "->", "::", "<=", ">=", "==", "!=", "<<", ">>", ".&", ".*", ".?",
+    "+%", "-%", "*%", "+|", "-|", "*|",
+    "%", "&", "(", ")", "*", "+", ",", "-", ".", "/", ":", ";", "<", "=", ">", "?", "@", "[", "]", "^", "{", "|", "}",
+];
+
+/// Suffixes that give a number literal a type, and the names of those types.
+const SUFFIXES: &[(&str, &str)] = &[
+    ("i8", "Int8"), ("i16", "Int16"), ("i32", "Int32"), ("i64", "Int64"), ("i128", "Int128"), ("isize", "IntSize"),
+    ("u8", "UInt8"), ("u16", "UInt16"), ("u32", "UInt32"), ("u64", "UInt64"), ("u128", "UInt128"), ("usize", "USize"),
+    ("f16", "Float16"), ("f32", "Float32"), ("f64", "Float64"), ("f128", "Float128"),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Tok<'input> {
+    Name(&'input str),
+    /// An integer literal and its suffix.
+    Num(&'input str, Option<&'input str>),
+    /// A float literal, which has a fraction or an exponent, and its suffix.
+    Float(&'input str, Option<&'input str>),
+    /// A string literal, with its escapes replaced by what they stand for.
+    Str(String),
+    Char(char),
+    /// A keyword or punctuation, by its text.
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Tok<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tok::Name(text) => write!(f, "{}", text),
+            Tok::Num(text, suffix) | Tok::Float(text, suffix) => write!(f, "{}{}", text, suffix.unwrap_or("")),
+            Tok::Str(string) => write!(f, "{:?}", string),
+            Tok::Char(character) => write!(f, "{:?}", character),
+            Tok::Symbol(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LexicalError {
+    UnexpectedCharacter { location: usize, character: char },
+    UnterminatedBlockComment { start: usize },
+    UnterminatedString { start: usize },
+    UnterminatedCharacter { start: usize },
+    /// A character literal with no characters or more than one, between its quotes.
+    InvalidCharacterLiteral { range: Range<usize> },
+    InvalidEscape { location: usize, escape: String },
+    InvalidSuffix { location: usize, suffix: String },
+    /// A float literal with a suffix for an integer type.
+    IntegerSuffixOnFloat { location: usize, suffix: String },
+    /// A float literal too large for any finite `Float64`.
+    FloatOutOfRange { range: Range<usize> },
+    /// A hexadecimal, octal or binary literal with nothing after its prefix.
+    MissingDigits { range: Range<usize> },
+    /// A digit that's too large for the base of its literal, like the `2` in `0b12`.
+    InvalidDigit { location: usize, digit: char, radix: u32 },
+    /// An integer literal too large for any integer type.
+    IntegerOutOfRange { range: Range<usize> },
+    /// An integer literal whose digits end with a `_`, like `1_` or `0xff_`.
+    TrailingUnderscore { location: usize },
+}
+
+impl LexicalError {
+    pub fn message(&self) -> String {
+        match self {
+            LexicalError::UnexpectedCharacter { character, .. } => format!("unexpected character `{}`", character),
+            LexicalError::UnterminatedBlockComment { .. } => "unterminated block comment".to_string(),
+            LexicalError::UnterminatedString { .. } => "unterminated string".to_string(),
+            LexicalError::UnterminatedCharacter { .. } => "unterminated character literal".to_string(),
+            LexicalError::InvalidCharacterLiteral { .. } => "character literals hold exactly one character".to_string(),
+            LexicalError::InvalidEscape { escape, .. } => format!("invalid escape sequence `{}`", escape),
+            LexicalError::InvalidSuffix { suffix, .. } => format!("invalid suffix `{}` for a number literal", suffix),
+            LexicalError::IntegerSuffixOnFloat { suffix, .. } => format!("float literal with integer suffix `{}`", suffix),
+            LexicalError::FloatOutOfRange { .. } => "float literal is out of range".to_string(),
+            LexicalError::MissingDigits { .. } => "integer literal has no digits".to_string(),
+            LexicalError::InvalidDigit { digit, radix, .. } => format!("invalid digit `{}` in {} literal", digit, radix_name(*radix)),
+            LexicalError::IntegerOutOfRange { .. } => "integer literal is out of range".to_string(),
+            LexicalError::TrailingUnderscore { .. } => "integer literal ends with `_`".to_string(),
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            LexicalError::UnexpectedCharacter { .. } => "this character isn't part of any token",
+            LexicalError::UnterminatedBlockComment { .. } => "this comment is never closed with `*/`",
+            LexicalError::UnterminatedString { .. } => "this string is never closed with `\"`",
+            LexicalError::UnterminatedCharacter { .. } => "this character literal is never closed with `'`",
+            LexicalError::InvalidCharacterLiteral { .. } => "use double quotes for strings",
+            LexicalError::InvalidEscape { .. } => "escapes are `\\n`, `\\r`, `\\t`, `\\0`, `\\\\`, `\\\"`, `\\'`, `\\x00` to `\\x7F` and `\\u{...}`",
+            LexicalError::InvalidSuffix { .. } => "suffixes are `i8` to `i128`, `isize`, `u8` to `u128`, `usize` and `f16` to `f128`",
+            LexicalError::IntegerSuffixOnFloat { .. } => "floats can only have a float suffix, convert them to integers with `as`",
+            LexicalError::FloatOutOfRange { .. } => "this is too large to be a `Float64`",
+            LexicalError::MissingDigits { .. } => "`0x`, `0o` and `0b` have to be followed by digits",
+            LexicalError::InvalidDigit { radix: 2, .. } => "binary literals only have the digits `0` and `1`",
+            LexicalError::InvalidDigit { .. } => "octal literals only have the digits `0` to `7`",
+            LexicalError::IntegerOutOfRange { .. } => "this is too large to be a `UInt128`",
+            LexicalError::TrailingUnderscore { .. } => "`_` can only separate digits, or the digits from a suffix",
+        }
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        match self {
+            LexicalError::UnexpectedCharacter { location, character } => *location..*location + character.len_utf8(),
+            LexicalError::UnterminatedBlockComment { start } => *start..*start + 2,
+            LexicalError::UnterminatedString { start } | LexicalError::UnterminatedCharacter { start } => *start..*start + 1,
+            LexicalError::InvalidCharacterLiteral { range } => range.clone(),
+            LexicalError::InvalidEscape { location, escape } => *location..*location + escape.len(),
+            LexicalError::InvalidSuffix { location, suffix } | LexicalError::IntegerSuffixOnFloat { location, suffix } => {
+                *location..*location + suffix.len()
+            }
+            LexicalError::FloatOutOfRange { range } | LexicalError::MissingDigits { range } | LexicalError::IntegerOutOfRange { range } => {
+                range.clone()
+            }
+            LexicalError::InvalidDigit { location, digit, .. } => *location..*location + digit.len_utf8(),
+            LexicalError::TrailingUnderscore { location } => *location..*location + 1,
+        }
+    }
+}
+
+/// Consecutive `///` comments, without the slashes.
+#[derive(Clone, Debug)]
+pub struct DocComment {
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+pub type Spanned<'input> = Result<(usize, Tok<'input>, usize), LexicalError>;
+
+/// Splits source code into tokens, skipping whitespace and comments. Line comments start with
+/// `//`, block comments are between `/*` and `*/` and can be nested. Doc comments are line
+/// comments that start with exactly three slashes, they're kept for the token that follows them.
+/// String and character literals have their escapes replaced while they're read.
+pub struct Lexer<'input> {
+    source: &'input str,
+    position: usize,
+    /// Doc comments by the offset of the token they come before, or the end of the source when
+    /// nothing follows them.
+    pub docs: HashMap<usize, DocComment>,
+    pending_doc: Option<DocComment>,
+}
+
+impl<'input> Lexer<'input> {
+    pub fn new(source: &'input str) -> Self {
+        Self { source, position: 0, docs: HashMap::new(), pending_doc: None }
+    }
+
+    fn rest(&self) -> &'input str {
+        &self.source[self.position..]
+    }
+
+    /// Skip whitespace and comments, returning an error for a block comment that never ends.
+    fn skip_trivia(&mut self) -> Result<(), LexicalError> {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.position += rest.len() - trimmed.len();
+            if trimmed.starts_with("//") {
+                let end = trimmed.find('\n').unwrap_or(trimmed.len());
+                let comment = &trimmed[..end];
+                if comment.starts_with("///") && !comment.starts_with("////") {
+                    let line = comment["///".len()..].trim_end();
+                    let line = line.strip_prefix(' ').unwrap_or(line);
+                    let range = self.position..self.position + comment.trim_end().len();
+                    match &mut self.pending_doc {
+                        Some(doc) => {
+                            doc.range.end = range.end;
+                            doc.text.push('\n');
+                            doc.text.push_str(line);
+                        }
+                        None => self.pending_doc = Some(DocComment { range, text: line.to_string() }),
+                    }
+                }
+                self.position += end;
+            } else if trimmed.starts_with("/*") {
+                self.skip_block_comment()?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn skip_block_comment(&mut self) -> Result<(), LexicalError> {
+        let start = self.position;
+        let mut depth = 0;
+        while self.position < self.source.len() {
+            let rest = self.rest();
+            if rest.starts_with("/*") {
+                depth += 1;
+                self.position += 2;
+            } else if rest.starts_with("*/") {
+                depth -= 1;
+                self.position += 2;
+                if depth == 0 {
+                    return Ok(());
+                }
+            } else {
+                self.position += rest.chars().next().unwrap().len_utf8();
+            }
+        }
+        Err(LexicalError::UnterminatedBlockComment { start })
+    }
+
+    /// Read the token at the current position and return it with its length. Errors come with
+    /// the length of the source they skip.
+    fn token(&self) -> Result<(Tok<'input>, usize), (LexicalError, usize)> {
+        let rest = self.rest();
+        let first = rest.chars().next().unwrap();
+        if let Some(hashes) = raw_string_hashes(rest) {
+            return self.raw_string(hashes);
+        }
+        if first.is_ascii_alphabetic() {
+            let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+            let word = &rest[..len];
+            return match KEYWORDS.iter().find(|keyword| **keyword == word) {
+                Some(keyword) => Ok((Tok::Symbol(keyword), len)),
+                None => Ok((Tok::Name(word), len)),
+            };
+        }
+        if first.is_ascii_digit() {
+            return self.number();
+        }
+        match first {
+            '"' => return self.string(),
+            '\'' => return self.character(),
+            _ => {}
+        }
+        // `&mut` and `.&mut` are single tokens, but only when `mut` isn't the start of a longer name
+        for symbol in [".&mut", "&mut"] {
+            if let Some(after) = rest.strip_prefix(symbol) {
+                if !after.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_') {
+                    return Ok((Tok::Symbol(symbol), symbol.len()));
+                }
+            }
+        }
+        match PUNCTUATION.iter().find(|punctuation| rest.starts_with(**punctuation)) {
+            Some(punctuation) => Ok((Tok::Symbol(punctuation), punctuation.len())),
+            None => Err((LexicalError::UnexpectedCharacter { location: self.position, character: first }, first.len_utf8())),
+        }
+    }
+
+    /// Read an integer or float literal and its suffix, like `12`, `1_000`, `0xff`, `1.5e-3`,
+    /// `0x1.8p3` or `7u8`. Hexadecimal floats need an exponent, which is a power of two.
+    fn number(&self) -> Result<(Tok<'input>, usize), (LexicalError, usize)> {
+        let rest = self.rest();
+        let digits = |from: usize| from + rest[from..].len() - rest[from..].trim_start_matches(|c: char| c.is_ascii_digit() || c == '_').len();
+        let (len, float) = match (hex_float_len(rest), radix(rest)) {
+            (Some(len), _) => (len, true),
+            (None, Some(radix)) => (self.prefixed_digits(radix)?, false),
+            (None, None) => {
+                let mut len = digits(0);
+                let mut float = false;
+                if rest[len..].starts_with('.') && rest[len + 1..].starts_with(|c: char| c.is_ascii_digit()) {
+                    len = digits(len + 1);
+                    float = true;
+                }
+                if let Some(exponent) = exponent_len(&rest[len..], 'e') {
+                    len += exponent;
+                    float = true;
+                }
+                (len, float)
+            }
+        };
+        let text = &rest[..len];
+        let suffix_len = rest[len..].len() - rest[len..].trim_start_matches(|c: char| c.is_ascii_alphanumeric() || c == '_').len();
+        let suffix = &rest[len..len + suffix_len];
+        let total = len + suffix_len;
+        if !suffix.is_empty() {
+            let location = self.position + len;
+            match suffix_type(suffix) {
+                None => return Err((LexicalError::InvalidSuffix { location, suffix: suffix.to_string() }, total)),
+                Some(typ) if float && !typ.starts_with("Float") => {
+                    return Err((LexicalError::IntegerSuffixOnFloat { location, suffix: suffix.to_string() }, total));
+                }
+                _ => {}
+            }
+        }
+        let suffix = (!suffix.is_empty()).then_some(suffix);
+        if !float {
+            if text.ends_with('_') && suffix.is_none() {
+                return Err((LexicalError::TrailingUnderscore { location: self.position + len - 1 }, total));
+            }
+            if checked_int_value(text).is_none() {
+                return Err((LexicalError::IntegerOutOfRange { range: self.position..self.position + len }, total));
+            }
+            return Ok((Tok::Num(text, suffix), total));
+        }
+        if float_value(text).is_infinite() {
+            return Err((LexicalError::FloatOutOfRange { range: self.position..self.position + len }, total));
+        }
+        Ok((Tok::Float(text, suffix), total))
+    }
+
+    /// The length of the hexadecimal, octal or binary literal at the current position, without
+    /// its suffix. Hexadecimal digits can't start a suffix, so octal and binary literals are read
+    /// with all decimal digits and the ones too large for them are reported.
+    fn prefixed_digits(&self, radix: u32) -> Result<usize, (LexicalError, usize)> {
+        let rest = self.rest();
+        let is_digit = |c: char| c == '_' || if radix == 16 { c.is_ascii_hexdigit() } else { c.is_ascii_digit() };
+        let len = 2 + rest[2..].len() - rest[2..].trim_start_matches(is_digit).len();
+        let word = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_alphanumeric() || c == '_').len();
+        if !rest[2..len].contains(|c: char| c != '_') {
+            return Err((LexicalError::MissingDigits { range: self.position..self.position + len }, word));
+        }
+        if let Some((offset, digit)) = rest[2..len].char_indices().find(|(_, c)| c.to_digit(radix).is_none() && *c != '_') {
+            return Err((LexicalError::InvalidDigit { location: self.position + 2 + offset, digit, radix }, word));
+        }
+        Ok(len)
+    }
+
+    /// Read a string between double quotes. A string with an invalid escape is still read to its
+    /// end, so lexing carries on after it.
+    fn string(&self) -> Result<(Tok<'input>, usize), (LexicalError, usize)> {
+        let rest = self.rest();
+        let mut string = String::new();
+        let mut error = None;
+        let mut offset = 1;
+        while let Some(c) = rest[offset..].chars().next() {
+            match c {
+                '"' => {
+                    let len = offset + 1;
+                    return match error {
+                        Some(error) => Err((error, len)),
+                        None => Ok((Tok::Str(string), len)),
+                    };
+                }
+                '\\' => match escape(&rest[offset..], self.position + offset) {
+                    Ok((escaped, len)) => {
+                        string.push(escaped);
+                        offset += len;
+                    }
+                    Err(invalid) => {
+                        offset += invalid.range().len();
+                        error.get_or_insert(invalid);
+                    }
+                },
+                c => {
+                    string.push(c);
+                    offset += c.len_utf8();
+                }
+            }
+        }
+        // nothing after a quote that's never closed can be lexed meaningfully
+        Err((LexicalError::UnterminatedString { start: self.position }, rest.len()))
+    }
+
+    /// Read a raw string, `r"..."` or `r#"..."#` with any number of `#`s. Raw strings have no
+    /// escapes, and can contain quotes followed by fewer `#`s than they started with.
+    fn raw_string(&self, hashes: usize) -> Result<(Tok<'input>, usize), (LexicalError, usize)> {
+        let rest = self.rest();
+        let start = 1 + hashes + 1;
+        let terminator = format!("\"{}", "#".repeat(hashes));
+        match rest[start..].find(&terminator) {
+            Some(end) => Ok((Tok::Str(rest[start..start + end].to_string()), start + end + terminator.len())),
+            None => Err((LexicalError::UnterminatedString { start: self.position }, rest.len())),
+        }
+    }
+
+    /// Read a character literal, which holds a single character or escape between single quotes.
+    fn character(&self) -> Result<(Tok<'input>, usize), (LexicalError, usize)> {
+        let rest = self.rest();
+        let literal = match rest[1..].chars().next() {
+            Some('\\') => Some(escape(&rest[1..], self.position + 1).map(|(c, len)| (c, 1 + len))),
+            Some(c) if c != '\'' && c != '\n' => Some(Ok((c, 1 + c.len_utf8()))),
+            _ => None,
+        };
+        match literal {
+            Some(Ok((c, len))) if rest[len..].starts_with('\'') => return Ok((Tok::Char(c), len + 1)),
+            Some(Err(error)) => {
+                let len = rest[1..].find(['\'', '\n']).map_or(rest.len(), |end| end + 2);
+                return Err((error, len));
+            }
+            _ => {}
+        }
+        // a closing quote later on the line means there's too much or nothing between the quotes
+        let line_end = rest[1..].find('\n').map_or(rest.len(), |end| end + 1);
+        match rest[1..line_end].find('\'') {
+            Some(end) => {
+                let range = self.position..self.position + end + 2;
+                Err((LexicalError::InvalidCharacterLiteral { range }, end + 2))
+            }
+            None => Err((LexicalError::UnterminatedCharacter { start: self.position }, 1)),
+        }
+    }
+}
+
+/// The length of the hexadecimal float `text` starts with, like `0x1.8p3`, if it starts with one.
+fn hex_float_len(text: &str) -> Option<usize> {
+    let digits = |from: usize| from + text[from..].len() - text[from..].trim_start_matches(|c: char| c.is_ascii_hexdigit()).len();
+    if !(text.starts_with("0x") || text.starts_with("0X")) {
+        return None;
+    }
+    let mut len = digits(2);
+    if len == 2 {
+        return None;
+    }
+    if text[len..].starts_with('.') {
+        len = digits(len + 1);
+    }
+    exponent_len(&text[len..], 'p').map(|exponent| len + exponent)
+}
+
+/// The length of the exponent `text` starts with, which is `marker` in either case, an optional
+/// sign and at least one digit.
+fn exponent_len(text: &str, marker: char) -> Option<usize> {
+    let after_marker = text.strip_prefix([marker, marker.to_ascii_uppercase()])?;
+    let after_sign = after_marker.strip_prefix(['+', '-']).unwrap_or(after_marker);
+    let digits = after_sign.len() - after_sign.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    (digits > 0).then_some(text.len() - after_sign.len() + digits)
+}
+
+/// The base of the integer literal `text` starts with, if it starts with `0x`, `0o` or `0b`.
+fn radix(text: &str) -> Option<u32> {
+    match text.get(..2)? {
+        "0x" | "0X" => Some(16),
+        "0o" | "0O" => Some(8),
+        "0b" | "0B" => Some(2),
+        _ => None,
+    }
+}
+
+fn radix_name(radix: u32) -> &'static str {
+    match radix {
+        16 => "a hexadecimal",
+        8 => "an octal",
+        2 => "a binary",
+        _ => "a decimal",
+    }
+}
+
+/// The value of an integer literal's text, which can have a `0x`, `0o` or `0b` prefix and `_`s
+/// between its digits, if it fits in a `u128`.
+fn checked_int_value(text: &str) -> Option<u128> {
+    let (radix, digits) = match radix(text) {
+        Some(radix) => (radix, &text[2..]),
+        None => (10, text),
+    };
+    digits.chars().filter(|c| *c != '_').try_fold(0u128, |value, digit| {
+        value.checked_mul(radix as u128)?.checked_add(digit.to_digit(radix)? as u128)
+    })
+}
+
+/// The value of an integer literal's text. The lexer only makes integer tokens of literals
+/// that fit in a `u128`.
+pub fn int_value(text: &str) -> u128 {
+    checked_int_value(text).unwrap()
+}
+
+/// The name of the type a number literal suffix stands for.
+pub fn suffix_type(suffix: &str) -> Option<&'static str> {
+    SUFFIXES.iter().find(|(s, _)| *s == suffix).map(|(_, typ)| *typ)
+}
+
+/// The value of a float literal's text, which can be a decimal or a hexadecimal float.
+pub fn float_value(text: &str) -> f64 {
+    let hex = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => hex,
+        None => return text.replace('_', "").parse().unwrap(),
+    };
+    let (mantissa, exponent) = hex.split_once(['p', 'P']).unwrap();
+    let exponent = exponent.parse::<i32>().unwrap_or(if exponent.starts_with('-') { i32::MIN } else { i32::MAX });
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let mut value = 0.0;
+    for digit in whole.chars().chain(fraction.chars()) {
+        value = value * 16.0 + digit.to_digit(16).unwrap() as f64;
+    }
+    if value == 0.0 {
+        return value;
+    }
+    // scaling in two steps keeps the power of two from overflowing when the result wouldn't
+    let exponent = exponent.saturating_sub(4 * fraction.len() as i32);
+    value * 2f64.powi(exponent / 2) * 2f64.powi(exponent - exponent / 2)
+}
+
+/// The number of `#`s a raw string starts with, if `text` starts with one.
+fn raw_string_hashes(text: &str) -> Option<usize> {
+    let after_r = text.strip_prefix('r')?;
+    let hashes = after_r.len() - after_r.trim_start_matches('#').len();
+    after_r[hashes..].starts_with('"').then_some(hashes)
+}
+
+/// Read the escape sequence `text` starts with, whose backslash is at `location` in the source.
+/// Returns the character it stands for and its length.
+fn escape(text: &str, location: usize) -> Result<(char, usize), LexicalError> {
+    // the escape in the error goes up to the character that made it invalid, or the quote that
+    // ends the string it's in
+    let invalid = |chars: usize| {
+        let end = text.char_indices().nth(chars).map_or(text.len(), |(i, _)| i);
+        let end = text[1..end].find('"').map_or(end, |quote| quote + 1);
+        LexicalError::InvalidEscape { location, escape: text[..end].to_string() }
+    };
+    let simple = match text[1..].chars().next() {
+        Some('n') => '\n',
+        Some('r') => '\r',
+        Some('t') => '\t',
+        Some('0') => '\0',
+        Some('\\') => '\\',
+        Some('"') => '"',
+        Some('\'') => '\'',
+        Some('x') => {
+            let digits = text.get(2..4).filter(|digits| digits.chars().all(|c| c.is_ascii_hexdigit()));
+            return match digits.map(|digits| u8::from_str_radix(digits, 16).unwrap()) {
+                Some(byte) if byte <= 0x7f => Ok((byte as char, 4)),
+                _ => Err(invalid(4)),
+            };
+        }
+        Some('u') => {
+            if !text[2..].starts_with('{') {
+                return Err(invalid(2));
+            }
+            let digits = text[3..].len() - text[3..].trim_start_matches(|c: char| c.is_ascii_hexdigit()).len();
+            let len = 3 + digits + 1;
+            if !text[3 + digits..].starts_with('}') {
+                return Err(invalid(3 + digits + 1));
+            }
+            return match u32::from_str_radix(&text[3..3 + digits], 16).ok().filter(|_| digits <= 6).and_then(char::from_u32) {
+                Some(c) => Ok((c, len)),
+                None => Err(invalid(len)),
+            };
+        }
+        _ => return Err(invalid(2)),
+    };
+    Ok((simple, 2))
+}
+
+impl<'input> Iterator for Lexer<'input> {
+    type Item = Spanned<'input>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(error) = self.skip_trivia() {
+            return Some(Err(error));
+        }
+        if let Some(doc) = self.pending_doc.take() {
+            self.docs.insert(self.position, doc);
+        }
+        if self.position >= self.source.len() {
+            return None;
+        }
+        let start = self.position;
+        match self.token() {
+            Ok((token, len)) => {
+                self.position += len;
+                Some(Ok((start, token, start + len)))
+            }
+            Err((error, len)) => {
+                self.position += len;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(source: &str) -> Vec<Result<Tok<'_>, String>> {
+        Lexer::new(source).map(|token| token.map(|(_, token, _)| token).map_err(|error| error.message())).collect()
+    }
+
+    #[test]
+    fn underscores_separate_digits() {
+        assert_eq!(lex("1_000"), [Ok(Tok::Num("1_000", None))]);
+        assert_eq!(lex("0x_ff_ff"), [Ok(Tok::Num("0x_ff_ff", None))]);
+        assert_eq!(lex("1_u8"), [Ok(Tok::Num("1_", Some("u8")))]);
+    }
+
+    #[test]
+    fn integer_literals_cant_end_with_an_underscore() {
+        let error = || Err("integer literal ends with `_`".to_string());
+        assert_eq!(lex("1_"), [error()]);
+        assert_eq!(lex("1__ + 2"), [error(), Ok(Tok::Symbol("+")), Ok(Tok::Num("2", None))]);
+        assert_eq!(lex("0xff_"), [error()]);
+        assert_eq!(lex("0b1_"), [error()]);
+    }
+
+    #[test]
+    fn trailing_underscores_are_reported_where_they_are() {
+        let errors: Vec<_> = Lexer::new("let x = 12_;").filter_map(Result::err).collect();
+        assert_eq!(errors, [LexicalError::TrailingUnderscore { location: 10 }]);
+    }
+
+    #[test]
+    fn mutable_borrows_are_single_tokens() {
+        assert_eq!(lex("x.&mut"), [Ok(Tok::Name("x")), Ok(Tok::Symbol(".&mut"))]);
+        assert_eq!(lex("x.&mutable"), [Ok(Tok::Name("x")), Ok(Tok::Symbol(".&")), Ok(Tok::Name("mutable"))]);
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        assert_eq!(lex("a // b\nc"), [Ok(Tok::Name("a")), Ok(Tok::Name("c"))]);
+        assert_eq!(lex("a /* b\n c */ d"), [Ok(Tok::Name("a")), Ok(Tok::Name("d"))]);
+        assert_eq!(lex("a /* b /* c */ d */ e"), [Ok(Tok::Name("a")), Ok(Tok::Name("e"))]);
+        assert_eq!(lex("a /* b /* c */ d"), [Ok(Tok::Name("a")), Err("unterminated block comment".to_string())]);
+    }
+
+    #[test]
+    fn doc_comments_are_kept_for_the_next_token() {
+        let mut lexer = Lexer::new("/// first\n///second\nfun\n//// not a doc\n// nor this\n/* nor this */ f");
+        let tokens: Vec<_> = lexer.by_ref().map(|token| token.unwrap().0).collect();
+        assert_eq!(tokens, [20, 66]);
+        assert_eq!(lexer.docs.len(), 1);
+        assert_eq!(lexer.docs[&20].text, "first\nsecond");
+        assert_eq!(lexer.docs[&20].range, 0..19);
+    }
+}
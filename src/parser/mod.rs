@@ -1,12 +1,15 @@
 use std::cell::RefCell;
 use codespan_reporting::diagnostic::{Diagnostic, Label};
 use lalrpop_util::*;
-use lalrpop_util::lexer::Token;
+use std::collections::HashMap;
 use std::ops::Range;
 
 use crate::ast::*;
 use crate::diagnostic::*;
 use generational_arena::Arena;
+use lexer::{DocComment, Lexer, LexicalError, Tok};
+
+pub mod lexer;
 
 lalrpop_mod!(#[allow(clippy::all)] #[allow(warnings)] #[allow(unknown_lints)] pub grammar, "/parser/grammar.rs");
 
@@ -21,30 +24,30 @@ impl Parser {
         }
     }
 
-    fn range(error: &ParseError<usize, Token, &str>) -> Range<usize> {
+    fn range(error: &ParseError<usize, Tok, LexicalError>) -> Range<usize> {
         match error {
             ParseError::InvalidToken { location } => (*location..*location),
             ParseError::UnrecognizedEOF { location, expected: _ } => (*location..*location),
             ParseError::UnrecognizedToken { token, expected: _ } => (token.0..token.2),
             ParseError::ExtraToken { token } => (token.0..token.2),
-            ParseError::User { error: _ } => (0..0)
+            ParseError::User { error } => error.range()
         }
     }
 
-    fn add_parse_error(&mut self, file_id: FileId, error: ParseError<usize, Token, &str>) {
+    fn add_parse_error(&mut self, file_id: FileId, error: ParseError<usize, Tok, LexicalError>) {
         let message = match &error {
             ParseError::InvalidToken { location: _ } => "encountered invalid token while parsing".to_string(),
             ParseError::UnrecognizedEOF { location: _, expected: _ } => {
                 format!("encountered unexpected EOF while parsing")
             }
             ParseError::UnrecognizedToken { token, expected: _ } => {
-                format!("encountered unexpected '{}' while parsing", (token.1).1)
+                format!("encountered unexpected '{}' while parsing", token.1)
             }
             ParseError::ExtraToken { token } => {
-                format!("encountered unexpected '{}' while parsing but it is not needed", (token.1).1)
+                format!("encountered unexpected '{}' while parsing but it is not needed", token.1)
             }
             ParseError::User { error } => {
-                error.to_string()
+                error.message()
             }
         };
         let label_message = match &error {
@@ -52,7 +55,7 @@ impl Parser {
             ParseError::UnrecognizedEOF { location: _, expected: _ } => Some("unexpected end of file"),
             ParseError::UnrecognizedToken { token: _, expected: _ } => Some("unexpected token"),
             ParseError::ExtraToken { token: _ } => Some("unexpected token"),
-            ParseError::User { error } => Some(*error),
+            ParseError::User { error } => Some(error.label()),
         };
 
         let mut label = Label::primary(file_id, Self::range(&error));
@@ -66,19 +69,47 @@ impl Parser {
         self.diagnostics.add_diagnostic(diagnostic);
     }
 
+    /// Attach doc comments to the nodes and enum variants that follow them, warning about the ones
+    /// that don't come before either.
+    fn attach_docs(&mut self, file_id: FileId, program_arena: &mut ProgramArena, mut docs: HashMap<usize, DocComment>) {
+        for (node, span) in program_arena.node_spans.iter() {
+            if let Some(doc) = docs.remove(&span.start().to_usize()) {
+                program_arena.node_docs.insert(*node, doc.text);
+            }
+        }
+        for (_, node) in program_arena.node_arena.iter_mut() {
+            if let Node::Enum { variants, .. } = node {
+                for variant in variants.iter_mut() {
+                    variant.doc = docs.remove(&variant.span.start().to_usize()).map(|doc| doc.text);
+                }
+            }
+        }
+        let mut dangling: Vec<DocComment> = docs.into_values().collect();
+        dangling.sort_by_key(|doc| doc.range.start);
+        for doc in dangling {
+            let diagnostic = Diagnostic::warning()
+                .with_message("doc comment doesn't document anything")
+                .with_labels(vec![Label::primary(file_id, doc.range)
+                    .with_message("doc comments go right before a declaration, use `//` for other comments")]);
+            self.diagnostics.add_diagnostic(diagnostic);
+        }
+    }
+
     pub fn parse(&mut self, path: Path, file_name: String, code: String) -> Option<Program> {
         let file_id = self.diagnostics.add_file(file_name.clone(), code.clone());
-        let file_name_parts: Vec<&str> = file_name.splitn(2, ".").collect();
-        let module_name = file_name_parts.get(0).unwrap();
+        let module_name = std::path::Path::new(&file_name).file_stem()
+            .map_or(file_name.clone(), |stem| stem.to_string_lossy().to_string());
 
-        let mut errors: Vec<ErrorRecovery<usize, Token, &str>> = Vec::new();
+        let mut errors: Vec<ErrorRecovery<usize, Tok, LexicalError>> = Vec::new();
         let mut program_arena = ProgramArena::new();
 
-        let result: Result<Vec<Path>, ParseError<usize, Token, &str>> = grammar::ProgramParser::new().parse(
+        let mut lexer = Lexer::new(&code);
+        let result: Result<Vec<Path>, ParseError<usize, Tok, LexicalError>> = grammar::ProgramParser::new().parse(
             &mut program_arena,
             &mut errors,
-            &code
+            &mut lexer
         );
+        self.attach_docs(file_id, &mut program_arena, lexer.docs);
 
         if errors.len() > 0 {
             for error in errors {
@@ -92,7 +123,9 @@ impl Parser {
                 Some(
                     Program {
                         path,
-                        file_name: module_name.to_string(),
+                        file_name: module_name,
+                        source_name: file_name,
+                        source: code,
                         imports,
                         program_arena,
                     }
@@ -105,3 +138,89 @@ impl Parser {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse `expression` and write it back with every operation in parentheses.
+    fn parenthesize(expression: &str) -> String {
+        let source = format!("fun f(a, b, c) {{\n    return {};\n}}", expression);
+        let program = Parser::new().parse(Path::of("test"), "test.nuv".to_string(), source).expect("the expression parses");
+        let value = program.program_arena.statement_arena.iter()
+            .find_map(|(_, statement)| match statement {
+                Statement::Return { value } => Some(*value),
+                _ => None,
+            })
+            .unwrap();
+        render(&program, value)
+    }
+
+    fn render(program: &Program, expression: ExpressionIndex) -> String {
+        match program.expression(expression) {
+            Expression::BinOp(lhs, op, rhs) => format!("({} {} {})", render(program, *lhs), op, render(program, *rhs)),
+            Expression::UnaryOp(op, value) => format!("({} {})", op, render(program, *value)),
+            Expression::Cast { value, .. } => format!("({} as _)", render(program, *value)),
+            expression => expression.to_string(&program.program_arena),
+        }
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        assert_eq!(parenthesize("1 + 2 * 3"), "(1 + (2 * 3))");
+        assert_eq!(parenthesize("1 * 2 + 3 * 4"), "((1 * 2) + (3 * 4))");
+        assert_eq!(parenthesize("a * 10000 + b * 100"), "((a * 10000) + (b * 100))");
+        assert_eq!(parenthesize("a - b / c - 1"), "((a - (b / c)) - 1)");
+        assert_eq!(parenthesize("a +% b *% c -| a % b"), "((a +% (b *% c)) -| (a % b))");
+        assert_eq!(parenthesize("(1 + 2) * 3"), "((1 + 2) * 3)");
+    }
+
+    #[test]
+    fn operators_of_a_level_associate_to_the_left() {
+        assert_eq!(parenthesize("a - b + c"), "((a - b) + c)");
+        assert_eq!(parenthesize("a / b * c"), "((a / b) * c)");
+        assert_eq!(parenthesize("a << b >> c"), "((a << b) >> c)");
+    }
+
+    #[test]
+    fn precedence_levels_from_loosest_to_tightest() {
+        assert_eq!(parenthesize("a or b and not c < a | b ^ c & a << b + c * -a as Int8"),
+                   "((a or b) and (not (c < (a | (b ^ (c & (a << (b + (c * ((- a) as _))))))))))");
+        assert_eq!(parenthesize("a * b as Int64 + c"), "((a * (b as _)) + c)");
+        assert_eq!(parenthesize("a == b + 1"), "(a == (b + 1))");
+    }
+    #[test]
+    fn doc_comments_attach_to_what_follows_them() {
+        let source = "/// A point.\nstruct P {\n    /// Across.\n    let x: Int32;\n    let y: Int32;\n}\n\n\
+                      /// Which way.\nenum D {\n    /// Upwards.\n    Up,\n    Down\n}\n\n\
+                      /// Adds one.\n@inline\nfun f(a: Int32): Int32 {\n    return a + 1;\n}\n";
+        let mut parser = Parser::new();
+        let program = parser.parse(Path::of("test"), "test.nuv".to_string(), source.to_string()).expect("the program parses");
+        let doc = |name: &str| {
+            let (node, _) = program.program_arena.node_arena.iter()
+                .find(|(_, node)| match node {
+                    Node::Struct { name: node_name, .. } | Node::Enum { name: node_name, .. } => node_name == name,
+                    Node::Variable { name: typed_name, .. } => typed_name.name == name,
+                    Node::Function(function) => function.name == name,
+                    _ => false,
+                })
+                .unwrap();
+            program.program_arena.node_docs.get(&node).map(String::as_str)
+        };
+        assert_eq!(doc("P"), Some("A point."));
+        assert_eq!(doc("x"), Some("Across."));
+        assert_eq!(doc("y"), None);
+        assert_eq!(doc("D"), Some("Which way."));
+        assert_eq!(doc("f"), Some("Adds one."));
+
+        let variants = program.program_arena.node_arena.iter()
+            .find_map(|(_, node)| match node {
+                Node::Enum { variants, .. } => Some(variants),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(variants[0].doc.as_deref(), Some("Upwards."));
+        assert_eq!(variants[1].doc, None);
+        assert!(!parser.diagnostics.emit_to_string().contains("doc comment"), "{}", parser.diagnostics.emit_to_string());
+    }
+}
@@ -0,0 +1,481 @@
+//! Compiles and runs every program in `tests/programs`, and checks what it printed and what
+//! `main` returned. A program says what it should do in comments at its top:
+//!
+//! ```text
+//! // result: 41
+//! // output: done
+//! ```
+//!
+//! The result is what `main` returns, which executables exit with, so it has to fit in a byte.
+//! Every `output` comment is a line the program prints.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const NUVAE: &str = env!("CARGO_BIN_EXE_nuvae");
+
+/// What running a program did.
+#[derive(Clone, Debug, PartialEq)]
+struct Outcome {
+    result: i64,
+    output: String,
+}
+
+struct Program {
+    name: String,
+    path: PathBuf,
+    expected: Outcome,
+}
+
+fn programs() -> Vec<Program> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "nuv"))
+        .collect();
+    paths.sort();
+    paths.into_iter().map(|path| {
+        let source = std::fs::read_to_string(&path).unwrap();
+        let mut result = None;
+        let mut output = String::new();
+        for line in source.lines() {
+            if let Some(value) = line.strip_prefix("// result: ") {
+                result = Some(value.parse().unwrap());
+            } else if let Some(line) = line.strip_prefix("// output: ") {
+                output.push_str(line);
+                output.push('\n');
+            }
+        }
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        let result = result.unwrap_or_else(|| panic!("{} doesn't say what it returns", path.display()));
+        Program { name, path, expected: Outcome { result, output } }
+    }).collect()
+}
+
+/// Run every program in the corpus through `run`, which returns how each configuration it tried
+/// did, and fail with every one that didn't do what the program expects.
+fn check_corpus(run: impl Fn(&Program) -> Vec<(String, Result<Outcome, String>)> + Sync) {
+    let programs = programs();
+    let run = &run;
+    let failures: Vec<String> = std::thread::scope(|scope| {
+        let runs: Vec<_> = programs.iter().map(|program| scope.spawn(move || (program, run(program)))).collect();
+        runs.into_iter().flat_map(|handle| {
+            let (program, outcomes) = handle.join().unwrap();
+            outcomes.into_iter().filter_map(move |(configuration, outcome)| match outcome {
+                Ok(outcome) if outcome == program.expected => None,
+                Ok(outcome) => Some(format!("{} with {}: expected {:?} but got {:?}", program.name, configuration, program.expected, outcome)),
+                Err(error) => Some(format!("{} with {}: {}", program.name, configuration, error)),
+            }).collect::<Vec<_>>()
+        }).collect()
+    });
+    assert!(failures.is_empty(), "{} failed:\n{}", failures.len(), failures.join("\n"));
+}
+
+fn nuvae(args: &[&str]) -> Result<std::process::Output, String> {
+    let output = Command::new(NUVAE).args(args).output().map_err(|e| format!("couldn't run nuvae: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("nuvae {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr)));
+    }
+    Ok(output)
+}
+
+/// Compile a program to an executable with a backend and run it.
+fn compile_and_run(program: &Program, backend: &str, flags: &[&str]) -> Result<Outcome, String> {
+    let executable = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join(format!("{}-{}{}", program.name, backend, flags.concat()));
+    let backend_flag = format!("--backend={}", backend);
+    let mut args = vec![backend_flag.as_str(), "-o", executable.to_str().unwrap()];
+    args.extend_from_slice(flags);
+    args.push(program.path.to_str().unwrap());
+    nuvae(&args)?;
+
+    let output = Command::new(&executable).output().map_err(|e| format!("couldn't run {}: {}", executable.display(), e))?;
+    let _ = std::fs::remove_file(&executable);
+    match output.status.code() {
+        Some(result) => Ok(Outcome { result: result as i64, output: String::from_utf8_lossy(&output.stdout).to_string() }),
+        None => Err(format!("{} was killed: {}", executable.display(), String::from_utf8_lossy(&output.stderr))),
+    }
+}
+
+/// Run a program with `nuvae run`, which uses the reference interpreter or the LLVM JIT.
+fn run(program: &Program, backend: &str, flags: &[&str]) -> Result<Outcome, String> {
+    let backend_flag = format!("--backend={}", backend);
+    let mut args = vec!["run", backend_flag.as_str()];
+    args.extend_from_slice(flags);
+    args.push(program.path.to_str().unwrap());
+    let output = nuvae(&args)?;
+
+    // the result is printed on a line of its own after what the program printed
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stdout = stdout.strip_suffix('\n').unwrap_or(&stdout);
+    let (output, result) = stdout.split_at(stdout.rfind('\n').map_or(0, |newline| newline + 1));
+    let result = result.parse().map_err(|_| format!("the interpreter printed `{}` instead of a result", result))?;
+    Ok(Outcome { result, output: output.to_string() })
+}
+
+/// Read `len` bytes at `address` from the memory a wasm module exports.
+fn read_wasm_memory<T>(caller: &wasmi::Caller<'_, T>, address: i32, len: i64) -> Vec<u8> {
+    let memory = caller.get_export("memory").and_then(wasmi::Extern::into_memory).expect("the module exports its memory");
+    let mut bytes = vec![0; len as usize];
+    memory.read(caller, address as u32 as usize, &mut bytes).expect("the bytes are in memory");
+    bytes
+}
+
+/// Compile a program to WebAssembly and call its `main` with wasmi. The runtime functions the
+/// module imports from `env` are provided here, and what it prints is kept in the store.
+fn run_wasm(program: &Program, flags: &[&str]) -> Result<Outcome, String> {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}{}.wasm", program.name, flags.concat()));
+    let mut args = vec!["--backend=wasm", "--emit=wasm", "-o", path.to_str().unwrap()];
+    args.extend_from_slice(flags);
+    args.push(program.path.to_str().unwrap());
+    nuvae(&args)?;
+    let bytes = std::fs::read(&path).map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+    let _ = std::fs::remove_file(&path);
+
+    let engine = wasmi::Engine::default();
+    let module = wasmi::Module::new(&engine, &bytes[..]).map_err(|e| e.to_string())?;
+    let mut store = wasmi::Store::new(&engine, Vec::<u8>::new());
+    let mut linker = wasmi::Linker::new(&engine);
+    linker.func_wrap("env", "nuvae_print", |mut caller: wasmi::Caller<'_, Vec<u8>>, data: i32, len: i64| {
+        let bytes = read_wasm_memory(&caller, data, len);
+        caller.data_mut().extend(bytes);
+    }).unwrap();
+    linker.func_wrap("env", "nuvae_panic", |caller: wasmi::Caller<'_, Vec<u8>>, message: i32, len: i64| -> Result<(), wasmi::Error> {
+        let message = read_wasm_memory(&caller, message, len);
+        Err(wasmi::Error::new(format!("panic: {}", String::from_utf8_lossy(&message))))
+    }).unwrap();
+    let instance = linker.instantiate(&mut store, &module)
+        .and_then(|instance| instance.start(&mut store))
+        .map_err(|e| e.to_string())?;
+
+    let main = instance.get_func(&store, "main").ok_or("`main` isn't exported")?;
+    let mut results = vec![wasmi::Val::I32(0); main.ty(&store).results().len()];
+    main.call(&mut store, &[], &mut results).map_err(|e| e.to_string())?;
+    let result = match results.first() {
+        Some(wasmi::Val::I32(result)) => *result as i64,
+        Some(wasmi::Val::I64(result)) => *result,
+        None => 0,
+        Some(result) => return Err(format!("`main` returned {:?}", result)),
+    };
+    Ok(Outcome { result, output: String::from_utf8_lossy(store.data()).to_string() })
+}
+
+#[test]
+fn wasm_modules_validate_and_run() {
+    check_corpus(|program| {
+        ["-O0", "-O2"].iter()
+            .map(|level| (format!("wasm {}", level), run_wasm(program, &[level])))
+            .collect()
+    });
+}
+
+#[test]
+fn backends_agree_with_the_interpreter() {
+    let mut backends = vec!["cranelift", "c"];
+    if cfg!(feature = "llvm") {
+        backends.push("llvm");
+    }
+    check_corpus(|program| {
+        let mut outcomes = vec![];
+        for level in ["-O0", "-O2"] {
+            outcomes.push((format!("the interpreter {}", level), run(program, "interpreter", &[level])));
+            if cfg!(feature = "llvm") {
+                outcomes.push((format!("the jit {}", level), run(program, "llvm", &[level])));
+            }
+            for backend in backends.iter() {
+                outcomes.push((format!("{} {}", backend, level), compile_and_run(program, backend, &[level])));
+            }
+        }
+        outcomes
+    });
+}
+
+#[cfg(feature = "llvm")]
+#[test]
+fn optimization_levels_agree() {
+    check_corpus(|program| {
+        ["-O0", "-O1", "-O2", "-O3", "-Os"].iter()
+            .map(|level| (format!("llvm {}", level), compile_and_run(program, "llvm", &[level])))
+            .collect()
+    });
+}
+
+#[test]
+fn c_output_compiles_without_warnings() {
+    check_corpus(|program| {
+        let source = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.c", program.name));
+        let compiled = nuvae(&["--backend=c", "--emit=c", "-o", source.to_str().unwrap(), program.path.to_str().unwrap()])
+            .and_then(|_| {
+                let output = Command::new("cc")
+                    .args(["-std=c99", "-Wall", "-Werror", "-c", "-o", "/dev/null"])
+                    .arg(&source)
+                    .output()
+                    .map_err(|e| format!("couldn't run cc: {}", e))?;
+                match output.status.success() {
+                    true => Ok(program.expected.clone()),
+                    false => Err(String::from_utf8_lossy(&output.stderr).to_string()),
+                }
+            });
+        let _ = std::fs::remove_file(&source);
+        vec![("cc -Wall -Werror".to_string(), compiled)]
+    });
+}
+
+#[test]
+fn main_gets_the_argument_count() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("argument_count.nuv");
+    std::fs::write(&path, "fun main(count: Int32): Int32 {\n    return count + 40;\n}\n").unwrap();
+    let mut backends = vec!["cranelift", "c"];
+    if cfg!(feature = "llvm") {
+        backends.push("llvm");
+    }
+    for backend in backends {
+        let executable = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("argument_count-{}", backend));
+        nuvae(&[&format!("--backend={}", backend), "-o", executable.to_str().unwrap(), path.to_str().unwrap()]).unwrap();
+        let status = Command::new(&executable).args(["a", "b"]).status().unwrap();
+        assert_eq!(status.code(), Some(43), "with {}", backend);
+    }
+}
+
+#[test]
+fn functions_without_a_result_are_inlined() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs/void_inline.nuv");
+    let output = nuvae(&["run", "--backend=interpreter", "-O2", "--print-after=inline", path.to_str().unwrap()]).unwrap();
+    let ir = String::from_utf8_lossy(&output.stderr);
+    let main = &ir[ir.find("fun main").unwrap()..];
+    assert!(!main.contains("call "), "{}", main);
+}
+
+#[test]
+fn pass_lists_keep_the_required_passes() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("pass_list.nuv");
+    let source = "@tailrec\nfun count(n: Int32, c: Int32): Int32 {\n    if n < 1 {\n        return c;\n    }\n    return count(n - 1, c + 1);\n}\n\n\
+                  fun main(): Int32 {\n    return count(20000, 7) - 20000;\n}\n";
+    std::fs::write(&path, source).unwrap();
+    for passes in ["--passes=sccp", "--passes=", "--passes=dce,tailcall"] {
+        let output = nuvae(&["run", "--backend=interpreter", passes, path.to_str().unwrap()]).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "7\n", "with {}", passes);
+    }
+}
+
+#[cfg(feature = "llvm")]
+#[test]
+fn the_jit_provides_the_runtime() {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("jit_panic.nuv");
+    let source = "fun add(x: Int32, y: Int32): Int32 {\n    return x + y;\n}\n\n\
+                  fun main(): Int32 {\n    print(\"before\");\n    return add(2147483647, 1);\n}\n";
+    std::fs::write(&path, source).unwrap();
+    let output = Command::new(NUVAE).args(["run", "-O0", path.to_str().unwrap()]).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "before");
+    assert!(stderr.contains(&format!("panic: {}:2:12: attempt to add with overflow", path.display())), "{}", stderr);
+}
+
+#[test]
+fn arithmetic_panics_agree() {
+    // (type, operation, operands, overflow checks only, message)
+    let cases = [
+        ("Int32", "-x", "-2147483648, 0", true, "attempt to negate with overflow"),
+        ("Int8", "-x", "-128, 0", true, "attempt to negate with overflow"),
+        ("Int64", "-x", "-9223372036854775808, 0", true, "attempt to negate with overflow"),
+        ("Int32", "x / y", "7, 0", false, "attempt to divide by zero"),
+        ("UInt16", "x % y", "7, 0", false, "attempt to calculate the remainder with a divisor of zero"),
+        ("Int32", "x / y", "-2147483648, -1", false, "attempt to divide with overflow"),
+        ("Int16", "x / y", "-32768, -1", false, "attempt to divide with overflow"),
+        ("Int64", "x % y", "-9223372036854775808, -1", false, "attempt to calculate the remainder with overflow"),
+    ];
+    let mut failures = vec![];
+    for (i, &(typ, operation, operands, checked, message)) in cases.iter().enumerate() {
+        let name = format!("arithmetic_panic_{}", i);
+        let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.nuv", name));
+        let source = format!("fun apply(x: {typ}, y: {typ}): {typ} {{\n    return {operation};\n}}\n\n\
+                              public fun main(): Int32 {{\n    return apply({operands}) as Int32;\n}}\n");
+        std::fs::write(&path, source).unwrap();
+        let program = Program { name, path, expected: Outcome { result: 0, output: String::new() } };
+        let message = format!("{}:2:12: {}", program.path.display(), message);
+
+        let levels: &[&str] = if checked { &["-O0"] } else { &["-O0", "-O2"] };
+        for level in levels {
+            failures.extend(panic_failures(&program, level, &message));
+        }
+    }
+    assert!(failures.is_empty(), "{} failed:\n{}", failures.len(), failures.join("\n"));
+}
+
+#[test]
+fn literal_arithmetic_overflows() {
+    // (binding, message), the literals are added in the type they're bound as
+    let cases = [
+        ("let x: UInt8 = 200 + 100;", "attempt to add with overflow"),
+        ("let x: Int32 = 2147483647 + 1;", "attempt to add with overflow"),
+        ("let x: Int16 = 200 * 200;", "attempt to multiply with overflow"),
+    ];
+    let mut failures = vec![];
+    for (i, &(binding, message)) in cases.iter().enumerate() {
+        let name = format!("literal_overflow_{}", i);
+        let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.nuv", name));
+        let source = format!("public fun main(): Int32 {{\n    {binding}\n    return x as Int32;\n}}\n");
+        std::fs::write(&path, source).unwrap();
+        let program = Program { name, path, expected: Outcome { result: 0, output: String::new() } };
+        let column = binding.find('=').unwrap() + 7;
+        let message = format!("{}:2:{}: {}", program.path.display(), column, message);
+        failures.extend(panic_failures(&program, "-O0", &message));
+    }
+    assert!(failures.is_empty(), "{} failed:\n{}", failures.len(), failures.join("\n"));
+}
+
+#[test]
+fn refinements_dont_remove_bounds_checks() {
+    // the refinement isn't checked when `get` is called, so the index still has to be
+    let name = "refined_index".to_string();
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.nuv", name));
+    let source = "fun get(a: [Int32; 4], i: (i: Int32 where i >= 0 and i < 4)): Int32 {\n    return a[i];\n}\n\n\
+                  public fun main(): Int32 {\n    let a = [1, 2, 3, 4];\n    return get(a, 100000000);\n}\n";
+    std::fs::write(&path, source).unwrap();
+    let program = Program { name, path, expected: Outcome { result: 0, output: String::new() } };
+    let message = format!("{}:2:14: index out of bounds", program.path.display());
+    let failures: Vec<String> = ["-O0", "-O2"].iter().flat_map(|level| panic_failures(&program, level, &message)).collect();
+    assert!(failures.is_empty(), "{} failed:\n{}", failures.len(), failures.join("\n"));
+}
+
+/// Run `program` on every backend at `level`, and describe those that didn't panic with `message`.
+fn panic_failures(program: &Program, level: &str, message: &str) -> Vec<String> {
+    let mut outcomes = vec![
+        (format!("the interpreter {}", level), run(program, "interpreter", &[level])),
+        (format!("cranelift {}", level), compile_and_run(program, "cranelift", &[level])),
+        (format!("c {}", level), compile_and_run(program, "c", &[level])),
+        (format!("wasm {}", level), run_wasm(program, &[level])),
+    ];
+    if cfg!(feature = "llvm") {
+        outcomes.push((format!("the jit {}", level), run(program, "llvm", &[level])));
+        outcomes.push((format!("llvm {}", level), compile_and_run(program, "llvm", &[level])));
+    }
+    outcomes.into_iter()
+        .filter(|(_, outcome)| !matches!(outcome, Err(error) if error.contains(message)))
+        .map(|(configuration, outcome)| format!("{} with {}: expected `{}` but got {:?}", program.name, configuration, message, outcome))
+        .collect()
+}
+
+/// Compile `source` with a backend, which should fail, and return what nuvae reported.
+fn compile_errors(name: &str, source: &str, backend: &str) -> String {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.nuv", name));
+    std::fs::write(&path, source).unwrap();
+    let executable = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let output = Command::new(NUVAE)
+        .args([&format!("--backend={}", backend), "-o", executable.to_str().unwrap(), path.to_str().unwrap()])
+        .output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    assert_eq!(output.status.code(), Some(1), "with {}: {}", backend, stderr);
+    stderr
+}
+
+#[test]
+fn bad_calls_are_reported() {
+    let source = "fun f(x: Int32): Int32 {\n    return x;\n}\n\n\
+                  public fun main(): Int32 {\n    return nope(1) + f(1, 2);\n}\n";
+    let mut backends = vec!["cranelift", "c", "wasm"];
+    if cfg!(feature = "llvm") {
+        backends.push("llvm");
+    }
+    for backend in backends {
+        let stderr = compile_errors("bad_calls", source, backend);
+        assert!(stderr.contains("unknown function `nope`"), "with {}: {}", backend, stderr);
+        assert!(stderr.contains("`f` takes 1 argument"), "with {}: {}", backend, stderr);
+    }
+}
+
+#[test]
+fn print_takes_a_str() {
+    let stderr = compile_errors("bad_print", "public fun main(): Int32 {\n    print(5);\n    return 0;\n}\n", "c");
+    assert!(stderr.contains("`print` takes a `Str`"), "{}", stderr);
+}
+
+#[test]
+fn bad_places_are_reported() {
+    let source = "struct P {\n    let x: Int32;\n}\n\npublic fun main(): Int32 {\n    let p = P { x: 1 };\n    p.z = 3;\n    let x = 1;\n    x.* = 3;\n    let r = (x + 1).&;\n    return p.z;\n}\n";
+    let stderr = compile_errors("bad_places", source, "cranelift");
+    assert_eq!(stderr.matches("struct `P` has no field `z`").count(), 2, "{}", stderr);
+    assert!(stderr.contains("can't dereference this"), "{}", stderr);
+    assert!(stderr.contains("can't borrow this"), "{}", stderr);
+}
+
+#[test]
+fn borrowed_fields_escape() {
+    let program = programs().into_iter().find(|program| program.name == "escaping_fields").unwrap();
+    let output = nuvae(&["run", "--backend=interpreter", "--report-escapes", program.path.to_str().unwrap()]).unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("`new P` in `y_of_new` escapes\n    12:12: a place inside it is borrowed"), "{}", stderr);
+    assert!(stderr.contains("`new P` in `clobber` doesn't escape"), "{}", stderr);
+}
+
+#[test]
+fn shared_borrows_are_read_only() {
+    let source = "struct P {\n    let x: Int32;\n}\n\nfun set(n: &mut Int32) {\n    n.* = 1;\n}\n\n\
+                  fun x_of(p: &P): &mut Int32 {\n    return p.x.&mut;\n}\n\n\
+                  public fun main(): Int32 {\n    let x: Int32 = 1;\n    let r = x.&;\n    r.* = 2;\n    set(x.&);\n    \
+                  let w = x.&mut;\n    w.* = 3;\n    set(w);\n    return x;\n}\n";
+    let stderr = compile_errors("shared_borrows", source, "c");
+    assert!(stderr.contains("can't borrow `&mut` through a `&` reference"), "{}", stderr);
+    assert!(stderr.contains("can't assign through a `&` reference"), "{}", stderr);
+    assert_eq!(stderr.matches("expected a `&mut` reference").count(), 1, "{}", stderr);
+}
+
+#[test]
+fn statements_after_returning_ifs_are_unreachable() {
+    let source = "fun f(x: Int32): Int32 {\n    if x > 1 {\n        return 1;\n    } else {\n        return 2;\n    }\n    let y = 3;\n    return y;\n}\n\n\
+                  fun g(x: Int32): Int32 {\n    if x > 1 {\n        return 1;\n    }\n    return 2;\n}\n\n\
+                  public fun main(): Int32 {\n    return f(2) + g(0);\n}\n";
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("returning_ifs.nuv");
+    std::fs::write(&path, source).unwrap();
+    let output = nuvae(&["run", "--backend=interpreter", path.to_str().unwrap()]).unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("unreachable statement").count(), 2, "{}", stderr);
+    assert!(stderr.contains("every branch of this `if` returns"), "{}", stderr);
+}
+
+#[test]
+fn literals_out_of_range_are_reported() {
+    let source = "public fun main(): Int32 {\n    let a: UInt8 = 256;\n    \
+                  let b: Int128 = 170141183460469231731687303715884105728;\n    return 0;\n}\n";
+    let stderr = compile_errors("literals_out_of_range", source, "c");
+    assert!(stderr.contains("integer literal `256` is out of range for `UInt8`"), "{}", stderr);
+    assert!(stderr.contains("integer literal `170141183460469231731687303715884105728` is out of range for `Int128`"), "{}", stderr);
+}
+
+#[test]
+fn wide_literals_keep_their_bits() {
+    // the C and wasm backends don't support 128-bit integers at all
+    let name = "wide_literals".to_string();
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.nuv", name));
+    let source = "fun top(x: UInt128): Int32 {\n    return (x >> 120) as Int32;\n}\n\n\
+                  public fun main(): Int32 {\n    let a: UInt128 = 340282366920938463463374607431768211455;\n    \
+                  let b: Int128 = -170141183460469231731687303715884105728;\n    let c: UInt128 = 18446744073709551616;\n    \
+                  let d: UInt64 = 18446744073709551615;\n    let r = 0;\n    \
+                  if a == 340282366920938463463374607431768211455 {\n        r = r + 1;\n    }\n    \
+                  if top(a) == 255 {\n        r = r + 2;\n    }\n    \
+                  if (b >> 126) as Int32 == -2 {\n        r = r + 4;\n    }\n    \
+                  if c >> 64 == 1 {\n        r = r + 8;\n    }\n    \
+                  if d == 18446744073709551615 and (d as UInt128) + 1 == c {\n        r = r + 16;\n    }\n    \
+                  if a - c + 1 == 340282366920938463444927863358058659840 {\n        r = r + 32;\n    }\n    \
+                  if a +% 1 == 0 {\n        r = r + 64;\n    }\n    return r;\n}\n";
+    std::fs::write(&path, source).unwrap();
+    let program = Program { name, path, expected: Outcome { result: 127, output: String::new() } };
+    let mut failures = vec![];
+    for level in ["-O0", "-O2"] {
+        let mut outcomes = vec![
+            (format!("the interpreter {}", level), run(&program, "interpreter", &[level])),
+            (format!("cranelift {}", level), compile_and_run(&program, "cranelift", &[level])),
+        ];
+        if cfg!(feature = "llvm") {
+            outcomes.push((format!("the jit {}", level), run(&program, "llvm", &[level])));
+            outcomes.push((format!("llvm {}", level), compile_and_run(&program, "llvm", &[level])));
+        }
+        for (configuration, outcome) in outcomes {
+            if outcome.as_ref() != Ok(&program.expected) {
+                failures.push(format!("{}: {:?}", configuration, outcome));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{} failed:\n{}", failures.len(), failures.join("\n"));
+}